@@ -5,16 +5,20 @@ use std::ffi::CString;
 use std::mem::replace;
 use std::str::FromStr;
 use std::sync::{mpsc, Once};
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::{
+    send_response, Backend, BackendFeature, GeneratedText, GenerationStream, InferError,
+    InferStreamResponse, STREAM_BUFFER_SIZE,
+};
 use text_generation_router::validation::ValidGenerateRequest;
 use text_generation_router::{FinishReason, Token};
 use thiserror::Error;
 use tokenizers::Tokenizer;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedSender};
 use tokio::sync::{oneshot, watch};
 use tokio::task::{spawn, spawn_blocking};
 use tokio::time::{timeout, Duration, Instant};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 use tracing::{debug, error, info, trace, warn};
 
@@ -157,7 +161,7 @@ struct LlamacppRequest {
     penalty_freq: f32,
     penalty_present: f32,
     max_new_tokens: usize,
-    tx: UnboundedSender<Result<InferStreamResponse, InferError>>,
+    tx: Sender<Result<InferStreamResponse, InferError>>,
     time: Instant,
 }
 
@@ -167,9 +171,16 @@ pub struct LlamacppBackend {
 }
 
 impl LlamacppRequest {
+    /// Send a response without waiting for the client to read the previous ones
+    ///
+    /// Returns false when the client is gone or too slow, its sequence should then be stopped.
+    fn send(&self, response: Result<InferStreamResponse, InferError>) -> bool {
+        send_response(&self.tx, response).is_ok()
+    }
+
     fn new(
         from: &ValidGenerateRequest,
-        tx: UnboundedSender<Result<InferStreamResponse, InferError>>,
+        tx: Sender<Result<InferStreamResponse, InferError>>,
     ) -> Option<Self> {
         from.input_ids.as_ref().map(|input_ids| LlamacppRequest {
             input_ids: input_ids.iter().map(|&x| x as i32).collect(),
@@ -513,7 +524,7 @@ impl LlamacppBackend {
                     let sampler = match LlamacppSampler::new(request) {
                         Some(sampler) => sampler,
                         _ => {
-                            let _ = request.tx.send(Err(InferError::IncompleteGeneration));
+                            let _ = request.send(Err(InferError::IncompleteGeneration));
                             continue;
                         }
                     };
@@ -543,9 +554,7 @@ impl LlamacppBackend {
                         warn!("llama_decode failed, clearing kv cache");
                         llamacpp.clear_kv_cache(-1);
                         for seq in seqs.iter_mut() {
-                            let _ = requests[seq.id]
-                                .tx
-                                .send(Err(InferError::IncompleteGeneration));
+                            let _ = requests[seq.id].send(Err(InferError::IncompleteGeneration));
                             seq.running = false;
                         }
                         break;
@@ -562,9 +571,8 @@ impl LlamacppBackend {
                             Ok(piece) => piece,
                             Err(e) => {
                                 error!("Failed to decode token: {e}");
-                                let _ = requests[seq.id]
-                                    .tx
-                                    .send(Err(InferError::IncompleteGeneration));
+                                let _ =
+                                    requests[seq.id].send(Err(InferError::IncompleteGeneration));
                                 seq.running = false;
                                 continue;
                             }
@@ -590,14 +598,16 @@ impl LlamacppBackend {
                             }
                         };
                         if let Some(reason) = finish {
-                            let _ = requests[seq.id].tx.send(Ok(InferStreamResponse::End {
+                            let _ = requests[seq.id].send(Ok(InferStreamResponse::End {
                                 token,
                                 top_tokens: vec![],
+                                top_logprobs: vec![],
                                 generated_text: GeneratedText {
                                     text: seq.text.clone(),
                                     generated_tokens: seq.n_new_tokens as _,
                                     finish_reason: reason,
                                     seed: Some(requests[seq.id].seed as _),
+                                    length_limit: None,
                                 },
                                 start: start_time,
                                 queued: requests[seq.id].time,
@@ -605,12 +615,14 @@ impl LlamacppBackend {
                             seq.running = false;
                             continue;
                         }
-                        let _ = requests[seq.id]
-                            .tx
-                            .send(Ok(InferStreamResponse::Intermediate {
-                                token,
-                                top_tokens: vec![],
-                            }));
+                        let response = InferStreamResponse::Intermediate {
+                            token,
+                            top_tokens: vec![],
+                            top_logprobs: vec![],
+                        };
+                        if !requests[seq.id].send(Ok(response)) {
+                            seq.running = false;
+                        }
                     }
                     // generate a new batch
                     llamacpp.batch.n_tokens = 0;
@@ -644,13 +656,15 @@ impl Backend for LlamacppBackend {
     fn schedule(
         &self,
         request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        // The response channel is closed along with the cancellation, which stops the generation
+        _cancellation: CancellationToken,
+    ) -> Result<GenerationStream, InferError> {
         debug!(?request);
-        let (tx, rx) = unbounded_channel::<Result<InferStreamResponse, InferError>>();
+        let (tx, rx) = channel::<Result<InferStreamResponse, InferError>>(STREAM_BUFFER_SIZE);
         match LlamacppRequest::new(&request, tx) {
             Some(v) => match self.tx.send(v) {
                 Err(e) => Err(InferError::GenerationError(e.to_string())),
-                _ => Ok(UnboundedReceiverStream::new(rx)),
+                _ => Ok(ReceiverStream::new(rx)),
             },
             _ => Err(InferError::GenerationError("Bad request".to_string())),
         }
@@ -663,6 +677,14 @@ impl Backend for LlamacppBackend {
     fn name(&self) -> &'static str {
         "llamacpp"
     }
+
+    fn supports(&self, feature: BackendFeature) -> bool {
+        match feature {
+            // The sampler chain has no grammar sampler, grammars would be silently ignored
+            BackendFeature::Grammar => false,
+            BackendFeature::PrefixCache => false,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -12,6 +12,9 @@ use clap::Parser;
 use hf_hub::api::tokio::ApiBuilder;
 use hf_hub::{Repo, RepoType};
 use std::path::Path;
+use text_generation_router::infer::energy::{EnergyLogFormat, EnergySource, EnergyUnit};
+use text_generation_router::infer::telemetry::TelemetryField;
+use text_generation_router::infer::TenantQuota;
 use text_generation_router::{logging, server, usage_stats};
 use thiserror::Error;
 use tokenizers::Tokenizer;
@@ -154,6 +157,114 @@ struct Args {
     /// Maximum payload size in bytes.
     #[clap(default_value = "2000000", long, env)]
     payload_limit: usize,
+
+    /// Flag outputs longer than this multiple of the rolling average length (reporting only).
+    #[clap(long, env)]
+    length_energy_penalty: Option<f32>,
+
+    /// GPU metrics sampled with every generated token. Disabled when empty.
+    #[clap(long, env, value_enum, value_delimiter = ',')]
+    telemetry_fields: Vec<TelemetryField>,
+
+    /// Requests with at most this many new tokens are interactive, the others are batch.
+    #[clap(long, env)]
+    interactive_max_new_tokens: Option<u32>,
+
+    /// Maximum number of concurrent interactive requests.
+    #[clap(long, env)]
+    max_concurrent_interactive_requests: Option<usize>,
+
+    /// Maximum number of concurrent batch requests.
+    #[clap(long, env)]
+    max_concurrent_batch_requests: Option<usize>,
+
+    /// NVML indices of the devices whose energy is measured, summed for sharded models (defaults to 0).
+    #[clap(long, env, value_delimiter = ',')]
+    energy_devices: Vec<u32>,
+
+    /// Interval between the keep-alive comments sent on idle SSE streams, in milliseconds. Defaults to 15 seconds, 0 disables the keep-alive.
+    #[clap(long, env)]
+    sse_keep_alive_interval_ms: Option<u64>,
+
+    /// Hardware the energy is read from: NVIDIA GPUs with NVML or CPU packages with RAPL.
+    #[clap(default_value = "nvml", long, env, value_enum)]
+    energy_source: EnergySource,
+
+    /// Carbon intensity of the electricity in grams of CO2 per kWh, used to estimate the emissions of each request.
+    #[clap(long, env)]
+    carbon_intensity_g_per_kwh: Option<f64>,
+
+    /// Number of generated tokens between two readings of the energy counter.
+    #[clap(default_value = "1", long, env)]
+    energy_sampling_interval: usize,
+
+    /// Unit of the energies in the responses of `/generate` and `/generate_stream`.
+    #[clap(default_value = "millijoules", long, env, value_enum)]
+    energy_unit: EnergyUnit,
+
+    /// Smoothing factor of the moving average of the tokens per joule, in (0, 1].
+    #[clap(default_value = "0.1", long, env)]
+    efficiency_ewma_alpha: f64,
+
+    /// Maximum size of the inputs in bytes, larger requests are rejected before tokenization.
+    #[clap(default_value = "10000000", long, env)]
+    max_input_bytes: usize,
+
+    /// Tokens per joule below which new requests are rejected until the efficiency recovers, disabled when unset.
+    #[clap(long, env)]
+    efficiency_floor: Option<f64>,
+
+    /// Number of responses kept for the requests sent with an `Idempotency-Key` header, 0 disables the replays.
+    #[clap(default_value = "1024", long, env)]
+    idempotency_cache_size: usize,
+
+    /// Time in seconds during which a request retried with the same `Idempotency-Key` gets the stored response.
+    #[clap(default_value = "600", long, env)]
+    idempotency_ttl_secs: u64,
+
+    /// Concurrency quotas of the tenants, as `api_key=max_concurrent_requests` pairs.
+    #[clap(long, env, value_delimiter = ',')]
+    tenant_quotas: Vec<TenantQuota>,
+
+    /// Concurrency quota shared by the requests without a key of `--tenant-quotas`, which are rejected without it.
+    #[clap(long, env)]
+    default_tenant_quota: Option<usize>,
+
+    /// Bearer token of the `/admin` routes, which are only served when it is set.
+    #[clap(long, env)]
+    admin_api_key: Option<String>,
+
+    /// UUID of the NVIDIA device to measure the energy of, such as a MIG instance (`MIG-...`), instead of the energy devices. MIG instances share the energy counter of their parent GPU.
+    #[clap(long, env)]
+    energy_device_uuid: Option<String>,
+
+    /// File the energy report of every completed request is appended to.
+    #[clap(long, env)]
+    energy_log_path: Option<String>,
+
+    /// Format of the energy log: one JSON report per line, or a binary energy trace.
+    #[clap(default_value = "jsonl", long, env, value_enum)]
+    energy_log_format: EnergyLogFormat,
+
+    /// Port of the gRPC interface, disabled when not set.
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
+
+    /// Number of blocks of 16 tokens remembered to detect the inputs sharing a prefix, prefix caching is disabled when unset.
+    #[clap(long, env)]
+    prefix_cache_blocks: Option<usize>,
+
+    /// Per token energies below this many millijoules are treated as measurement noise and added to the next token, the energy of the requests stays exact.
+    #[clap(long, env)]
+    token_energy_floor_mj: Option<u64>,
+
+    /// Per token energies above this many millijoules are counted as outliers instead of being recorded in the token energy histogram.
+    #[clap(long, env)]
+    token_energy_cap_mj: Option<u64>,
+
+    /// Energy counter readings replayed by the `replay` energy source, in millijoules.
+    #[clap(long, env, value_delimiter = ',')]
+    energy_replay_readings: Vec<u64>,
 }
 
 #[tokio::main]
@@ -201,6 +312,12 @@ async fn main() -> Result<(), RouterError> {
             "`max_batch_size` * `max_total_tokens` must be <= `max_batch_total_tokens`".to_string(),
         ));
     }
+    if !(args.efficiency_ewma_alpha > 0.0 && args.efficiency_ewma_alpha <= 1.0) {
+        return Err(RouterError::ArgumentValidation(format!(
+            "`efficiency_ewma_alpha` must be > 0 and <= 1. Given: {}",
+            args.efficiency_ewma_alpha
+        )));
+    }
 
     let api_builder = || {
         let mut builder = ApiBuilder::new().with_progress(true);
@@ -302,7 +419,8 @@ async fn main() -> Result<(), RouterError> {
         args.max_input_tokens,
         args.max_total_tokens,
         args.validation_workers,
-        None,          // api_key
+        None, // api_key
+        args.admin_api_key,
         args.model_id, // tokenizer_name
         args.tokenizer_config_path,
         Some(args.revision),
@@ -317,6 +435,33 @@ async fn main() -> Result<(), RouterError> {
         args.max_client_batch_size,
         args.usage_stats,
         args.payload_limit,
+        args.length_energy_penalty,
+        args.telemetry_fields,
+        args.interactive_max_new_tokens,
+        args.max_concurrent_interactive_requests,
+        args.max_concurrent_batch_requests,
+        args.energy_devices,
+        args.sse_keep_alive_interval_ms,
+        args.energy_source,
+        args.carbon_intensity_g_per_kwh,
+        args.energy_sampling_interval,
+        args.energy_unit,
+        args.efficiency_ewma_alpha,
+        args.max_input_bytes,
+        args.efficiency_floor,
+        args.idempotency_cache_size,
+        args.idempotency_ttl_secs,
+        args.tenant_quotas,
+        args.default_tenant_quota,
+        args.energy_device_uuid,
+        args.energy_log_path,
+        args.energy_log_format,
+        args.grpc_port,
+        args.prefix_cache_blocks,
+        args.token_energy_floor_mj,
+        args.token_energy_cap_mj,
+        args.energy_replay_readings,
+        None,
     )
     .await?;
     Ok(())
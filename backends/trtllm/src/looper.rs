@@ -5,15 +5,19 @@ use std::hint;
 use std::ops::Deref;
 use std::path::Path;
 use tokenizers::Tokenizer;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
 use tokio::sync::TryAcquireError;
 use tokio::task::spawn_blocking;
 use tokio::time::Instant;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, warn};
 
 use text_generation_router::infer::InferError::{GenerationError, ValidationError};
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::{
+    send_response, Backend, GeneratedText, GenerationStream, InferError, InferStreamResponse,
+    STREAM_BUFFER_SIZE,
+};
 use text_generation_router::validation::ValidationError::{
     EmptyInput, Grammar, TopNTokensDisabled, UnsupportedModality,
 };
@@ -31,7 +35,7 @@ type InferResult<T> = Result<T, InferError>;
 /// Wrap the requests along with the channel used to stream back to the client the decoded tokens
 struct GenerationContext {
     request: ValidGenerateRequest,
-    streamer: UnboundedSender<InferResult<InferStreamResponse>>,
+    streamer: Sender<InferResult<InferStreamResponse>>,
     tokens: Vec<u32>,
     start: Option<Instant>,
     queued: Instant,
@@ -106,7 +110,7 @@ fn executor_status_looper(
                         error!(error = what.as_str(), "Failed to schedule request");
 
                         let err = Err(InferError::Overloaded(TryAcquireError::NoPermits));
-                        if let Err(_) = ctx.streamer.send(err) {
+                        if let Err(_) = send_response(&ctx.streamer, err) {
                             error!("Failed to send back error to the client");
                         }
                     }
@@ -141,8 +145,8 @@ fn executor_status_looper(
                             };
 
                             // Attempt to send back the response to the client
-                            if let Err(_) = ctx.streamer.send(response) {
-                                // Client has dropped, remove from tracked requests
+                            if let Err(_) = send_response(&ctx.streamer, response) {
+                                // Client has dropped or is too slow, remove from tracked requests
                                 debug!(
                                     "Client dropped - removing request {} from tracked requests",
                                     step.request_id
@@ -190,6 +194,7 @@ fn post_process_decoded_token(
                 InferStreamResponse::Intermediate {
                     token,
                     top_tokens: vec![],
+                    top_logprobs: vec![],
                 }
             } else {
                 let text = tokenizer.decode(&ctx.tokens, true);
@@ -198,11 +203,13 @@ fn post_process_decoded_token(
                     generated_tokens: ctx.tokens.len() as u32,
                     finish_reason: decoded_token.finish_reason.into(),
                     seed: None,
+                    length_limit: None,
                 };
 
                 InferStreamResponse::End {
                     token,
                     top_tokens: vec![],
+                    top_logprobs: vec![],
                     generated_text,
                     start: ctx.start.unwrap(),
                     queued: ctx.queued,
@@ -315,11 +322,13 @@ impl Backend for TensorRtLlmBackendV2 {
     fn schedule(
         &self,
         request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        // The response channel is closed along with the cancellation, which stops the generation
+        _cancellation: CancellationToken,
+    ) -> Result<GenerationStream, InferError> {
         Self::validate(&request)?;
 
         // Open-up the stream to send tokens
-        let (streamer, receiver) = unbounded_channel::<InferResult<InferStreamResponse>>();
+        let (streamer, receiver) = channel::<InferResult<InferStreamResponse>>(STREAM_BUFFER_SIZE);
 
         // Send the context to the executor for scheduling
         let queued = Instant::now();
@@ -330,7 +339,7 @@ impl Backend for TensorRtLlmBackendV2 {
             start: None,
             queued,
         }) {
-            Ok(_) => Ok(UnboundedReceiverStream::new(receiver)),
+            Ok(_) => Ok(ReceiverStream::new(receiver)),
             Err(_) => Err(GenerationError(
                 "Failed to submit request to the backend".into(),
             )),
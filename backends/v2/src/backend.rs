@@ -4,13 +4,16 @@ use crate::queue::{Entry, Queue};
 use async_trait::async_trait;
 use nohash_hasher::IntMap;
 use std::sync::Arc;
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::{
+    send_response, Backend, BackendFeature, GeneratedText, GenerationStream, InferError,
+    InferStreamResponse, StreamSendError, STREAM_BUFFER_SIZE,
+};
 use text_generation_router::validation::ValidGenerateRequest;
 use text_generation_router::{FinishReason, PrefillToken, Token};
-use tokio::sync::mpsc::error::SendError;
 use tokio::sync::{mpsc, Notify};
 use tokio::time::Instant;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tracing::{info_span, instrument, Instrument, Span};
 
 pub struct BackendV2 {
@@ -73,9 +76,11 @@ impl Backend for BackendV2 {
     fn schedule(
         &self,
         request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        // The response channel is closed along with the cancellation, which stops the generation
+        _cancellation: CancellationToken,
+    ) -> Result<GenerationStream, InferError> {
         // MPSC channel to communicate with the background batching task
-        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let (response_tx, response_rx) = mpsc::channel(STREAM_BUFFER_SIZE);
 
         // Append the request to the queue
         self.queue.append(Entry {
@@ -92,7 +97,7 @@ impl Backend for BackendV2 {
         self.batching_task_notifier.notify_one();
 
         // Return stream
-        Ok(UnboundedReceiverStream::new(response_rx))
+        Ok(ReceiverStream::new(response_rx))
     }
 
     async fn health(&self, current_health: bool) -> bool {
@@ -112,6 +117,13 @@ impl Backend for BackendV2 {
     fn name(&self) -> &'static str {
         "tgi-v2"
     }
+
+    fn supports(&self, feature: BackendFeature) -> bool {
+        match feature {
+            BackendFeature::Grammar => true,
+            BackendFeature::PrefixCache => false,
+        }
+    }
 }
 
 /// Batching logic
@@ -370,12 +382,15 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
         // Create and enter a span to link this function back to the entry
         let _span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered();
         // Send generation responses back to the infer task
-        // If the receive an error from the Flume channel, it means that the client dropped the
-        // request and we need to stop generating hence why we unwrap_or(true)
-        let stopped = send_responses(generation, entry).inspect_err(|_err| {
-            tracing::error!("Entry response channel error.");
-            metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
-        }).unwrap_or(true);
+        // If we cannot send, it means that the client dropped the request or let its buffer
+        // fill up and we need to stop generating hence why we unwrap_or(true)
+        let stopped = send_responses(generation, entry)
+            .inspect_err(|err| {
+                let err = err.metric_label();
+                tracing::error!("Entry response channel error: {err}.");
+                metrics::counter!("tgi_request_failure", "err" => err).increment(1);
+            })
+            .unwrap_or(true);
         if stopped {
             entries.remove(&id).expect("ID not found in entries. This is a bug.");
         }
@@ -383,10 +398,7 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
 }
 
 /// Send responses through the `entry` response channel
-fn send_responses(
-    generation: Generation,
-    entry: &Entry,
-) -> Result<bool, Box<SendError<Result<InferStreamResponse, InferError>>>> {
+fn send_responses(generation: Generation, entry: &Entry) -> Result<bool, StreamSendError> {
     // Return directly if the channel is disconnected
     if entry.response_tx.is_closed() {
         metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
@@ -407,9 +419,13 @@ fn send_responses(
             .collect();
 
         // Send message
-        entry
-            .response_tx
-            .send(Ok(InferStreamResponse::Prefill(prefill_tokens)))?;
+        send_response(
+            &entry.response_tx,
+            Ok(InferStreamResponse::Prefill {
+                tokens: prefill_tokens,
+                prefill_energy_mj: None,
+            }),
+        )?;
     }
 
     // Create last Token
@@ -431,6 +447,18 @@ fn send_responses(
             logprob,
             special,
         };
+        // The shard returns enough top tokens for both `top_n_tokens` and `top_logprobs`
+        let top_logprobs = if let Some(top_tokens_) = generation.top_tokens.get(i) {
+            top_tokens_
+                .ids
+                .iter()
+                .copied()
+                .zip(top_tokens_.logprobs.iter().copied())
+                .take(entry.request.top_logprobs as usize)
+                .collect()
+        } else {
+            vec![]
+        };
         let top_tokens = if let Some(top_tokens_) = generation.top_tokens.get(i) {
             top_tokens_
                 .ids
@@ -438,6 +466,7 @@ fn send_responses(
                 .zip(top_tokens_.logprobs.iter())
                 .zip(top_tokens_.texts.iter())
                 .zip(top_tokens_.is_special.iter())
+                .take(entry.request.top_n_tokens as usize)
                 .map(|(((&id, &logprob), text), &special)| Token {
                     id,
                     text: text.to_string(),
@@ -453,19 +482,28 @@ fn send_responses(
                 // Generation has ended
                 stopped = true;
                 // Send message
-                entry.response_tx.send(Ok(InferStreamResponse::End {
-                    token,
-                    top_tokens,
-                    generated_text: GeneratedText::from(generated_text.clone()),
-                    queued: entry.queue_time,
-                    start: entry.batch_time.unwrap(),
-                }))?;
+                send_response(
+                    &entry.response_tx,
+                    Ok(InferStreamResponse::End {
+                        token,
+                        top_tokens,
+                        top_logprobs,
+                        generated_text: GeneratedText::from(generated_text.clone()),
+                        queued: entry.queue_time,
+                        start: entry.batch_time.unwrap(),
+                    }),
+                )?;
             }
             _ => {
                 // Send message
-                entry
-                    .response_tx
-                    .send(Ok(InferStreamResponse::Intermediate { token, top_tokens }))?;
+                send_response(
+                    &entry.response_tx,
+                    Ok(InferStreamResponse::Intermediate {
+                        token,
+                        top_tokens,
+                        top_logprobs,
+                    }),
+                )?;
             }
         }
     }
@@ -484,10 +522,8 @@ fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
         tracing::error!("{err}");
 
         // unwrap_or is valid here as we don't care if the receiver is gone.
-        entry
-            .response_tx
-            .send(Err(err))
-            .unwrap_or(());
+        // The error takes the slot kept for the terminal event, even when the buffer is full.
+        send_response(&entry.response_tx, Err(err)).unwrap_or(());
     });
 }
 
@@ -505,6 +541,7 @@ impl From<crate::client::GeneratedText> for GeneratedText {
             generated_tokens: value.generated_tokens,
             finish_reason,
             seed: value.seed,
+            length_limit: None,
         }
     }
 }
@@ -19,7 +19,7 @@ pub(crate) struct Entry {
     /// Request
     pub request: ValidGenerateRequest,
     /// Response sender to communicate between the Infer struct and the batching_task
-    pub response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+    pub response_tx: mpsc::Sender<Result<InferStreamResponse, InferError>>,
     /// Span that will live as long as entry
     pub span: Span,
     /// Temporary span used as a guard when logging inference, wait times...
@@ -293,7 +293,8 @@ impl State {
                 stopping_parameters: Some(StoppingCriteriaParameters::from(
                     entry.request.stopping_parameters.clone(),
                 )),
-                top_n_tokens: entry.request.top_n_tokens,
+                // The raw logprobs are taken from the top tokens of the shard
+                top_n_tokens: entry.request.top_n_tokens.max(entry.request.top_logprobs),
             });
             // Set batch_time
             entry.batch_time = Some(Instant::now());
@@ -402,13 +403,14 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
 mod tests {
     use super::*;
     use std::sync::Arc;
+    use text_generation_router::EnergyPriority;
     use tracing::info_span;
 
     fn default_entry() -> (
         Entry,
-        mpsc::UnboundedReceiver<Result<InferStreamResponse, InferError>>,
+        mpsc::Receiver<Result<InferStreamResponse, InferError>>,
     ) {
-        let (response_tx, receiver_tx) = mpsc::unbounded_channel();
+        let (response_tx, receiver_tx) = mpsc::channel(1);
 
         let entry = Entry {
             request: ValidGenerateRequest {
@@ -418,6 +420,8 @@ mod tests {
                 add_special_tokens: true,
                 truncate: 0,
                 decoder_input_details: false,
+                measure_energy: true,
+                energy_priority: EnergyPriority::Throughput,
                 parameters: ValidParameters {
                     temperature: 0.0,
                     top_k: 0,
@@ -437,6 +441,7 @@ mod tests {
                     stop_sequences: vec![],
                 },
                 top_n_tokens: 0,
+                top_logprobs: 0,
                 adapter_id: None,
             },
             response_tx,
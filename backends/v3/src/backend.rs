@@ -6,13 +6,16 @@ use crate::queue::{Entry, Queue};
 use async_trait::async_trait;
 use nohash_hasher::IntMap;
 use std::sync::Arc;
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::{
+    send_response, Backend, BackendFeature, GeneratedText, GenerationStream, InferError,
+    InferStreamResponse, StreamSendError, STREAM_BUFFER_SIZE,
+};
 use text_generation_router::validation::ValidGenerateRequest;
 use text_generation_router::{FinishReason, PrefillToken, Token};
-use tokio::sync::mpsc::error::SendError;
 use tokio::sync::{mpsc, Notify};
 use tokio::time::Instant;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tracing::{info_span, instrument, Instrument, Span};
 
 pub struct BackendV3 {
@@ -22,6 +25,8 @@ pub struct BackendV3 {
     batching_task_notifier: Arc<Notify>,
     /// Client clone, used for health checks to skip the queue
     client: ShardedClient,
+    /// Whether the shards reuse the KV cache of the prefixes of recent requests
+    prefix_caching: bool,
 }
 
 impl BackendV3 {
@@ -69,6 +74,7 @@ impl BackendV3 {
             queue,
             batching_task_notifier,
             client,
+            prefix_caching: shard_info.use_prefix_caching,
         }
     }
 }
@@ -79,19 +85,22 @@ impl Backend for BackendV3 {
     fn schedule(
         &self,
         request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        cancellation: CancellationToken,
+    ) -> Result<GenerationStream, InferError> {
         // MPSC channel to communicate with the background batching task
-        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let (response_tx, response_rx) = mpsc::channel(STREAM_BUFFER_SIZE);
 
         // Append the request to the queue
         self.queue.append(Entry {
             request,
             response_tx,
+            cancellation,
             span: Span::current(),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
             block_allocation: None,
+            next_seq: 0,
         });
 
         // Notify the background task that we have a new entry in the queue that needs
@@ -99,7 +108,7 @@ impl Backend for BackendV3 {
         self.batching_task_notifier.notify_one();
 
         // Return stream
-        Ok(UnboundedReceiverStream::new(response_rx))
+        Ok(ReceiverStream::new(response_rx))
     }
 
     async fn health(&self, current_health: bool) -> bool {
@@ -119,6 +128,13 @@ impl Backend for BackendV3 {
     fn name(&self) -> &'static str {
         "tgi-v3"
     }
+
+    fn supports(&self, feature: BackendFeature) -> bool {
+        match feature {
+            BackendFeature::Grammar => true,
+            BackendFeature::PrefixCache => self.prefix_caching,
+        }
+    }
 }
 
 /// Batching logic
@@ -426,18 +442,21 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
         // Get entry
         // We can `expect` here as the request id should always be in the entries
         let entry = entries
-            .get(&id)
+            .get_mut(&id)
             .expect("ID not found in entries. This is a bug.");
 
         // Create and enter a span to link this function back to the entry
         let _span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered();
         // Send generation responses back to the infer task
-        // If the receive an error from the Flume channel, it means that the client dropped the
-        // request and we need to stop generating hence why we unwrap_or(true)
-        let stopped = send_responses(generation, entry).inspect_err(|_err| {
-            tracing::error!("Entry response channel error.");
-            metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
-        }).unwrap_or(true);
+        // If we cannot send, it means that the client dropped the request or let its buffer
+        // fill up and we need to stop generating hence why we unwrap_or(true)
+        let stopped = send_responses(generation, entry)
+            .inspect_err(|err| {
+                let err = err.metric_label();
+                tracing::error!("Entry response channel error: {err}.");
+                metrics::counter!("tgi_request_failure", "err" => err).increment(1);
+            })
+            .unwrap_or(true);
         if stopped {
             entries.remove(&id).expect("ID not found in entries. This is a bug.");
         }
@@ -445,12 +464,9 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
 }
 
 /// Send responses through the `entry` response channel
-fn send_responses(
-    generation: Generation,
-    entry: &Entry,
-) -> Result<bool, Box<SendError<Result<InferStreamResponse, InferError>>>> {
-    // Return directly if the channel is disconnected
-    if entry.response_tx.is_closed() {
+fn send_responses(generation: Generation, entry: &mut Entry) -> Result<bool, StreamSendError> {
+    // Return directly if the request was cancelled or the channel is disconnected
+    if entry.is_dropped() {
         metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
         return Ok(true);
     }
@@ -469,9 +485,13 @@ fn send_responses(
             .collect();
 
         // Send message
-        entry
-            .response_tx
-            .send(Ok(InferStreamResponse::Prefill(prefill_tokens)))?;
+        send_response(
+            &entry.response_tx,
+            Ok(InferStreamResponse::Prefill {
+                tokens: prefill_tokens,
+                prefill_energy_mj: None,
+            }),
+        )?;
     }
 
     // Create last Token
@@ -487,12 +507,31 @@ fn send_responses(
         .enumerate()
         .peekable();
     while let Some((i, (((id, logprob), text), special))) = iterator.next() {
+        let seq = Some(entry.next_seq);
+        entry.next_seq += 1;
         let token = Token {
             id,
             text,
             logprob,
             special,
             energy_consumption: None,
+            telemetry: None,
+            continuation_boundary: false,
+            step_energy: None,
+            offsets: None,
+            inter_token_latency_ms: None,
+        };
+        // The shard returns enough top tokens for both `top_n_tokens` and `top_logprobs`
+        let top_logprobs = if let Some(top_tokens_) = generation.top_tokens.get(i) {
+            top_tokens_
+                .ids
+                .iter()
+                .copied()
+                .zip(top_tokens_.logprobs.iter().copied())
+                .take(entry.request.top_logprobs as usize)
+                .collect()
+        } else {
+            vec![]
         };
         let top_tokens = if let Some(top_tokens_) = generation.top_tokens.get(i) {
             top_tokens_
@@ -501,12 +540,18 @@ fn send_responses(
                 .zip(top_tokens_.logprobs.iter())
                 .zip(top_tokens_.texts.iter())
                 .zip(top_tokens_.is_special.iter())
+                .take(entry.request.top_n_tokens as usize)
                 .map(|(((&id, &logprob), text), &special)| Token {
                     id,
                     text: text.to_string(),
                     logprob,
                     special,
                     energy_consumption: None,
+                    telemetry: None,
+                    continuation_boundary: false,
+                    step_energy: None,
+                    offsets: None,
+                    inter_token_latency_ms: None,
                 })
                 .collect()
         } else {
@@ -517,20 +562,35 @@ fn send_responses(
                 // Generation has ended
                 stopped = true;
                 // Send message
-                entry.response_tx.send(Ok(InferStreamResponse::End {
-                    token,
-                    top_tokens,
-                    generated_text: GeneratedText::from(generated_text.clone()),
-                    queued: entry.queue_time,
-                    start: entry.batch_time.unwrap(),
-                    energy_consumption: None,
-                }))?;
+                send_response(
+                    &entry.response_tx,
+                    Ok(InferStreamResponse::End {
+                        token,
+                        top_tokens,
+                        top_logprobs,
+                        generated_text: GeneratedText::from(generated_text.clone()),
+                        queued: entry.queue_time,
+                        start: entry.batch_time.unwrap(),
+                        energy_consumption: None,
+                        prefill_energy: None,
+                        decode_energy: None,
+                        prefill_energy_saved: None,
+                        seq,
+                    }),
+                )?;
             }
             _ => {
                 // Send message
-                entry
-                    .response_tx
-                    .send(Ok(InferStreamResponse::Intermediate { token, top_tokens, energy_consumption: None }))?;
+                send_response(
+                    &entry.response_tx,
+                    Ok(InferStreamResponse::Intermediate {
+                        token,
+                        top_tokens,
+                        top_logprobs,
+                        energy_consumption: None,
+                        seq,
+                    }),
+                )?;
             }
         }
     }
@@ -549,10 +609,8 @@ fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
         tracing::error!("{err}");
 
         // unwrap_or is valid here as we don't care if the receiver is gone.
-        entry
-            .response_tx
-            .send(Err(err))
-            .unwrap_or(());
+        // The error takes the slot kept for the terminal event, even when the buffer is full.
+        send_response(&entry.response_tx, Err(err)).unwrap_or(());
     });
 }
 
@@ -570,6 +628,63 @@ impl From<crate::client::GeneratedText> for GeneratedText {
             generated_tokens: value.generated_tokens,
             finish_reason,
             seed: value.seed,
+            length_limit: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Tokens;
+    use crate::queue::tests::default_entry;
+
+    /// Generation of the single token `id` of the request `0`
+    fn generation(id: u32) -> Generation {
+        Generation {
+            request_id: 0,
+            prefill_tokens: None,
+            tokens: Some(Tokens {
+                ids: vec![id],
+                logprobs: vec![0.0],
+                texts: vec![id.to_string()],
+                is_special: vec![false],
+            }),
+            generated_text: None,
+            top_tokens: vec![],
         }
     }
+
+    #[test]
+    fn test_slow_client_backpressure() {
+        let (mut entry, _) = default_entry();
+        let (response_tx, mut receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+        entry.response_tx = response_tx;
+        entry.temp_span = Some(info_span!("batch"));
+        let mut entries = IntMap::default();
+        entries.insert(0, entry);
+
+        // The client never reads its stream, its generation is stopped once its buffer is full
+        let mut steps = 0;
+        while entries.contains_key(&0) {
+            assert!(steps < 2 * STREAM_BUFFER_SIZE as u32);
+            filter_send_generations(vec![generation(steps)], &mut entries);
+            steps += 1;
+        }
+        assert_eq!(steps, STREAM_BUFFER_SIZE as u32);
+        assert_eq!(receiver.len(), STREAM_BUFFER_SIZE);
+
+        // The buffered tokens are followed by the error taking the last slot
+        for id in 0..STREAM_BUFFER_SIZE as u32 - 1 {
+            match receiver.try_recv().unwrap() {
+                Ok(InferStreamResponse::Intermediate { token, .. }) => assert_eq!(token.id, id),
+                _ => panic!("expected an intermediate response"),
+            }
+        }
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            Err(InferError::SlowClient)
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
 }
@@ -1,4 +1,7 @@
 use clap::{Parser, Subcommand};
+use text_generation_router::infer::energy::{EnergyLogFormat, EnergySource, EnergyUnit};
+use text_generation_router::infer::telemetry::TelemetryField;
+use text_generation_router::infer::TenantQuota;
 use text_generation_router::{server, usage_stats};
 use text_generation_router_v3::{connect_backend, V3Error};
 use thiserror::Error;
@@ -51,6 +54,8 @@ struct Args {
     #[clap(long, env)]
     api_key: Option<String>,
     #[clap(long, env)]
+    admin_api_key: Option<String>,
+    #[clap(long, env)]
     json_output: bool,
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
@@ -72,6 +77,84 @@ struct Args {
     usage_stats: usage_stats::UsageStatsLevel,
     #[clap(default_value = "2000000", long, env)]
     payload_limit: usize,
+
+    #[clap(long, env)]
+    length_energy_penalty: Option<f32>,
+
+    #[clap(long, env, value_enum, value_delimiter = ',')]
+    telemetry_fields: Vec<TelemetryField>,
+
+    #[clap(long, env)]
+    interactive_max_new_tokens: Option<u32>,
+
+    #[clap(long, env)]
+    max_concurrent_interactive_requests: Option<usize>,
+
+    #[clap(long, env)]
+    max_concurrent_batch_requests: Option<usize>,
+
+    #[clap(long, env, value_delimiter = ',')]
+    energy_devices: Vec<u32>,
+
+    #[clap(long, env)]
+    sse_keep_alive_interval_ms: Option<u64>,
+
+    #[clap(default_value = "nvml", long, env, value_enum)]
+    energy_source: EnergySource,
+
+    #[clap(long, env)]
+    carbon_intensity_g_per_kwh: Option<f64>,
+
+    #[clap(default_value = "1", long, env)]
+    energy_sampling_interval: usize,
+
+    #[clap(default_value = "millijoules", long, env, value_enum)]
+    energy_unit: EnergyUnit,
+
+    #[clap(default_value = "0.1", long, env)]
+    efficiency_ewma_alpha: f64,
+
+    #[clap(default_value = "10000000", long, env)]
+    max_input_bytes: usize,
+
+    #[clap(long, env)]
+    efficiency_floor: Option<f64>,
+
+    #[clap(default_value = "1024", long, env)]
+    idempotency_cache_size: usize,
+
+    #[clap(default_value = "600", long, env)]
+    idempotency_ttl_secs: u64,
+
+    #[clap(long, env, value_delimiter = ',')]
+    tenant_quotas: Vec<TenantQuota>,
+
+    #[clap(long, env)]
+    default_tenant_quota: Option<usize>,
+
+    #[clap(long, env)]
+    energy_device_uuid: Option<String>,
+
+    #[clap(long, env)]
+    energy_log_path: Option<String>,
+
+    #[clap(default_value = "jsonl", long, env, value_enum)]
+    energy_log_format: EnergyLogFormat,
+
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
+
+    #[clap(long, env)]
+    prefix_cache_blocks: Option<usize>,
+
+    #[clap(long, env)]
+    token_energy_floor_mj: Option<u64>,
+
+    #[clap(long, env)]
+    token_energy_cap_mj: Option<u64>,
+
+    #[clap(long, env, value_delimiter = ',')]
+    energy_replay_readings: Vec<u64>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -106,6 +189,7 @@ async fn main() -> Result<(), RouterError> {
         trust_remote_code,
         validation_workers,
         api_key,
+        admin_api_key,
         json_output,
         otlp_endpoint,
         otlp_service_name,
@@ -117,6 +201,32 @@ async fn main() -> Result<(), RouterError> {
         max_client_batch_size,
         usage_stats,
         payload_limit,
+        length_energy_penalty,
+        telemetry_fields,
+        interactive_max_new_tokens,
+        max_concurrent_interactive_requests,
+        max_concurrent_batch_requests,
+        energy_devices,
+        sse_keep_alive_interval_ms,
+        energy_source,
+        carbon_intensity_g_per_kwh,
+        energy_sampling_interval,
+        energy_unit,
+        efficiency_ewma_alpha,
+        max_input_bytes,
+        efficiency_floor,
+        idempotency_cache_size,
+        idempotency_ttl_secs,
+        tenant_quotas,
+        default_tenant_quota,
+        energy_device_uuid,
+        energy_log_path,
+        energy_log_format,
+        grpc_port,
+        prefix_cache_blocks,
+        token_energy_floor_mj,
+        token_energy_cap_mj,
+        energy_replay_readings,
     } = args;
 
     if let Some(Commands::PrintSchema) = command {
@@ -134,6 +244,11 @@ async fn main() -> Result<(), RouterError> {
             "`validation_workers` must be > 0".to_string(),
         ));
     }
+    if !(efficiency_ewma_alpha > 0.0 && efficiency_ewma_alpha <= 1.0) {
+        return Err(RouterError::ArgumentValidation(format!(
+            "`efficiency_ewma_alpha` must be > 0 and <= 1. Given: {efficiency_ewma_alpha}"
+        )));
+    }
     if let Some(max_batch_size) = max_batch_size {
         if max_batch_size == 0 {
             return Err(RouterError::ArgumentValidation(
@@ -200,6 +315,7 @@ async fn main() -> Result<(), RouterError> {
         max_total_tokens,
         validation_workers,
         api_key,
+        admin_api_key,
         tokenizer_name,
         tokenizer_config_path,
         revision,
@@ -214,6 +330,33 @@ async fn main() -> Result<(), RouterError> {
         max_client_batch_size,
         usage_stats,
         payload_limit,
+        length_energy_penalty,
+        telemetry_fields,
+        interactive_max_new_tokens,
+        max_concurrent_interactive_requests,
+        max_concurrent_batch_requests,
+        energy_devices,
+        sse_keep_alive_interval_ms,
+        energy_source,
+        carbon_intensity_g_per_kwh,
+        energy_sampling_interval,
+        energy_unit,
+        efficiency_ewma_alpha,
+        max_input_bytes,
+        efficiency_floor,
+        idempotency_cache_size,
+        idempotency_ttl_secs,
+        tenant_quotas,
+        default_tenant_quota,
+        energy_device_uuid,
+        energy_log_path,
+        energy_log_format,
+        grpc_port,
+        prefix_cache_blocks,
+        token_energy_floor_mj,
+        token_energy_cap_mj,
+        energy_replay_readings,
+        None,
     )
     .await?;
     Ok(())
@@ -14,6 +14,7 @@ use text_generation_router::validation::{
 };
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tracing::{info_span, instrument, Instrument, Span};
 
 /// Queue entry
@@ -22,7 +23,9 @@ pub(crate) struct Entry {
     /// Request
     pub request: ValidGenerateRequest,
     /// Response sender to communicate between the Infer struct and the batching_task
-    pub response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+    pub response_tx: mpsc::Sender<Result<InferStreamResponse, InferError>>,
+    /// Cancelled when the router no longer needs the generation
+    pub cancellation: CancellationToken,
     /// Span that will live as long as entry
     pub span: Span,
     /// Temporary span used as a guard when logging inference, wait times...
@@ -33,6 +36,15 @@ pub(crate) struct Entry {
     pub batch_time: Option<Instant>,
     /// Block Allocation
     pub block_allocation: Option<BlockAllocation>,
+    /// Sequence number of the next generated token, the router restores their order with it
+    pub next_seq: u32,
+}
+
+impl Entry {
+    /// Whether the request was dropped by the client
+    pub(crate) fn is_dropped(&self) -> bool {
+        self.cancellation.is_cancelled() || self.response_tx.is_closed()
+    }
 }
 
 /// Request Queue
@@ -273,7 +285,7 @@ impl State {
         'entry_loop: while let Some((id, entry)) = self.entries.pop_front() {
             // Filter entries where the response receiver was dropped (== entries where the request
             // was dropped by the client)
-            if entry.response_tx.is_closed() {
+            if entry.is_dropped() {
                 metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
                 tracing::debug!("Dropping entry");
                 continue;
@@ -451,7 +463,8 @@ impl State {
                 stopping_parameters: Some(StoppingCriteriaParameters::from(
                     entry.request.stopping_parameters.clone(),
                 )),
-                top_n_tokens: entry.request.top_n_tokens,
+                // The raw logprobs are taken from the top tokens of the shard
+                top_n_tokens: entry.request.top_n_tokens.max(entry.request.top_logprobs),
                 blocks,
                 slots,
                 cache_len: prefix_len,
@@ -537,17 +550,18 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::sync::Arc;
 
     use super::*;
+    use text_generation_router::EnergyPriority;
     use tracing::info_span;
 
-    fn default_entry() -> (
+    pub(crate) fn default_entry() -> (
         Entry,
-        mpsc::UnboundedReceiver<Result<InferStreamResponse, InferError>>,
+        mpsc::Receiver<Result<InferStreamResponse, InferError>>,
     ) {
-        let (response_tx, receiver_tx) = mpsc::unbounded_channel();
+        let (response_tx, receiver_tx) = mpsc::channel(1);
 
         let entry = Entry {
             request: ValidGenerateRequest {
@@ -557,6 +571,8 @@ mod tests {
                 add_special_tokens: true,
                 truncate: 0,
                 decoder_input_details: false,
+                measure_energy: true,
+                energy_priority: EnergyPriority::Throughput,
                 parameters: ValidParameters {
                     temperature: 0.0,
                     top_k: 0,
@@ -576,14 +592,17 @@ mod tests {
                     stop_sequences: vec![],
                 },
                 top_n_tokens: 0,
+                top_logprobs: 0,
                 adapter_id: None,
             },
             response_tx,
+            cancellation: CancellationToken::new(),
             span: info_span!("entry"),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
             block_allocation: None,
+            next_seq: 0,
         };
         (entry, receiver_tx)
     }
@@ -22,5 +22,18 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("cargo:rustc-env=DOCKER_LABEL={label}");
     }
 
+    // gRPC interface of the router
+    println!("cargo:rerun-if-changed=../proto/router/");
+    let mut config = prost_build::Config::new();
+    config.protoc_arg("--experimental_allow_proto3_optional");
+    tonic_build::configure()
+        .build_client(false)
+        .build_server(true)
+        .compile_with_config(
+            config,
+            &["../proto/router/v1/generate.proto"],
+            &["../proto"],
+        )?;
+
     Ok(())
 }
@@ -1,8 +1,11 @@
 use crate::{
-    infer::InferError, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionDelta,
-    ChatCompletionLogprobs, CompletionType, DeltaToolCall, Function, FunctionDefinition,
+    infer::{energy::tokens_per_joule, InferError},
+    ChatCompletionChoice, ChatCompletionChunk, ChatCompletionDelta, ChatCompletionLogprobs,
+    ChunkEnergy, CompletionType, DeltaToolCall, FinishReason, Function, FunctionDefinition,
     StreamOptions, StreamResponse, TextMessage, ToolCallDelta, Usage,
 };
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::Deserialize;
 use serde_json::Value;
 
@@ -30,13 +33,47 @@ pub(crate) enum ChatChoice {
     ToolCalls(Vec<crate::ToolCall>),
 }
 
-pub(crate) fn parse_output(generated_text: &str) -> Result<ChatChoice, InferError> {
-    let call: Call = serde_json::from_str(generated_text).map_err(|e| {
-        InferError::ToolError(format!(
-            "Failed to parse generated text: {} {:?}",
-            e, generated_text
-        ))
-    })?;
+/// Matches the tool name generated by the tool grammar
+static TOOL_NAME: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#""_name"\s*:\s*"((?:[^"\\]|\\.)*)"\s*,?"#).unwrap());
+
+/// Recover the name and the partial arguments of a tool call cut off by the token limit
+fn parse_truncated_output(generated_text: &str) -> Option<(String, String)> {
+    let captures = TOOL_NAME.captures(generated_text)?;
+    let name = serde_json::from_str(&format!("\"{}\"", &captures[1])).ok()?;
+    let rest = generated_text[captures.get(0)?.end()..].trim_start();
+    Some((name, format!("{{{rest}")))
+}
+
+pub(crate) fn parse_output(
+    generated_text: &str,
+    finish_reason: Option<&FinishReason>,
+) -> Result<ChatChoice, InferError> {
+    let call: Call = match serde_json::from_str(generated_text) {
+        Ok(call) => call,
+        Err(e) => {
+            let truncated = matches!(finish_reason, Some(FinishReason::Length))
+                .then(|| parse_truncated_output(generated_text))
+                .flatten();
+            return match truncated {
+                Some((name, _)) if name == "no_tool" => Ok(ChatChoice::NoTool),
+                Some((name, arguments)) => Ok(ChatChoice::ToolCalls(vec![crate::ToolCall {
+                    id: "0".to_string(),
+                    r#type: "function".to_string(),
+                    function: FunctionDefinition {
+                        description: None,
+                        name,
+                        arguments: Value::String(arguments),
+                    },
+                    truncated: true,
+                }])),
+                None => Err(InferError::ToolError(format!(
+                    "Failed to parse generated text: {} {:?}",
+                    e, generated_text
+                ))),
+            };
+        }
+    };
     let name = call.function._name;
 
     match &name[..] {
@@ -57,6 +94,7 @@ pub(crate) fn parse_output(generated_text: &str) -> Result<ChatChoice, InferErro
                         ))
                     })?,
                 },
+                truncated: false,
             }];
             Ok(ChatChoice::ToolCalls(tool_calls))
         }
@@ -262,6 +300,20 @@ impl ChatState {
             }
         }
 
+        if self.options.include_energy {
+            let energy = ChunkEnergy {
+                energy_consumption: stream_token.energy_consumption,
+                tokens_per_joule: stream_token.details.as_ref().and_then(|details| {
+                    tokens_per_joule(details.generated_tokens, stream_token.energy_consumption)
+                }),
+            };
+            for event in events.iter_mut() {
+                if let CompletionType::ChatCompletionChunk(chunk) = event {
+                    chunk.energy = Some(energy.clone());
+                }
+            }
+        }
+
         if self.options.include_usage {
             if let Some(details) = stream_token.details {
                 let completion_tokens = details.generated_tokens;
@@ -272,6 +324,7 @@ impl ChatState {
                     completion_tokens,
                     prompt_tokens,
                     total_tokens,
+                    energy_consumption: stream_token.energy_consumption,
                 };
                 let current_time = std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
@@ -288,7 +341,9 @@ impl ChatState {
                         prompt_tokens: usage.prompt_tokens,
                         completion_tokens: usage.completion_tokens,
                         total_tokens: usage.total_tokens,
+                        energy_consumption: usage.energy_consumption,
                     }),
+                    energy: None,
                 });
 
                 events.push(chat_complete);
@@ -300,6 +355,7 @@ impl ChatState {
 
 #[cfg(test)]
 mod tests {
+    use crate::infer::energy::EnergyUnit;
     use crate::{
         ChatCompletionChoice, ChatCompletionDelta, FinishReason, StreamDetails, TextMessage, Token,
     };
@@ -340,6 +396,7 @@ mod tests {
             false,
             StreamOptions {
                 include_usage: false,
+                include_energy: false,
             },
             "fingerprint".to_string(),
             "model_id".to_string(),
@@ -354,10 +411,23 @@ mod tests {
                 text: "Hi".to_string(),
                 logprob: 0.0,
                 special: false,
+                energy_consumption: None,
+                telemetry: None,
+                continuation_boundary: false,
+                step_energy: None,
+                offsets: None,
+                inter_token_latency_ms: None,
             },
             top_tokens: vec![],
             index: 0,
             details: None,
+            energy_consumption: None,
+            prefill_energy: None,
+            decode_energy: None,
+            prefill_energy_saved_estimate: None,
+            long_output_warning: false,
+            energy_unit: EnergyUnit::Millijoules,
+            metadata: None,
         });
         if let ChatEvent::Events(events) = events {
             assert_eq!(events.len(), 1);
@@ -390,6 +460,7 @@ mod tests {
             false,
             StreamOptions {
                 include_usage: true,
+                include_energy: false,
             },
             "fingerprint".to_string(),
             "model_id".to_string(),
@@ -404,6 +475,12 @@ mod tests {
                 text: "Hi".to_string(),
                 logprob: 0.0,
                 special: false,
+                energy_consumption: None,
+                telemetry: None,
+                continuation_boundary: false,
+                step_energy: None,
+                offsets: None,
+                inter_token_latency_ms: None,
             },
             top_tokens: vec![],
             index: 0,
@@ -412,7 +489,15 @@ mod tests {
                 generated_tokens: 10,
                 seed: None,
                 finish_reason: FinishReason::Length,
+                length_limit: None,
             }),
+            energy_consumption: None,
+            prefill_energy: None,
+            decode_energy: None,
+            prefill_energy_saved_estimate: None,
+            long_output_warning: false,
+            energy_unit: EnergyUnit::Millijoules,
+            metadata: None,
         });
         if let ChatEvent::Events(events) = events {
             assert_eq!(events.len(), 2);
@@ -443,6 +528,7 @@ mod tests {
                             prompt_tokens: 2,
                             completion_tokens: 10,
                             total_tokens: 12,
+                            energy_consumption: None,
                         })
                     );
                 }
@@ -459,6 +545,7 @@ mod tests {
             true,
             StreamOptions {
                 include_usage: true,
+                include_energy: false,
             },
             "fingerprint".to_string(),
             "model_id".to_string(),
@@ -500,10 +587,23 @@ mod tests {
                     text: text.to_string(),
                     logprob: 0.0,
                     special: false,
+                    energy_consumption: None,
+                    telemetry: None,
+                    continuation_boundary: false,
+                    step_energy: None,
+                    offsets: None,
+                    inter_token_latency_ms: None,
                 },
                 top_tokens: vec![],
                 index: 0,
                 details: None,
+                energy_consumption: None,
+                prefill_energy: None,
+                decode_energy: None,
+                prefill_energy_saved_estimate: None,
+                long_output_warning: false,
+                energy_unit: EnergyUnit::Millijoules,
+                metadata: None,
             })
             .collect();
 
@@ -532,6 +632,7 @@ mod tests {
             true,
             StreamOptions {
                 include_usage: true,
+                include_energy: false,
             },
             "fingerprint".to_string(),
             "model_id".to_string(),
@@ -566,10 +667,23 @@ mod tests {
                     text: text.to_string(),
                     logprob: 0.0,
                     special: false,
+                    energy_consumption: None,
+                    telemetry: None,
+                    continuation_boundary: false,
+                    step_energy: None,
+                    offsets: None,
+                    inter_token_latency_ms: None,
                 },
                 top_tokens: vec![],
                 index: 0,
                 details: None,
+                energy_consumption: None,
+                prefill_energy: None,
+                decode_energy: None,
+                prefill_energy_saved_estimate: None,
+                long_output_warning: false,
+                energy_unit: EnergyUnit::Millijoules,
+                metadata: None,
             })
             .collect();
 
@@ -598,6 +712,7 @@ mod tests {
             true,
             StreamOptions {
                 include_usage: true,
+                include_energy: false,
             },
             "fingerprint".to_string(),
             "model_id".to_string(),
@@ -646,10 +761,23 @@ mod tests {
                     text: text.to_string(),
                     logprob: 0.0,
                     special: false,
+                    energy_consumption: None,
+                    telemetry: None,
+                    continuation_boundary: false,
+                    step_energy: None,
+                    offsets: None,
+                    inter_token_latency_ms: None,
                 },
                 top_tokens: vec![],
                 index: 0,
                 details: None,
+                energy_consumption: None,
+                prefill_energy: None,
+                decode_energy: None,
+                prefill_energy_saved_estimate: None,
+                long_output_warning: false,
+                energy_unit: EnergyUnit::Millijoules,
+                metadata: None,
             })
             .collect();
 
@@ -697,4 +825,150 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_parse_truncated_tool_call() {
+        let generated_text =
+            r#"{"function": {"_name": "get_current_weather", "location": "San Francisco, C"#;
+
+        let choice = parse_output(generated_text, Some(&FinishReason::Length)).unwrap();
+        let ChatChoice::ToolCalls(tool_calls) = choice else {
+            panic!("Expected a tool call");
+        };
+        assert_eq!(tool_calls.len(), 1);
+        assert!(tool_calls[0].truncated);
+        assert_eq!(tool_calls[0].function.name, "get_current_weather");
+        assert_eq!(
+            tool_calls[0].function.arguments,
+            Value::String(r#"{"location": "San Francisco, C"#.to_string())
+        );
+
+        // Partial arguments are returned verbatim
+        let serialized = serde_json::to_value(&tool_calls[0]).unwrap();
+        assert_eq!(
+            serialized["function"]["arguments"],
+            r#"{"location": "San Francisco, C"#
+        );
+        assert_eq!(serialized["truncated"], true);
+
+        // Invalid output that was not cut by the token limit is still an error
+        assert!(matches!(
+            parse_output(generated_text, Some(&FinishReason::EndOfSequenceToken)),
+            Err(InferError::ToolError(_))
+        ));
+        // Nothing can be recovered before the tool name is generated
+        assert!(matches!(
+            parse_output(r#"{"function": {"_na"#, Some(&FinishReason::Length)),
+            Err(InferError::ToolError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_complete_tool_call() {
+        let generated_text =
+            r#"{"function": {"_name": "get_current_weather", "location": "Paris"}}"#;
+        let ChatChoice::ToolCalls(tool_calls) =
+            parse_output(generated_text, Some(&FinishReason::Length)).unwrap()
+        else {
+            panic!("Expected a tool call");
+        };
+        assert!(!tool_calls[0].truncated);
+        assert_eq!(
+            tool_calls[0].function.arguments,
+            serde_json::json!({"location": "Paris"})
+        );
+    }
+
+    #[test]
+    fn test_chat_stream_energy() {
+        let stream_token = |text: &str, energy_consumption, details| StreamResponse {
+            generated_text: None,
+            token: Token {
+                id: 42,
+                text: text.to_string(),
+                logprob: 0.0,
+                special: false,
+                energy_consumption: None,
+                telemetry: None,
+                continuation_boundary: false,
+                step_energy: None,
+                offsets: None,
+                inter_token_latency_ms: None,
+            },
+            top_tokens: vec![],
+            index: 0,
+            details,
+            energy_consumption,
+            prefill_energy: None,
+            decode_energy: None,
+            prefill_energy_saved_estimate: None,
+            long_output_warning: false,
+            energy_unit: EnergyUnit::Millijoules,
+            metadata: None,
+        };
+        let details = StreamDetails {
+            input_length: 2,
+            generated_tokens: 10,
+            seed: None,
+            finish_reason: FinishReason::Length,
+            length_limit: None,
+        };
+        let energies = |events: ChatEvent| match events {
+            ChatEvent::Events(events) => events
+                .into_iter()
+                .map(|event| match event {
+                    CompletionType::ChatCompletionChunk(chunk) => chunk.energy,
+                    _ => panic!("Unexpected chunk"),
+                })
+                .collect::<Vec<_>>(),
+            ChatEvent::NoTool => panic!("Expected chat events"),
+        };
+
+        let mut chat_state = ChatState::new(
+            false,
+            StreamOptions {
+                include_usage: false,
+                include_energy: true,
+            },
+            "fingerprint".to_string(),
+            "model_id".to_string(),
+            false,
+            "0".to_string(),
+        );
+        let events = chat_state.push(stream_token("Hi", Some(1_200), None));
+        assert_eq!(
+            energies(events),
+            vec![Some(ChunkEnergy {
+                energy_consumption: Some(1_200),
+                tokens_per_joule: None,
+            })]
+        );
+        let events = chat_state.push(stream_token("!", Some(4_000), Some(details.clone())));
+        assert_eq!(
+            energies(events),
+            vec![Some(ChunkEnergy {
+                energy_consumption: Some(4_000),
+                tokens_per_joule: Some(2.5),
+            })]
+        );
+
+        // Without the flag the chunks stay OpenAI compatible
+        let mut chat_state = ChatState::new(
+            false,
+            StreamOptions {
+                include_usage: false,
+                include_energy: false,
+            },
+            "fingerprint".to_string(),
+            "model_id".to_string(),
+            false,
+            "0".to_string(),
+        );
+        let events = chat_state.push(stream_token("Hi", Some(4_000), Some(details)));
+        let ChatEvent::Events(events) = events else {
+            panic!("Expected chat events");
+        };
+        let serialized = serde_json::to_value(&events[0]).unwrap();
+        assert!(serialized.get("energy").is_none());
+    }
 }
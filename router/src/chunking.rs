@@ -0,0 +1,180 @@
+use crate::{Chunking, Token, TokenOffsets};
+
+/// Group the streamed tokens into words or sentences
+///
+/// The tokens of a chunk are merged into a single token: the text is concatenated, the id is the
+/// one of the last token, and the logprobs, energies and latencies are summed over the chunk.
+#[derive(Debug)]
+pub(crate) struct TokenChunker {
+    chunking: Chunking,
+    /// Tokens of the chunk being built, merged
+    buffer: Option<Token>,
+}
+
+impl TokenChunker {
+    /// `None` when every token is streamed on its own
+    pub(crate) fn new(chunking: Chunking) -> Option<Self> {
+        match chunking {
+            Chunking::None => None,
+            Chunking::Word | Chunking::Sentence => Some(Self {
+                chunking,
+                buffer: None,
+            }),
+        }
+    }
+
+    /// Add a token, returns the chunks it completes
+    pub(crate) fn push(&mut self, token: Token) -> Vec<Token> {
+        let mut chunks = Vec::new();
+        // Tokenizers often put the space before a word, which then ends the previous one
+        if self.chunking == Chunking::Word && token.text.starts_with(char::is_whitespace) {
+            chunks.extend(self.flush());
+        }
+        let buffer = match self.buffer.take() {
+            Some(buffer) => merge(buffer, token),
+            None => token,
+        };
+        if self.ends_chunk(&buffer.text) {
+            chunks.push(buffer);
+        } else {
+            self.buffer = Some(buffer);
+        }
+        chunks
+    }
+
+    /// Add the last token of the stream, returns the chunks it completes before the last chunk,
+    /// which holds everything left in the buffer
+    pub(crate) fn finish(&mut self, token: Token) -> (Vec<Token>, Token) {
+        let mut chunks = self.push(token);
+        let last = match self.flush() {
+            Some(last) => last,
+            None => chunks
+                .pop()
+                .expect("the token is either buffered or in a chunk"),
+        };
+        (chunks, last)
+    }
+
+    /// The chunk still being built
+    fn flush(&mut self) -> Option<Token> {
+        self.buffer.take()
+    }
+
+    fn ends_chunk(&self, text: &str) -> bool {
+        match self.chunking {
+            Chunking::None => true,
+            Chunking::Word => text.ends_with(char::is_whitespace),
+            Chunking::Sentence => {
+                text.ends_with('\n') || text.trim_end().ends_with(['.', '!', '?'])
+            }
+        }
+    }
+}
+
+fn merge(chunk: Token, token: Token) -> Token {
+    Token {
+        id: token.id,
+        text: chunk.text + &token.text,
+        logprob: chunk.logprob + token.logprob,
+        special: chunk.special && token.special,
+        energy_consumption: sum(chunk.energy_consumption, token.energy_consumption),
+        telemetry: token.telemetry.or(chunk.telemetry),
+        continuation_boundary: chunk.continuation_boundary || token.continuation_boundary,
+        step_energy: sum(chunk.step_energy, token.step_energy),
+        offsets: match (chunk.offsets, token.offsets) {
+            (Some(first), Some(last)) => Some(TokenOffsets {
+                start: first.start,
+                stop: last.stop,
+            }),
+            (first, last) => first.or(last),
+        },
+        inter_token_latency_ms: sum(chunk.inter_token_latency_ms, token.inter_token_latency_ms),
+    }
+}
+
+/// Sum of the values that are set, `None` when neither is
+fn sum<T: std::ops::Add<Output = T> + Default>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(id: u32, text: &str, energy_consumption: Option<u64>) -> Token {
+        Token {
+            id,
+            text: text.to_string(),
+            logprob: -0.5,
+            special: false,
+            energy_consumption,
+            telemetry: None,
+            continuation_boundary: false,
+            step_energy: None,
+            offsets: None,
+            inter_token_latency_ms: None,
+        }
+    }
+
+    fn texts(chunker: &mut TokenChunker, tokens: &[&str]) -> Vec<String> {
+        let mut chunks: Vec<String> = Vec::new();
+        for (id, text) in tokens.iter().enumerate() {
+            chunks.extend(
+                chunker
+                    .push(token(id as u32, text, None))
+                    .into_iter()
+                    .map(|token| token.text),
+            );
+        }
+        chunks.extend(chunker.flush().map(|token| token.text));
+        chunks
+    }
+
+    #[test]
+    fn test_chunk_words() {
+        assert!(TokenChunker::new(Chunking::None).is_none());
+
+        let mut chunker = TokenChunker::new(Chunking::Word).unwrap();
+        assert_eq!(
+            texts(&mut chunker, &["Hel", "lo", " wor", "ld", "! ", "Bye"]),
+            ["Hello", " world! ", "Bye"]
+        );
+    }
+
+    #[test]
+    fn test_chunk_sentences() {
+        let mut chunker = TokenChunker::new(Chunking::Sentence).unwrap();
+        assert_eq!(
+            texts(
+                &mut chunker,
+                &["Hi", " there", ".", " How", " are", " you", "?\n", "Fine"]
+            ),
+            ["Hi there.", " How are you?\n", "Fine"]
+        );
+    }
+
+    #[test]
+    fn test_chunk_energy() {
+        let mut chunker = TokenChunker::new(Chunking::Word).unwrap();
+        assert!(chunker.push(token(1, "Hel", Some(10))).is_empty());
+        assert!(chunker.push(token(2, "l", None)).is_empty());
+        let chunk = chunker.push(token(3, "o ", Some(5))).pop().unwrap();
+        assert_eq!(chunk.id, 3);
+        assert_eq!(chunk.text, "Hello ");
+        assert_eq!(chunk.logprob, -1.5);
+        assert_eq!(chunk.energy_consumption, Some(15));
+
+        // The last chunk ends with the last token, even in the middle of a word
+        assert!(chunker.push(token(4, "By", Some(1))).is_empty());
+        let (chunks, last) = chunker.finish(token(5, "e", Some(2)));
+        assert!(chunks.is_empty());
+        assert_eq!(last.text, "Bye");
+        assert_eq!(last.energy_consumption, Some(3));
+        let (chunks, last) = chunker.finish(token(6, " end", None));
+        assert!(chunks.is_empty());
+        assert_eq!(last.text, " end");
+    }
+}
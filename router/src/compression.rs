@@ -0,0 +1,245 @@
+use async_stream::stream;
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::http::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use futures::StreamExt;
+use std::io::Write;
+
+/// Responses with a known length below this many bytes are not worth compressing
+const MIN_COMPRESSED_LENGTH: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Preferred encoding accepted by the client, gzip on ties
+    fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+        for value in headers.get_all(ACCEPT_ENCODING) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for item in value.split(',') {
+                let mut parts = item.split(';').map(str::trim);
+                let encoding = match parts.next() {
+                    Some(name) if name.eq_ignore_ascii_case("gzip") => Encoding::Gzip,
+                    Some(name) if name.eq_ignore_ascii_case("deflate") => Encoding::Deflate,
+                    _ => continue,
+                };
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                let better = match best {
+                    None => true,
+                    Some((best_encoding, best_quality)) => {
+                        quality > best_quality
+                            || (quality == best_quality
+                                && encoding == Encoding::Gzip
+                                && best_encoding != Encoding::Gzip)
+                    }
+                };
+                if quality > 0.0 && better {
+                    best = Some((encoding, quality));
+                }
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+/// Compressor writing to an in-memory buffer that is drained after every chunk
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::fast())),
+            Encoding::Deflate => {
+                Encoder::Deflate(DeflateEncoder::new(Vec::new(), Compression::fast()))
+            }
+        }
+    }
+
+    /// Compress `chunk` and return everything compressed so far
+    ///
+    /// The compressor is flushed so that streamed events reach the client as soon as they are
+    /// produced instead of waiting for the compressor to fill its window.
+    fn compress(&mut self, chunk: &[u8]) -> std::io::Result<Bytes> {
+        match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+        }
+    }
+
+    /// End of the compressed stream
+    fn finish(self) -> std::io::Result<Bytes> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish().map(Bytes::from),
+            Encoder::Deflate(encoder) => encoder.finish().map(Bytes::from),
+        }
+    }
+}
+
+/// Whether `response` should be sent as is
+fn skip(response: &Response) -> bool {
+    let headers = response.headers();
+    let small = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|length| length < MIN_COMPRESSED_LENGTH);
+    let compressed_content = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("image/"));
+    small
+        || compressed_content
+        || headers.contains_key(CONTENT_ENCODING)
+        || response.status() == StatusCode::NO_CONTENT
+        || response.status() == StatusCode::NOT_MODIFIED
+}
+
+/// Compress the responses, including the event streams, with the encoding preferred by the
+/// client
+///
+/// The body is compressed chunk by chunk and never buffered, so streamed tokens are not
+/// delayed.
+pub(crate) async fn compress(request: Request, next: Next) -> Response {
+    let encoding = Encoding::negotiate(request.headers());
+    let response = next.run(request).await;
+    compress_response(response, encoding)
+}
+
+fn compress_response(mut response: Response, encoding: Option<Encoding>) -> Response {
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = encoding.filter(|_| !skip(&response)) else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+
+    let mut encoder = Encoder::new(encoding);
+    let mut chunks = body.into_data_stream();
+    let compressed = stream! {
+        while let Some(chunk) = chunks.next().await {
+            match chunk.and_then(|chunk| encoder.compress(&chunk).map_err(axum::Error::new)) {
+                Ok(compressed) if compressed.is_empty() => {}
+                Ok(compressed) => yield Ok(compressed),
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            }
+        }
+        yield encoder.finish().map_err(axum::Error::new);
+    };
+    Response::from_parts(parts, Body::from_stream(compressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, value.parse().unwrap());
+        headers
+    }
+
+    fn gunzip(compressed: &[u8]) -> String {
+        let mut decoded = Vec::new();
+        // Partial streams end early, what was decoded so far is kept
+        let _ = GzDecoder::new(compressed).read_to_end(&mut decoded);
+        String::from_utf8(decoded).unwrap()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), None);
+        assert_eq!(Encoding::negotiate(&accept("br")), None);
+        assert_eq!(
+            Encoding::negotiate(&accept("deflate, gzip")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            Encoding::negotiate(&accept("gzip;q=0.5, deflate")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(Encoding::negotiate(&accept("GZIP;q=0, deflate;q=0")), None);
+    }
+
+    #[tokio::test]
+    async fn test_compress_stream() {
+        let events = ["data: {\"token\":1}\n\n", "data: {\"token\":2}\n\n"];
+        let body = || {
+            Body::from_stream(
+                futures::stream::iter(events)
+                    .map(|event| Ok::<_, std::io::Error>(Bytes::from_static(event.as_bytes()))),
+            )
+        };
+
+        let response = compress_response(Response::new(body()), Some(Encoding::Gzip));
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+
+        // Every event can be decompressed as soon as it is received
+        let mut chunks = response.into_body().into_data_stream();
+        let mut received = Vec::new();
+        for event in events {
+            received.extend_from_slice(&chunks.next().await.unwrap().unwrap());
+            assert!(gunzip(&received).ends_with(event));
+        }
+        while let Some(chunk) = chunks.next().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(gunzip(&received), events.concat());
+
+        // Sent as is when the client does not accept a compressed response
+        let response = compress_response(Response::new(body()), None);
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+
+        let mut small = Response::new(Body::from("{}"));
+        small
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from_static("2"));
+        let response = compress_response(small, Some(Encoding::Gzip));
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,300 @@
+//! Replayable energy traces
+//!
+//! An energy trace is a versioned binary file holding one [`EnergyReport`] per request, the
+//! compact alternative to the JSONL energy log. All integers are little endian.
+//!
+//! ```text
+//! header:  magic "TGIE" (4 bytes) | version (u16)
+//! record:  timestamp_ms (u64) | request_id (u64) | input_tokens (u32) | generated_tokens (u32)
+//!          | energy_mj (u64) | duration_ms (u64) | finish_reason (u8)
+//!          | metadata_length (u32) | metadata (JSON, metadata_length bytes)
+//!          | token_count (u32) | token_energy_mj (u64 * token_count)
+//! ```
+//!
+//! Missing energies are encoded as `u64::MAX` and a missing metadata by a zero length. The finish
+//! reasons are numbered in the order of [`FinishReason`].
+use crate::FinishReason;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"TGIE";
+/// Current version of the trace format
+pub const ENERGY_TRACE_VERSION: u16 = 1;
+const MISSING_ENERGY: u64 = u64::MAX;
+
+/// Energy consumed by a single request, one record of the energy log
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EnergyReport {
+    /// Unix timestamp of the end of the request, in milliseconds
+    pub timestamp_ms: u64,
+    /// Number of the request since the server started, in the order they were scheduled
+    pub request_id: u64,
+    pub input_tokens: u32,
+    pub generated_tokens: u32,
+    /// Energy consumed by the whole request, in millijoules
+    pub energy_mj: Option<u64>,
+    /// Time from the request being scheduled to its last token, in milliseconds
+    pub duration_ms: u64,
+    pub finish_reason: FinishReason,
+    /// Metadata sent with the request, as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    /// Cumulative energy after each generated token, in millijoules
+    pub token_energy_mj: Vec<Option<u64>>,
+}
+
+#[derive(Debug, Error)]
+pub enum EnergyTraceError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("not an energy trace")]
+    InvalidMagic,
+    #[error("unsupported energy trace version {0}, expected {ENERGY_TRACE_VERSION}")]
+    UnsupportedVersion(u16),
+    #[error("energy trace ends in the middle of a record")]
+    Truncated,
+    #[error("invalid energy trace record: {0}")]
+    InvalidRecord(String),
+}
+
+/// Write energy reports to a trace
+pub struct EnergyTraceWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> EnergyTraceWriter<W> {
+    /// Write the trace header and return the writer
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&ENERGY_TRACE_VERSION.to_le_bytes())?;
+        Ok(Self { inner })
+    }
+
+    /// Writer appending to a trace whose header was already written, see [`read_header`]
+    pub fn resume(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn write(&mut self, report: &EnergyReport) -> io::Result<()> {
+        let metadata = match &report.metadata {
+            Some(metadata) => serde_json::to_vec(metadata)?,
+            None => Vec::new(),
+        };
+        let mut record = Vec::with_capacity(49 + metadata.len() + 8 * report.token_energy_mj.len());
+        record.extend_from_slice(&report.timestamp_ms.to_le_bytes());
+        record.extend_from_slice(&report.request_id.to_le_bytes());
+        record.extend_from_slice(&report.input_tokens.to_le_bytes());
+        record.extend_from_slice(&report.generated_tokens.to_le_bytes());
+        record.extend_from_slice(&encode_energy(report.energy_mj).to_le_bytes());
+        record.extend_from_slice(&report.duration_ms.to_le_bytes());
+        record.push(encode_finish_reason(&report.finish_reason));
+        let metadata_length = u32::try_from(metadata.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "metadata too large"))?;
+        record.extend_from_slice(&metadata_length.to_le_bytes());
+        record.extend_from_slice(&metadata);
+        let token_count = u32::try_from(report.token_energy_mj.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many tokens"))?;
+        record.extend_from_slice(&token_count.to_le_bytes());
+        for energy in &report.token_energy_mj {
+            record.extend_from_slice(&encode_energy(*energy).to_le_bytes());
+        }
+        self.inner.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Check that `reader` starts with the header of a trace of the current version
+pub fn read_header(mut reader: impl Read) -> Result<(), EnergyTraceError> {
+    let mut magic = [0u8; 4];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| EnergyTraceError::InvalidMagic)?;
+    if &magic != MAGIC {
+        return Err(EnergyTraceError::InvalidMagic);
+    }
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if version != ENERGY_TRACE_VERSION {
+        return Err(EnergyTraceError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+/// Read all the energy reports of a trace
+pub fn read_energy_trace(mut reader: impl Read) -> Result<Vec<EnergyReport>, EnergyTraceError> {
+    read_header(&mut reader)?;
+
+    let mut reports = Vec::new();
+    loop {
+        // A trace may only end on a record boundary
+        let mut timestamp = [0u8; 8];
+        match reader.read(&mut timestamp)? {
+            0 => break,
+            8 => {}
+            n => reader.read_exact(&mut timestamp[n..]).map_err(truncated)?,
+        }
+        let request_id = u64::from_le_bytes(read_array(&mut reader)?);
+        let input_tokens = u32::from_le_bytes(read_array(&mut reader)?);
+        let generated_tokens = u32::from_le_bytes(read_array(&mut reader)?);
+        let energy_mj = decode_energy(u64::from_le_bytes(read_array(&mut reader)?));
+        let duration_ms = u64::from_le_bytes(read_array(&mut reader)?);
+        let [finish_reason] = read_array(&mut reader)?;
+        let finish_reason = decode_finish_reason(finish_reason)?;
+        let metadata_length = u32::from_le_bytes(read_array(&mut reader)?);
+        let metadata = match metadata_length {
+            0 => None,
+            length => {
+                let mut metadata = vec![0u8; length as usize];
+                reader.read_exact(&mut metadata).map_err(truncated)?;
+                let metadata = serde_json::from_slice(&metadata)
+                    .map_err(|err| EnergyTraceError::InvalidRecord(err.to_string()))?;
+                Some(metadata)
+            }
+        };
+        let token_count = u32::from_le_bytes(read_array(&mut reader)?);
+        let token_energy_mj = (0..token_count)
+            .map(|_| Ok(decode_energy(u64::from_le_bytes(read_array(&mut reader)?))))
+            .collect::<Result<_, EnergyTraceError>>()?;
+        reports.push(EnergyReport {
+            timestamp_ms: u64::from_le_bytes(timestamp),
+            request_id,
+            input_tokens,
+            generated_tokens,
+            energy_mj,
+            duration_ms,
+            finish_reason,
+            metadata,
+            token_energy_mj,
+        });
+    }
+    Ok(reports)
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], EnergyTraceError> {
+    let mut buffer = [0u8; N];
+    reader.read_exact(&mut buffer).map_err(truncated)?;
+    Ok(buffer)
+}
+
+fn truncated(err: io::Error) -> EnergyTraceError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => EnergyTraceError::Truncated,
+        _ => EnergyTraceError::Io(err),
+    }
+}
+
+fn encode_energy(energy: Option<u64>) -> u64 {
+    energy.unwrap_or(MISSING_ENERGY)
+}
+
+fn decode_energy(energy: u64) -> Option<u64> {
+    (energy != MISSING_ENERGY).then_some(energy)
+}
+
+fn encode_finish_reason(finish_reason: &FinishReason) -> u8 {
+    match finish_reason {
+        FinishReason::Length => 0,
+        FinishReason::EndOfSequenceToken => 1,
+        FinishReason::StopSequence => 2,
+        FinishReason::EnergyBudget => 3,
+        FinishReason::TimeLimit => 4,
+    }
+}
+
+fn decode_finish_reason(finish_reason: u8) -> Result<FinishReason, EnergyTraceError> {
+    match finish_reason {
+        0 => Ok(FinishReason::Length),
+        1 => Ok(FinishReason::EndOfSequenceToken),
+        2 => Ok(FinishReason::StopSequence),
+        3 => Ok(FinishReason::EnergyBudget),
+        4 => Ok(FinishReason::TimeLimit),
+        n => Err(EnergyTraceError::InvalidRecord(format!(
+            "unknown finish reason {n}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reports() -> Vec<EnergyReport> {
+        (0..32)
+            .map(|i| EnergyReport {
+                timestamp_ms: 1_700_000_000_000 + i * 1000,
+                request_id: i,
+                input_tokens: 12 + i as u32,
+                generated_tokens: i as u32,
+                energy_mj: (i % 5 != 0).then_some(i * 250),
+                duration_ms: 40 * i,
+                finish_reason: match i % 3 {
+                    0 => FinishReason::Length,
+                    1 => FinishReason::EndOfSequenceToken,
+                    _ => FinishReason::StopSequence,
+                },
+                metadata: (i % 4 == 1).then(|| serde_json::json!({"experiment": i})),
+                token_energy_mj: (0..i).map(|t| (t % 7 != 3).then_some(t * 10)).collect(),
+            })
+            .collect()
+    }
+
+    fn write(reports: &[EnergyReport]) -> Vec<u8> {
+        let mut writer = EnergyTraceWriter::new(Vec::new()).unwrap();
+        for report in reports {
+            writer.write(report).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_energy_trace_round_trip() {
+        let reports = reports();
+        let trace = write(&reports);
+        assert_eq!(read_energy_trace(trace.as_slice()).unwrap(), reports);
+    }
+
+    #[test]
+    fn test_empty_energy_trace() {
+        let trace = write(&[]);
+        assert_eq!(trace.len(), 6);
+        assert!(read_energy_trace(trace.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_energy_trace() {
+        assert!(matches!(
+            read_energy_trace(&b"JSON{}"[..]),
+            Err(EnergyTraceError::InvalidMagic)
+        ));
+
+        let mut trace = write(&reports());
+        trace[4] = 2;
+        assert!(matches!(
+            read_energy_trace(trace.as_slice()),
+            Err(EnergyTraceError::UnsupportedVersion(2))
+        ));
+
+        let mut trace = write(&reports());
+        trace.truncate(trace.len() - 3);
+        assert!(matches!(
+            read_energy_trace(trace.as_slice()),
+            Err(EnergyTraceError::Truncated)
+        ));
+
+        // Finish reason of the first record, after the header and the fixed size fields
+        let mut trace = write(&reports());
+        trace[46] = 9;
+        assert!(matches!(
+            read_energy_trace(trace.as_slice()),
+            Err(EnergyTraceError::InvalidRecord(_))
+        ));
+    }
+}
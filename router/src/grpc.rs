@@ -0,0 +1,344 @@
+use crate::infer::{Infer, InferError};
+use crate::server::{generate_stream_internal, shutdown_signal, ComputeType, API_KEY_HEADER};
+use crate::{
+    default_parameters, FinishReason, GenerateParameters, GenerateRequest, StreamDetails,
+    StreamResponse, Token,
+};
+use axum::http::header::AUTHORIZATION;
+use axum::Json;
+use futures::{Stream, StreamExt};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tonic::{Code, Request, Response, Status};
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+mod pb {
+    tonic::include_proto!("router.v1");
+}
+
+use pb::text_generation_server::{TextGeneration, TextGenerationServer};
+
+/// Serve the gRPC interface on `addr` until the server shuts down
+///
+/// When `bearer_token` is set, the requests must send it in their `authorization` metadata, like
+/// the `Authorization` header of the HTTP routes.
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    infer: Infer,
+    compute_type: ComputeType,
+    bearer_token: Option<&'static str>,
+) {
+    tracing::info!("Serving gRPC on {addr}");
+    let service = TextGenerationServer::with_interceptor(
+        GenerateService {
+            infer,
+            compute_type,
+        },
+        move |request| check_bearer_token(request, bearer_token),
+    );
+    if let Err(err) = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_shutdown(addr, shutdown_signal())
+        .await
+    {
+        tracing::error!("gRPC server failed: {err}");
+    }
+}
+
+/// Reject the requests whose `authorization` metadata is not `expected`
+fn check_bearer_token(request: Request<()>, expected: Option<&str>) -> Result<Request<()>, Status> {
+    let Some(expected) = expected else {
+        return Ok(request);
+    };
+    match request
+        .metadata()
+        .get(AUTHORIZATION.as_str())
+        .and_then(|token| token.to_str().ok())
+    {
+        Some(token) if token.to_lowercase() == expected.to_lowercase() => Ok(request),
+        _ => Err(Status::unauthenticated("invalid or missing bearer token")),
+    }
+}
+
+/// gRPC generation, going through the same path as `/generate_stream`
+struct GenerateService {
+    infer: Infer,
+    compute_type: ComputeType,
+}
+
+type ResponseStream =
+    Pin<Box<dyn Stream<Item = Result<pb::GenerateStreamResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl TextGeneration for GenerateService {
+    type GenerateStreamStream = ResponseStream;
+
+    async fn generate_stream(
+        &self,
+        request: Request<pb::GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let span = tracing::info_span!(
+            "grpc_generate_stream",
+            total_time = tracing::field::Empty,
+            validation_time = tracing::field::Empty,
+            queue_time = tracing::field::Empty,
+            inference_time = tracing::field::Empty,
+            time_per_token = tracing::field::Empty,
+            seed = tracing::field::Empty,
+        );
+        // Same tenant as the `x-api-key` header of the HTTP routes
+        let api_key = request
+            .metadata()
+            .get(API_KEY_HEADER)
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_string);
+        let mut request: GenerateRequest = request.into_inner().into();
+        request.parameters.api_key = api_key;
+        let (_headers, stream) = generate_stream_internal(
+            self.infer.clone(),
+            self.compute_type.clone(),
+            Json(request),
+            span,
+        )
+        .await;
+        let stream = stream.map(|response| {
+            response
+                .map(pb::GenerateStreamResponse::from)
+                .map_err(Status::from)
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+impl From<pb::GenerateRequest> for GenerateRequest {
+    fn from(request: pb::GenerateRequest) -> Self {
+        let parameters = request.parameters.unwrap_or_default();
+        GenerateRequest {
+            inputs: request.inputs,
+            parameters: GenerateParameters {
+                do_sample: parameters.do_sample,
+                max_new_tokens: parameters.max_new_tokens,
+                temperature: parameters.temperature,
+                top_k: parameters.top_k.map(|top_k| top_k as i32),
+                top_p: parameters.top_p,
+                typical_p: parameters.typical_p,
+                repetition_penalty: parameters.repetition_penalty,
+                frequency_penalty: parameters.frequency_penalty,
+                stop: parameters.stop,
+                truncate: parameters.truncate.map(|truncate| truncate as usize),
+                seed: parameters.seed,
+                details: parameters.details,
+                return_full_text: Some(parameters.return_full_text),
+                max_energy_millijoules: parameters.max_energy_millijoules,
+                measure_energy: !parameters.skip_energy,
+                // Priorities above the highest level share it
+                priority: parameters
+                    .priority
+                    .map(|priority| u8::try_from(priority).unwrap_or(u8::MAX)),
+                ..default_parameters()
+            },
+            add_special_tokens: true,
+            metadata: None,
+        }
+    }
+}
+
+impl From<Token> for pb::Token {
+    fn from(token: Token) -> Self {
+        pb::Token {
+            id: token.id,
+            text: token.text,
+            logprob: token.logprob,
+            special: token.special,
+            energy_consumption_mj: token.energy_consumption,
+        }
+    }
+}
+
+impl From<FinishReason> for pb::FinishReason {
+    fn from(finish_reason: FinishReason) -> Self {
+        match finish_reason {
+            FinishReason::Length => pb::FinishReason::Length,
+            FinishReason::EndOfSequenceToken => pb::FinishReason::EosToken,
+            FinishReason::StopSequence => pb::FinishReason::StopSequence,
+            FinishReason::EnergyBudget => pb::FinishReason::EnergyBudget,
+            FinishReason::TimeLimit => pb::FinishReason::TimeLimit,
+        }
+    }
+}
+
+impl From<StreamDetails> for pb::StreamDetails {
+    fn from(details: StreamDetails) -> Self {
+        pb::StreamDetails {
+            finish_reason: pb::FinishReason::from(details.finish_reason) as i32,
+            generated_tokens: details.generated_tokens,
+            seed: details.seed,
+            input_length: details.input_length,
+        }
+    }
+}
+
+/// The energies are always sent in millijoules, whatever the unit configured on the server
+impl From<StreamResponse> for pb::GenerateStreamResponse {
+    fn from(response: StreamResponse) -> Self {
+        pb::GenerateStreamResponse {
+            index: response.index,
+            token: Some(response.token.into()),
+            top_tokens: response
+                .top_tokens
+                .into_iter()
+                .map(pb::Token::from)
+                .collect(),
+            generated_text: response.generated_text,
+            details: response.details.map(pb::StreamDetails::from),
+            energy_consumption_mj: response.energy_consumption,
+            prefill_energy_mj: response.prefill_energy,
+            decode_energy_mj: response.decode_energy,
+            prefill_energy_saved_estimate_mj: response.prefill_energy_saved_estimate,
+            long_output_warning: response.long_output_warning,
+        }
+    }
+}
+
+impl From<InferError> for Status {
+    fn from(err: InferError) -> Self {
+        let code = match err.status_code() {
+            401 => Code::Unauthenticated,
+            422 => Code::InvalidArgument,
+            429 => Code::ResourceExhausted,
+            _ => Code::Internal,
+        };
+        Status::new(code, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::energy::EnergyUnit;
+    use crate::validation::ValidationError;
+
+    fn token(id: u32, energy_consumption: Option<u64>) -> Token {
+        Token {
+            id,
+            text: format!("t{id}"),
+            logprob: -0.5,
+            special: false,
+            energy_consumption,
+            telemetry: None,
+            continuation_boundary: false,
+            step_energy: None,
+            offsets: None,
+            inter_token_latency_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_generate_request() {
+        let request = GenerateRequest::from(pb::GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: Some(pb::GenerateParameters {
+                max_new_tokens: Some(8),
+                top_k: Some(4),
+                details: true,
+                skip_energy: true,
+                priority: Some(1000),
+                ..Default::default()
+            }),
+        });
+        assert_eq!(request.inputs, "Hello");
+        assert_eq!(request.parameters.max_new_tokens, Some(8));
+        assert_eq!(request.parameters.top_k, Some(4));
+        assert!(request.parameters.details);
+        assert!(!request.parameters.measure_energy);
+        assert_eq!(request.parameters.priority, Some(u8::MAX));
+        assert_eq!(request.parameters.return_full_text, Some(false));
+
+        // The defaults of the HTTP API apply to the missing parameters
+        let request = GenerateRequest::from(pb::GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: None,
+        });
+        assert_eq!(request.parameters.max_new_tokens, None);
+        assert!(request.parameters.measure_energy);
+        assert_eq!(request.parameters.priority, None);
+    }
+
+    #[test]
+    fn test_stream_response() {
+        let response = pb::GenerateStreamResponse::from(StreamResponse {
+            index: 3,
+            token: token(7, Some(40)),
+            top_tokens: vec![token(8, None)],
+            generated_text: Some("t5t6t7".to_string()),
+            details: Some(StreamDetails {
+                finish_reason: FinishReason::EnergyBudget,
+                length_limit: None,
+                generated_tokens: 3,
+                seed: None,
+                input_length: 2,
+            }),
+            energy_consumption: Some(120),
+            prefill_energy: Some(50),
+            decode_energy: Some(70),
+            prefill_energy_saved_estimate: None,
+            long_output_warning: false,
+            energy_unit: EnergyUnit::Joules,
+            metadata: None,
+        });
+        assert_eq!(response.index, 3);
+        assert_eq!(response.token.unwrap().energy_consumption_mj, Some(40));
+        assert_eq!(response.top_tokens[0].id, 8);
+        assert_eq!(response.generated_text.as_deref(), Some("t5t6t7"));
+        let details = response.details.unwrap();
+        assert_eq!(details.finish_reason(), pb::FinishReason::EnergyBudget);
+        assert_eq!(details.generated_tokens, 3);
+        // Millijoules whatever the unit of the request
+        assert_eq!(response.energy_consumption_mj, Some(120));
+        assert_eq!(response.prefill_energy_mj, Some(50));
+        assert_eq!(response.decode_energy_mj, Some(70));
+
+        // An unset finish reason is not mistaken for one
+        assert_eq!(
+            pb::StreamDetails::default().finish_reason(),
+            pb::FinishReason::Unspecified
+        );
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        fn request(token: Option<&str>) -> Request<()> {
+            let mut request = Request::new(());
+            if let Some(token) = token {
+                request
+                    .metadata_mut()
+                    .insert("authorization", token.parse().unwrap());
+            }
+            request
+        }
+
+        // Without an API key every request is served
+        assert!(check_bearer_token(request(None), None).is_ok());
+        let expected = Some("Bearer secret");
+        assert!(check_bearer_token(request(Some("Bearer secret")), expected).is_ok());
+        assert!(check_bearer_token(request(Some("bearer SECRET")), expected).is_ok());
+        for token in [None, Some("Bearer other"), Some("secret")] {
+            let status = check_bearer_token(request(token), expected).unwrap_err();
+            assert_eq!(status.code(), Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn test_status() {
+        let status = Status::from(InferError::ValidationError(ValidationError::BestOfStream));
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = Status::from(InferError::Overloaded(
+            tokio::sync::TryAcquireError::NoPermits,
+        ));
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(
+            Status::from(InferError::IncompleteGenerationStream).code(),
+            Code::Internal
+        );
+    }
+}
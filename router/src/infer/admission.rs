@@ -0,0 +1,775 @@
+use crate::infer::InferError;
+use crate::GenerateParameters;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use utoipa::ToSchema;
+
+/// Class of a request used for admission control
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestClass {
+    /// Short, latency sensitive request
+    Interactive,
+    /// Long running request
+    Batch,
+}
+
+impl RequestClass {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RequestClass::Interactive => "interactive",
+            RequestClass::Batch => "batch",
+        }
+    }
+}
+
+/// Concurrency quota of the tenant using an API key, parsed from `key=max_concurrent_requests`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantQuota {
+    pub key: String,
+    pub max_concurrent_requests: usize,
+}
+
+impl FromStr for TenantQuota {
+    type Err = String;
+
+    fn from_str(quota: &str) -> Result<Self, Self::Err> {
+        let (key, limit) = quota
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected `key=max_concurrent_requests`, got `{quota}`"))?;
+        let max_concurrent_requests = limit
+            .parse()
+            .map_err(|err| format!("invalid quota `{limit}`: {err}"))?;
+        Ok(Self {
+            key: key.to_string(),
+            max_concurrent_requests,
+        })
+    }
+}
+
+/// Longest a request with a priority waits for a global permit before being rejected
+///
+/// The request holds its tenant permit while it waits.
+const PRIORITY_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Semaphore permits held for as long as the request is running
+#[derive(Debug)]
+pub(crate) struct RequestPermit {
+    _global: GlobalPermit,
+    _class: Option<OwnedSemaphorePermit>,
+    _tenant: Option<OwnedSemaphorePermit>,
+}
+
+/// Global permit, given back through [`GlobalLimit::release`] when dropped
+///
+/// This is also the case for a permit handed to a waiter that stopped waiting before receiving
+/// it, so that it goes to the next waiter or is forgotten after the limit was lowered.
+#[derive(Debug)]
+struct GlobalPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    limit: Arc<GlobalLimit>,
+}
+
+impl GlobalPermit {
+    /// Take the semaphore permit back without releasing it
+    fn into_inner(mut self) -> OwnedSemaphorePermit {
+        self.permit.take().expect("permit taken twice")
+    }
+}
+
+impl Drop for GlobalPermit {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.limit.release(permit);
+            self.limit.record_load();
+        }
+    }
+}
+
+/// Request with a priority waiting for a global permit
+#[derive(Debug)]
+struct Waiter {
+    priority: u8,
+    /// Order of arrival, the earliest waiter of a priority is served first
+    arrival: u64,
+    sender: oneshot::Sender<GlobalPermit>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.arrival.cmp(&self.arrival))
+    }
+}
+
+/// Requests waiting for a global permit, the next one to serve first
+#[derive(Debug, Default)]
+struct Waiters {
+    queue: BinaryHeap<Waiter>,
+    arrivals: u64,
+}
+
+impl Waiters {
+    /// Number of requests still waiting, after dropping those that stopped
+    fn waiting(&mut self) -> usize {
+        self.queue.retain(|waiter| !waiter.sender.is_closed());
+        self.queue.len()
+    }
+}
+
+/// Global concurrency limit that can be changed while requests are running
+///
+/// The semaphore is FIFO, so the requests with a priority wait in a queue of their own. Released
+/// permits are handed to the waiters directly, the other requests only get the permits nobody
+/// waits for.
+#[derive(Debug)]
+struct GlobalLimit {
+    semaphore: Arc<Semaphore>,
+    /// Current limit and number of permits to forget when the running requests release them
+    state: Mutex<(usize, usize)>,
+    waiters: Mutex<Waiters>,
+}
+
+impl GlobalLimit {
+    fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Mutex::new((limit, 0)),
+            waiters: Default::default(),
+        }
+    }
+
+    fn guard(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> GlobalPermit {
+        GlobalPermit {
+            permit: Some(permit),
+            limit: self.clone(),
+        }
+    }
+
+    /// Take a permit if one is free and no request with a priority waits for it
+    fn try_acquire(self: &Arc<Self>) -> Result<GlobalPermit, TryAcquireError> {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        if waiters.waiting() > 0 {
+            return Err(TryAcquireError::NoPermits);
+        }
+        let permit = self.semaphore.clone().try_acquire_owned()?;
+        Ok(self.guard(permit))
+    }
+
+    /// Wait for a permit, the requests of higher priority are served first
+    ///
+    /// Gives up after [`PRIORITY_WAIT_TIMEOUT`].
+    async fn acquire(self: &Arc<Self>, priority: u8) -> Result<GlobalPermit, TryAcquireError> {
+        let receiver = {
+            let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+            if waiters.waiting() == 0 {
+                if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+                    return Ok(self.guard(permit));
+                }
+            }
+            let (sender, receiver) = oneshot::channel();
+            let arrival = waiters.arrivals;
+            waiters.arrivals += 1;
+            waiters.queue.push(Waiter {
+                priority,
+                arrival,
+                sender,
+            });
+            receiver
+        };
+        // Dropping the receiver, on timeout or when the client disconnects, releases the permit
+        // it may have been handed
+        match tokio::time::timeout(PRIORITY_WAIT_TIMEOUT, receiver).await {
+            Ok(permit) => permit.map_err(|_| TryAcquireError::Closed),
+            Err(_) => Err(TryAcquireError::NoPermits),
+        }
+    }
+
+    /// Number of requests waiting for a permit
+    fn waiting(&self) -> usize {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        waiters.waiting()
+    }
+
+    /// Give `permit` to the next waiter, or back to the semaphore when none is left
+    fn hand_over(self: &Arc<Self>, mut permit: OwnedSemaphorePermit) {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(waiter) = waiters.queue.pop() {
+            match waiter.sender.send(self.guard(permit)) {
+                Ok(()) => return,
+                Err(returned) => permit = returned.into_inner(),
+            }
+        }
+        // Released while the queue is locked so that no request starts waiting in between
+        drop(permit);
+    }
+
+    /// Serve the waiters with the available permits
+    fn serve_waiters(self: &Arc<Self>) {
+        loop {
+            if self.waiting() == 0 {
+                return;
+            }
+            match self.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => self.hand_over(permit),
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn limit(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).0
+    }
+
+    /// Number of requests holding a permit
+    fn running(&self) -> usize {
+        let (limit, excess) = *self.state.lock().unwrap_or_else(|e| e.into_inner());
+        (limit + excess).saturating_sub(self.semaphore.available_permits())
+    }
+
+    /// Report the running requests and the permits left
+    fn record_load(&self) {
+        metrics::gauge!("tgi_inflight_requests").set(self.running() as f64);
+        metrics::gauge!("tgi_available_permits").set(self.semaphore.available_permits() as f64);
+    }
+
+    /// Add or remove permits to reach `new_limit`
+    ///
+    /// Permits held by running requests cannot be taken back, they are forgotten when the
+    /// requests finish instead so that running requests are never cancelled.
+    fn set(self: &Arc<Self>, new_limit: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (limit, excess) = &mut *state;
+        if new_limit > *limit {
+            let added = new_limit - *limit;
+            let cancelled = added.min(*excess);
+            *excess -= cancelled;
+            self.semaphore.add_permits(added - cancelled);
+        } else {
+            let removed = *limit - new_limit;
+            *excess += removed - self.semaphore.forget_permits(removed);
+        }
+        *limit = new_limit;
+        drop(state);
+        self.serve_waiters();
+    }
+
+    fn release(self: &Arc<Self>, permit: OwnedSemaphorePermit) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.1 > 0 {
+            state.1 -= 1;
+            permit.forget();
+            return;
+        }
+        drop(state);
+        self.hand_over(permit);
+    }
+}
+
+/// Concurrency quotas of the tenants, identified by their API key
+///
+/// Only the configured keys get a quota of their own. Any other key is unknown to the server, and
+/// a client could get a fresh quota by changing it, so the requests with another key or without a
+/// key share the default quota, or are rejected when there is none.
+#[derive(Debug, Default)]
+struct TenantLimits {
+    /// Semaphores of the configured keys
+    quotas: HashMap<String, Arc<Semaphore>>,
+    /// Semaphore shared by the requests without a configured key
+    default_quota: Option<Arc<Semaphore>>,
+}
+
+impl TenantLimits {
+    /// Semaphore the request sent with `key` counts against, `None` without tenant quotas
+    fn semaphore(&self, key: Option<&str>) -> Result<Option<Arc<Semaphore>>, InferError> {
+        if let Some(semaphore) = key.and_then(|key| self.quotas.get(key)) {
+            return Ok(Some(semaphore.clone()));
+        }
+        match &self.default_quota {
+            Some(semaphore) => Ok(Some(semaphore.clone())),
+            None if self.quotas.is_empty() => Ok(None),
+            None => {
+                metrics::counter!("tgi_request_failure", "err" => "unknown_api_key").increment(1);
+                tracing::error!("Request without a configured API key");
+                Err(InferError::UnknownApiKey)
+            }
+        }
+    }
+}
+
+/// Concurrency limits of the requests
+///
+/// Every request goes through the global limit. Requests that can be classified also go through
+/// the limit of their class, if there is one, and through the quota of their tenant when there are
+/// tenant quotas.
+#[derive(Clone, Debug)]
+pub(crate) struct ConcurrencyLimits {
+    global: Arc<GlobalLimit>,
+    /// Requests with at most this many new tokens are interactive, the others are batch
+    interactive_max_new_tokens: Option<u32>,
+    interactive: Option<Arc<Semaphore>>,
+    batch: Option<Arc<Semaphore>>,
+    tenants: Arc<TenantLimits>,
+}
+
+impl ConcurrencyLimits {
+    pub(crate) fn new(
+        max_concurrent_requests: usize,
+        interactive_max_new_tokens: Option<u32>,
+        max_concurrent_interactive_requests: Option<usize>,
+        max_concurrent_batch_requests: Option<usize>,
+    ) -> Self {
+        Self {
+            global: Arc::new(GlobalLimit::new(max_concurrent_requests)),
+            interactive_max_new_tokens,
+            interactive: max_concurrent_interactive_requests
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            batch: max_concurrent_batch_requests.map(|limit| Arc::new(Semaphore::new(limit))),
+            tenants: Default::default(),
+        }
+    }
+
+    /// Limit the concurrent requests of each configured API key to its quota, and those of all
+    /// the other requests together to `default_quota`
+    ///
+    /// With quotas but without `default_quota`, the requests without a configured key are
+    /// rejected.
+    pub(crate) fn tenant_quotas(
+        mut self,
+        quotas: Vec<TenantQuota>,
+        default_quota: Option<usize>,
+    ) -> Self {
+        self.tenants = Arc::new(TenantLimits {
+            quotas: quotas
+                .into_iter()
+                .map(|quota| {
+                    let semaphore = Arc::new(Semaphore::new(quota.max_concurrent_requests));
+                    (quota.key, semaphore)
+                })
+                .collect(),
+            default_quota: default_quota.map(|quota| Arc::new(Semaphore::new(quota))),
+        });
+        self
+    }
+
+    /// Current global limit
+    pub(crate) fn limit(&self) -> usize {
+        self.global.limit()
+    }
+
+    /// Number of requests holding a global permit
+    pub(crate) fn running(&self) -> usize {
+        self.global.running()
+    }
+
+    /// Change the global limit, see [`GlobalLimit::set`]
+    pub(crate) fn set_limit(&self, new_limit: usize) {
+        self.global.set(new_limit);
+        self.global.record_load();
+    }
+
+    /// Class of the request: the explicit class if any, otherwise based on `max_new_tokens`
+    pub(crate) fn classify(&self, parameters: &GenerateParameters) -> Option<RequestClass> {
+        if let Some(class) = parameters.request_class {
+            return Some(class);
+        }
+        let threshold = self.interactive_max_new_tokens?;
+        match parameters.max_new_tokens {
+            Some(max_new_tokens) if max_new_tokens <= threshold => Some(RequestClass::Interactive),
+            _ => Some(RequestClass::Batch),
+        }
+    }
+
+    /// Acquire the permit of the tenant, the global permit and the permit of the request class
+    pub(crate) fn try_acquire(
+        &self,
+        parameters: &GenerateParameters,
+    ) -> Result<RequestPermit, InferError> {
+        let tenant_permit = self.tenant_permit(parameters)?;
+        let global = self.global.try_acquire().map_err(global_overloaded)?;
+        self.class_permit(parameters, global, tenant_permit)
+    }
+
+    /// Like [`ConcurrencyLimits::try_acquire`], but a request with a priority waits for a global
+    /// permit instead of being rejected
+    pub(crate) async fn acquire(
+        &self,
+        parameters: &GenerateParameters,
+    ) -> Result<RequestPermit, InferError> {
+        let Some(priority) = parameters.priority else {
+            return self.try_acquire(parameters);
+        };
+        let tenant_permit = self.tenant_permit(parameters)?;
+        let global = self
+            .global
+            .acquire(priority)
+            .await
+            .map_err(global_overloaded)?;
+        self.class_permit(parameters, global, tenant_permit)
+    }
+
+    /// The tenant permit is taken first, a tenant over its quota does not hold global permits
+    fn tenant_permit(
+        &self,
+        parameters: &GenerateParameters,
+    ) -> Result<Option<OwnedSemaphorePermit>, InferError> {
+        let semaphore = self.tenants.semaphore(parameters.api_key.as_deref())?;
+        let tenant_permit = match semaphore {
+            Some(semaphore) => Some(semaphore.try_acquire_owned().map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "overloaded", "reason" => "tenant_quota")
+                    .increment(1);
+                tracing::error!("{err} for the tenant of the API key");
+                err
+            })?),
+            None => None,
+        };
+        Ok(tenant_permit)
+    }
+
+    /// Take the class permit of a request holding its global permit
+    fn class_permit(
+        &self,
+        parameters: &GenerateParameters,
+        global: GlobalPermit,
+        tenant_permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<RequestPermit, InferError> {
+        // Built first so that the global permit goes to the next waiter if the request is rejected
+        let mut permit = RequestPermit {
+            _global: global,
+            _class: None,
+            _tenant: tenant_permit,
+        };
+
+        let class = self.classify(parameters);
+        let semaphore = match class {
+            Some(RequestClass::Interactive) => self.interactive.as_ref(),
+            Some(RequestClass::Batch) => self.batch.as_ref(),
+            None => None,
+        };
+        let class_permit = match (class, semaphore) {
+            (Some(class), Some(semaphore)) => {
+                Some(semaphore.clone().try_acquire_owned().map_err(|err| {
+                    let class = class.as_str();
+                    metrics::counter!("tgi_request_failure", "err" => "overloaded", "class" => class)
+                        .increment(1);
+                    tracing::error!("{err} for {class} requests");
+                    err
+                })?)
+            }
+            _ => None,
+        };
+
+        permit._class = class_permit;
+        self.global.record_load();
+        Ok(permit)
+    }
+}
+
+fn global_overloaded(err: TryAcquireError) -> InferError {
+    metrics::counter!("tgi_request_failure", "err" => "overloaded").increment(1);
+    tracing::error!("{err}");
+    err.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(max_new_tokens: Option<u32>) -> GenerateParameters {
+        GenerateParameters {
+            max_new_tokens,
+            ..crate::default_parameters()
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let limits = ConcurrencyLimits::new(8, Some(64), None, None);
+        assert_eq!(
+            limits.classify(&parameters(Some(16))),
+            Some(RequestClass::Interactive)
+        );
+        assert_eq!(
+            limits.classify(&parameters(Some(512))),
+            Some(RequestClass::Batch)
+        );
+        assert_eq!(
+            limits.classify(&parameters(None)),
+            Some(RequestClass::Batch)
+        );
+
+        let explicit = GenerateParameters {
+            request_class: Some(RequestClass::Batch),
+            ..parameters(Some(16))
+        };
+        assert_eq!(limits.classify(&explicit), Some(RequestClass::Batch));
+
+        // Without threshold only explicit classes are used
+        let limits = ConcurrencyLimits::new(8, None, Some(1), Some(1));
+        assert_eq!(limits.classify(&parameters(Some(16))), None);
+        assert_eq!(limits.classify(&explicit), Some(RequestClass::Batch));
+    }
+
+    #[test]
+    fn test_classes_limited_independently() {
+        let limits = ConcurrencyLimits::new(8, Some(64), Some(2), Some(1));
+        let interactive = parameters(Some(16));
+        let batch = parameters(Some(1024));
+
+        let _batch = limits.try_acquire(&batch).unwrap();
+        assert!(matches!(
+            limits.try_acquire(&batch),
+            Err(InferError::Overloaded(_))
+        ));
+
+        // The batch limit does not starve interactive requests
+        let _first = limits.try_acquire(&interactive).unwrap();
+        let second = limits.try_acquire(&interactive).unwrap();
+        assert!(matches!(
+            limits.try_acquire(&interactive),
+            Err(InferError::Overloaded(_))
+        ));
+
+        drop(second);
+        let _second = limits.try_acquire(&interactive).unwrap();
+    }
+
+    #[test]
+    fn test_fallback_to_global_limit() {
+        let limits = ConcurrencyLimits::new(2, Some(64), Some(4), None);
+        let _interactive = limits.try_acquire(&parameters(Some(16))).unwrap();
+        // Batch requests have no limit of their own
+        let _batch = limits.try_acquire(&parameters(None)).unwrap();
+        assert!(matches!(
+            limits.try_acquire(&parameters(Some(16))),
+            Err(InferError::Overloaded(_))
+        ));
+    }
+
+    #[test]
+    fn test_tenant_quotas() {
+        let quotas = vec!["team-a=2".parse().unwrap(), "team-b=1".parse().unwrap()];
+        let limits = ConcurrencyLimits::new(8, None, None, None).tenant_quotas(quotas, Some(1));
+        let tenant = |key: &str| GenerateParameters {
+            api_key: Some(key.to_string()),
+            ..parameters(None)
+        };
+
+        let _first = limits.try_acquire(&tenant("team-a")).unwrap();
+        let second = limits.try_acquire(&tenant("team-a")).unwrap();
+        // Over its quota although there is global capacity left
+        assert!(matches!(
+            limits.try_acquire(&tenant("team-a")),
+            Err(InferError::Overloaded(_))
+        ));
+        // Other tenants are not affected
+        let _b = limits.try_acquire(&tenant("team-b")).unwrap();
+        // Unknown keys and requests without a key share the default quota, changing the key does
+        // not give a fresh quota
+        let unknown = limits.try_acquire(&tenant("unknown")).unwrap();
+        assert!(limits.try_acquire(&tenant("unknown")).is_err());
+        assert!(limits.try_acquire(&tenant("other-unknown")).is_err());
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+
+        drop(second);
+        let _second = limits.try_acquire(&tenant("team-a")).unwrap();
+        drop(unknown);
+        let _anonymous = limits.try_acquire(&parameters(None)).unwrap();
+
+        // Without a default quota, the requests without a configured key are rejected
+        let quotas = vec!["team-a=1".parse().unwrap()];
+        let limits = ConcurrencyLimits::new(8, None, None, None).tenant_quotas(quotas, None);
+        let _a = limits.try_acquire(&tenant("team-a")).unwrap();
+        assert!(matches!(
+            limits.try_acquire(&tenant("unknown")),
+            Err(InferError::UnknownApiKey)
+        ));
+        assert!(matches!(
+            limits.try_acquire(&parameters(None)),
+            Err(InferError::UnknownApiKey)
+        ));
+
+        // Without tenant quotas, requests only go through the global limit
+        let limits = ConcurrencyLimits::new(8, None, None, None);
+        let _first = limits.try_acquire(&tenant("unknown")).unwrap();
+        let _second = limits.try_acquire(&tenant("unknown")).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tenant_quota() {
+        assert_eq!(
+            "key=with=equals=4".parse::<TenantQuota>(),
+            Ok(TenantQuota {
+                key: "key=with=equals".to_string(),
+                max_concurrent_requests: 4,
+            })
+        );
+        assert!("no-quota".parse::<TenantQuota>().is_err());
+        assert!("key=many".parse::<TenantQuota>().is_err());
+    }
+
+    #[test]
+    fn test_running_requests() {
+        let limits = ConcurrencyLimits::new(4, None, None, None);
+        let mut running: Vec<_> = (0..3)
+            .map(|_| limits.try_acquire(&parameters(None)).unwrap())
+            .collect();
+        assert_eq!(limits.running(), 3);
+
+        // Requests over a lowered limit are still counted
+        limits.set_limit(1);
+        assert_eq!(limits.running(), 3);
+        running.pop();
+        assert_eq!(limits.running(), 2);
+        running.clear();
+        assert_eq!(limits.running(), 0);
+    }
+
+    #[test]
+    fn test_raise_limit() {
+        let limits = ConcurrencyLimits::new(1, None, None, None);
+        let _first = limits.try_acquire(&parameters(None)).unwrap();
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+
+        limits.set_limit(3);
+        assert_eq!(limits.limit(), 3);
+        let _second = limits.try_acquire(&parameters(None)).unwrap();
+        let _third = limits.try_acquire(&parameters(None)).unwrap();
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+    }
+
+    #[test]
+    fn test_lower_limit_below_running_requests() {
+        let limits = ConcurrencyLimits::new(4, None, None, None);
+        let mut running: Vec<_> = (0..3)
+            .map(|_| limits.try_acquire(&parameters(None)).unwrap())
+            .collect();
+
+        // The running requests keep their permits
+        limits.set_limit(1);
+        assert_eq!(limits.limit(), 1);
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+
+        // No request is admitted until the running ones drain below the new limit
+        running.pop();
+        running.pop();
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+        running.pop();
+        let only = limits.try_acquire(&parameters(None)).unwrap();
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+        drop(only);
+
+        // Raising the limit again first cancels the permits still to forget
+        let running = limits.try_acquire(&parameters(None)).unwrap();
+        limits.set_limit(0);
+        limits.set_limit(2);
+        drop(running);
+        let _first = limits.try_acquire(&parameters(None)).unwrap();
+        let _second = limits.try_acquire(&parameters(None)).unwrap();
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_priority_admission_order() {
+        let limits = ConcurrencyLimits::new(1, None, None, None);
+        let running = limits.try_acquire(&parameters(None)).unwrap();
+        let with_priority = |priority| GenerateParameters {
+            priority: Some(priority),
+            ..parameters(None)
+        };
+
+        // Requests without a priority are still rejected right away
+        assert!(limits.acquire(&parameters(None)).await.is_err());
+
+        let waiting = |priority| {
+            let limits = limits.clone();
+            tokio::spawn(async move { limits.acquire(&with_priority(priority)).await })
+        };
+        let low = waiting(1);
+        while limits.global.waiting() < 1 {
+            tokio::task::yield_now().await;
+        }
+        let high = waiting(5);
+        while limits.global.waiting() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // The high priority request is admitted ahead of the low priority one queued before it
+        drop(running);
+        let high = high.await.unwrap().unwrap();
+        tokio::task::yield_now().await;
+        assert!(!low.is_finished());
+        assert_eq!(limits.global.waiting(), 1);
+        assert!(limits.try_acquire(&parameters(None)).is_err());
+
+        drop(high);
+        let _low = low.await.unwrap().unwrap();
+        assert_eq!(limits.global.waiting(), 0);
+        assert_eq!(limits.running(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter_releases_its_permit() {
+        let limits = ConcurrencyLimits::new(1, None, None, None);
+        let with_priority = GenerateParameters {
+            priority: Some(1),
+            ..parameters(None)
+        };
+        let waiting = || {
+            let limits = limits.clone();
+            let parameters = with_priority.clone();
+            tokio::spawn(async move { limits.acquire(&parameters).await })
+        };
+        let queue = |running: RequestPermit| async move {
+            let cancelled = waiting();
+            while limits.global.waiting() < 1 {
+                tokio::task::yield_now().await;
+            }
+            let next = waiting();
+            while limits.global.waiting() < 2 {
+                tokio::task::yield_now().await;
+            }
+            // The permit is handed to the first waiter, which stops waiting before taking it
+            drop(running);
+            cancelled.abort();
+            next
+        };
+
+        // The permit goes to the next waiter
+        let running = limits.try_acquire(&parameters(None)).unwrap();
+        let next = queue(running).await;
+        let running = next.await.unwrap().unwrap();
+        assert_eq!(limits.running(), 1);
+
+        // The permit is forgotten when the limit was lowered in between
+        let next = queue(running).await;
+        limits.set_limit(0);
+        tokio::task::yield_now().await;
+        tokio::task::yield_now().await;
+        assert_eq!(limits.running(), 0);
+        assert!(!next.is_finished());
+        limits.set_limit(1);
+        let _running = next.await.unwrap().unwrap();
+        assert_eq!(limits.running(), 1);
+    }
+}
@@ -0,0 +1,48 @@
+use crate::BenchmarkStats;
+
+/// Prompt of the benchmarks that do not set one
+pub(crate) const DEFAULT_PROMPT: &str = "Write a short story about a robot learning to paint.";
+pub(crate) const DEFAULT_ITERATIONS: u32 = 5;
+pub(crate) const DEFAULT_MAX_NEW_TOKENS: u32 = 64;
+/// Iterations are run one after the other, this bounds how long a benchmark holds the server
+pub(crate) const MAX_ITERATIONS: u32 = 100;
+
+/// Mean and nearest rank percentiles of `values`, `None` when there are none
+pub(crate) fn benchmark_stats(mut values: Vec<f64>) -> Option<BenchmarkStats> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable_by(f64::total_cmp);
+    let percentile = |p: f64| {
+        let rank = (values.len() as f64 * p).ceil() as usize;
+        values[rank.max(1) - 1]
+    };
+    Some(BenchmarkStats {
+        mean: values.iter().sum::<f64>() / values.len() as f64,
+        p50: percentile(0.5),
+        p95: percentile(0.95),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_benchmark_stats() {
+        assert_eq!(benchmark_stats(vec![]), None);
+        assert_eq!(
+            benchmark_stats(vec![3.0]),
+            Some(BenchmarkStats {
+                mean: 3.0,
+                p50: 3.0,
+                p95: 3.0,
+            })
+        );
+
+        let stats = benchmark_stats((1..=20).rev().map(f64::from).collect()).unwrap();
+        assert_eq!(stats.mean, 10.5);
+        assert_eq!(stats.p50, 10.0);
+        assert_eq!(stats.p95, 19.0);
+    }
+}
@@ -0,0 +1,449 @@
+use super::admission::{ConcurrencyLimits, TenantQuota};
+use super::chat_template::{
+    ChatTemplate, ChatTemplateCache, CHAT_TEMPLATE_CACHE_SIZE, DEFAULT_CHAT_TEMPLATE,
+};
+use super::energy::{
+    DeviceMeter, EnergyLogFormat, EnergyMeter, EnergySource, EnergyStats, EnergyUnit,
+    MockEnergyMeter, NvmlUuidMeter, RaplMeter, POWERCAP_ROOT,
+};
+use super::energy_log::EnergyLog;
+use super::idempotency::IdempotencyCache;
+use super::output_length::OutputLengthMonitor;
+use super::prefix_cache::PrefixCache;
+use super::sampling_hook::SamplingHook;
+use super::telemetry::{NvmlTelemetry, TelemetryField, TelemetrySource};
+use super::{Backend, Infer};
+use crate::validation::Validation;
+use crate::{ChatTemplateVersions, HubProcessorConfig, HubTokenizerConfig, SpecialTokensResponse};
+use nvml_wrapper::Nvml;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Builds an [`Infer`], created with [`Infer::builder`]
+///
+/// Everything but the backend and the validation is optional, with the same defaults as the
+/// command line arguments.
+pub(crate) struct InferBuilder {
+    backend: Arc<dyn Backend + Send + Sync>,
+    validation: Validation,
+    max_concurrent_requests: usize,
+    max_input_bytes: usize,
+    tokenizer_config: HubTokenizerConfig,
+    processor_config: HubProcessorConfig,
+    length_energy_penalty: Option<f32>,
+    telemetry_fields: Vec<TelemetryField>,
+    interactive_max_new_tokens: Option<u32>,
+    max_concurrent_interactive_requests: Option<usize>,
+    max_concurrent_batch_requests: Option<usize>,
+    tenant_quotas: Vec<TenantQuota>,
+    default_tenant_quota: Option<usize>,
+    energy_devices: Vec<u32>,
+    /// NVML device to measure instead of `energy_devices`, e.g. a MIG instance
+    energy_device_uuid: Option<String>,
+    energy_source: EnergySource,
+    /// Counter readings of the [`EnergySource::Replay`] source, in millijoules
+    energy_replay_readings: Vec<u64>,
+    /// Meter used instead of the one of `energy_source`, `Some(None)` disabling energy tracking
+    energy_meter: Option<Option<Arc<dyn EnergyMeter>>>,
+    carbon_intensity_g_per_kwh: Option<f64>,
+    energy_sampling_interval: usize,
+    token_energy_floor: Option<u64>,
+    token_energy_cap: Option<u64>,
+    energy_unit: EnergyUnit,
+    /// Smoothing factor of the moving average of the tokens per joule
+    efficiency_ewma_alpha: f64,
+    efficiency_floor: Option<f64>,
+    idempotency_cache_size: usize,
+    idempotency_ttl: Duration,
+    sampling_hook: Option<SamplingHook>,
+    energy_log_path: Option<PathBuf>,
+    energy_log_format: EnergyLogFormat,
+    prefix_cache_blocks: Option<usize>,
+}
+
+impl InferBuilder {
+    pub(crate) fn new(
+        backend: impl Backend + Send + Sync + 'static,
+        validation: Validation,
+    ) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            validation,
+            max_concurrent_requests: 128,
+            max_input_bytes: 10_000_000,
+            tokenizer_config: HubTokenizerConfig::default(),
+            processor_config: HubProcessorConfig::default(),
+            length_energy_penalty: None,
+            telemetry_fields: Vec::new(),
+            interactive_max_new_tokens: None,
+            max_concurrent_interactive_requests: None,
+            max_concurrent_batch_requests: None,
+            tenant_quotas: Vec::new(),
+            default_tenant_quota: None,
+            energy_devices: Vec::new(),
+            energy_device_uuid: None,
+            energy_source: EnergySource::Nvml,
+            energy_replay_readings: Vec::new(),
+            energy_meter: None,
+            carbon_intensity_g_per_kwh: None,
+            energy_sampling_interval: 1,
+            token_energy_floor: None,
+            token_energy_cap: None,
+            energy_unit: EnergyUnit::Millijoules,
+            efficiency_ewma_alpha: 0.1,
+            efficiency_floor: None,
+            idempotency_cache_size: 1024,
+            idempotency_ttl: Duration::from_secs(600),
+            sampling_hook: None,
+            energy_log_path: None,
+            energy_log_format: EnergyLogFormat::default(),
+            prefix_cache_blocks: None,
+        }
+    }
+
+    pub(crate) fn max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests;
+        self
+    }
+
+    /// Reject the inputs longer than `max_input_bytes` before tokenizing them
+    pub(crate) fn max_input_bytes(mut self, max_input_bytes: usize) -> Self {
+        self.max_input_bytes = max_input_bytes;
+        self
+    }
+
+    pub(crate) fn tokenizer_config(mut self, tokenizer_config: HubTokenizerConfig) -> Self {
+        self.tokenizer_config = tokenizer_config;
+        self
+    }
+
+    pub(crate) fn processor_config(mut self, processor_config: HubProcessorConfig) -> Self {
+        self.processor_config = processor_config;
+        self
+    }
+
+    /// Flag the outputs longer than `penalty` times the recent average
+    pub(crate) fn length_energy_penalty(mut self, penalty: Option<f32>) -> Self {
+        self.length_energy_penalty = penalty;
+        self
+    }
+
+    pub(crate) fn telemetry_fields(mut self, telemetry_fields: Vec<TelemetryField>) -> Self {
+        self.telemetry_fields = telemetry_fields;
+        self
+    }
+
+    /// Requests with at most `max_new_tokens` are interactive, the others are batch requests
+    pub(crate) fn interactive_max_new_tokens(mut self, max_new_tokens: Option<u32>) -> Self {
+        self.interactive_max_new_tokens = max_new_tokens;
+        self
+    }
+
+    pub(crate) fn max_concurrent_interactive_requests(mut self, max: Option<usize>) -> Self {
+        self.max_concurrent_interactive_requests = max;
+        self
+    }
+
+    pub(crate) fn max_concurrent_batch_requests(mut self, max: Option<usize>) -> Self {
+        self.max_concurrent_batch_requests = max;
+        self
+    }
+
+    /// Limit the concurrent requests of each API key, `default_quota` applying to the keys
+    /// without a quota of their own
+    pub(crate) fn tenant_quotas(
+        mut self,
+        quotas: Vec<TenantQuota>,
+        default_quota: Option<usize>,
+    ) -> Self {
+        self.tenant_quotas = quotas;
+        self.default_tenant_quota = default_quota;
+        self
+    }
+
+    /// Devices the energy is measured on, the first one when empty
+    pub(crate) fn energy_devices(mut self, energy_devices: Vec<u32>) -> Self {
+        self.energy_devices = energy_devices;
+        self
+    }
+
+    /// Measure the energy on a single device
+    #[cfg(test)]
+    pub(crate) fn device_index(self, device_index: u32) -> Self {
+        self.energy_devices(vec![device_index])
+    }
+
+    /// Measure the energy of the NVML device with this UUID, which takes precedence over the
+    /// device indices
+    pub(crate) fn energy_device_uuid(mut self, uuid: Option<String>) -> Self {
+        self.energy_device_uuid = uuid;
+        self
+    }
+
+    pub(crate) fn energy_source(mut self, energy_source: EnergySource) -> Self {
+        self.energy_source = energy_source;
+        self
+    }
+
+    /// Readings replayed by the [`EnergySource::Replay`] source
+    pub(crate) fn energy_replay_readings(mut self, readings: Vec<u64>) -> Self {
+        self.energy_replay_readings = readings;
+        self
+    }
+
+    /// Read the energy with `energy_meter` instead of the meter of the energy source, so that
+    /// the tests do not depend on the devices of the machine. `None` disables energy tracking.
+    #[cfg(test)]
+    pub(crate) fn energy_meter(mut self, energy_meter: Option<Arc<dyn EnergyMeter>>) -> Self {
+        self.energy_meter = Some(energy_meter);
+        self
+    }
+
+    pub(crate) fn carbon_intensity_g_per_kwh(mut self, carbon_intensity: Option<f64>) -> Self {
+        self.carbon_intensity_g_per_kwh = carbon_intensity;
+        self
+    }
+
+    pub(crate) fn energy_sampling_interval(mut self, energy_sampling_interval: usize) -> Self {
+        self.energy_sampling_interval = energy_sampling_interval;
+        self
+    }
+
+    /// Bounds of the plausible token energies in millijoules: the energies below `floor` are
+    /// added to the next token and the ones above `cap` are counted as outliers. The energies of
+    /// the requests are unchanged.
+    pub(crate) fn token_energy_bounds(mut self, floor: Option<u64>, cap: Option<u64>) -> Self {
+        self.token_energy_floor = floor;
+        self.token_energy_cap = cap;
+        self
+    }
+
+    pub(crate) fn energy_unit(mut self, energy_unit: EnergyUnit) -> Self {
+        self.energy_unit = energy_unit;
+        self
+    }
+
+    pub(crate) fn efficiency_ewma_alpha(mut self, alpha: f64) -> Self {
+        self.efficiency_ewma_alpha = alpha;
+        self
+    }
+
+    /// Reject new requests while the moving average of the tokens per joule is below `floor`
+    pub(crate) fn efficiency_floor(mut self, floor: Option<f64>) -> Self {
+        self.efficiency_floor = floor;
+        self
+    }
+
+    /// Keep the responses of up to `size` requests sent with an idempotency key
+    pub(crate) fn idempotency_cache_size(mut self, size: usize) -> Self {
+        self.idempotency_cache_size = size;
+        self
+    }
+
+    /// Replay the stored responses for `ttl` after they were generated
+    pub(crate) fn idempotency_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency_ttl = ttl;
+        self
+    }
+
+    /// Let `hook` change the sampling parameters during the generations
+    pub(crate) fn sampling_hook(mut self, hook: Option<SamplingHook>) -> Self {
+        self.sampling_hook = hook;
+        self
+    }
+
+    /// Append the energy report of every completed request to the file at `path`
+    pub(crate) fn energy_log(mut self, path: Option<PathBuf>, format: EnergyLogFormat) -> Self {
+        self.energy_log_path = path;
+        self.energy_log_format = format;
+        self
+    }
+
+    /// Detect the inputs sharing a prefix with a recent one, remembering up to `blocks` blocks
+    /// of their prefixes
+    pub(crate) fn prefix_cache_blocks(mut self, blocks: Option<usize>) -> Self {
+        self.prefix_cache_blocks = blocks;
+        self
+    }
+
+    pub(crate) fn build(self) -> Infer {
+        let tokenizer_config = self.tokenizer_config;
+        let special_tokens = SpecialTokensResponse::from(&tokenizer_config);
+        let eos_tokens = tokenizer_config
+            .eos_tokens
+            .iter()
+            .map(|token| token.as_str().to_string())
+            .collect();
+        let chat_template_cache = Arc::new(ChatTemplateCache::new(
+            CHAT_TEMPLATE_CACHE_SIZE,
+            tokenizer_config.bos_token.clone(),
+            tokenizer_config.eos_tokens.clone(),
+        ));
+        // All the templates are kept so that requests can select one by name
+        let templates = match tokenizer_config
+            .chat_template
+            .or(self.processor_config.chat_template)
+        {
+            Some(ChatTemplateVersions::Single(template)) => {
+                vec![(DEFAULT_CHAT_TEMPLATE.to_string(), template)]
+            }
+            Some(ChatTemplateVersions::Multiple(templates)) => templates
+                .into_iter()
+                .map(|t| (t.name, t.template))
+                .collect(),
+            None => Vec::new(),
+        };
+        let chat_templates: HashMap<_, _> = templates
+            .into_iter()
+            .map(|(name, template)| {
+                let template = ChatTemplate::new(
+                    template,
+                    tokenizer_config.bos_token.clone(),
+                    &tokenizer_config.eos_tokens,
+                );
+                (name, template)
+            })
+            .collect();
+
+        // Inference limit with a semaphore, and optionally one per request class
+        let limits = ConcurrencyLimits::new(
+            self.max_concurrent_requests,
+            self.interactive_max_new_tokens,
+            self.max_concurrent_interactive_requests,
+            self.max_concurrent_batch_requests,
+        )
+        .tenant_quotas(self.tenant_quotas, self.default_tenant_quota);
+
+        // Backend health
+        let backend_health = Arc::new(AtomicBool::new(self.backend.start_health()));
+
+        // Energy is measured on the first device unless told otherwise
+        let energy_devices = if self.energy_devices.is_empty() {
+            vec![0]
+        } else {
+            self.energy_devices
+        };
+        let by_uuid = self.energy_device_uuid.is_some();
+        let (nvml, energy_meter) = match (self.energy_meter, self.energy_source) {
+            (Some(energy_meter), _) => (
+                None,
+                energy_meter.and_then(|meter| DeviceMeter::new(meter, &energy_devices)),
+            ),
+            (None, EnergySource::Nvml) => {
+                // Energy is not tracked on machines without NVIDIA drivers
+                let nvml = match Nvml::init() {
+                    Ok(nvml) => Some(Arc::new(nvml)),
+                    Err(err) => {
+                        tracing::warn!(
+                            "Could not initialize NVML, energy tracking is disabled: {err}"
+                        );
+                        None
+                    }
+                };
+                let energy_meter = match (nvml.clone(), self.energy_device_uuid) {
+                    (Some(nvml), Some(uuid)) => {
+                        DeviceMeter::new(Arc::new(NvmlUuidMeter::new(nvml, uuid)), &[0])
+                    }
+                    (Some(nvml), None) => DeviceMeter::new(nvml, &energy_devices),
+                    (None, _) => None,
+                };
+                (nvml, energy_meter)
+            }
+            (None, EnergySource::Rapl) => {
+                let energy_meter = match RaplMeter::new(Path::new(POWERCAP_ROOT)) {
+                    Ok(rapl) => DeviceMeter::new(Arc::new(rapl), &energy_devices),
+                    Err(err) => {
+                        tracing::warn!(
+                            "Could not initialize RAPL, energy tracking is disabled: {err}"
+                        );
+                        None
+                    }
+                };
+                (None, energy_meter)
+            }
+            (None, EnergySource::Replay) => {
+                tracing::warn!("Energy is replayed from synthetic readings, not measured");
+                let meter = MockEnergyMeter::new(self.energy_replay_readings);
+                (None, DeviceMeter::new(Arc::new(meter), &[0]))
+            }
+        };
+
+        if let Some(energy_meter) = &energy_meter {
+            tracing::info!(
+                devices = ?energy_meter.devices(),
+                strategy = ?energy_meter.strategy(),
+                "Energy tracking enabled"
+            );
+        }
+
+        // Extended telemetry is sampled with NVML on the first device the energy is measured on,
+        // it is not sampled on a device addressed by its UUID since it has no index
+        let telemetry_fields = self.telemetry_fields;
+        let telemetry: Option<Arc<dyn TelemetrySource>> = match (&nvml, &energy_meter) {
+            (Some(nvml), Some(energy_meter)) if !telemetry_fields.is_empty() && !by_uuid => Some(
+                Arc::new(NvmlTelemetry::new(nvml.clone(), energy_meter.devices()[0])),
+            ),
+            _ => None,
+        };
+
+        // Requests are still served when the log cannot be opened
+        let format = self.energy_log_format;
+        let energy_log =
+            self.energy_log_path
+                .and_then(|path| match EnergyLog::open(&path, format) {
+                    Ok(energy_log) => {
+                        tracing::info!(
+                            "Appending the energy of the requests to {}",
+                            path.display()
+                        );
+                        Some(Arc::new(energy_log))
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "Could not open the energy log {}, it is disabled: {err}",
+                            path.display()
+                        );
+                        None
+                    }
+                });
+
+        Infer {
+            validation: self.validation,
+            backend: self.backend,
+            chat_templates: Arc::new(chat_templates),
+            chat_template_cache,
+            idempotency_cache: Arc::new(IdempotencyCache::new(
+                self.idempotency_cache_size,
+                self.idempotency_ttl,
+            )),
+            special_tokens,
+            eos_tokens,
+            max_input_bytes: self.max_input_bytes,
+            efficiency_floor: self.efficiency_floor,
+            limit_concurrent_requests: limits,
+            backend_health,
+            energy_meter,
+            energy_stats: Arc::new(EnergyStats::new(self.efficiency_ewma_alpha)),
+            carbon_intensity_g_per_kwh: self.carbon_intensity_g_per_kwh,
+            energy_sampling_interval: self.energy_sampling_interval,
+            token_energy_floor: self.token_energy_floor,
+            token_energy_cap: self.token_energy_cap,
+            energy_unit: self.energy_unit,
+            idle_power_mw: Default::default(),
+            output_length_monitor: self
+                .length_energy_penalty
+                .map(|penalty| Arc::new(OutputLengthMonitor::new(penalty))),
+            telemetry_fields: telemetry_fields.into(),
+            telemetry,
+            nvml,
+            sampling_hook: self.sampling_hook,
+            energy_log,
+            prefix_cache: self
+                .prefix_cache_blocks
+                .map(|blocks| Arc::new(PrefixCache::new(blocks))),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::infer::{InferError, InferStreamResponse};
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio_util::sync::CancellationToken;
+
+/// Stream of a request that cancels its generation when dropped before the end
+///
+/// The stream is dropped early when the client disconnects. Cancelling the token lets the backend
+/// stop generating tokens nobody will read.
+pub(crate) struct CancelOnDrop<S> {
+    inner: Pin<Box<S>>,
+    cancellation: CancellationToken,
+    finished: bool,
+}
+
+impl<S> CancelOnDrop<S> {
+    pub(crate) fn new(inner: S, cancellation: CancellationToken) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            cancellation,
+            finished: false,
+        }
+    }
+}
+
+impl<S: Stream<Item = Result<InferStreamResponse, InferError>>> Stream for CancelOnDrop<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.inner.as_mut().poll_next(cx));
+        // The request is over once its last token or an error was sent
+        if matches!(
+            item,
+            None | Some(Err(_)) | Some(Ok(InferStreamResponse::End { .. }))
+        ) {
+            self.finished = true;
+        }
+        Poll::Ready(item)
+    }
+}
+
+impl<S> Drop for CancelOnDrop<S> {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::debug!("Request cancelled before the end of the generation");
+            metrics::counter!("tgi_request_cancelled").increment(1);
+            self.cancellation.cancel();
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use crate::infer::InferError;
 use crate::{
-    ChatTemplateInputs, Message, MessageBody, MessageChunk, TextMessage, TokenizerConfigToken, Tool,
+    ChatTemplateInputs, Message, MessageBody, MessageChunk, TemplateErrorDetail, TextMessage,
+    TokenizerConfigToken, Tool,
 };
 use chrono::Local;
-use minijinja::{Environment, ErrorKind, Template};
+use minijinja::{Environment, ErrorKind};
 use minijinja_contrib::pycompat;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
 /// Raise a exception (custom function) used in the chat templates
 pub(crate) fn raise_exception(err_text: String) -> Result<String, minijinja::Error> {
@@ -16,52 +19,76 @@ pub(crate) fn strftime_now(format_str: String) -> Result<String, minijinja::Erro
     Ok(Local::now().format(&format_str).to_string())
 }
 
+/// Name of the template used when the request does not select one, and of the template of
+/// models shipping a single one
+pub(crate) const DEFAULT_CHAT_TEMPLATE: &str = "default";
+
+/// Number of templates compiled from requests kept in the cache
+pub(crate) const CHAT_TEMPLATE_CACHE_SIZE: usize = 16;
+
+/// Name of the chat template in its environment
+const TEMPLATE_NAME: &str = "chat_template";
+
+/// Environment with the custom functions and methods used by the chat templates, owning the
+/// compiled `template`
+fn environment(template: String) -> Result<Environment<'static>, minijinja::Error> {
+    let mut env = Environment::new();
+    // enable things like .strip() or .capitalize()
+    env.set_unknown_method_callback(pycompat::unknown_method_callback);
+    env.add_function("raise_exception", raise_exception);
+    env.add_function("strftime_now", strftime_now);
+    env.add_template_owned(TEMPLATE_NAME, prepare_source(template))?;
+    Ok(env)
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct ChatTemplate {
-    template: Template<'static, 'static>,
+    env: Arc<Environment<'static>>,
     bos_token: Option<String>,
     eos_token: Option<String>,
     use_default_tool_template: bool,
 }
 
 impl ChatTemplate {
+    /// `eos_tokens` are the end of sequence tokens of the model, templates render the first one
     pub(crate) fn new(
         template: String,
         bos_token: Option<TokenizerConfigToken>,
-        eos_token: Option<TokenizerConfigToken>,
+        eos_tokens: &[TokenizerConfigToken],
     ) -> Self {
-        let mut env = Box::new(Environment::new());
-        // enable things like .strip() or .capitalize()
-        env.set_unknown_method_callback(pycompat::unknown_method_callback);
-
-        // TODO: replace with better solution
-        // hack to adjust gemma3 template for debug
-        // replace 'messages[0]['content'][0]['text']' with 'messages[0]['content']'
-        let mutated_template = template.replace(
-            "messages[0]['content'][0]['text']",
-            "messages[0]['content']",
-        );
-
-        let template_str = mutated_template.into_boxed_str();
-        env.add_function("raise_exception", raise_exception);
-        env.add_function("strftime_now", strftime_now);
-        tracing::debug!("Loading template: {}", template_str);
+        tracing::debug!("Loading template: {}", template);
+        let env = environment(template).unwrap();
+        Self::from_env(env, bos_token, eos_tokens)
+    }
 
-        // leaking env and template_str as read-only, static resources for performance.
-        let template = Box::leak(env)
-            .template_from_str(Box::leak(template_str))
-            .unwrap();
+    /// Compile a template sent in a request
+    pub(crate) fn from_request(
+        template: &str,
+        bos_token: Option<TokenizerConfigToken>,
+        eos_tokens: &[TokenizerConfigToken],
+    ) -> Result<Self, InferError> {
+        let env = environment(template.to_string()).map_err(InferError::TemplateError)?;
+        Ok(Self::from_env(env, bos_token, eos_tokens))
+    }
 
+    fn from_env(
+        env: Environment<'static>,
+        bos_token: Option<TokenizerConfigToken>,
+        eos_tokens: &[TokenizerConfigToken],
+    ) -> Self {
         // get the list of variables that are used in the template
-        let variables = template.undeclared_variables(true);
+        let variables = env
+            .get_template(TEMPLATE_NAME)
+            .expect("the chat template is added to its environment")
+            .undeclared_variables(true);
         // check if the `tools` variable is used in the template
         let use_default_tool_template = !variables.contains("tools");
         tracing::debug!("Use default tool template: {}", use_default_tool_template);
 
         Self {
-            template,
+            env: Arc::new(env),
             bos_token: bos_token.map(|token| token.as_str().to_string()),
-            eos_token: eos_token.map(|token| token.as_str().to_string()),
+            eos_token: eos_tokens.first().map(|token| token.as_str().to_string()),
             use_default_tool_template,
         }
     }
@@ -97,7 +124,9 @@ impl ChatTemplate {
         let messages: Vec<TextMessage> = messages.into_iter().map(|c| c.into()).collect();
         let final_message = messages.last().cloned();
         let mut rendered_template = self
-            .template
+            .env
+            .get_template(TEMPLATE_NAME)
+            .map_err(InferError::TemplateError)?
             .render(ChatTemplateInputs {
                 messages,
                 bos_token: self.bos_token.as_deref(),
@@ -105,7 +134,7 @@ impl ChatTemplate {
                 add_generation_prompt: true,
                 tools,
             })
-            .map_err(InferError::TemplateError)?;
+            .map_err(render_error)?;
 
         // if the last message is from the assistant, continue the generation prompt
         rendered_template = match final_message {
@@ -126,17 +155,114 @@ impl ChatTemplate {
     }
 }
 
+/// Name the expression that uses an undefined variable, so that the template can be fixed
+fn render_error(err: minijinja::Error) -> InferError {
+    if err.kind() == ErrorKind::UndefinedError {
+        let expression = err
+            .range()
+            .and_then(|range| err.template_source()?.get(range))
+            .map(str::trim)
+            .filter(|expression| !expression.is_empty());
+        if let Some(expression) = expression {
+            return InferError::MissingTemplateVariable(expression.to_string(), err);
+        }
+    }
+    InferError::TemplateError(err)
+}
+
+/// Location of `err` in the template, when minijinja kept it
+pub(crate) fn template_error_detail(
+    err: &minijinja::Error,
+    undefined_variable: Option<&str>,
+) -> TemplateErrorDetail {
+    let start = err.range().map(|range| range.start);
+    let before = start.and_then(|start| err.template_source()?.get(..start));
+    let line = err
+        .line()
+        .or_else(|| before.map(|before| before.matches('\n').count() + 1));
+    let column = before.map(|before| {
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        before[line_start..].chars().count() + 1
+    });
+    TemplateErrorDetail {
+        kind: format!("{:?}", err.kind()),
+        line,
+        column,
+        undefined_variable: undefined_variable.map(str::to_string),
+        detail: err.detail().map(str::to_string),
+    }
+}
+
+fn prepare_source(template: String) -> String {
+    // TODO: replace with better solution
+    // hack to adjust gemma3 template for debug
+    // replace 'messages[0]['content'][0]['text']' with 'messages[0]['content']'
+    template.replace(
+        "messages[0]['content'][0]['text']",
+        "messages[0]['content']",
+    )
+}
+
+/// Least recently used cache of the templates compiled from requests
+#[derive(Debug)]
+pub(crate) struct ChatTemplateCache {
+    capacity: usize,
+    bos_token: Option<TokenizerConfigToken>,
+    eos_tokens: Vec<TokenizerConfigToken>,
+    /// Templates by source, the most recently used last
+    templates: Mutex<VecDeque<(String, ChatTemplate)>>,
+}
+
+impl ChatTemplateCache {
+    pub(crate) fn new(
+        capacity: usize,
+        bos_token: Option<TokenizerConfigToken>,
+        eos_tokens: Vec<TokenizerConfigToken>,
+    ) -> Self {
+        Self {
+            capacity,
+            bos_token,
+            eos_tokens,
+            templates: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Return the compiled template, compiling it if it is not cached
+    pub(crate) fn get(&self, source: &str) -> Result<ChatTemplate, InferError> {
+        {
+            let mut templates = self.templates.lock().expect("chat template lock poisoned");
+            if let Some(index) = templates.iter().position(|(cached, _)| cached == source) {
+                let entry = templates.remove(index).expect("index in bounds");
+                let template = entry.1.clone();
+                templates.push_back(entry);
+                return Ok(template);
+            }
+        }
+
+        // Compile without holding the lock, the same template may be compiled twice
+        let template =
+            ChatTemplate::from_request(source, self.bos_token.clone(), &self.eos_tokens)?;
+        let mut templates = self.templates.lock().expect("chat template lock poisoned");
+        if templates.len() >= self.capacity {
+            templates.pop_front();
+        }
+        templates.push_back((source.to_string(), template.clone()));
+        Ok(template)
+    }
+}
+
 // tests
 #[cfg(test)]
 mod tests {
-    use crate::infer::chat_template::{raise_exception, strftime_now};
-    use crate::infer::ChatTemplate;
+    use crate::infer::chat_template::{raise_exception, strftime_now, ChatTemplateCache};
+    use crate::infer::{ChatTemplate, InferError};
     use crate::{
         ChatTemplateInputs, Message, MessageBody, MessageChunk, MessageContent, TextMessage,
         TokenizerConfigToken, Tool, Url,
     };
     use chrono::Local;
     use minijinja::Environment;
+    use std::sync::Arc;
 
     #[test]
     fn test_chat_template() {
@@ -1162,7 +1288,7 @@ TOOL CALL ID: 0
         let ct = ChatTemplate::new(
             "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token + ' ' }}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}".to_string(),
             Some(TokenizerConfigToken::String("<s>".to_string())),
-            Some(TokenizerConfigToken::String("</s>".to_string())),
+            &[TokenizerConfigToken::String("</s>".to_string())],
         );
 
         // convert TextMessage to Message
@@ -1208,7 +1334,7 @@ TOOL CALL ID: 0
         let ct = ChatTemplate::new(
             "{{- bos_token }}\n{%- if not tools_in_user_message is defined %}\n    {%- set tools_in_user_message = true %}\n{%- endif %}\n{%- if not date_string is defined %}\n    {%- set date_string = \"26 Jul 2024\" %}\n{%- endif %}\n{%- if not tools is defined %}\n    {%- set tools = none %}\n{%- endif %}\n\n{#- This block extracts the system message, so we can slot it into the right place. #}\n{%- if messages[0]['role'] == 'system' %}\n    {%- set system_message = messages[0]['content']|trim %}\n    {%- set messages = messages[1:] %}\n{%- else %}\n    {%- set system_message = \"\" %}\n{%- endif %}\n\n{#- System message + builtin tools #}\n{{- \"<|start_header_id|>system<|end_header_id|>\\n\\n\" }}\n{%- if builtin_tools is defined or tools is not none %}\n    {{- \"Environment: ipython\\n\" }}\n{%- endif %}\n{%- if builtin_tools is defined %}\n    {{- \"Tools: \" + builtin_tools | reject('equalto', 'code_interpreter') | join(\", \") + \"\\n\\n\"}}\n{%- endif %}\n{{- \"Cutting Knowledge Date: December 2023\\n\" }}\n{{- \"Today Date: \" + date_string + \"\\n\\n\" }}\n{%- if tools is not none and not tools_in_user_message %}\n    {{- \"You have access to the following functions. To call a function, please respond with JSON for a function call.\" }}\n    {{- 'Respond in the format {\"name\": function name, \"parameters\": dictionary of argument name and its value}.' }}\n    {{- \"Do not use variables.\\n\\n\" }}\n    {%- for t in tools %}\n        {{- t | tojson(indent=4) }}\n        {{- \"\\n\\n\" }}\n    {%- endfor %}\n{%- endif %}\n{{- system_message }}\n{{- \"<|eot_id|>\" }}\n\n{#- Custom tools are passed in a user message with some extra guidance #}\n{%- if tools_in_user_message and not tools is none %}\n    {#- Extract the first user message so we can plug it in here #}\n    {%- if messages | length != 0 %}\n        {%- set first_user_message = messages[0]['content']|trim %}\n        {%- set messages = messages[1:] %}\n    {%- else %}\n        {{- raise_exception(\"Cannot put tools in the first user message when there's no first user message!\") }}\n{%- endif %}\n    {{- '<|start_header_id|>user<|end_header_id|>\\n\\n' -}}\n    {{- \"Given the following functions, please respond with a JSON for a function call \" }}\n    {{- \"with its proper arguments that best answers the given prompt.\\n\\n\" }}\n    {{- 'Respond in the format {\"name\": function name, \"parameters\": dictionary of argument name and its value}.' }}\n    {{- \"Do not use variables.\\n\\n\" }}\n    {%- for t in tools %}\n        {{- t | tojson(indent=4) }}\n        {{- \"\\n\\n\" }}\n    {%- endfor %}\n    {{- first_user_message + \"<|eot_id|>\"}}\n{%- endif %}\n\n{%- for message in messages %}\n    {%- if not (message.role == 'ipython' or message.role == 'tool' or 'tool_calls' in message) %}\n        {{- '<|start_header_id|>' + message['role'] + '<|end_header_id|>\\n\\n'+ message['content'] | trim + '<|eot_id|>' }}\n    {%- elif 'tool_calls' in message %}\n        {%- if not message.tool_calls|length == 1 %}\n            {{- raise_exception(\"This model only supports single tool-calls at once!\") }}\n        {%- endif %}\n        {%- set tool_call = message.tool_calls[0].function %}\n        {%- if builtin_tools is defined and tool_call.name in builtin_tools %}\n            {{- '<|start_header_id|>assistant<|end_header_id|>\\n\\n' -}}\n            {{- \"<|python_tag|>\" + tool_call.name + \".call(\" }}\n            {%- for arg_name, arg_val in tool_call.arguments | items %}\n                {{- arg_name + '=\"' + arg_val + '\"' }}\n                {%- if not loop.last %}\n                    {{- \", \" }}\n                {%- endif %}\n                {%- endfor %}\n            {{- \")\" }}\n        {%- else  %}\n            {{- '<|start_header_id|>assistant<|end_header_id|>\\n\\n' -}}\n            {{- '{\"name\": \"' + tool_call.name + '\", ' }}\n            {{- '\"parameters\": ' }}\n            {{- tool_call.arguments | tojson }}\n            {{- \"}\" }}\n        {%- endif %}\n        {%- if builtin_tools is defined %}\n            {#- This means we're in ipython mode #}\n            {{- \"<|eom_id|>\" }}\n        {%- else %}\n            {{- \"<|eot_id|>\" }}\n        {%- endif %}\n    {%- elif message.role == \"tool\" or message.role == \"ipython\" %}\n        {{- \"<|start_header_id|>ipython<|end_header_id|>\\n\\n\" }}\n        {%- if message.content is mapping or message.content is iterable %}\n            {{- message.content | tojson }}\n        {%- else %}\n            {{- message.content }}\n        {%- endif %}\n        {{- \"<|eot_id|>\" }}\n    {%- endif %}\n{%- endfor %}\n{%- if add_generation_prompt %}\n    {{- '<|start_header_id|>assistant<|end_header_id|>\\n\\n' }}\n{%- endif %}\n".to_string(),
             Some(TokenizerConfigToken::String("<s>".to_string())),
-            Some(TokenizerConfigToken::String("</s>".to_string())),
+            &[TokenizerConfigToken::String("</s>".to_string())],
         );
         let msgs: Vec<Message> = vec![
             Message {
@@ -1288,7 +1414,7 @@ TOOL CALL ID: 0
 "#
             .to_string(),
             Some(TokenizerConfigToken::String("<bos>".to_string())),
-            Some(TokenizerConfigToken::String("</eos>".to_string())),
+            &[TokenizerConfigToken::String("</eos>".to_string())],
         );
         let msgs: Vec<Message> = vec![
             Message {
@@ -1333,4 +1459,120 @@ TOOL CALL ID: 0
         let expected = "<bos><start_of_turn>user\nYou are a helpful assistant.\n\nI'm already using this supplement ![](https://huggingface.co/datasets/merve/vlm_test_images/resolve/main/IMG_3018.JPG)and I want to use this one too ![](https://huggingface.co/datasets/merve/vlm_test_images/resolve/main/IMG_3015.jpg) what are cautions?<end_of_turn>\n<start_of_turn>model\n".to_string();
         assert_eq!(result.unwrap(), expected);
     }
+
+    fn user_message(text: &str) -> Message {
+        Message {
+            name: None,
+            role: "user".to_string(),
+            body: MessageBody::Content {
+                content: MessageContent::SingleText(text.to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn test_request_template_cache() {
+        let cache = ChatTemplateCache::new(
+            2,
+            Some(TokenizerConfigToken::String("<s>".to_string())),
+            Vec::new(),
+        );
+        let first = "{{ bos_token }}{% for m in messages %}[{{ m.content }}]{% endfor %}";
+        let second = "{% for m in messages %}{{ m.role }}{% endfor %}";
+        let third = "{{ messages | length }}";
+
+        let template = cache.get(first).unwrap();
+        let rendered = template.apply(vec![user_message("Hi")], None).unwrap();
+        assert_eq!(rendered, "<s>[Hi]");
+
+        // The cached template is shared, not compiled again
+        let cached = cache.get(first).unwrap();
+        assert!(Arc::ptr_eq(&cached.env, &template.env));
+
+        // `first` was used last, `second` is evicted
+        cache.get(second).unwrap();
+        cache.get(first).unwrap();
+        cache.get(third).unwrap();
+        let sources: Vec<String> = cache
+            .templates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(source, _)| source.clone())
+            .collect();
+        assert_eq!(sources, [first, third]);
+
+        // Evicted templates stay usable by the requests holding them
+        drop(cache);
+        let rendered = template.apply(vec![user_message("Hello")], None).unwrap();
+        assert_eq!(rendered, "<s>[Hello]");
+    }
+
+    #[test]
+    fn test_multiple_eos_tokens() {
+        let eos_tokens = [
+            TokenizerConfigToken::String("<|eot_id|>".to_string()),
+            TokenizerConfigToken::String("</s>".to_string()),
+        ];
+        let template = ChatTemplate::new(
+            "{% for m in messages %}{{ m.content + eos_token }}{% endfor %}".to_string(),
+            None,
+            &eos_tokens,
+        );
+        let rendered = template.apply(vec![user_message("Hi")], None).unwrap();
+        assert_eq!(rendered, "Hi<|eot_id|>");
+
+        let cache = ChatTemplateCache::new(2, None, eos_tokens.to_vec());
+        let template = cache.get("{{ eos_token }}").unwrap();
+        assert_eq!(
+            template.apply(vec![user_message("Hi")], None).unwrap(),
+            "<|eot_id|>"
+        );
+    }
+
+    #[test]
+    fn test_invalid_request_template() {
+        let cache = ChatTemplateCache::new(2, None, Vec::new());
+        assert!(matches!(
+            cache.get("{% for m in messages %}"),
+            Err(InferError::TemplateError(_))
+        ));
+        assert!(cache.templates.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_missing_template_variable() {
+        let cache = ChatTemplateCache::new(2, None, Vec::new());
+        let template = cache
+            .get("{% for m in messages %}{{ m.content }}{{ system.prefix }}{% endfor %}")
+            .unwrap();
+        let err = template.apply(vec![user_message("Hi")], None).unwrap_err();
+        assert!(
+            matches!(&err, InferError::MissingTemplateVariable(name, _) if name == "system.prefix"),
+            "{err}"
+        );
+        let detail = err.template_error_detail().unwrap();
+        assert_eq!(detail.kind, "UndefinedError");
+        assert_eq!(detail.undefined_variable.as_deref(), Some("system.prefix"));
+        assert_eq!(detail.line, Some(1));
+        assert_eq!(detail.column, Some(42));
+    }
+
+    #[test]
+    fn test_template_syntax_error_detail() {
+        let cache = ChatTemplateCache::new(2, None, Vec::new());
+        let err = cache
+            .get("{% for m in messages %}\n{{ m.content }\n{% endfor %}")
+            .unwrap_err();
+        let detail = err.template_error_detail().unwrap();
+        assert_eq!(detail.kind, "SyntaxError");
+        assert_eq!(detail.line, Some(2));
+        assert!(detail.column.is_some());
+        assert_eq!(detail.undefined_variable, None);
+        assert!(detail.detail.is_some());
+        assert_eq!(
+            InferError::IncompleteGeneration.template_error_detail(),
+            None
+        );
+    }
 }
@@ -0,0 +1,1379 @@
+use crate::infer::InferError;
+use crate::{EnergySummary, FinishReason, TokenEnergyStats};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::{Device, Nvml};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// Root of the powercap tree exposing the RAPL counters
+pub(crate) const POWERCAP_ROOT: &str = "/sys/class/powercap";
+
+/// Hardware the energy is read from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EnergySource {
+    /// NVIDIA GPUs, through NVML
+    #[default]
+    Nvml,
+    /// CPU packages, through the Intel RAPL powercap interface
+    Rapl,
+    /// Synthetic readings replayed from `--energy-replay-readings`, for reproducible load tests
+    Replay,
+}
+
+/// Format of the energy log
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EnergyLogFormat {
+    /// One JSON energy report per line
+    #[default]
+    Jsonl,
+    /// Binary energy trace, read back with [`crate::energy_trace::read_energy_trace`]
+    Trace,
+}
+
+/// Unit of the energies reported to the clients
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum EnergyUnit {
+    /// Raw readings of the energy counters
+    #[default]
+    Millijoules,
+    Joules,
+    WattHours,
+}
+
+/// Fields of the responses holding an energy in millijoules
+const ENERGY_FIELDS: [&str; 7] = [
+    "energy_consumption",
+    "prefill_energy",
+    "decode_energy",
+    "batch_energy_consumption",
+    "wasted_energy",
+    "step_energy",
+    "prefill_energy_saved_estimate",
+];
+
+/// Fields of the responses holding an object whose numbers are all energies in millijoules
+const ENERGY_OBJECTS: [&str; 1] = ["token_energy_stats"];
+
+/// Fields of the responses echoing the client as is, whatever their content
+const OPAQUE_FIELDS: [&str; 1] = ["metadata"];
+
+impl EnergyUnit {
+    pub(crate) fn convert(self, millijoules: u64) -> f64 {
+        self.convert_f64(millijoules as f64)
+    }
+
+    fn convert_f64(self, millijoules: f64) -> f64 {
+        match self {
+            EnergyUnit::Millijoules => millijoules,
+            EnergyUnit::Joules => millijoules / 1e3,
+            EnergyUnit::WattHours => millijoules / 3.6e6,
+        }
+    }
+
+    /// Serialize a response with its energies in this unit
+    ///
+    /// This is the only place energies are converted, so that the per token energies and the
+    /// totals of a response always use the same unit. Millijoules are left untouched and stay
+    /// integers.
+    pub(crate) fn to_json(self, response: &impl Serialize) -> serde_json::Result<Value> {
+        let mut value = serde_json::to_value(response)?;
+        if self != EnergyUnit::Millijoules {
+            self.convert_fields(&mut value);
+        }
+        Ok(value)
+    }
+
+    fn convert_fields(self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    match field {
+                        _ if OPAQUE_FIELDS.contains(&name.as_str()) => {}
+                        Value::Object(energies) if ENERGY_OBJECTS.contains(&name.as_str()) => {
+                            for energy in energies.values_mut() {
+                                if let Some(millijoules) = energy.as_f64() {
+                                    *energy = Value::from(self.convert_f64(millijoules));
+                                }
+                            }
+                        }
+                        _ => match field.as_u64() {
+                            Some(millijoules) if ENERGY_FIELDS.contains(&name.as_str()) => {
+                                *field = Value::from(self.convert(millijoules));
+                            }
+                            _ => self.convert_fields(field),
+                        },
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|v| self.convert_fields(v)),
+            _ => {}
+        }
+    }
+}
+
+/// Cumulative energy counters of the accelerators
+pub(crate) trait EnergyMeter: Send + Sync {
+    /// Number of devices visible to the meter
+    fn device_count(&self) -> Result<u32, InferError>;
+
+    /// Energy consumed by a device since the driver was loaded, in millijoules
+    fn total_energy_consumption(&self, device_index: u32) -> Result<u64, InferError>;
+
+    /// Instantaneous power draw of a device, in milliwatts
+    fn power_usage(&self, device_index: u32) -> Result<u32, InferError>;
+
+    /// Whether the device has an energy counter at all
+    ///
+    /// Devices without one have their energy estimated from their power draw instead.
+    fn energy_counter_supported(&self, device_index: u32) -> bool {
+        self.total_energy_consumption(device_index).is_ok()
+    }
+}
+
+impl EnergyMeter for Nvml {
+    fn device_count(&self) -> Result<u32, InferError> {
+        Nvml::device_count(self).map_err(|e| InferError::EnergyConsumptionError(e.to_string()))
+    }
+
+    fn total_energy_consumption(&self, device_index: u32) -> Result<u64, InferError> {
+        self.device_by_index(device_index)
+            .and_then(|device| device.total_energy_consumption())
+            .map_err(|e| InferError::EnergyConsumptionError(e.to_string()))
+    }
+
+    fn power_usage(&self, device_index: u32) -> Result<u32, InferError> {
+        self.device_by_index(device_index)
+            .and_then(|device| device.power_usage())
+            .map_err(|e| InferError::EnergyConsumptionError(e.to_string()))
+    }
+
+    /// Cards older than Volta do not have an energy counter, other errors are not a reason to
+    /// give up on it
+    fn energy_counter_supported(&self, device_index: u32) -> bool {
+        !matches!(
+            self.device_by_index(device_index)
+                .and_then(|device| device.total_energy_consumption()),
+            Err(NvmlError::NotSupported)
+        )
+    }
+}
+
+/// NVML device addressed by its UUID, such as a MIG instance, seen as the only device 0
+///
+/// Addressing a MIG instance by index would read the whole GPU. The energy of the instance is
+/// still subject to what NVML reports for it: depending on the driver, MIG device handles do not
+/// support the energy counter, the energy is then integrated from the power draw, or they report
+/// the counters of the parent GPU.
+pub(crate) struct NvmlUuidMeter {
+    nvml: Arc<Nvml>,
+    uuid: String,
+}
+
+impl NvmlUuidMeter {
+    pub(crate) fn new(nvml: Arc<Nvml>, uuid: String) -> Self {
+        Self { nvml, uuid }
+    }
+
+    fn device(&self, device_index: u32) -> Result<Device<'_>, NvmlError> {
+        match device_index {
+            0 => self.nvml.device_by_uuid(self.uuid.as_str()),
+            _ => Err(NvmlError::InvalidArg),
+        }
+    }
+}
+
+impl EnergyMeter for NvmlUuidMeter {
+    fn device_count(&self) -> Result<u32, InferError> {
+        self.device(0)
+            .map(|_| 1)
+            .map_err(|e| InferError::EnergyConsumptionError(format!("{}: {e}", self.uuid)))
+    }
+
+    fn total_energy_consumption(&self, device_index: u32) -> Result<u64, InferError> {
+        self.device(device_index)
+            .and_then(|device| device.total_energy_consumption())
+            .map_err(|e| InferError::EnergyConsumptionError(e.to_string()))
+    }
+
+    fn power_usage(&self, device_index: u32) -> Result<u32, InferError> {
+        self.device(device_index)
+            .and_then(|device| device.power_usage())
+            .map_err(|e| InferError::EnergyConsumptionError(e.to_string()))
+    }
+
+    fn energy_counter_supported(&self, device_index: u32) -> bool {
+        !matches!(
+            self.device(device_index)
+                .and_then(|device| device.total_energy_consumption()),
+            Err(NvmlError::NotSupported)
+        )
+    }
+}
+
+/// Energy counters of the CPU packages, read from the RAPL powercap interface
+///
+/// Device `n` is the package exposed as `intel-rapl:n`. The counters are in microjoules and wrap
+/// around at `max_energy_range_uj`, the meter adds up the wraps so that its readings keep
+/// increasing as long as it is read at least once per wrap period.
+pub(crate) struct RaplMeter {
+    packages: Vec<RaplPackage>,
+}
+
+struct RaplPackage {
+    energy_path: PathBuf,
+    max_energy_range_uj: u64,
+    /// Last raw reading and energy of the previous wraps, in microjoules
+    counter: Mutex<(u64, u64)>,
+}
+
+impl RaplMeter {
+    /// Find the packages under the powercap `root`, usually [`POWERCAP_ROOT`]
+    pub(crate) fn new(root: &Path) -> Result<Self, InferError> {
+        let mut packages: Vec<(u32, PathBuf)> = std::fs::read_dir(root)
+            .map_err(|e| rapl_error(root, e))?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                // Subzones such as `intel-rapl:0:1` are already counted in their package
+                let index = path.file_name()?.to_str()?.strip_prefix("intel-rapl:")?;
+                Some((index.parse().ok()?, path))
+            })
+            .collect();
+        packages.sort_by_key(|(index, _)| *index);
+
+        let packages = packages
+            .into_iter()
+            .map(|(_, path)| {
+                let max_energy_range_uj = read_counter(&path.join("max_energy_range_uj"))?;
+                Ok(RaplPackage {
+                    energy_path: path.join("energy_uj"),
+                    max_energy_range_uj,
+                    counter: Mutex::new((0, 0)),
+                })
+            })
+            .collect::<Result<_, InferError>>()?;
+        let meter = Self { packages };
+        // Start counting from the current value of the counters
+        for device_index in 0..meter.packages.len() as u32 {
+            meter.total_energy_consumption(device_index)?;
+        }
+        Ok(meter)
+    }
+}
+
+impl EnergyMeter for RaplMeter {
+    fn device_count(&self) -> Result<u32, InferError> {
+        Ok(self.packages.len() as u32)
+    }
+
+    fn total_energy_consumption(&self, device_index: u32) -> Result<u64, InferError> {
+        let package = self.packages.get(device_index as usize).ok_or_else(|| {
+            InferError::EnergyConsumptionError(format!("no RAPL package {device_index}"))
+        })?;
+        let reading = read_counter(&package.energy_path)?;
+        let mut counter = package.counter.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, wrapped) = &mut *counter;
+        if reading < *last {
+            *wrapped += package.max_energy_range_uj;
+        }
+        *last = reading;
+        Ok((*wrapped + reading) / 1000)
+    }
+
+    fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+        Err(InferError::EnergyConsumptionError(
+            "RAPL does not report the instantaneous power usage".to_string(),
+        ))
+    }
+}
+
+fn read_counter(path: &Path) -> Result<u64, InferError> {
+    std::fs::read_to_string(path)
+        .map_err(|e| rapl_error(path, e))?
+        .trim()
+        .parse()
+        .map_err(|e| InferError::EnergyConsumptionError(format!("{}: {e}", path.display())))
+}
+
+fn rapl_error(path: &Path, err: std::io::Error) -> InferError {
+    InferError::EnergyConsumptionError(format!("could not read {}: {err}", path.display()))
+}
+
+/// Single device replaying a fixed sequence of counter readings
+///
+/// Once the readings are exhausted, the counter keeps growing by the last step of the sequence,
+/// so a run of any length gets the same energies as long as it reads the counter in the same
+/// order.
+#[derive(Debug)]
+pub(crate) struct MockEnergyMeter {
+    readings: Vec<u64>,
+    next: AtomicU64,
+}
+
+impl MockEnergyMeter {
+    /// Meter returning `readings` in order, in millijoules
+    pub(crate) fn new(readings: Vec<u64>) -> Self {
+        Self {
+            readings,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    fn reading(&self, index: usize) -> Option<u64> {
+        let last = self.readings.len().checked_sub(1)?;
+        if index <= last {
+            return Some(self.readings[index]);
+        }
+        let step = match last {
+            0 => 0,
+            _ => self.readings[last].saturating_sub(self.readings[last - 1]),
+        };
+        Some(self.readings[last] + step * (index - last) as u64)
+    }
+}
+
+impl EnergyMeter for MockEnergyMeter {
+    /// No device at all without readings to replay
+    fn device_count(&self) -> Result<u32, InferError> {
+        Ok(u32::from(!self.readings.is_empty()))
+    }
+
+    fn total_energy_consumption(&self, device_index: u32) -> Result<u64, InferError> {
+        if device_index != 0 {
+            return Err(InferError::EnergyConsumptionError(format!(
+                "no replayed device {device_index}"
+            )));
+        }
+        let index = self.next.fetch_add(1, Ordering::SeqCst);
+        self.reading(index as usize).ok_or_else(|| {
+            InferError::EnergyConsumptionError("no energy readings to replay".to_string())
+        })
+    }
+
+    fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+        Err(InferError::EnergyConsumptionError(
+            "replayed readings have no power usage".to_string(),
+        ))
+    }
+
+    /// Does not consume a reading, so that the replay starts with the first request
+    fn energy_counter_supported(&self, device_index: u32) -> bool {
+        device_index == 0
+    }
+}
+
+/// How the energy of the devices is measured
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EnergyStrategy {
+    /// Hardware energy counter of the devices
+    Counter,
+    /// Power draw integrated over time, for devices without energy counter
+    PowerIntegration,
+}
+
+/// Energy of a device estimated from its power draw
+#[derive(Debug)]
+struct IntegratedEnergy {
+    last: Instant,
+    /// Power draw at the last reading, in milliwatts
+    power_mw: u32,
+    energy_mj: f64,
+}
+
+impl IntegratedEnergy {
+    fn new(now: Instant, power_mw: u32) -> Self {
+        Self {
+            last: now,
+            power_mw,
+            energy_mj: 0.0,
+        }
+    }
+
+    /// Add the energy consumed since the last reading and return the total, in millijoules
+    ///
+    /// The power is assumed to change linearly between two readings, so the estimate is only as
+    /// good as the readings are frequent.
+    fn update(&mut self, now: Instant, power_mw: u32) -> u64 {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        // mW x s = mJ
+        self.energy_mj += (self.power_mw as f64 + power_mw as f64) / 2.0 * elapsed;
+        self.last = now;
+        self.power_mw = power_mw;
+        self.energy_mj as u64
+    }
+}
+
+/// Energy meter restricted to the devices the model runs on
+#[derive(Clone)]
+pub(crate) struct DeviceMeter {
+    meter: Arc<dyn EnergyMeter>,
+    devices: Arc<[u32]>,
+    strategy: EnergyStrategy,
+    /// Energy of each device, only used with [`EnergyStrategy::PowerIntegration`]
+    integrated: Arc<Mutex<Vec<IntegratedEnergy>>>,
+}
+
+impl DeviceMeter {
+    /// Return a meter over `devices`, or `None` if energy cannot be tracked
+    ///
+    /// A meter without any device would fail on every request, so energy tracking is disabled
+    /// instead and responses are returned without energy. Devices that do not exist are ignored.
+    pub(crate) fn new(meter: Arc<dyn EnergyMeter>, devices: &[u32]) -> Option<Self> {
+        let device_count = match meter.device_count() {
+            Ok(0) => {
+                tracing::warn!(
+                    "No device reported by the energy meter, energy tracking is disabled"
+                );
+                return None;
+            }
+            Ok(device_count) => device_count,
+            Err(err) => {
+                tracing::warn!(
+                    "Could not count energy devices, energy tracking is disabled: {err}"
+                );
+                return None;
+            }
+        };
+
+        let (devices, missing): (Vec<u32>, Vec<u32>) = devices
+            .iter()
+            .partition(|&&device_index| device_index < device_count);
+        if !missing.is_empty() {
+            tracing::warn!(
+                "Ignoring energy devices {missing:?}, the meter only reports {device_count} devices"
+            );
+        }
+        if devices.is_empty() {
+            tracing::warn!("No energy device to read, energy tracking is disabled");
+            return None;
+        }
+
+        if devices
+            .iter()
+            .all(|&device_index| meter.energy_counter_supported(device_index))
+        {
+            return Some(Self {
+                meter,
+                devices: devices.into(),
+                strategy: EnergyStrategy::Counter,
+                integrated: Default::default(),
+            });
+        }
+
+        let now = Instant::now();
+        let integrated = match devices
+            .iter()
+            .map(|&device_index| Ok(IntegratedEnergy::new(now, meter.power_usage(device_index)?)))
+            .collect::<Result<Vec<_>, InferError>>()
+        {
+            Ok(integrated) => integrated,
+            Err(err) => {
+                tracing::warn!(
+                    "No energy counter nor power reading on the devices, energy tracking is disabled: {err}"
+                );
+                return None;
+            }
+        };
+        tracing::warn!(
+            "No energy counter on the devices, energy is estimated from their power draw"
+        );
+        Some(Self {
+            meter,
+            devices: devices.into(),
+            strategy: EnergyStrategy::PowerIntegration,
+            integrated: Arc::new(Mutex::new(integrated)),
+        })
+    }
+
+    /// Devices read by the meter
+    pub(crate) fn devices(&self) -> &[u32] {
+        &self.devices
+    }
+
+    /// How the energy of the devices is measured
+    pub(crate) fn strategy(&self) -> EnergyStrategy {
+        self.strategy
+    }
+
+    /// Energy consumed by all the devices, in millijoules
+    pub(crate) fn read_millijoules(&self) -> Result<u64, InferError> {
+        Ok(self.read_devices()?.iter().sum())
+    }
+
+    /// Energy consumed by each device, in millijoules, in the order of [`Self::devices`]
+    pub(crate) fn read_devices(&self) -> Result<Vec<u64>, InferError> {
+        match self.strategy {
+            EnergyStrategy::Counter => self
+                .devices
+                .iter()
+                .map(|&device_index| self.meter.total_energy_consumption(device_index))
+                .collect(),
+            EnergyStrategy::PowerIntegration => {
+                let power_usage = self.power_usage()?;
+                let now = Instant::now();
+                let mut integrated = self.integrated.lock().unwrap_or_else(|e| e.into_inner());
+                Ok(integrated
+                    .iter_mut()
+                    .zip(power_usage)
+                    .map(|(energy, power_mw)| energy.update(now, power_mw))
+                    .collect())
+            }
+        }
+    }
+
+    /// Instantaneous power draw of each device, in milliwatts
+    pub(crate) fn power_usage(&self) -> Result<Vec<u32>, InferError> {
+        self.devices
+            .iter()
+            .map(|&device_index| self.meter.power_usage(device_index))
+            .collect()
+    }
+}
+
+/// Attempts at reading the energy counter before the reading is given up
+const ENERGY_READ_ATTEMPTS: u32 = 3;
+/// Delay before retrying a failed reading, doubled after each retry
+const ENERGY_READ_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Read the energy counter of the devices, `None` when energy tracking is disabled
+///
+/// Failed readings are retried a few times. When they keep failing, the reading is `None` as
+/// well: the generation goes on without its energy.
+pub(crate) async fn read_energy(meter: Option<&DeviceMeter>) -> Option<u64> {
+    read_with_retry(meter?, DeviceMeter::read_millijoules).await
+}
+
+/// Read the energy counter of each device, `None` when energy tracking is disabled or the
+/// reading failed, see [`read_energy`]
+pub(crate) async fn read_device_energies(meter: Option<&DeviceMeter>) -> Option<Vec<u64>> {
+    read_with_retry(meter?, DeviceMeter::read_devices).await
+}
+
+async fn read_with_retry<T>(
+    meter: &DeviceMeter,
+    read: impl Fn(&DeviceMeter) -> Result<T, InferError>,
+) -> Option<T> {
+    let mut backoff = ENERGY_READ_BACKOFF;
+    for attempt in 1..=ENERGY_READ_ATTEMPTS {
+        match read(meter) {
+            Ok(reading) => return Some(reading),
+            Err(err) if attempt < ENERGY_READ_ATTEMPTS => {
+                tracing::debug!("Energy reading failed, retrying in {backoff:?}: {err}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Energy reading failed {attempt} times, the energy is not reported: {err}"
+                );
+                metrics::counter!("tgi_energy_read_failure").increment(1);
+            }
+        }
+    }
+    None
+}
+
+/// Energy consumed by each device between two readings of [`read_device_energies`]
+///
+/// Only reported when the energy is measured on several devices, a single device would repeat
+/// the total energy.
+pub(crate) fn per_device_energy(
+    devices: &[u32],
+    start: Option<&[u64]>,
+    end: Option<&[u64]>,
+) -> Vec<(u32, u64)> {
+    match (start, end) {
+        (Some(start), Some(end)) if devices.len() > 1 => devices
+            .iter()
+            .zip(start.iter().zip(end))
+            .filter_map(|(&device_index, (&start, &end))| {
+                Some((device_index, energy_delta(Some(start), Some(end))?))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Energy consumed between two readings of the counter
+///
+/// NVML counters are reset when the driver is reloaded, a decreasing counter is reported as no
+/// energy consumed rather than underflowing.
+pub(crate) fn energy_delta(start: Option<u64>, end: Option<u64>) -> Option<u64> {
+    let (start, end) = (start?, end?);
+    if end < start {
+        tracing::warn!("Energy counter went from {start}mJ down to {end}mJ, it was probably reset");
+    }
+    Some(end.saturating_sub(start))
+}
+
+/// Cumulative energy of a request, read token after token
+///
+/// With a sampling interval of `k`, the counter is only read every `k` tokens and the energy of
+/// the interval is split evenly between its tokens. The total energy of the request is still
+/// exact, but the energy of a single token is an average: a slow token next to fast ones is not
+/// told apart, and the cumulative energy reported on the tokens in between lags behind. The first
+/// token is always read so that the prefill energy is not mixed with the decode.
+#[derive(Debug)]
+pub(crate) struct RequestEnergy {
+    last: Option<u64>,
+    total: u64,
+    /// Energy consumed until the first token, set on the first reading
+    prefill: Option<u64>,
+    sampling_interval: usize,
+    /// Tokens generated since the last reading
+    skipped: usize,
+    /// Idle power of the devices in milliwatts and time of the last reading, when the idle
+    /// energy is subtracted
+    idle: Option<(u32, Instant)>,
+    /// Token energies below this floor are measurement noise, added to the next token
+    token_floor: Option<u64>,
+    /// Energy of the readings below the floor, not attributed to a token yet
+    carried: u64,
+}
+
+impl RequestEnergy {
+    /// A `sampling_interval` of 0 is treated as 1, the counter is read for every token
+    pub(crate) fn new(start: Option<u64>, sampling_interval: usize) -> Self {
+        Self {
+            last: start,
+            total: 0,
+            prefill: None,
+            sampling_interval: sampling_interval.max(1),
+            skipped: 0,
+            idle: None,
+            token_floor: None,
+            carried: 0,
+        }
+    }
+
+    /// Subtract the energy the devices draw at `idle_power_mw` from every reading after `start`
+    ///
+    /// Readings never go below 0, the energy of a request is at least its idle energy.
+    pub(crate) fn subtract_idle(mut self, idle_power_mw: Option<u32>, start: Instant) -> Self {
+        self.idle = idle_power_mw.map(|idle_power_mw| (idle_power_mw, start));
+        self
+    }
+
+    /// Report no energy for the tokens below `floor` millijoules and add theirs to the next one
+    ///
+    /// NVML counters move in steps, so the tokens generated faster than a step alternate
+    /// between no energy and a spike. Only the energies of the tokens are changed, the totals of
+    /// the request stay exact.
+    pub(crate) fn token_floor(mut self, floor: Option<u64>) -> Self {
+        self.token_floor = floor;
+        self
+    }
+
+    /// Count a token without reading the counter, unless a reading is due
+    ///
+    /// Returns `false` when the counter must be read for this token with [`Self::update`].
+    pub(crate) fn skip(&mut self) -> bool {
+        if self.prefill.is_none() || self.skipped + 1 >= self.sampling_interval {
+            return false;
+        }
+        self.skipped += 1;
+        true
+    }
+
+    /// Record a new reading of the counter and return the energy of the current token
+    ///
+    /// The energy consumed since the last reading is divided between the tokens skipped in
+    /// between and the current one. When the counter was reset, the energy of this reading is 0
+    /// and the following readings are counted from the new counter value. A missing reading leaves
+    /// a gap in the measurement, the energy of the request is then unknown.
+    pub(crate) fn update(&mut self, reading: Option<u64>) -> Option<u64> {
+        self.update_at(reading, Instant::now())
+    }
+
+    fn update_at(&mut self, reading: Option<u64>, now: Instant) -> Option<u64> {
+        let tokens = self.skipped as u64 + 1;
+        self.skipped = 0;
+        if reading.is_none() {
+            self.last = None;
+            self.prefill = None;
+            return None;
+        }
+        let mut energy = energy_delta(self.last, reading)?;
+        if let Some((idle_power_mw, last_reading)) = &mut self.idle {
+            let elapsed = now.saturating_duration_since(*last_reading);
+            energy = energy.saturating_sub(idle_energy(*idle_power_mw, elapsed));
+            *last_reading = now;
+        }
+        self.last = reading;
+        self.total += energy;
+        self.prefill.get_or_insert(self.total);
+        let energy = energy + std::mem::take(&mut self.carried);
+        match self.token_floor {
+            Some(floor) if energy / tokens < floor => {
+                self.carried = energy;
+                None
+            }
+            _ => Some(energy / tokens),
+        }
+    }
+
+    /// Energy consumed since the start of the request, `None` when energy tracking is disabled
+    pub(crate) fn total(&self) -> Option<u64> {
+        self.last.map(|_| self.total)
+    }
+
+    /// Energy consumed until the first token was generated
+    ///
+    /// Continuations of a request are counted as decode.
+    pub(crate) fn prefill(&self) -> Option<u64> {
+        self.prefill
+    }
+
+    /// Energy consumed generating the tokens after the first one
+    pub(crate) fn decode(&self) -> Option<u64> {
+        Some(self.total()? - self.prefill?)
+    }
+}
+
+/// Number of generated tokens per joule consumed
+///
+/// Energies are in millijoules. Returns `None` when energy is not tracked or no energy was
+/// measured, which happens when the counter did not move during a short generation.
+pub(crate) fn tokens_per_joule(generated_tokens: u32, energy_mj: Option<u64>) -> Option<f64> {
+    match energy_mj? {
+        0 => None,
+        energy_mj => Some(generated_tokens as f64 / (energy_mj as f64 / 1000.0)),
+    }
+}
+
+/// Energy drawn by devices idling at `idle_power_mw` for `elapsed`, in millijoules
+pub(crate) fn idle_energy(idle_power_mw: u32, elapsed: Duration) -> u64 {
+    (idle_power_mw as f64 * elapsed.as_secs_f64()) as u64
+}
+
+/// Average power over `elapsed` of the energy consumed during that time, in milliwatts
+pub(crate) fn average_power(energy_mj: u64, elapsed: Duration) -> u32 {
+    (energy_mj as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u32
+}
+
+/// Millijoules in a kilowatt-hour
+const MILLIJOULES_PER_KWH: f64 = 3.6e9;
+
+/// Estimated emissions of `energy_mj`, in grams of CO2
+///
+/// `None` when the energy is not tracked or no carbon intensity is configured.
+pub(crate) fn co2_grams(
+    energy_mj: Option<u64>,
+    carbon_intensity_g_per_kwh: Option<f64>,
+) -> Option<f64> {
+    Some(energy_mj? as f64 / MILLIJOULES_PER_KWH * carbon_intensity_g_per_kwh?)
+}
+
+/// Summary of the energies of the generation steps, from the cumulative energies of the tokens
+///
+/// Tokens without a reading, when the energy is sampled, are skipped: the next reading covers
+/// them. `None` when no energy was read.
+pub(crate) fn token_energy_stats(cumulative_energies: &[Option<u64>]) -> Option<TokenEnergyStats> {
+    let mut previous = 0;
+    let mut steps: Vec<u64> = cumulative_energies
+        .iter()
+        .flatten()
+        .map(|&energy| {
+            let step = energy.saturating_sub(previous);
+            previous = previous.max(energy);
+            step
+        })
+        .collect();
+    if steps.is_empty() {
+        return None;
+    }
+    steps.sort_unstable();
+    let sum = steps.iter().sum::<u64>();
+    let p95_rank = (steps.len() as f64 * 0.95).ceil() as usize;
+    Some(TokenEnergyStats {
+        sum,
+        mean: sum as f64 / steps.len() as f64,
+        max: steps[steps.len() - 1],
+        p95: steps[p95_rank.max(1) - 1],
+    })
+}
+
+/// Measured requests needed before the moving average of the efficiency is used to estimate
+/// the energy of new requests
+const MIN_ESTIMATE_SAMPLES: u64 = 10;
+
+/// Totals of the requests served since the process started
+#[derive(Debug)]
+pub(crate) struct EnergyStats {
+    requests: AtomicU64,
+    /// Requests whose efficiency was measured
+    measured_requests: AtomicU64,
+    generated_tokens: AtomicU64,
+    /// Tokens of the requests whose energy was measured
+    measured_tokens: AtomicU64,
+    energy_mj: AtomicU64,
+    /// Smoothing factor of `efficiency_ewma`, the weight of the last request
+    efficiency_ewma_alpha: f64,
+    /// Bits of the moving average of the tokens per joule, NaN until a request is measured
+    efficiency_ewma: AtomicU64,
+}
+
+impl EnergyStats {
+    pub(crate) fn new(efficiency_ewma_alpha: f64) -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            measured_requests: AtomicU64::new(0),
+            generated_tokens: AtomicU64::new(0),
+            measured_tokens: AtomicU64::new(0),
+            energy_mj: AtomicU64::new(0),
+            efficiency_ewma_alpha,
+            efficiency_ewma: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+
+    /// Add a finished request
+    pub(crate) fn record(&self, generated_tokens: u32, energy_mj: Option<u64>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.generated_tokens
+            .fetch_add(generated_tokens as u64, Ordering::Relaxed);
+        if let Some(energy_mj) = energy_mj {
+            self.measured_tokens
+                .fetch_add(generated_tokens as u64, Ordering::Relaxed);
+            self.energy_mj.fetch_add(energy_mj, Ordering::Relaxed);
+        }
+        if let Some(efficiency) = tokens_per_joule(generated_tokens, energy_mj) {
+            self.update_efficiency_ewma(efficiency);
+            self.measured_requests.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn update_efficiency_ewma(&self, efficiency: f64) {
+        let alpha = self.efficiency_ewma_alpha;
+        let mut average = efficiency;
+        // The closure always returns `Some`, the update cannot fail
+        let _ = self
+            .efficiency_ewma
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let previous = f64::from_bits(bits);
+                if !previous.is_nan() {
+                    average = alpha * efficiency + (1.0 - alpha) * previous;
+                }
+                Some(average.to_bits())
+            });
+        metrics::gauge!("tgi_efficiency_ewma").set(average);
+    }
+
+    /// Moving average of the tokens per joule of the finished requests, `None` until the
+    /// energy of a request was measured
+    pub(crate) fn efficiency_ewma(&self) -> Option<f64> {
+        let average = f64::from_bits(self.efficiency_ewma.load(Ordering::Relaxed));
+        (!average.is_nan()).then_some(average)
+    }
+
+    /// Energy in millijoules of generating `new_tokens` at the moving average of the efficiency
+    ///
+    /// `None` until enough requests were measured for the average to be stable.
+    pub(crate) fn estimate_energy(&self, new_tokens: u32) -> Option<u64> {
+        if self.measured_requests.load(Ordering::Relaxed) < MIN_ESTIMATE_SAMPLES {
+            return None;
+        }
+        let efficiency = self
+            .efficiency_ewma()
+            .filter(|efficiency| *efficiency > 0.0)?;
+        Some((new_tokens as f64 / efficiency * 1000.0).round() as u64)
+    }
+
+    /// Snapshot of the totals, along with the current power draw of the devices and the moving
+    /// average of the efficiency
+    pub(crate) fn summary(
+        &self,
+        power_watts: Option<f64>,
+        efficiency_ewma: Option<f64>,
+    ) -> EnergySummary {
+        let energy_mj = self.energy_mj.load(Ordering::Relaxed);
+        EnergySummary {
+            requests: self.requests.load(Ordering::Relaxed),
+            generated_tokens: self.generated_tokens.load(Ordering::Relaxed),
+            energy_joules: energy_mj as f64 / 1000.0,
+            tokens_per_joule: tokens_per_joule_total(
+                self.measured_tokens.load(Ordering::Relaxed),
+                energy_mj,
+            ),
+            power_watts,
+            efficiency_ewma,
+        }
+    }
+}
+
+fn tokens_per_joule_total(tokens: u64, energy_mj: u64) -> Option<f64> {
+    (energy_mj > 0).then(|| tokens as f64 / (energy_mj as f64 / 1000.0))
+}
+
+/// Record the energy of a generated token in the per token histogram
+///
+/// Energies above `cap` are counted as outliers instead, so that they do not skew the histogram.
+pub(crate) fn record_token_energy(
+    backend: &'static str,
+    token_energy: Option<u64>,
+    cap: Option<u64>,
+) {
+    match (token_energy, cap) {
+        (Some(token_energy), Some(cap)) if token_energy > cap => {
+            metrics::counter!("tgi_token_energy_outliers", "backend" => backend).increment(1);
+        }
+        (Some(token_energy), _) => {
+            metrics::histogram!("tgi_token_energy_millijoules", "backend" => backend)
+                .record(token_energy as f64);
+        }
+        (None, _) => {}
+    }
+}
+
+/// Count a finished request by finish reason and add its energy to the total
+pub(crate) fn record_request_energy(
+    backend: &'static str,
+    finish_reason: &FinishReason,
+    energy: Option<u64>,
+) {
+    let finish_reason = finish_reason.to_string();
+    metrics::counter!("tgi_finish_reason", "reason" => finish_reason.clone()).increment(1);
+    if let Some(energy) = energy {
+        metrics::counter!(
+            "tgi_request_energy_millijoules_total",
+            "backend" => backend,
+            "finish_reason" => finish_reason
+        )
+        .increment(energy);
+    }
+}
+
+/// Add the energy consumed by a failed request to the total, under the `error` finish reason
+pub(crate) fn record_failed_request_energy(backend: &'static str, energy: Option<u64>) {
+    if let Some(energy) = energy {
+        metrics::counter!(
+            "tgi_request_energy_millijoules_total",
+            "backend" => backend,
+            "finish_reason" => "error"
+        )
+        .increment(energy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every device has consumed a fixed amount of energy
+    struct FixedMeter(Vec<u64>);
+
+    impl EnergyMeter for FixedMeter {
+        fn device_count(&self) -> Result<u32, InferError> {
+            Ok(self.0.len() as u32)
+        }
+
+        fn total_energy_consumption(&self, device_index: u32) -> Result<u64, InferError> {
+            Ok(self.0[device_index as usize])
+        }
+
+        fn power_usage(&self, device_index: u32) -> Result<u32, InferError> {
+            Ok(self.0[device_index as usize] as u32 / 10)
+        }
+    }
+
+    fn meter(energies: &[u64]) -> Arc<dyn EnergyMeter> {
+        Arc::new(FixedMeter(energies.to_vec()))
+    }
+
+    /// Devices without energy counter, drawing a fixed power in milliwatts
+    struct PowerOnlyMeter(Option<u32>);
+
+    impl EnergyMeter for PowerOnlyMeter {
+        fn device_count(&self) -> Result<u32, InferError> {
+            Ok(2)
+        }
+
+        fn total_energy_consumption(&self, _device_index: u32) -> Result<u64, InferError> {
+            Err(InferError::EnergyConsumptionError(
+                "Not supported".to_string(),
+            ))
+        }
+
+        fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+            self.0
+                .ok_or_else(|| InferError::EnergyConsumptionError("Not supported".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_energy_summed_across_devices() {
+        let device_meter = DeviceMeter::new(meter(&[100, 250, 1000]), &[0, 2]).unwrap();
+        assert_eq!(read_energy(Some(&device_meter)).await, Some(1100));
+
+        let device_meter = DeviceMeter::new(meter(&[100, 250, 1000]), &[1]).unwrap();
+        assert_eq!(read_energy(Some(&device_meter)).await, Some(250));
+        assert_eq!(read_energy(None).await, None);
+    }
+
+    #[test]
+    fn test_per_device_energy() {
+        let start = [1_000, 5_000];
+        let end = [1_300, 5_100];
+        assert_eq!(
+            per_device_energy(&[2, 3], Some(&start), Some(&end)),
+            vec![(2, 300), (3, 100)]
+        );
+        assert!(per_device_energy(&[2], Some(&start[..1]), Some(&end[..1])).is_empty());
+        assert!(per_device_energy(&[2, 3], None, None).is_empty());
+
+        let device_meter = DeviceMeter::new(meter(&[100, 250, 1000]), &[2, 0]).unwrap();
+        assert_eq!(device_meter.read_devices().unwrap(), vec![1000, 100]);
+    }
+
+    #[test]
+    fn test_power_usage_per_device() {
+        let device_meter = DeviceMeter::new(meter(&[100, 250, 1000]), &[2, 0]).unwrap();
+        assert_eq!(device_meter.power_usage().unwrap(), vec![100, 10]);
+    }
+
+    #[test]
+    fn test_energy_counter_reset() {
+        let mut request_energy = RequestEnergy::new(Some(1_000), 1);
+        let readings = [1_010, 1_025, 5, 20, 18, 40];
+        let token_energies: Vec<_> = readings
+            .iter()
+            .map(|&reading| request_energy.update(Some(reading)).unwrap())
+            .collect();
+        assert_eq!(token_energies, vec![10, 15, 0, 15, 0, 22]);
+        assert_eq!(request_energy.total(), Some(62));
+
+        assert_eq!(energy_delta(Some(u64::MAX), Some(3)), Some(0));
+    }
+
+    #[test]
+    fn test_request_energy_without_meter() {
+        let mut request_energy = RequestEnergy::new(None, 1);
+        assert_eq!(request_energy.update(None), None);
+        assert_eq!(request_energy.total(), None);
+        assert_eq!(request_energy.prefill(), None);
+        assert_eq!(request_energy.decode(), None);
+    }
+
+    #[test]
+    fn test_prefill_decode_split() {
+        let mut request_energy = RequestEnergy::new(Some(100), 1);
+        assert_eq!(request_energy.prefill(), None);
+        for reading in [400, 420, 445, 460] {
+            request_energy.update(Some(reading));
+        }
+        assert_eq!(request_energy.prefill(), Some(300));
+        assert_eq!(request_energy.decode(), Some(60));
+        assert_eq!(request_energy.total(), Some(360));
+    }
+
+    #[test]
+    fn test_sampled_request_energy() {
+        let mut request_energy = RequestEnergy::new(Some(100), 3);
+        // The first token is always read
+        assert!(!request_energy.skip());
+        assert_eq!(request_energy.update(Some(400)), Some(300));
+
+        let mut token_energies = vec![];
+        for reading in [430, 460] {
+            while request_energy.skip() {
+                token_energies.push(None);
+            }
+            token_energies.push(request_energy.update(Some(reading)));
+        }
+        assert_eq!(
+            token_energies,
+            vec![None, None, Some(10), None, None, Some(10)]
+        );
+        // A reading before the end of the interval only covers the skipped tokens
+        assert!(request_energy.skip());
+        assert_eq!(request_energy.update(Some(480)), Some(10));
+
+        assert_eq!(request_energy.total(), Some(380));
+        assert_eq!(request_energy.prefill(), Some(300));
+        assert_eq!(request_energy.decode(), Some(80));
+    }
+
+    #[test]
+    fn test_token_energy_floor() {
+        let mut request_energy = RequestEnergy::new(Some(100), 1).token_floor(Some(5));
+        let token_energies: Vec<_> = [110, 112, 113, 120, 140]
+            .into_iter()
+            .map(|reading| request_energy.update(Some(reading)))
+            .collect();
+        // The readings below the floor are added to the next token
+        assert_eq!(token_energies, [Some(10), None, None, Some(10), Some(20)]);
+        // The totals are exact
+        assert_eq!(request_energy.total(), Some(40));
+        assert_eq!(request_energy.prefill(), Some(10));
+        assert_eq!(request_energy.decode(), Some(30));
+    }
+
+    #[test]
+    fn test_request_energy_net_of_idle() {
+        let start = Instant::now();
+        // Devices idling at 50W
+        let mut request_energy =
+            RequestEnergy::new(Some(1_000), 1).subtract_idle(Some(50_000), start);
+        // 80J over a second, 50J of which would have been drawn anyway
+        assert_eq!(
+            request_energy.update_at(Some(81_000), start + Duration::from_secs(1)),
+            Some(30_000)
+        );
+        // Below the idle draw
+        assert_eq!(
+            request_energy.update_at(Some(101_000), start + Duration::from_secs(2)),
+            Some(0)
+        );
+        assert_eq!(request_energy.total(), Some(30_000));
+
+        let mut request_energy = RequestEnergy::new(Some(1_000), 1).subtract_idle(None, start);
+        assert_eq!(
+            request_energy.update_at(Some(81_000), start + Duration::from_secs(1)),
+            Some(80_000)
+        );
+        assert_eq!(average_power(25_000, Duration::from_millis(500)), 50_000);
+    }
+
+    #[test]
+    fn test_tokens_per_joule() {
+        // 100 tokens for 2.5J
+        assert_eq!(tokens_per_joule(100, Some(2_500)), Some(40.0));
+        assert_eq!(tokens_per_joule(3, Some(500)), Some(6.0));
+        assert_eq!(tokens_per_joule(0, Some(500)), Some(0.0));
+        assert_eq!(tokens_per_joule(100, Some(0)), None);
+        assert_eq!(tokens_per_joule(100, None), None);
+    }
+
+    /// Powercap tree with one counter per package, in microjoules
+    fn powercap(name: &str, counters: &[u64]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tgi-powercap-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (index, counter) in counters.iter().enumerate() {
+            let package = root.join(format!("intel-rapl:{index}"));
+            std::fs::create_dir_all(&package).unwrap();
+            set_counter(&root, index, *counter);
+            std::fs::write(package.join("max_energy_range_uj"), "1000000\n").unwrap();
+            // Subzones are part of their package and must not be counted twice
+            std::fs::create_dir_all(root.join(format!("intel-rapl:{index}:0"))).unwrap();
+        }
+        root
+    }
+
+    fn set_counter(root: &Path, index: usize, counter: u64) {
+        let path = root.join(format!("intel-rapl:{index}/energy_uj"));
+        std::fs::write(path, format!("{counter}\n")).unwrap();
+    }
+
+    #[test]
+    fn test_rapl_meter() {
+        let root = powercap("packages", &[0, 250_000]);
+        let rapl = RaplMeter::new(&root).unwrap();
+        let device_meter = DeviceMeter::new(Arc::new(rapl), &[0, 1]).unwrap();
+        assert_eq!(device_meter.read_millijoules().unwrap(), 250);
+
+        set_counter(&root, 0, 40_000);
+        set_counter(&root, 1, 300_000);
+        assert_eq!(device_meter.read_millijoules().unwrap(), 340);
+        assert!(device_meter.power_usage().is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_rapl_counter_wraps() {
+        let root = powercap("wraps", &[900_000]);
+        let rapl = RaplMeter::new(&root).unwrap();
+        assert_eq!(rapl.total_energy_consumption(0).unwrap(), 900);
+        // The counter wrapped at 1J
+        set_counter(&root, 0, 100_000);
+        assert_eq!(rapl.total_energy_consumption(0).unwrap(), 1_100);
+        set_counter(&root, 0, 150_000);
+        assert_eq!(rapl.total_energy_consumption(0).unwrap(), 1_150);
+        std::fs::remove_dir_all(root).unwrap();
+
+        assert!(RaplMeter::new(Path::new("/nonexistent/powercap")).is_err());
+    }
+
+    #[test]
+    fn test_mock_energy_meter() {
+        let meter = MockEnergyMeter::new(vec![100, 130, 170]);
+        assert!(meter.energy_counter_supported(0));
+        assert!(!meter.energy_counter_supported(1));
+        let readings: Vec<_> = (0..5)
+            .map(|_| meter.total_energy_consumption(0).unwrap())
+            .collect();
+        // Keeps growing by the last step once the readings are exhausted
+        assert_eq!(readings, [100, 130, 170, 210, 250]);
+        assert!(meter.total_energy_consumption(1).is_err());
+
+        let meter = MockEnergyMeter::new(vec![]);
+        assert_eq!(meter.device_count().unwrap(), 0);
+        assert!(meter.total_energy_consumption(0).is_err());
+        assert!(DeviceMeter::new(Arc::new(meter), &[0]).is_none());
+    }
+
+    #[test]
+    fn test_co2_grams() {
+        // 1kWh at 400g/kWh
+        assert_eq!(co2_grams(Some(3_600_000_000), Some(400.0)), Some(400.0));
+        // 36J at 250g/kWh
+        assert_eq!(co2_grams(Some(36_000), Some(250.0)), Some(0.0025));
+        assert_eq!(co2_grams(None, Some(400.0)), None);
+        assert_eq!(co2_grams(Some(36_000), None), None);
+    }
+
+    #[test]
+    fn test_energy_summary() {
+        let stats = EnergyStats::new(0.1);
+        let summary = stats.summary(None, None);
+        assert_eq!(summary.requests, 0);
+        assert_eq!(summary.tokens_per_joule, None);
+
+        stats.record(40, Some(2_000));
+        stats.record(10, None);
+        stats.record(20, Some(500));
+        let summary = stats.summary(Some(250.0), None);
+        assert_eq!(summary.requests, 3);
+        assert_eq!(summary.generated_tokens, 70);
+        assert_eq!(summary.energy_joules, 2.5);
+        // Requests without energy do not count in the efficiency
+        assert_eq!(summary.tokens_per_joule, Some(24.0));
+        assert_eq!(summary.power_watts, Some(250.0));
+    }
+
+    #[test]
+    fn test_efficiency_ewma() {
+        let stats = EnergyStats::new(0.5);
+        assert_eq!(stats.efficiency_ewma(), None);
+        // 20 tokens per joule
+        stats.record(40, Some(2_000));
+        assert_eq!(stats.efficiency_ewma(), Some(20.0));
+        // Requests without energy leave the average unchanged
+        stats.record(10, None);
+        assert_eq!(stats.efficiency_ewma(), Some(20.0));
+        // 40 tokens per joule
+        stats.record(20, Some(500));
+        assert_eq!(stats.efficiency_ewma(), Some(30.0));
+    }
+
+    #[test]
+    fn test_estimate_energy() {
+        let stats = EnergyStats::new(0.5);
+        assert_eq!(stats.estimate_energy(100), None);
+        // 20 tokens per joule, not estimated before enough requests were measured
+        for _ in 0..MIN_ESTIMATE_SAMPLES - 1 {
+            stats.record(40, Some(2_000));
+            stats.record(10, None);
+        }
+        assert_eq!(stats.estimate_energy(100), None);
+        stats.record(40, Some(2_000));
+        assert_eq!(stats.estimate_energy(100), Some(5_000));
+        assert_eq!(stats.estimate_energy(0), Some(0));
+    }
+
+    #[test]
+    fn test_power_integration_fallback() {
+        let device_meter = DeviceMeter::new(meter(&[100]), &[0]).unwrap();
+        assert_eq!(device_meter.strategy(), EnergyStrategy::Counter);
+
+        let device_meter = DeviceMeter::new(Arc::new(PowerOnlyMeter(Some(200_000))), &[0, 1]);
+        let device_meter = device_meter.unwrap();
+        assert_eq!(device_meter.strategy(), EnergyStrategy::PowerIntegration);
+        let start = device_meter.read_millijoules().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        // 2 devices at 200W for at least 50ms
+        assert!(device_meter.read_millijoules().unwrap() - start >= 20_000);
+
+        // Without power reading either there is nothing to measure
+        assert!(DeviceMeter::new(Arc::new(PowerOnlyMeter(None)), &[0]).is_none());
+    }
+
+    #[test]
+    fn test_integrated_energy() {
+        let start = Instant::now();
+        let mut energy = IntegratedEnergy::new(start, 100_000);
+        // Power ramping from 100W to 300W over 2s
+        assert_eq!(
+            energy.update(start + std::time::Duration::from_secs(2), 300_000),
+            400_000
+        );
+        assert_eq!(
+            energy.update(start + std::time::Duration::from_secs(3), 300_000),
+            700_000
+        );
+        // Readings out of order do not count any energy
+        assert_eq!(energy.update(start, 300_000), 700_000);
+    }
+
+    #[test]
+    fn test_missing_devices_ignored() {
+        let device_meter = DeviceMeter::new(meter(&[100, 250]), &[1, 4]).unwrap();
+        assert_eq!(device_meter.devices(), &[1]);
+        assert!(DeviceMeter::new(meter(&[100, 250]), &[2, 3]).is_none());
+        assert!(DeviceMeter::new(meter(&[]), &[0]).is_none());
+    }
+
+    #[test]
+    fn test_energy_unit() {
+        assert_eq!(EnergyUnit::Millijoules.convert(1500), 1500.0);
+        assert_eq!(EnergyUnit::Joules.convert(1500), 1.5);
+        assert_eq!(EnergyUnit::WattHours.convert(7_200_000), 2.0);
+
+        let response = serde_json::json!({
+            "energy_consumption": 1500,
+            "tokens_per_joule": 4.0,
+            "details": {"tokens": [{"id": 1, "step_energy": 500, "energy_consumption": 1000}]},
+            "per_device_energy": [{"device": 0, "energy_consumption": 1500}],
+            "token_energy_stats": {"sum": 1500, "mean": 750.0, "max": 1000, "p95": 1000},
+            "metadata": {"energy_consumption": 1500},
+        });
+        // Millijoules are left as they are
+        assert_eq!(
+            EnergyUnit::Millijoules.to_json(&response).unwrap(),
+            response
+        );
+        assert_eq!(
+            EnergyUnit::Joules.to_json(&response).unwrap(),
+            serde_json::json!({
+                "energy_consumption": 1.5,
+                "tokens_per_joule": 4.0,
+                "details": {"tokens": [{"id": 1, "step_energy": 0.5, "energy_consumption": 1.0}]},
+                "per_device_energy": [{"device": 0, "energy_consumption": 1.5}],
+                "token_energy_stats": {"sum": 1.5, "mean": 0.75, "max": 1.0, "p95": 1.0},
+                // The metadata of the client is not interpreted
+                "metadata": {"energy_consumption": 1500},
+            })
+        );
+    }
+
+    #[test]
+    fn test_token_energy_stats() {
+        assert_eq!(token_energy_stats(&[]), None);
+        assert_eq!(token_energy_stats(&[None, None]), None);
+
+        // Steps of 100, 300, 50 and 50, the unread tokens being covered by the next reading
+        let stats =
+            token_energy_stats(&[Some(100), None, Some(400), Some(450), Some(500)]).unwrap();
+        assert_eq!(
+            stats,
+            TokenEnergyStats {
+                sum: 500,
+                mean: 125.0,
+                max: 300,
+                p95: 300,
+            }
+        );
+
+        // The 95th percentile ignores the worst step once there are more than 20
+        let cumulative: Vec<_> = (1..=40).map(|i| Some(i * 10 + i / 40 * 1000)).collect();
+        let stats = token_energy_stats(&cumulative).unwrap();
+        assert_eq!(stats.max, 1010);
+        assert_eq!(stats.p95, 10);
+        assert_eq!(stats.sum, 1400);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::energy_trace::{read_header, EnergyReport, EnergyTraceWriter};
+use crate::infer::energy::EnergyLogFormat;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Append-only file with the [`EnergyReport`] of every completed request
+///
+/// Reports are sent to a dedicated thread doing the writes, so the generations never wait for
+/// the disk. Reports still queued when the process exits are lost.
+#[derive(Debug)]
+pub(crate) struct EnergyLog {
+    sender: mpsc::UnboundedSender<EnergyReport>,
+    next_request_id: AtomicU64,
+}
+
+impl EnergyLog {
+    /// Open `path` for appending, creating it if needed
+    ///
+    /// An existing energy trace is only appended to when it has the header of the current
+    /// version.
+    pub(crate) fn open(path: &Path, format: EnergyLogFormat) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let writer = match format {
+            EnergyLogFormat::Jsonl => LogWriter::Jsonl(BufWriter::new(file)),
+            EnergyLogFormat::Trace if file.metadata()?.len() == 0 => {
+                LogWriter::Trace(EnergyTraceWriter::new(BufWriter::new(file))?)
+            }
+            EnergyLogFormat::Trace => {
+                read_header(&file)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                LogWriter::Trace(EnergyTraceWriter::resume(BufWriter::new(file)))
+            }
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("energy-log".to_string())
+            .spawn(move || write_reports(receiver, writer))?;
+        Ok(Self {
+            sender,
+            next_request_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Id of a new request
+    pub(crate) fn request_id(&self) -> u64 {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn append(&self, report: EnergyReport) {
+        // The writer only stops when the log is dropped
+        let _ = self.sender.send(report);
+    }
+}
+
+enum LogWriter {
+    Jsonl(BufWriter<File>),
+    Trace(EnergyTraceWriter<BufWriter<File>>),
+}
+
+impl LogWriter {
+    fn write(&mut self, report: &EnergyReport) -> io::Result<()> {
+        match self {
+            LogWriter::Jsonl(file) => {
+                serde_json::to_writer(&mut *file, report)?;
+                file.write_all(b"\n")
+            }
+            LogWriter::Trace(trace) => trace.write(report),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Jsonl(file) => file.flush(),
+            LogWriter::Trace(trace) => trace.flush(),
+        }
+    }
+}
+
+/// Write the reports until the log is dropped, flushing whenever no report is waiting
+fn write_reports(mut receiver: mpsc::UnboundedReceiver<EnergyReport>, mut writer: LogWriter) {
+    while let Some(report) = receiver.blocking_recv() {
+        let written = writer
+            .write(&report)
+            .and_then(|()| match receiver.is_empty() {
+                true => writer.flush(),
+                false => Ok(()),
+            });
+        if let Err(err) = written {
+            tracing::error!("Could not write to the energy log: {err}");
+        }
+    }
+}
+
+/// Unix timestamp of now, in milliseconds
+pub(crate) fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy_trace::read_energy_trace;
+    use crate::FinishReason;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn report(request_id: u64) -> EnergyReport {
+        EnergyReport {
+            timestamp_ms: 1_700_000_000_000,
+            request_id,
+            input_tokens: 12,
+            generated_tokens: 2,
+            energy_mj: (request_id == 0).then_some(1500),
+            duration_ms: 250,
+            finish_reason: FinishReason::Length,
+            metadata: (request_id == 1).then(|| serde_json::json!({"experiment": "a"})),
+            token_energy_mj: vec![Some(700), None],
+        }
+    }
+
+    /// Append two reports to a new log at `path`
+    fn write_log(path: &Path, format: EnergyLogFormat) {
+        let _ = std::fs::remove_file(path);
+        let log = EnergyLog::open(path, format).unwrap();
+        assert_eq!(log.request_id(), 0);
+        assert_eq!(log.request_id(), 1);
+        log.append(report(0));
+        log.append(report(1));
+    }
+
+    /// Contents of `path` once `is_complete`, the writer thread finishes after the log is dropped
+    fn read_log<T>(
+        path: &Path,
+        read: impl Fn(Vec<u8>) -> T,
+        is_complete: impl Fn(&T) -> bool,
+    ) -> T {
+        let mut contents = read(Vec::new());
+        for _ in 0..100 {
+            contents = read(std::fs::read(path).unwrap());
+            if is_complete(&contents) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(path).unwrap();
+        contents
+    }
+
+    fn log_path(extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tgi-energy-log-{}.{extension}", std::process::id()))
+    }
+
+    #[test]
+    fn test_energy_log() {
+        let path = log_path("jsonl");
+        write_log(&path, EnergyLogFormat::Jsonl);
+        let lines = read_log(
+            &path,
+            |contents| {
+                String::from_utf8(contents)
+                    .unwrap()
+                    .lines()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            },
+            |lines| lines.len() == 2,
+        );
+        assert_eq!(
+            lines,
+            [
+                r#"{"timestamp_ms":1700000000000,"request_id":0,"input_tokens":12,"generated_tokens":2,"energy_mj":1500,"duration_ms":250,"finish_reason":"length","token_energy_mj":[700,null]}"#,
+                r#"{"timestamp_ms":1700000000000,"request_id":1,"input_tokens":12,"generated_tokens":2,"energy_mj":null,"duration_ms":250,"finish_reason":"length","metadata":{"experiment":"a"},"token_energy_mj":[700,null]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_energy_log_trace() {
+        let path = log_path("tgie");
+        write_log(&path, EnergyLogFormat::Trace);
+        // Reopening an existing trace appends to it without a second header
+        for _ in 0..100 {
+            if read_energy_trace(std::fs::read(&path).unwrap().as_slice())
+                .is_ok_and(|reports| reports.len() == 2)
+            {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        EnergyLog::open(&path, EnergyLogFormat::Trace)
+            .unwrap()
+            .append(report(2));
+        let reports = read_log(
+            &path,
+            |contents| read_energy_trace(contents.as_slice()).unwrap_or_default(),
+            |reports| reports.len() == 3,
+        );
+        assert_eq!(reports, [report(0), report(1), report(2)]);
+
+        // A JSONL log is not appended to as a trace
+        let path = log_path("not-a-trace");
+        std::fs::write(&path, "{}\n").unwrap();
+        let err = EnergyLog::open(&path, EnergyLogFormat::Trace).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::infer::InferResponse;
+use crate::GenerateRequest;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Idempotency key of a request, only valid for the tenant that sent it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct IdempotencyKey {
+    /// API key of the tenant, so that another tenant reusing the key never gets its response
+    tenant: Option<String>,
+    key: String,
+}
+
+impl IdempotencyKey {
+    pub(crate) fn new(tenant: Option<String>, key: String) -> Self {
+        Self { tenant, key }
+    }
+}
+
+/// Fingerprint of the request sent with an idempotency key
+///
+/// The parsed request is hashed rather than the raw body, so that the formatting of the JSON does
+/// not matter.
+pub(crate) fn fingerprint(request: &GenerateRequest) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    format!("{request:?}").hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Response of an idempotency key, or the one being generated
+#[derive(Debug)]
+enum Slot {
+    Pending(watch::Receiver<Option<InferResponse>>),
+    Done {
+        response: InferResponse,
+        stored_at: Instant,
+    },
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    fingerprint: u64,
+    slot: Slot,
+    /// Identifies the request that stored the entry
+    id: u64,
+    /// Position of the entry in `Entries::order`
+    last_use: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<IdempotencyKey, CacheEntry>,
+    /// Keys by last use, the least recently used first
+    order: BTreeMap<u64, IdempotencyKey>,
+    uses: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &IdempotencyKey) {
+        self.uses += 1;
+        let last_use = self.uses;
+        if let Some(entry) = self.by_key.get_mut(key) {
+            self.order.remove(&entry.last_use);
+            entry.last_use = last_use;
+            self.order.insert(last_use, key.clone());
+        }
+    }
+
+    /// Whether the entry of `key` was stored by the request `id`
+    fn stored_by(&self, key: &IdempotencyKey, id: u64) -> bool {
+        self.by_key.get(key).is_some_and(|entry| entry.id == id)
+    }
+
+    fn insert(&mut self, key: IdempotencyKey, fingerprint: u64, slot: Slot) -> u64 {
+        self.remove(&key);
+        self.uses += 1;
+        let last_use = self.uses;
+        self.order.insert(last_use, key.clone());
+        self.by_key.insert(
+            key,
+            CacheEntry {
+                fingerprint,
+                slot,
+                id: last_use,
+                last_use,
+            },
+        );
+        last_use
+    }
+
+    fn remove(&mut self, key: &IdempotencyKey) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.order.remove(&entry.last_use);
+        }
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some((_, key)) = self.order.pop_first() {
+            self.by_key.remove(&key);
+        }
+    }
+}
+
+/// Outcome of looking up the idempotency key of a request
+#[derive(Debug)]
+pub(crate) enum Lookup<'a> {
+    /// Stored response of the key
+    Stored(InferResponse),
+    /// A request with the key is being generated, its response is sent on the receiver. The
+    /// sender is dropped without a response if that generation fails.
+    InFlight(watch::Receiver<Option<InferResponse>>),
+    /// First request with the key, its response is to be stored with [`PendingResponse::store`]
+    New(PendingResponse<'a>),
+    /// The key was used for a different request
+    Mismatch,
+}
+
+/// Least recently used cache of the responses of the requests sent with an idempotency key
+///
+/// A request retried with the same key within the time to live gets the stored response
+/// instead of being generated again, and a retry arriving while the first attempt is running
+/// waits for its response.
+#[derive(Debug)]
+pub(crate) struct IdempotencyCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl IdempotencyCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// Look up `key` for a request with `fingerprint`, registering it as in flight when unknown
+    pub(crate) fn lookup(&self, key: &IdempotencyKey, fingerprint: u64) -> Lookup<'_> {
+        let mut entries = self.entries.lock().expect("idempotency lock poisoned");
+        let live = match entries.by_key.get(key) {
+            Some(CacheEntry {
+                slot: Slot::Done { stored_at, .. },
+                ..
+            }) => stored_at.elapsed() < self.ttl,
+            Some(_) => true,
+            None => false,
+        };
+        if live {
+            if entries.by_key[key].fingerprint != fingerprint {
+                return Lookup::Mismatch;
+            }
+            entries.touch(key);
+            return match &entries.by_key[key].slot {
+                Slot::Pending(receiver) => Lookup::InFlight(receiver.clone()),
+                Slot::Done { response, .. } => Lookup::Stored(response.clone()),
+            };
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        let id = if self.capacity > 0 {
+            if entries.by_key.len() >= self.capacity && !entries.by_key.contains_key(key) {
+                entries.evict_least_recently_used();
+            }
+            Some(entries.insert(key.clone(), fingerprint, Slot::Pending(receiver)))
+        } else {
+            None
+        };
+        Lookup::New(PendingResponse {
+            cache: self,
+            key: key.clone(),
+            fingerprint,
+            id,
+            sender,
+        })
+    }
+}
+
+/// Response being generated for the first request sent with an idempotency key
+///
+/// Dropping it without storing a response, when the generation fails or is cancelled, forgets the
+/// key so that the waiting retries generate it again.
+#[derive(Debug)]
+pub(crate) struct PendingResponse<'a> {
+    cache: &'a IdempotencyCache,
+    key: IdempotencyKey,
+    fingerprint: u64,
+    /// Id of the pending entry, `None` when the cache is disabled
+    id: Option<u64>,
+    sender: watch::Sender<Option<InferResponse>>,
+}
+
+impl PendingResponse<'_> {
+    /// Store `response` and hand it to the retries waiting for it
+    pub(crate) fn store(mut self, response: InferResponse) {
+        if let Some(id) = self.id.take() {
+            let mut entries = self
+                .cache
+                .entries
+                .lock()
+                .expect("idempotency lock poisoned");
+            // The pending entry may have been evicted, the response is then stored again unless
+            // another request took the key in between
+            if entries.stored_by(&self.key, id) || !entries.by_key.contains_key(&self.key) {
+                let slot = Slot::Done {
+                    response: response.clone(),
+                    stored_at: Instant::now(),
+                };
+                if entries.by_key.len() >= self.cache.capacity
+                    && !entries.by_key.contains_key(&self.key)
+                {
+                    entries.evict_least_recently_used();
+                }
+                entries.insert(self.key.clone(), self.fingerprint, slot);
+            }
+        }
+        self.sender.send_replace(Some(response));
+    }
+}
+
+impl Drop for PendingResponse<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let mut entries = self
+            .cache
+            .entries
+            .lock()
+            .expect("idempotency lock poisoned");
+        if entries.stored_by(&self.key, id) {
+            entries.remove(&self.key);
+        }
+    }
+}
@@ -1,37 +1,167 @@
 // pub(crate) mod v2;
+mod admission;
+pub(crate) mod benchmark;
+mod builder;
+mod cancellation;
 mod chat_template;
+pub mod energy;
+mod energy_log;
+mod idempotency;
+pub mod openai;
+mod output_length;
+mod prefix_cache;
+pub mod sampling_hook;
+mod sequence;
+mod stop_sequence;
+pub mod telemetry;
 pub mod tool_grammar;
 
+use crate::energy_trace::EnergyReport;
 use crate::validation::{ValidGenerateRequest, Validation, ValidationError};
 use crate::Tool;
 use crate::{
-    ChatTemplateVersions, FinishReason, GenerateRequest, HubProcessorConfig, HubTokenizerConfig,
-    Message, PrefillToken, Token,
+    default_parameters, BenchmarkReport, BenchmarkRequest, EffectiveParameters, EnergyHealth,
+    EnergySummary, FinishReason, GenerateParameters, GenerateRequest, HealthResponse, LengthLimit,
+    Message, PrefillToken, SpecialTokensResponse, TemplateErrorDetail, Token, TokenEnergyStats,
+    TokenOffsets, ValidationReport,
 };
+pub(crate) use admission::RequestClass;
+pub use admission::TenantQuota;
+use admission::{ConcurrencyLimits, RequestPermit};
 use async_stream::stream;
 use async_trait::async_trait;
+use axum::http::StatusCode;
 use axum::response::sse::Event;
-use chat_template::ChatTemplate;
+use benchmark::benchmark_stats;
+pub(crate) use builder::InferBuilder;
+use cancellation::CancelOnDrop;
+use chat_template::{
+    template_error_detail, ChatTemplate, ChatTemplateCache, DEFAULT_CHAT_TEMPLATE,
+};
+use energy::{
+    average_power, co2_grams, energy_delta, per_device_energy, read_device_energies, read_energy,
+    record_failed_request_energy, record_request_energy, record_token_energy, token_energy_stats,
+    tokens_per_joule, DeviceMeter, EnergyStats, EnergyUnit, RequestEnergy,
+};
+use energy_log::{timestamp_ms, EnergyLog};
 use futures::future::try_join_all;
 use futures::Stream;
+use idempotency::{IdempotencyCache, IdempotencyKey, Lookup};
 use minijinja::ErrorKind;
+use nvml_wrapper::Nvml;
+use output_length::OutputLengthMonitor;
+use prefix_cache::PrefixCache;
+pub use prefix_cache::PrefixCacheHint;
+use sampling_hook::{GenerationState, SamplingHook, SamplingOverride};
+use sequence::{TokenSequencer, SEQUENCE_REORDER_WINDOW};
 use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use stop_sequence::StopSequenceMatcher;
+use telemetry::{
+    attach_telemetry, device_telemetry, list_devices, DeviceInfo, DeviceTelemetry, TelemetryField,
+    TelemetrySource,
+};
 use thiserror::Error;
-use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, TryAcquireError};
 use tokio::time::Instant;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
-use nvml_wrapper::Nvml;
+
+/// Maximum number of times a request is scheduled again after reaching its length limit
+pub(crate) const MAX_CONTINUATION_ROUNDS: u32 = 8;
+
+/// Time during which the idle power of the devices is measured at startup
+pub(crate) const IDLE_POWER_WINDOW: Duration = Duration::from_millis(500);
+
+/// Responses buffered for a request before its client is considered too slow
+///
+/// The last slot is kept for the final response or error, see [`send_response`].
+pub const STREAM_BUFFER_SIZE: usize = 32;
+
+/// Reason why [`send_response`] could not send a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamSendError {
+    /// The client no longer reads the stream
+    Closed,
+    /// The client let its buffer fill up and was sent [`InferError::SlowClient`]
+    SlowClient,
+}
+
+impl StreamSendError {
+    /// Label of the error in the `tgi_request_failure` metric
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            StreamSendError::Closed => "dropped",
+            StreamSendError::SlowClient => "slow_client",
+        }
+    }
+}
+
+/// Send a response of a generation to its client without waiting for the client to read it
+///
+/// Intermediate responses never take the last slot of the buffer: a client whose buffer is
+/// full gets [`InferError::SlowClient`] in that slot instead, and the backend should stop
+/// generating for it. Final responses and errors are always sent in the slot kept for them,
+/// so the client receives a terminal event even when it stopped reading. A backend waiting
+/// for one client would hold back every other request of its batch.
+pub fn send_response(
+    sender: &mpsc::Sender<Result<InferStreamResponse, InferError>>,
+    response: Result<InferStreamResponse, InferError>,
+) -> Result<(), StreamSendError> {
+    let terminal = matches!(response, Ok(InferStreamResponse::End { .. }) | Err(_));
+    if !terminal && sender.capacity() <= 1 {
+        return match sender.try_send(Err(InferError::SlowClient)) {
+            Err(TrySendError::Closed(_)) => Err(StreamSendError::Closed),
+            _ => Err(StreamSendError::SlowClient),
+        };
+    }
+    sender.try_send(response).map_err(|err| match err {
+        TrySendError::Closed(_) => StreamSendError::Closed,
+        TrySendError::Full(_) => StreamSendError::SlowClient,
+    })
+}
+
+/// Responses of a scheduled request, bounded to [`STREAM_BUFFER_SIZE`] responses
+pub type GenerationStream = ReceiverStream<Result<InferStreamResponse, InferError>>;
 
 #[async_trait]
 pub trait Backend {
+    /// Start generating `request`
+    ///
+    /// `cancellation` is cancelled when the router no longer needs the generation, typically
+    /// because the client disconnected. The backend should then stop generating for this request
+    /// and free its resources.
+    ///
+    /// `request.energy_priority` is a hint on whether to favor throughput or energy efficiency,
+    /// for example when picking batch sizes. Backends are free to ignore it.
+    ///
+    /// The stream is bounded and read as fast as the client reads the response. Backends never
+    /// wait for a slow client: they send through [`send_response`], which fails a client whose
+    /// buffer is full with [`InferError::SlowClient`], and then stop generating for it.
     fn schedule(
         &self,
         request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError>;
+        cancellation: CancellationToken,
+    ) -> Result<GenerationStream, InferError>;
+
+    /// Start generating `request`, whose input starts with a prefix seen in a recent request
+    ///
+    /// Backends keeping the KV cache of the recent prefixes can skip their prefill, they report it
+    /// with [`BackendFeature::PrefixCache`]. The default ignores the hint.
+    fn schedule_with_cache(
+        &self,
+        request: ValidGenerateRequest,
+        cancellation: CancellationToken,
+        _hint: PrefixCacheHint,
+    ) -> Result<GenerationStream, InferError> {
+        self.schedule(request, cancellation)
+    }
 
     async fn health(&self, current_health: bool) -> bool;
 
@@ -43,6 +173,20 @@ pub trait Backend {
     }
 
     fn name(&self) -> &'static str;
+
+    /// Whether the backend implements the given optional feature
+    fn supports(&self, _feature: BackendFeature) -> bool {
+        false
+    }
+}
+
+/// Optional features a backend may implement
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendFeature {
+    /// Constrained generation with a grammar (JSON schema, JSON mode or regex)
+    Grammar,
+    /// Reuse of the KV cache of the prefixes of recent requests, skipping part of their prefill
+    PrefixCache,
 }
 
 /// Inference struct
@@ -52,200 +196,955 @@ pub struct Infer {
     validation: Validation,
     /// Request backend
     backend: Arc<dyn Backend + Send + Sync>,
-    /// Chat template
-    pub(crate) chat_template: Option<ChatTemplate>,
+    /// Chat templates of the model by name
+    pub(crate) chat_templates: Arc<HashMap<String, ChatTemplate>>,
+    /// Templates sent in the requests
+    chat_template_cache: Arc<ChatTemplateCache>,
+    /// Responses of the requests sent with an idempotency key
+    idempotency_cache: Arc<IdempotencyCache>,
+    /// Special tokens declared in the tokenizer config
+    special_tokens: SpecialTokensResponse,
+    /// Text of the end of sequence tokens, generations stop at any of them
+    eos_tokens: Arc<[String]>,
+    /// Inputs longer than this many bytes are rejected without being tokenized
+    max_input_bytes: usize,
+    /// New requests are rejected while the moving average of the tokens per joule is below
+    /// this floor
+    efficiency_floor: Option<f64>,
     /// Inference limit
-    limit_concurrent_requests: Arc<Semaphore>,
+    limit_concurrent_requests: ConcurrencyLimits,
     /// Backend health
     backend_health: Arc<AtomicBool>,
-    /// NVML instance
-    nvml: Arc<Nvml>,
+    /// Energy meter, `None` when energy tracking is disabled
+    energy_meter: Option<DeviceMeter>,
+    /// Totals of the finished requests
+    energy_stats: Arc<EnergyStats>,
+    /// Grams of CO2 emitted per kWh, used to estimate the emissions of the requests
+    carbon_intensity_g_per_kwh: Option<f64>,
+    /// Number of tokens between two readings of the energy counter
+    energy_sampling_interval: usize,
+    /// Token energies below this floor, in millijoules, are added to the next token
+    token_energy_floor: Option<u64>,
+    /// Token energies above this cap, in millijoules, are counted as outliers
+    token_energy_cap: Option<u64>,
+    /// Unit of the energies in the responses, unless the request asks for another one
+    energy_unit: EnergyUnit,
+    /// Power drawn by the devices without any request, in milliwatts
+    idle_power_mw: Arc<OnceLock<u32>>,
+    /// Long output detection
+    output_length_monitor: Option<Arc<OutputLengthMonitor>>,
+    /// Extended per token telemetry, empty when disabled
+    telemetry_fields: Arc<[TelemetryField]>,
+    telemetry: Option<Arc<dyn TelemetrySource>>,
+    /// NVML handle, `None` when energy is not read through NVML
+    nvml: Option<Arc<Nvml>>,
+    /// Changes the sampling parameters during the generations
+    sampling_hook: Option<SamplingHook>,
+    /// File the energy of every completed request is appended to
+    energy_log: Option<Arc<EnergyLog>>,
+    /// Prefixes of the recent inputs, `None` when prefix caching is disabled
+    prefix_cache: Option<Arc<PrefixCache>>,
 }
 
 impl Infer {
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) fn new(
+    /// Start building an [`Infer`] over `backend`
+    pub(crate) fn builder(
         backend: impl Backend + Send + Sync + 'static,
         validation: Validation,
-        max_concurrent_requests: usize,
-        tokenizer_config: HubTokenizerConfig,
-        processor_config: HubProcessorConfig,
-    ) -> Self {
-        let chat_template = tokenizer_config
-            .chat_template
-            .or(processor_config.chat_template)
-            .and_then(|t| match t {
-                ChatTemplateVersions::Single(template) => Some(template),
-                ChatTemplateVersions::Multiple(templates) => templates
-                    .into_iter()
-                    .find(|t| t.name == "default")
-                    .map(|t| t.template),
-            })
-            .map(|t| ChatTemplate::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token));
+    ) -> InferBuilder {
+        InferBuilder::new(backend, validation)
+    }
 
-        // Inference limit with a semaphore
-        let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+    /// Unit of the energies in the responses of the requests that do not set `energy_unit`
+    pub(crate) fn energy_unit(&self) -> EnergyUnit {
+        self.energy_unit
+    }
 
-        // Backend health
-        let backend_health = Arc::new(AtomicBool::new(backend.start_health()));
+    /// Instantaneous power draw of each device energy is measured on, in milliwatts
+    ///
+    /// Empty when energy tracking is disabled.
+    pub(crate) fn current_power_usage(&self) -> Result<Vec<u32>, InferError> {
+        match &self.energy_meter {
+            Some(energy_meter) => energy_meter.power_usage(),
+            None => Ok(Vec::new()),
+        }
+    }
 
-        // Initialize NVML
-        let nvml = Nvml::init().expect("Failed to initialize NVML");
+    /// Temperature, power draw and throttle reasons of each device energy is measured on
+    ///
+    /// Empty when energy is not measured through NVML.
+    pub(crate) fn device_telemetry(&self) -> Result<Vec<DeviceTelemetry>, InferError> {
+        match &self.nvml {
+            Some(nvml) => self
+                .energy_devices()
+                .iter()
+                .map(|&device_index| device_telemetry(nvml, device_index))
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
 
-        Self {
-            validation,
-            backend: Arc::new(backend),
-            chat_template,
-            limit_concurrent_requests: semaphore,
-            backend_health,
-            nvml: Arc::new(nvml),
+    /// All the devices visible to NVML, to pick the ones to measure energy on
+    ///
+    /// Empty when energy is not measured through NVML.
+    pub(crate) fn list_devices(&self) -> Result<Vec<DeviceInfo>, InferError> {
+        match &self.nvml {
+            Some(nvml) => list_devices(nvml),
+            None => Ok(Vec::new()),
         }
     }
 
+    /// Measure the power drawn by the devices while no request is running
+    ///
+    /// Called once at startup, the average power over `window` is the baseline subtracted from
+    /// the energy of the requests with `subtract_idle`.
+    pub(crate) async fn measure_idle_power(&self, window: Duration) {
+        let Some(energy_meter) = &self.energy_meter else {
+            return;
+        };
+        let start = Instant::now();
+        let energy_start = match energy_meter.read_millijoules() {
+            Ok(energy_start) => energy_start,
+            Err(err) => {
+                tracing::warn!("Could not measure the idle power: {err}");
+                return;
+            }
+        };
+        tokio::time::sleep(window).await;
+        let energy = match energy_meter.read_millijoules() {
+            Ok(energy_end) => energy_delta(Some(energy_start), Some(energy_end)).unwrap_or(0),
+            Err(err) => {
+                tracing::warn!("Could not measure the idle power: {err}");
+                return;
+            }
+        };
+        let idle_power_mw = average_power(energy, start.elapsed());
+        tracing::info!(idle_power_mw, "Idle power of the devices");
+        metrics::gauge!("tgi_idle_power_milliwatts").set(idle_power_mw as f64);
+        let _ = self.idle_power_mw.set(idle_power_mw);
+    }
+
+    /// Idle power of the devices in milliwatts, `None` until measured
+    pub(crate) fn idle_power_mw(&self) -> Option<u32> {
+        self.idle_power_mw.get().copied()
+    }
+
+    /// Change the maximum number of concurrent requests
+    ///
+    /// Lowering the limit below the number of running requests does not cancel them, new
+    /// requests are rejected until enough of them finish.
+    pub(crate) fn set_concurrency_limit(&self, new_limit: usize) {
+        tracing::info!(
+            from = self.limit_concurrent_requests.limit(),
+            to = new_limit,
+            "Changing the concurrency limit"
+        );
+        self.limit_concurrent_requests.set_limit(new_limit);
+    }
+
+    /// Maximum number of concurrent requests
+    pub(crate) fn current_concurrency_limit(&self) -> usize {
+        self.limit_concurrent_requests.limit()
+    }
+
+    /// Totals of the requests served since the router started and the current power draw
+    pub(crate) fn energy_summary(&self) -> EnergySummary {
+        let power_watts = match self.current_power_usage() {
+            Ok(power_usage) if !power_usage.is_empty() => {
+                Some(power_usage.iter().map(|&mw| mw as f64).sum::<f64>() / 1000.0)
+            }
+            Ok(_) => None,
+            Err(err) => {
+                tracing::debug!("Could not read the power draw: {err}");
+                None
+            }
+        };
+        self.energy_stats
+            .summary(power_watts, self.efficiency_ewma())
+    }
+
+    /// Moving average of the tokens per joule of the finished requests, a smoothed efficiency
+    /// signal for autoscaling
+    ///
+    /// `None` until the energy of a request was measured.
+    pub(crate) fn efficiency_ewma(&self) -> Option<f64> {
+        self.energy_stats.efficiency_ewma()
+    }
+
+    /// Predicted energy of `request` in millijoules, from its token budget and the moving
+    /// average of the tokens per joule
+    ///
+    /// This is an upper bound for requests stopping before their budget. `None` until enough
+    /// requests were measured.
+    pub(crate) fn estimate_energy(&self, request: &ValidGenerateRequest) -> Option<u64> {
+        self.energy_stats
+            .estimate_energy(request.stopping_parameters.max_total_new_tokens)
+    }
+
+    /// Whether the model has at least one chat template
+    pub(crate) fn has_chat_template(&self) -> bool {
+        !self.chat_templates.is_empty()
+    }
+
+    /// Devices energy is measured on, empty when energy tracking is disabled
+    pub(crate) fn energy_devices(&self) -> &[u32] {
+        self.energy_meter
+            .as_ref()
+            .map(|energy_meter| energy_meter.devices())
+            .unwrap_or_default()
+    }
+
     /// Add a new request to the queue and return a stream of InferStreamResponse
-    #[instrument(skip_all)]
+    ///
+    /// The span records the seed and input length of the request once validated, and its energy
+    /// when the generation ends.
+    #[instrument(
+        skip_all,
+        fields(
+            seed = tracing::field::Empty,
+            input_length = tracing::field::Empty,
+            energy_mj = tracing::field::Empty
+        )
+    )]
     pub(crate) async fn generate_stream<'a>(
         &'a self,
         request: GenerateRequest,
     ) -> Result<
         (
-            OwnedSemaphorePermit,
+            RequestPermit,
             u32, // input_length
             impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a,
         ),
         InferError,
     > {
-        // Get device and initial energy consumption
-        let device = self.nvml.device_by_index(0).map_err(|e| InferError::EnergyConsumptionError(e.to_string()))?;
-        let energy_start = device.total_energy_consumption().map_err(|e| InferError::EnergyConsumptionError(e.to_string()))?;
-        println!("energy_start: {:?}", energy_start);
+        let idle_power_mw = request
+            .parameters
+            .subtract_idle
+            .then(|| self.idle_power_mw())
+            .flatten();
+        let metadata = request.metadata.clone();
 
-        // Limit concurrent requests by acquiring a permit from the semaphore
-        let permit = self
-            .clone()
-            .limit_concurrent_requests
-            .try_acquire_owned()
-            .map_err(|err| {
-                metrics::counter!("tgi_request_failure", "err" => "overloaded").increment(1);
-                tracing::error!("{err}");
-                err
-            })?;
+        // Oversized inputs are rejected before taking a permit and tokenizing them
+        if let Err(err) = self.check_input_bytes(&request) {
+            let err = InferError::from(err);
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // Grammars are validated by the router but enforced by the backend
+        if request.parameters.grammar.is_some() && !self.backend.supports(BackendFeature::Grammar) {
+            let err = InferError::from(ValidationError::Grammar);
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // Energy budgets can only be enforced when the energy is measured
+        let max_energy = request.parameters.max_energy_millijoules;
+        if max_energy.is_some() && self.energy_meter.is_none() {
+            let err = InferError::from(ValidationError::EnergyBudgetUnavailable);
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            tracing::error!("{err}");
+            return Err(err);
+        }
 
-        // Validate request
+        // Check the parameters before taking a permit so that invalid requests do not hold a
+        // concurrency slot
         let mut local_request = request.clone();
-        let valid_request = self.validation.validate(request).await.map_err(|err| {
+        let validation_error = |err: ValidationError| {
             metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
             err
-        })?;
+        };
+        let checked_request = self
+            .validation
+            .check_parameters(request)
+            .map_err(validation_error)?;
+
+        // Shed load while the efficiency is below the floor. Requests are still admitted when
+        // none are running, so that the efficiency can be measured again and recover.
+        let efficiency_collapsed = self.efficiency_floor.is_some_and(|floor| {
+            self.efficiency_ewma()
+                .is_some_and(|efficiency| efficiency < floor)
+        });
+        if efficiency_collapsed && self.limit_concurrent_requests.running() > 0 {
+            metrics::counter!("tgi_request_failure", "err" => "overloaded", "reason" => "efficiency")
+                .increment(1);
+            tracing::error!("Efficiency is below the floor, shedding load");
+            return Err(InferError::Overloaded(TryAcquireError::NoPermits));
+        }
+
+        // Limit concurrent requests by acquiring permits from the semaphores, requests with a
+        // priority wait for theirs
+        let permit = self
+            .limit_concurrent_requests
+            .acquire(&local_request.parameters)
+            .await?;
+
+        // Tokenize the inputs, the permit is held since this can be slow
+        let valid_request = self
+            .validation
+            .validate_checked(checked_request)
+            .await
+            .map_err(validation_error)?;
+
+        // Get initial energy consumption, `None` when energy tracking is disabled globally or
+        // for this request
+        let energy_meter = self
+            .energy_meter
+            .as_ref()
+            .filter(|_| valid_request.measure_energy);
+        let energy_start = read_energy(energy_meter).await;
+        let energy_start_at = Instant::now();
+        tracing::debug!(energy_start_mj = ?energy_start, "Energy before generation");
 
         let seed = valid_request.parameters.seed;
+        let do_sample = valid_request.parameters.do_sample;
+        // Greedy requests do not depend on the seed, it is only reported for sampled ones
+        let sampled_seed = do_sample.then_some(seed);
         local_request.parameters.seed = Some(seed);
         let input_length = valid_request.input_length;
         let max_total_new_tokens = valid_request.stopping_parameters.max_total_new_tokens;
+        let stop_sequences = valid_request.stopping_parameters.stop_sequences.clone();
+        let ignore_eos_token = valid_request.stopping_parameters.ignore_eos_token;
+        let return_offsets = local_request.parameters.return_offsets;
+        let return_latency = local_request.parameters.return_inter_token_latency;
+        let continue_on_length = local_request.parameters.continue_on_length;
+        // Without `max_total_new_tokens`, `max_new_tokens` bounds all the rounds
+        let total_length_limit = match local_request.parameters.max_total_new_tokens {
+            Some(_) => LengthLimit::MaxTotalNewTokens,
+            None => LengthLimit::MaxNewTokens,
+        };
+        let max_time = local_request
+            .parameters
+            .max_time_ms
+            .map(Duration::from_millis);
+        // The stream is polled outside of this function, the span is kept to record the energy
+        let span = tracing::Span::current();
+        span.record("seed", seed);
+        span.record("input_length", input_length);
 
-        let mut generation_stream = self.backend.schedule(valid_request)?;
+        let scheduled = Instant::now();
+        let request_id = self
+            .energy_log
+            .as_ref()
+            .map(|energy_log| energy_log.request_id());
+        let deadline = max_time.map(|max_time| scheduled + max_time);
+        // Cancelled when the stream is dropped before the end, e.g. when the client disconnects
+        let cancellation = CancellationToken::new();
+        let prefix_hint = match (&self.prefix_cache, &valid_request.input_ids) {
+            (Some(prefix_cache), Some(input_ids)) => prefix_cache.lookup(input_ids),
+            _ => None,
+        };
+        let mut generation_stream = match prefix_hint {
+            Some(hint) => {
+                self.backend
+                    .schedule_with_cache(valid_request, cancellation.child_token(), hint)?
+            }
+            None => self
+                .backend
+                .schedule(valid_request, cancellation.child_token())?,
+        };
+        let backend = self.backend.name();
+        let rounds_cancellation = cancellation.clone();
 
         // Wrap generation stream to update the backend health if the stream contains an error
         let final_stream = stream! {
             let mut total_generated_tokens = 0;
+            // Tokens left in the budget of the request, over all the rounds
+            let mut remaining_tokens = max_total_new_tokens;
+            let mut continuation_rounds = 0;
             let mut first_start = None;
             let mut first_queued = None;
             let mut all_generated_text: Option<GeneratedText> = None;
-            let mut energy_consumption_results: Option<u64> = None;
-            let mut energy_last: Option<u64> = Some(energy_start);
-            while let Some(response) = generation_stream.next().await {
+            // Text of the tokens of the current round, used when the energy budget stops it
+            let mut round_text = String::new();
+            let mut energy_consumption_results: Option<u64>;
+            let mut request_energy = RequestEnergy::new(energy_start, self.energy_sampling_interval)
+                .subtract_idle(idle_power_mw, energy_start_at.into_std())
+                .token_floor(self.token_energy_floor);
+            let mut sequencer = TokenSequencer::new(SEQUENCE_REORDER_WINDOW);
+            // Looks for the stop sequences over the text of all the rounds
+            let mut stop_matcher = StopSequenceMatcher::new(&stop_sequences);
+            // Length in bytes of the text generated so far, over all the rounds
+            let mut text_cursor = 0;
+            let mut previous_token_at = scheduled;
+            'stream: loop {
+                let next = match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, generation_stream.next()).await.ok(),
+                    None => Some(generation_stream.next().await),
+                };
+                // The time limit is reached while waiting for the backend, the generation ends
+                // with an empty special token
+                let Some(next) = next else {
+                    request_energy.update(read_energy(energy_meter).await);
+                    tracing::debug!(generated_tokens = total_generated_tokens, "Time limit exceeded");
+                    let generated_text = router_end_text(
+                        all_generated_text.take(),
+                        &round_text,
+                        total_generated_tokens,
+                        FinishReason::TimeLimit,
+                        sampled_seed,
+                    );
+                    let mut token = time_limit_token();
+                    if return_offsets {
+                        set_offsets(&mut token, &mut text_cursor);
+                    }
+                    metrics::histogram!("tgi_request_continuation_rounds").record(continuation_rounds as f64);
+                    // Dropping the backend stream cancels the generation
+                    yield Ok(self.finish_stream(backend, &span, &request_energy, total_generated_tokens, StreamEnd {
+                        token,
+                        top_tokens: Vec::new(),
+                        top_logprobs: Vec::new(),
+                        generated_text,
+                        start: first_start.unwrap_or(scheduled),
+                        queued: first_queued.unwrap_or(scheduled),
+                        seq: None,
+                    }));
+                    return;
+                };
+                let Some(response) = next else {
+                    break;
+                };
                 let response = response.inspect_err(|_err| {
                     self.backend_health.store(false, Ordering::SeqCst);
                 })?;
 
-                match response {
-                    InferStreamResponse::Prefill(_) => yield Ok(response),
-                    InferStreamResponse::Intermediate { token, top_tokens, energy_consumption } => {
-                        total_generated_tokens += 1;
-                        // Get current energy consumption
-                        let current_energy = device.total_energy_consumption()
-                            .map_err(|e| InferError::EnergyConsumptionError(e.to_string()))?;
-
-                        let token_energy = current_energy - energy_last.unwrap();
-                        energy_last = Some(current_energy);
-                        energy_consumption_results = Some(current_energy - energy_start);
-                        println!("total_generated_tokens: {:?}", total_generated_tokens);
-                        println!("token_energy: {:?}", token_energy);
-                        println!("energy_consumption_results: {:?}", energy_consumption_results);
-                        yield Ok(InferStreamResponse::Intermediate { 
-                            token, 
-                            top_tokens,
-                            energy_consumption: energy_consumption_results,
-                        });
-                    }
-                    InferStreamResponse::End { token, top_tokens,generated_text, start, queued, energy_consumption } => {
-                        total_generated_tokens += 1;
-                        first_start = first_start.or(Some(start));
-                        first_queued = first_queued.or(Some(queued));
-                        if let Some(v) = all_generated_text.as_mut() {
-                            v.text.push_str(&generated_text.text);
-                            v.generated_tokens = total_generated_tokens;
-                            v.finish_reason = generated_text.finish_reason.clone();
-                        };
-
-                        if matches!(generated_text.finish_reason, FinishReason::Length) && total_generated_tokens < max_total_new_tokens {
-                            local_request.inputs.push_str(&generated_text.text);
-                            all_generated_text = all_generated_text.or(Some(generated_text));
-
-                            let valid_request = match self.validation.validate(local_request.clone()).await {
-                                Ok(valid_request) => valid_request,
-                                Err(err) => {
-                                    tracing::debug!("Failed to continue request: {err}");
-                                    let energy_end = device.total_energy_consumption()
-                                        .map_err(|e| InferError::GenerationError(e.to_string()))?;
-                                    energy_consumption_results = Some(energy_end - energy_start);
-                                    println!("energy_consumption_results: {:?}", energy_consumption_results);
-                                    yield Ok(InferStreamResponse::End {token, top_tokens, generated_text: all_generated_text.unwrap(), start: first_start.unwrap(), queued: first_queued.unwrap(), energy_consumption: energy_consumption_results });
-                                    break;
+                for response in sequencer.push(response)? {
+                    match response {
+                        InferStreamResponse::Prefill { tokens, .. } => {
+                            // The backends send the prefill tokens once the prefill is done, the
+                            // energy until then is the prefill energy
+                            request_energy.update(read_energy(energy_meter).await);
+                            yield Ok(InferStreamResponse::Prefill {
+                                tokens,
+                                prefill_energy_mj: request_energy.prefill(),
+                            });
+                        }
+                        InferStreamResponse::Intermediate { mut token, mut top_tokens, top_logprobs, seq, .. } => {
+                            total_generated_tokens += 1;
+                            remaining_tokens = remaining_tokens.saturating_sub(1);
+                            attach_telemetry(&mut token, self.telemetry.as_deref(), &self.telemetry_fields);
+                            // Backends that do not stop at the stop sequences are stopped here
+                            let stop_end = match stop_matcher.as_mut() {
+                                Some(matcher) if !token.special => matcher.push(&token.text),
+                                _ => None,
+                            };
+                            if let Some(stop_end) = stop_end {
+                                // The text after the stop sequence is not returned
+                                token.text.truncate(stop_end);
+                            }
+                            // Backends stop at the EOS token of the model, the other end of
+                            // sequence tokens are stopped at here
+                            let stop_reason = match stop_end {
+                                Some(_) => Some(FinishReason::StopSequence),
+                                None if !ignore_eos_token && self.eos_tokens.contains(&token.text) => {
+                                    Some(FinishReason::EndOfSequenceToken)
                                 }
+                                None => None,
+                            };
+                            if return_offsets {
+                                set_offsets(&mut token, &mut text_cursor);
+                            }
+                            if return_latency {
+                                set_inter_token_latency(&mut token, &mut previous_token_at);
+                            }
+                            // Get current energy consumption, once every sampling interval and
+                            // on the last token
+                            let token_energy = if stop_reason.is_none() && request_energy.skip() {
+                                None
+                            } else {
+                                request_energy.update(read_energy(energy_meter).await)
                             };
+                            energy_consumption_results = request_energy.total();
+                            set_step_energy(&mut top_tokens, token_energy);
+                            tracing::trace!(
+                                token_index = total_generated_tokens,
+                                token_energy_mj = ?token_energy,
+                                energy_mj = ?energy_consumption_results,
+                                "Token energy"
+                            );
+                            record_token_energy(backend, token_energy, self.token_energy_cap);
+
+                            if let (Some(max_energy), Some(energy)) = (max_energy, energy_consumption_results) {
+                                if energy > max_energy {
+                                    tracing::debug!(generated_tokens = total_generated_tokens, energy_mj = energy, max_energy_mj = max_energy, "Energy budget exceeded");
+                                    if !token.special {
+                                        round_text.push_str(&token.text);
+                                    }
+                                    let generated_text = router_end_text(
+                                        all_generated_text.take(),
+                                        &round_text,
+                                        total_generated_tokens,
+                                        FinishReason::EnergyBudget,
+                                        sampled_seed,
+                                    );
+                                    metrics::histogram!("tgi_request_continuation_rounds").record(continuation_rounds as f64);
+                                    // Dropping the backend stream cancels the generation
+                                    yield Ok(self.finish_stream(backend, &span, &request_energy, total_generated_tokens, StreamEnd {
+                                        token,
+                                        top_tokens,
+                                        top_logprobs,
+                                        generated_text,
+                                        start: first_start.unwrap_or(scheduled),
+                                        queued: first_queued.unwrap_or(scheduled),
+                                        seq,
+                                    }));
+                                    return;
+                                }
+                            }
 
-                            generation_stream = match self.backend.schedule(valid_request) {
-                                Ok(stream) => {
-                                    tracing::debug!("Continue request");
-                                    println!("HERE: {:?}", energy_consumption);
-                                    yield Ok(InferStreamResponse::Intermediate { token, top_tokens, energy_consumption,} );
-                                    stream
-                                },
-                                Err(err) => {
-                                    tracing::debug!("Failed to continue request: {err}");
-                                    let energy_end = device.total_energy_consumption()
-                                        .map_err(|e| InferError::GenerationError(e.to_string()))?;
-                                    energy_consumption_results = Some(energy_end - energy_start);
-                                    println!("energy_consumption_results: {:?}", energy_consumption_results);
-                                    yield Ok(InferStreamResponse::End {token, top_tokens, generated_text: all_generated_text.unwrap(), start: first_start.unwrap(), queued: first_queued.unwrap(), energy_consumption: energy_consumption_results });
-                                    break;
+                            if let Some(finish_reason) = stop_reason {
+                                // The end of sequence tokens are not part of the text
+                                if matches!(finish_reason, FinishReason::StopSequence) {
+                                    round_text.push_str(&token.text);
                                 }
+                                tracing::debug!(generated_tokens = total_generated_tokens, ?finish_reason, "Stop sequence or token generated");
+                                let generated_text = router_end_text(
+                                    all_generated_text.take(),
+                                    &round_text,
+                                    total_generated_tokens,
+                                    finish_reason,
+                                    sampled_seed,
+                                );
+                                metrics::histogram!("tgi_request_continuation_rounds").record(continuation_rounds as f64);
+                                // Dropping the backend stream cancels the generation
+                                yield Ok(self.finish_stream(backend, &span, &request_energy, total_generated_tokens, StreamEnd {
+                                    token,
+                                    top_tokens,
+                                    top_logprobs,
+                                    generated_text,
+                                    start: first_start.unwrap_or(scheduled),
+                                    queued: first_queued.unwrap_or(scheduled),
+                                    seq,
+                                }));
+                                return;
                             }
-                        } else {
-                            // Get final energy consumption
-                            let energy_end = device.total_energy_consumption()
-                                .map_err(|e| InferError::GenerationError(e.to_string()))?;
-                            energy_consumption_results = Some(energy_end - energy_start);
-                            println!("energy_consumption_results: {:?}", energy_consumption_results);
-                            yield Ok(InferStreamResponse::End {
+
+                            if !token.special {
+                                round_text.push_str(&token.text);
+                            }
+
+                            // The rest of the generation is scheduled again when the hook changes the sampling
+                            let sampling = self.sampling_hook.as_ref().and_then(|hook| {
+                                hook(&GenerationState {
+                                    generated_tokens: total_generated_tokens,
+                                    continuation_rounds,
+                                    token: &token,
+                                    round_text: &round_text,
+                                    energy_consumption: energy_consumption_results,
+                                    sampling: SamplingOverride::current(&local_request.parameters),
+                                })
+                            });
+                            if let Some(sampling) = sampling.filter(|sampling| {
+                                sampling.changes(&local_request.parameters)
+                                    && remaining_tokens > 0
+                                    && continuation_rounds < MAX_CONTINUATION_ROUNDS
+                            }) {
+                                // A character split by the end of the round is generated again by the next one
+                                let (complete, _) = split_incomplete_tail(&round_text);
+                                let mut continued_request = local_request.clone();
+                                sampling.apply(&mut continued_request.parameters);
+                                continued_request.inputs.push_str(complete);
+                                continued_request.parameters.seed = Some(continuation_seed(seed, continuation_rounds + 1));
+                                if !continue_on_length {
+                                    continued_request.parameters.max_new_tokens = Some(remaining_tokens);
+                                }
+                                let stream = match self.validation.validate(continued_request.clone()).await {
+                                    Ok(mut valid_request) => {
+                                        limit_to_remaining_tokens(&mut valid_request, remaining_tokens);
+                                        self.backend.schedule(valid_request, rounds_cancellation.child_token())
+                                    }
+                                    Err(err) => Err(err.into()),
+                                };
+                                match stream {
+                                    Ok(stream) => {
+                                        continuation_rounds += 1;
+                                        tracing::debug!(continuation_rounds, ?sampling, "Continue request with new sampling parameters");
+                                        metrics::counter!("tgi_request_continuations").increment(1);
+                                        let all_text = all_generated_text.get_or_insert(GeneratedText {
+                                            text: String::new(),
+                                            generated_tokens: 0,
+                                            finish_reason: FinishReason::Length,
+                                            seed: sampled_seed,
+                                            length_limit: None,
+                                        });
+                                        all_text.text.push_str(complete);
+                                        all_text.generated_tokens = total_generated_tokens;
+                                        local_request = continued_request;
+                                        // Dropping the stream of the round cancels it
+                                        generation_stream = stream;
+                                        sequencer = TokenSequencer::new(SEQUENCE_REORDER_WINDOW);
+                                        round_text.clear();
+                                        token.continuation_boundary = true;
+                                        yield Ok(InferStreamResponse::Intermediate {
+                                            token,
+                                            top_tokens,
+                                            top_logprobs,
+                                            energy_consumption: energy_consumption_results,
+                                            seq: None,
+                                        });
+                                        continue 'stream;
+                                    }
+                                    Err(err) => tracing::debug!("Ignoring the sampling hook, the request cannot be scheduled again: {err}"),
+                                }
+                            }
+
+                            yield Ok(InferStreamResponse::Intermediate {
                                 token,
                                 top_tokens,
-                                generated_text: all_generated_text.unwrap_or(generated_text),
-                                start: first_start.unwrap(),
-                                queued: first_queued.unwrap(),
+                                top_logprobs,
                                 energy_consumption: energy_consumption_results,
+                                seq,
                             });
-                            break;
                         }
+                        InferStreamResponse::End { mut token, mut top_tokens, top_logprobs, mut generated_text, start, queued, seq, .. } => {
+                            total_generated_tokens += 1;
+                            remaining_tokens = remaining_tokens.saturating_sub(1);
+                            attach_telemetry(&mut token, self.telemetry.as_deref(), &self.telemetry_fields);
+                            // The last token can complete a stop sequence the backend did not stop at
+                            let stop_end = match stop_matcher.as_mut() {
+                                Some(matcher) if !token.special => matcher.push(&token.text),
+                                _ => None,
+                            };
+                            if let Some(stop_end) = stop_end {
+                                let trimmed = token.text.split_off(stop_end);
+                                if generated_text.text.ends_with(&trimmed) {
+                                    generated_text.text.truncate(generated_text.text.len() - trimmed.len());
+                                }
+                                generated_text.finish_reason = FinishReason::StopSequence;
+                            }
+                            if return_offsets {
+                                set_offsets(&mut token, &mut text_cursor);
+                            }
+                            if return_latency {
+                                set_inter_token_latency(&mut token, &mut previous_token_at);
+                            }
+                            let start = *first_start.get_or_insert(start);
+                            let queued = *first_queued.get_or_insert(queued);
+                            if let Some(v) = all_generated_text.as_mut() {
+                                v.text.push_str(&generated_text.text);
+                                v.generated_tokens = total_generated_tokens;
+                                v.finish_reason = generated_text.finish_reason.clone();
+                            };
+
+                            let continue_request = continue_on_length
+                                && matches!(generated_text.finish_reason, FinishReason::Length)
+                                && remaining_tokens > 0
+                                && continuation_rounds < MAX_CONTINUATION_ROUNDS;
+                            // Limit reported if the generation stops here on its length
+                            let length_limit = if !continue_on_length {
+                                LengthLimit::MaxNewTokens
+                            } else if remaining_tokens == 0 {
+                                total_length_limit
+                            } else if continuation_rounds >= MAX_CONTINUATION_ROUNDS {
+                                LengthLimit::ContinuationRounds
+                            } else {
+                                // The round could not be continued
+                                LengthLimit::MaxNewTokens
+                            };
+                            let next_round = if continue_request {
+                                continuation_rounds += 1;
+                                // A character split by the end of the round is generated again by the next one
+                                let (complete, incomplete) = split_incomplete_tail(&generated_text.text);
+                                let incomplete_len = incomplete.len();
+                                local_request.inputs.push_str(complete);
+                                local_request.parameters.seed = Some(continuation_seed(seed, continuation_rounds));
+                                let all_text = &mut all_generated_text.get_or_insert_with(|| generated_text.clone()).text;
+                                all_text.truncate(all_text.len() - incomplete_len);
+
+                                let stream = match self.validation.validate(local_request.clone()).await {
+                                    Ok(mut valid_request) => {
+                                        limit_to_remaining_tokens(&mut valid_request, remaining_tokens);
+                                        self.backend.schedule(valid_request, rounds_cancellation.child_token())
+                                    }
+                                    Err(err) => Err(err.into()),
+                                };
+                                stream.inspect_err(|err| tracing::debug!("Failed to continue request: {err}")).ok()
+                            } else {
+                                None
+                            };
+
+                            // The last token of the round is measured like any other token
+                            let token_energy = request_energy.update(read_energy(energy_meter).await);
+                            energy_consumption_results = request_energy.total();
+                            set_step_energy(&mut top_tokens, token_energy);
+                            record_token_energy(backend, token_energy, self.token_energy_cap);
+                            match next_round {
+                                Some(stream) => {
+                                    tracing::debug!(continuation_rounds, energy_mj = ?energy_consumption_results, "Continue request");
+                                    // Each round runs the prefill again, which shows in the energy
+                                    metrics::counter!("tgi_request_continuations").increment(1);
+                                    // Dropping the stream of the round cancels it. The continuation
+                                    // restarts its sequence numbers from zero.
+                                    generation_stream = stream;
+                                    sequencer = TokenSequencer::new(SEQUENCE_REORDER_WINDOW);
+                                    round_text.clear();
+                                    token.continuation_boundary = true;
+                                    yield Ok(InferStreamResponse::Intermediate { token, top_tokens, top_logprobs, energy_consumption: energy_consumption_results, seq: None });
+                                }
+                                None => {
+                                    let generated_text = with_length_limit(all_generated_text.take().unwrap_or(generated_text), length_limit);
+                                    yield Ok(self.finish_stream(backend, &span, &request_energy, total_generated_tokens, StreamEnd {
+                                        token,
+                                        top_tokens,
+                                        top_logprobs,
+                                        generated_text,
+                                        start,
+                                        queued,
+                                        seq,
+                                    }));
+                                    break 'stream;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            metrics::histogram!("tgi_request_continuation_rounds").record(continuation_rounds as f64);
+            sequencer.finish()?;
+        };
+
+        // Failed generations consumed energy too, it is measured up to the failure
+        let final_stream = stream! {
+            let mut final_stream = std::pin::pin!(final_stream);
+            while let Some(response) = final_stream.next().await {
+                match response {
+                    Err(err) if energy_meter.is_some() => {
+                        let mut failed_energy = RequestEnergy::new(energy_start, 1)
+                            .subtract_idle(idle_power_mw, energy_start_at.into_std());
+                        failed_energy.update(read_energy(energy_meter).await);
+                        let energy_consumption = failed_energy.total();
+                        tracing::debug!(energy_mj = ?energy_consumption, "Generation failed");
+                        record_failed_request_energy(backend, energy_consumption);
+                        yield Err(InferError::with_energy(err, energy_consumption));
+                    }
+                    response => yield response,
+                }
+            }
+        };
 
+        // Backends do not always report the seed. The resolved seed of a sampled request is what
+        // reproduces it, including when the client did not send one.
+        let energy_log = self.energy_log.clone();
+        let prefix_cache = self.prefix_cache.clone();
+        // The prefill of a hit is only saved when the backend reuses the cached prefixes
+        let prefix_reused = self.backend.supports(BackendFeature::PrefixCache);
+        let mut token_energy_mj = Vec::new();
+        let final_stream = final_stream.map(move |mut response| {
+            // The log keeps the energy after each token
+            if let Ok(InferStreamResponse::Intermediate {
+                energy_consumption, ..
+            }) = &response
+            {
+                if energy_log.is_some() {
+                    token_energy_mj.push(*energy_consumption);
+                }
+            }
+            if let Ok(InferStreamResponse::End {
+                generated_text,
+                energy_consumption,
+                prefill_energy,
+                prefill_energy_saved,
+                ..
+            }) = &mut response
+            {
+                if do_sample {
+                    generated_text.seed = Some(seed);
+                }
+                // The prefill energy per token is learnt from the requests prefilled in full
+                if let Some(prefix_cache) = &prefix_cache {
+                    match &prefix_hint {
+                        Some(hint) if prefix_reused => {
+                            *prefill_energy_saved = prefix_cache.saved_energy_estimate(hint)
+                        }
+                        _ => prefix_cache.record_prefill(input_length, *prefill_energy),
                     }
                 }
+                if let (Some(energy_log), Some(request_id)) = (&energy_log, request_id) {
+                    energy_log.append(EnergyReport {
+                        timestamp_ms: timestamp_ms(),
+                        request_id,
+                        input_tokens: input_length,
+                        generated_tokens: generated_text.generated_tokens,
+                        energy_mj: *energy_consumption,
+                        duration_ms: scheduled.elapsed().as_millis() as u64,
+                        finish_reason: generated_text.finish_reason.clone(),
+                        metadata: metadata.clone(),
+                        token_energy_mj: std::mem::take(&mut token_energy_mj),
+                    });
+                }
+            }
+            response
+        });
+
+        Ok((
+            permit,
+            input_length,
+            CancelOnDrop::new(final_stream, cancellation),
+        ))
+    }
+
+    /// Record the energy of a generation ending with `end` and return its last response
+    fn finish_stream(
+        &self,
+        backend: &'static str,
+        span: &tracing::Span,
+        request_energy: &RequestEnergy,
+        generated_tokens: u32,
+        end: StreamEnd,
+    ) -> InferStreamResponse {
+        let energy_consumption = request_energy.total();
+        let finish_reason = &end.generated_text.finish_reason;
+        tracing::debug!(
+            generated_tokens,
+            energy_mj = ?energy_consumption,
+            ?finish_reason,
+            "Request energy"
+        );
+        record_request_energy(backend, finish_reason, energy_consumption);
+        self.energy_stats
+            .record(generated_tokens, energy_consumption);
+        span.record("energy_mj", energy_consumption);
+        InferStreamResponse::End {
+            token: end.token,
+            top_tokens: end.top_tokens,
+            top_logprobs: end.top_logprobs,
+            generated_text: end.generated_text,
+            start: end.start,
+            queued: end.queued,
+            energy_consumption,
+            prefill_energy: request_energy.prefill(),
+            decode_energy: request_energy.decode(),
+            prefill_energy_saved: None,
+            seq: end.seq,
+        }
+    }
+
+    /// Cheap check of the size of the inputs, the precise limits are in tokens and checked by
+    /// the validation
+    fn check_input_bytes(&self, request: &GenerateRequest) -> Result<(), ValidationError> {
+        let input_bytes = request.inputs.len();
+        if input_bytes > self.max_input_bytes {
+            return Err(ValidationError::InputBytes(
+                self.max_input_bytes,
+                input_bytes,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Validate and tokenize `request` without generating it
+    ///
+    /// Neither a permit nor the backend are used. A rejected request is reported rather than
+    /// returned as an error, errors are reserved to the tokenizer failing.
+    #[instrument(skip_all)]
+    pub(crate) async fn validate_only(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<ValidationReport, InferError> {
+        let validated = match self.check_input_bytes(&request) {
+            Ok(()) => self.validation.validate(request).await,
+            Err(err) => Err(err),
+        };
+        match validated {
+            Ok(valid_request) => {
+                let parameters = &valid_request.parameters;
+                let stopping_parameters = &valid_request.stopping_parameters;
+                let estimated_energy = self.estimate_energy(&valid_request);
+                Ok(ValidationReport {
+                    accepted: true,
+                    error: None,
+                    input_length: Some(valid_request.input_length),
+                    estimated_energy,
+                    estimated_co2_grams: co2_grams(
+                        estimated_energy,
+                        self.carbon_intensity_g_per_kwh,
+                    ),
+                    parameters: Some(EffectiveParameters {
+                        temperature: parameters.temperature,
+                        top_k: parameters.top_k,
+                        top_p: parameters.top_p,
+                        typical_p: parameters.typical_p,
+                        do_sample: parameters.do_sample,
+                        seed: parameters.seed,
+                        repetition_penalty: parameters.repetition_penalty,
+                        frequency_penalty: parameters.frequency_penalty,
+                        watermark: parameters.watermark,
+                        grammar: parameters.grammar.is_some(),
+                        max_new_tokens: stopping_parameters.max_new_tokens,
+                        max_total_new_tokens: stopping_parameters.max_total_new_tokens,
+                        stop: stopping_parameters.stop_sequences.clone(),
+                        truncate: valid_request.truncate,
+                        top_n_tokens: valid_request.top_n_tokens,
+                    }),
+                })
             }
+            Err(err @ ValidationError::Tokenizer(_)) => Err(err.into()),
+            Err(err) => Ok(ValidationReport {
+                accepted: false,
+                error: Some(err.to_string()),
+                input_length: None,
+                estimated_energy: None,
+                estimated_co2_grams: None,
+                parameters: None,
+            }),
+        }
+    }
+
+    /// Generate the prompt of `request` greedily, once per iteration, and summarize the
+    /// latency, throughput and energy of the generations
+    ///
+    /// Iterations run one after the other so that the energy of a generation is not shared with
+    /// another one. Requests served at the same time still show in the energy.
+    #[instrument(skip_all, fields(iterations = request.iterations))]
+    pub(crate) async fn benchmark(
+        &self,
+        request: BenchmarkRequest,
+    ) -> Result<BenchmarkReport, InferError> {
+        if request.iterations == 0 || request.iterations > benchmark::MAX_ITERATIONS {
+            return Err(ValidationError::BenchmarkIterations(
+                benchmark::MAX_ITERATIONS,
+                request.iterations,
+            )
+            .into());
+        }
+        let generate_request = GenerateRequest {
+            inputs: request.prompt,
+            add_special_tokens: true,
+            metadata: None,
+            parameters: GenerateParameters {
+                do_sample: false,
+                max_new_tokens: Some(request.max_new_tokens),
+                ..default_parameters()
+            },
         };
 
-        Ok((permit, input_length, final_stream))
+        let mut generated_tokens = 0;
+        let mut latencies_ms = Vec::new();
+        let mut tokens_per_second = Vec::new();
+        let mut millijoules_per_token = Vec::new();
+        for _ in 0..request.iterations {
+            let start = Instant::now();
+            let response = self.generate(generate_request.clone()).await?;
+            let latency = start.elapsed();
+            let tokens = response.generated_text.generated_tokens;
+            generated_tokens += tokens;
+            latencies_ms.push(latency.as_secs_f64() * 1000.0);
+            tokens_per_second.push(tokens as f64 / latency.as_secs_f64());
+            if let Some(energy) = response.energy_consumption.filter(|_| tokens > 0) {
+                millijoules_per_token.push(energy as f64 / tokens as f64);
+            }
+        }
+
+        Ok(BenchmarkReport {
+            iterations: request.iterations,
+            generated_tokens,
+            latency_ms: benchmark_stats(latencies_ms).expect("at least one iteration"),
+            tokens_per_second: benchmark_stats(tokens_per_second).expect("at least one iteration"),
+            millijoules_per_token: benchmark_stats(millijoules_per_token),
+        })
     }
 
     /// Tokenizer the input
@@ -271,22 +1170,122 @@ impl Infer {
         Ok(encoding.0)
     }
 
+    /// Special tokens of the tokenizer along with their ids
+    #[instrument(skip_all)]
+    pub(crate) async fn special_tokens(&self) -> SpecialTokensResponse {
+        let mut special_tokens = self.special_tokens.clone();
+        for token in special_tokens.tokens_mut() {
+            // Tokens that are not a single id in the vocabulary keep `id: None`
+            token.id = match self
+                .validation
+                .tokenize(token.content.clone(), false, None)
+                .await
+            {
+                Ok((encoding, _)) => match encoding.get_ids() {
+                    [id] => Some(*id),
+                    _ => None,
+                },
+                Err(err) => {
+                    tracing::debug!("Could not tokenize special token: {err}");
+                    None
+                }
+            };
+        }
+        special_tokens
+    }
+
     /// Apply the chat template to the chat request
+    ///
+    /// `chat_template` overrides the template of the model for this request, otherwise the
+    /// template of the model named `template_name` is used, the default one when unset.
+    /// Returns the prompt and the name of the applied template, `None` for a request template.
     #[instrument(skip_all)]
     pub(crate) fn apply_chat_template(
         &self,
         messages: Vec<Message>,
         tools_and_prompt: Option<(Vec<Tool>, String)>,
-    ) -> Result<String, InferError> {
-        self.chat_template
-            .as_ref()
-            .ok_or_else(|| InferError::TemplateError(ErrorKind::TemplateNotFound.into()))?
+        chat_template: Option<&str>,
+        template_name: Option<&str>,
+    ) -> Result<(String, Option<String>), InferError> {
+        let template_error = |e: InferError| {
+            metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
+            tracing::error!("{e}");
+            e
+        };
+        if let Some(source) = chat_template {
+            let template = self
+                .chat_template_cache
+                .get(source)
+                .map_err(template_error)?;
+            let prompt = template
+                .apply(messages, tools_and_prompt)
+                .map_err(template_error)?;
+            return Ok((prompt, None));
+        }
+
+        let name = template_name.unwrap_or(DEFAULT_CHAT_TEMPLATE);
+        let template = self.chat_templates.get(name).ok_or_else(|| {
+            let err = match template_name {
+                Some(name) => minijinja::Error::new(
+                    ErrorKind::TemplateNotFound,
+                    format!("the model has no chat template named `{name}`"),
+                ),
+                None => ErrorKind::TemplateNotFound.into(),
+            };
+            template_error(InferError::TemplateError(err))
+        })?;
+        let prompt = template
             .apply(messages, tools_and_prompt)
-            .map_err(|e| {
-                metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
-                tracing::error!("{e}");
-                e
-            })
+            .map_err(template_error)?;
+        Ok((prompt, Some(name.to_string())))
+    }
+
+    /// Like [`Infer::generate`], but a request retried with the same `idempotency_key` gets the
+    /// stored response instead of being generated again
+    ///
+    /// The key is scoped to the API key of the request, and a key reused for a different request
+    /// is rejected. A retry arriving while the first attempt is running waits for its response.
+    /// Also returns whether the response is a replay. Only successful responses are stored.
+    pub(crate) async fn generate_idempotent(
+        &self,
+        request: GenerateRequest,
+        idempotency_key: Option<&str>,
+    ) -> Result<(InferResponse, bool), InferError> {
+        let Some(key) = idempotency_key else {
+            return Ok((self.generate(request).await?, false));
+        };
+        let cache_key = IdempotencyKey::new(request.parameters.api_key.clone(), key.to_string());
+        let fingerprint = idempotency::fingerprint(&request);
+        let response = loop {
+            match self.idempotency_cache.lookup(&cache_key, fingerprint) {
+                Lookup::Stored(response) => break response,
+                Lookup::InFlight(mut receiver) => {
+                    // The first attempt failed when it drops the sender, the retry then runs
+                    let response = receiver
+                        .wait_for(Option::is_some)
+                        .await
+                        .ok()
+                        .and_then(|response| response.clone());
+                    if let Some(response) = response {
+                        break response;
+                    }
+                }
+                Lookup::New(pending) => {
+                    let response = self.generate(request).await?;
+                    pending.store(response.clone());
+                    return Ok((response, false));
+                }
+                Lookup::Mismatch => {
+                    metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                    let err = ValidationError::IdempotencyKeyReused;
+                    tracing::error!("{err}");
+                    return Err(err.into());
+                }
+            }
+        };
+        metrics::counter!("tgi_request_idempotent_replay").increment(1);
+        tracing::info!("Replaying the response of idempotency key {key}");
+        Ok((response, true))
     }
 
     /// Add a new request to the queue and return a InferResponse
@@ -295,11 +1294,14 @@ impl Infer {
         &self,
         request: GenerateRequest,
     ) -> Result<InferResponse, InferError> {
-        // Get device and initial energy consumption
-        let device = self.nvml.device_by_index(0).map_err(|e| InferError::EnergyConsumptionError(e.to_string()))?;
-        let energy_start = device.total_energy_consumption().map_err(|e| InferError::EnergyConsumptionError(e.to_string()))?;
-        println!("energy_start: {:?}", energy_start);
+        // The energy of the request is the one measured by the stream, so that both report the
+        // same value. Only the breakdown per device is measured around the stream.
+        let energy_meter = self.energy_meter.as_ref().filter(|energy_meter| {
+            request.parameters.measure_energy && energy_meter.devices().len() > 1
+        });
+        let devices_start = read_device_energies(energy_meter).await;
         let use_top_tokens = request.parameters.top_n_tokens.is_some_and(|x| x > 0);
+        let use_top_logprobs = request.parameters.top_logprobs.is_some_and(|x| x > 0);
 
         // Create stream and keep semaphore permit as long as generate lives
         let (_permit, _input_length, stream) = self.generate_stream(request).await?;
@@ -308,11 +1310,18 @@ impl Infer {
         let mut result_prefill = Vec::new();
         let mut result_tokens = Vec::new();
         let mut result_top_tokens = Vec::new();
+        let mut result_top_logprobs = Vec::new();
         let mut result_generated_text = None;
         let mut result_start = None;
         let mut result_queued = None;
         let mut result_energy_consumption = None;
+        let mut result_tokens_per_joule = None;
+        let mut result_per_device_energy = Vec::new();
+        let mut result_prefill_energy = None;
+        let mut result_decode_energy = None;
+        let mut result_prefill_energy_saved = None;
         let mut result_token_energy_consumptions = Vec::new();
+        let mut result_energy_mismatch = false;
 
         let mut stream = Box::pin(stream);
 
@@ -320,15 +1329,22 @@ impl Infer {
         while let Some(response) = stream.next().await {
             match response? {
                 // Add prefill tokens
-                InferStreamResponse::Prefill(prefill_tokens) => {
-                    result_prefill = prefill_tokens;
+                InferStreamResponse::Prefill { tokens, .. } => {
+                    result_prefill = tokens;
                 }
                 // Push last token
-                InferStreamResponse::Intermediate { token, top_tokens, energy_consumption } => {
+                InferStreamResponse::Intermediate {
+                    token,
+                    top_tokens,
+                    top_logprobs,
+                    energy_consumption,
+                    ..
+                } => {
                     let mut token = token;
                     token.energy_consumption = energy_consumption;
                     result_tokens.push(token);
-                    result_top_tokens.push(top_tokens);
+                    collect_top_tokens(&mut result_top_tokens, top_tokens, use_top_tokens);
+                    collect_top_tokens(&mut result_top_logprobs, top_logprobs, use_top_logprobs);
                     result_token_energy_consumptions.push(energy_consumption);
                 }
                 // Final message
@@ -339,18 +1355,41 @@ impl Infer {
                     start,
                     queued,
                     top_tokens,
+                    top_logprobs,
                     energy_consumption,
+                    prefill_energy,
+                    decode_energy,
+                    prefill_energy_saved,
+                    ..
                 } => {
                     result_tokens.push(token);
-                    result_top_tokens.push(top_tokens);
-                    result_generated_text = Some(generated_text);
+                    collect_top_tokens(&mut result_top_tokens, top_tokens, use_top_tokens);
+                    collect_top_tokens(&mut result_top_logprobs, top_logprobs, use_top_logprobs);
                     result_start = Some(start);
                     result_queued = Some(queued);
-                    let energy_end = device.total_energy_consumption()
-                        .map_err(|e| InferError::GenerationError(e.to_string()))?;
-                    println!("energy_end: {:?}", energy_end);
-                    result_energy_consumption = Some(energy_end - energy_start);
+                    let devices_end = read_device_energies(energy_meter).await;
+                    result_per_device_energy = per_device_energy(
+                        self.energy_devices(),
+                        devices_start.as_deref(),
+                        devices_end.as_deref(),
+                    );
+                    result_energy_consumption = energy_consumption;
+                    result_tokens_per_joule = tokens_per_joule(
+                        generated_text.generated_tokens,
+                        result_energy_consumption,
+                    );
+                    if let Some(efficiency) = result_tokens_per_joule {
+                        metrics::histogram!("tgi_tokens_per_joule").record(efficiency);
+                    }
+                    result_prefill_energy = prefill_energy;
+                    result_decode_energy = decode_energy;
+                    result_prefill_energy_saved = prefill_energy_saved;
+                    // Only the intermediate tokens so far, the last energy is the request total
+                    result_energy_mismatch = energy_consumption.is_some_and(|total| {
+                        reconcile_energy(&result_token_energy_consumptions, total)
+                    });
                     result_token_energy_consumptions.push(energy_consumption);
+                    result_generated_text = Some(generated_text);
                 }
             }
         }
@@ -366,13 +1405,22 @@ impl Infer {
                 generated_text,
                 queued,
                 start,
-                top_tokens: if use_top_tokens {
-                    result_top_tokens
-                } else {
-                    Vec::new()
-                },
+                queue_time_ms: start.saturating_duration_since(queued).as_millis() as u64,
+                inference_time_ms: start.elapsed().as_millis() as u64,
+                top_tokens: result_top_tokens,
+                top_logprobs: result_top_logprobs,
                 energy_consumption: result_energy_consumption,
+                prefill_energy: result_prefill_energy,
+                decode_energy: result_decode_energy,
+                prefill_energy_saved: result_prefill_energy_saved,
+                batch_energy_consumption: None,
+                wasted_energy: None,
+                tokens_per_joule: result_tokens_per_joule,
+                co2_grams: co2_grams(result_energy_consumption, self.carbon_intensity_g_per_kwh),
+                per_device_energy: result_per_device_energy,
+                token_energy_stats: token_energy_stats(&result_token_energy_consumptions),
                 token_energy_consumptions: result_token_energy_consumptions,
+                energy_mismatch: result_energy_mismatch,
             })
         } else {
             let err = InferError::IncompleteGeneration;
@@ -381,6 +1429,51 @@ impl Infer {
             Err(err)
         }
     }
+    /// Add n new requests to the queue and return all their responses as equal choices
+    ///
+    /// Each response holds its share of the energy of the batch in `energy_consumption` and the
+    /// energy of the whole batch in `batch_energy_consumption`.
+    #[instrument(skip(self, request))]
+    pub(crate) async fn generate_n(
+        &self,
+        request: GenerateRequest,
+        n: usize,
+    ) -> Result<Vec<InferResponse>, InferError> {
+        let n = self.validation.validate_n(n)?;
+        let (mut infer_responses, batch_energy) = self.generate_batch(request, n).await?;
+        for response in infer_responses.iter_mut() {
+            response.batch_energy_consumption = batch_energy;
+        }
+        Ok(infer_responses)
+    }
+
+    /// Generate `n` sequences of the same request concurrently and return them along with the
+    /// energy of the whole batch
+    async fn generate_batch(
+        &self,
+        request: GenerateRequest,
+        n: usize,
+    ) -> Result<(Vec<InferResponse>, Option<u64>), InferError> {
+        // The sequences run at the same time on the same devices, their energy is measured once
+        let energy_meter = self
+            .energy_meter
+            .as_ref()
+            .filter(|_| request.parameters.measure_energy);
+        let energy_start = read_energy(energy_meter).await;
+
+        let mut infer_responses: Vec<InferResponse> =
+            try_join_all((0..n).map(|_| self.generate(request.clone()))).await?;
+
+        let batch_energy = energy_delta(energy_start, read_energy(energy_meter).await);
+        tracing::debug!(n, energy_mj = ?batch_energy, "Batch energy");
+        share_batch_energy(&mut infer_responses, batch_energy);
+        for response in infer_responses.iter_mut() {
+            response.co2_grams =
+                co2_grams(response.energy_consumption, self.carbon_intensity_g_per_kwh);
+        }
+        Ok((infer_responses, batch_energy))
+    }
+
     /// Add best_of new requests to the queue and return a InferResponse of the sequence with
     /// the highest log probability per token
     #[instrument(skip(self, request))]
@@ -391,10 +1484,7 @@ impl Infer {
     ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
         // validate  best_of parameter separately
         let best_of = self.validation.validate_best_of(best_of)?;
-
-        // create multiple generate requests
-        let mut infer_responses: Vec<InferResponse> =
-            try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await?;
+        let (mut infer_responses, batch_energy) = self.generate_batch(request, best_of).await?;
 
         // get the sequence with the highest log probability per token
         let mut max_index = 0;
@@ -415,51 +1505,305 @@ impl Infer {
                 max_logprob = sequence_logprob;
             }
         }
-        let best_response = infer_responses.remove(max_index);
+        let mut best_response = infer_responses.remove(max_index);
+        best_response.batch_energy_consumption = batch_energy;
+        // The energy spent on the discarded sequences, `None` when one of them is not measured
+        best_response.wasted_energy = infer_responses
+            .iter()
+            .map(|response| response.energy_consumption)
+            .sum();
         Ok((best_response, infer_responses))
     }
 
+    /// Record the length of a finished generation and return whether it is
+    /// abnormally long compared to the recent ones
+    pub(crate) fn observe_output_length(&self, generated_tokens: u32) -> bool {
+        let long_output = self
+            .output_length_monitor
+            .as_ref()
+            .is_some_and(|monitor| monitor.observe(generated_tokens));
+        if long_output {
+            metrics::counter!("tgi_request_long_output").increment(1);
+            tracing::warn!("Generated {generated_tokens} tokens, far above the rolling average");
+        }
+        long_output
+    }
+
+    /// Health of the backend and of the energy measurement
+    ///
+    /// Both are reported separately: requests are still served when the energy counters stop
+    /// answering, and the counters may answer while the model is down.
     #[instrument(skip(self))]
-    pub(crate) async fn health(&self) -> bool {
-        let health = self
+    pub(crate) async fn health(&self) -> HealthResponse {
+        let backend = self
             .backend
             .health(self.backend_health.load(Ordering::SeqCst))
             .await;
-        self.backend_health.store(health, Ordering::SeqCst);
-        health
+        self.backend_health.store(backend, Ordering::SeqCst);
+        let energy = match &self.energy_meter {
+            Some(energy_meter) => match energy_meter.read_millijoules() {
+                Ok(_) => EnergyHealth::Healthy,
+                Err(err) => {
+                    tracing::warn!("Energy counters are not responding: {err}");
+                    EnergyHealth::Degraded
+                }
+            },
+            None => EnergyHealth::Disabled,
+        };
+        HealthResponse { backend, energy }
     }
 }
 
-#[derive(Debug)]
-pub struct GeneratedText {
-    pub text: String,
-    pub generated_tokens: u32,
-    pub finish_reason: FinishReason,
-    pub seed: Option<u64>,
+/// Maximum relative difference tolerated between the token energies and the request total
+const ENERGY_RECONCILIATION_TOLERANCE: f64 = 0.1;
+
+/// Check that the energy attributed to the intermediate tokens matches the energy measured
+/// around the whole request. Returns true when both diverge beyond the tolerance.
+///
+/// `intermediate_energies` are the cumulative energies of the tokens before the last one, the
+/// last token is the one reporting `total`. Nothing is compared when none of them was read.
+fn reconcile_energy(intermediate_energies: &[Option<u64>], total: u64) -> bool {
+    let Some(tokens_sum) = token_energy_stats(intermediate_energies).map(|stats| stats.sum) else {
+        return false;
+    };
+    let error = if total == 0 {
+        if tokens_sum == 0 {
+            0.0
+        } else {
+            f64::INFINITY
+        }
+    } else {
+        (tokens_sum as f64 - total as f64).abs() / total as f64
+    };
+    metrics::gauge!("tgi_energy_reconciliation_error").set(error);
+
+    let diverged = error > ENERGY_RECONCILIATION_TOLERANCE;
+    if diverged {
+        tracing::warn!(
+            "Energy accounting mismatch: tokens sum to {tokens_sum} mJ but the request consumed {total} mJ"
+        );
+    }
+    diverged
 }
 
-#[derive(Debug)]
-pub enum InferStreamResponse {
-    // Optional first message
-    Prefill(Vec<PrefillToken>),
-    // Intermediate messages
-    Intermediate {
-        token: Token,
-        top_tokens: Vec<Token>,
-        energy_consumption: Option<u64>,
-    },
-    // Last message
-    End {
-        token: Token,
-        top_tokens: Vec<Token>,
-        generated_text: GeneratedText,
-        start: Instant,
-        queued: Instant,
+/// Keep the top tokens of a generated token only when the request asked for them,
+/// so that `result_top_tokens` never allocates otherwise
+fn collect_top_tokens<T>(
+    result_top_tokens: &mut Vec<Vec<T>>,
+    top_tokens: Vec<T>,
+    use_top_tokens: bool,
+) {
+    if use_top_tokens {
+        result_top_tokens.push(top_tokens);
+    }
+}
+
+/// Attribute the energy of a generation step to the top tokens it produced
+///
+/// Top tokens are only returned when the request sets `top_n_tokens`, so this is a no-op otherwise.
+/// Seed of a continuation round
+///
+/// The backends do not expose their RNG state, so a continuation cannot pick up where the
+/// previous round stopped. Reusing the seed of the request would replay the random draws of the
+/// first round on every continuation, each round gets its own seed derived from the request seed
+/// instead. The generation stays reproducible for a given seed, but differs from a single round
+/// generating the same number of tokens. The seed reported in the response is the request seed.
+fn continuation_seed(seed: u64, round: u32) -> u64 {
+    seed.wrapping_add(round as u64)
+}
+
+/// Set the byte range of `token` in the generated text, which is `cursor` bytes long so far, and
+/// move the cursor past the token
+fn set_offsets(token: &mut Token, cursor: &mut usize) {
+    let len = if token.special { 0 } else { token.text.len() };
+    token.offsets = Some(TokenOffsets {
+        start: *cursor,
+        stop: *cursor + len,
+    });
+    *cursor += len;
+}
+
+/// Set the limit a generation stopped at, if it stopped on its length
+fn with_length_limit(
+    mut generated_text: GeneratedText,
+    length_limit: LengthLimit,
+) -> GeneratedText {
+    if matches!(generated_text.finish_reason, FinishReason::Length) {
+        generated_text.length_limit = Some(length_limit);
+    }
+    generated_text
+}
+
+/// Set the time since `previous`, the time of the previous token, and move it to now
+fn set_inter_token_latency(token: &mut Token, previous: &mut Instant) {
+    let now = Instant::now();
+    token.inter_token_latency_ms = Some(now.duration_since(*previous).as_secs_f64() * 1000.0);
+    *previous = now;
+}
+
+/// Last response of a generation, before its energy is added by [`Infer::finish_stream`]
+struct StreamEnd {
+    token: Token,
+    top_tokens: Vec<Token>,
+    top_logprobs: Vec<(u32, f32)>,
+    generated_text: GeneratedText,
+    start: Instant,
+    queued: Instant,
+    seq: Option<u32>,
+}
+
+/// Generated text of a generation ended by the router rather than by the backend
+///
+/// It is the text of the previous rounds, if any, followed by `round_text`. `seed` is only set
+/// for sampled requests, like the backends do.
+fn router_end_text(
+    previous_rounds: Option<GeneratedText>,
+    round_text: &str,
+    generated_tokens: u32,
+    finish_reason: FinishReason,
+    seed: Option<u64>,
+) -> GeneratedText {
+    let mut generated_text = previous_rounds.unwrap_or(GeneratedText {
+        text: String::new(),
+        generated_tokens: 0,
+        finish_reason: finish_reason.clone(),
+        seed,
+        length_limit: None,
+    });
+    generated_text.text.push_str(round_text);
+    generated_text.generated_tokens = generated_tokens;
+    generated_text.finish_reason = finish_reason;
+    generated_text
+}
+
+/// Last token of a generation stopped by its time limit, no token was generated in time
+fn time_limit_token() -> Token {
+    Token {
+        id: 0,
+        text: String::new(),
+        logprob: f32::NAN,
+        special: true,
+        energy_consumption: None,
+        telemetry: None,
+        continuation_boundary: false,
+        step_energy: None,
+        offsets: None,
+        inter_token_latency_ms: None,
+    }
+}
+
+/// Limit a continuation round to the tokens left in the budget of the request
+///
+/// The budget comes from the first validation. A continued request is validated again with a
+/// longer prompt, which gives it a budget of its own that ignores the tokens already generated.
+fn limit_to_remaining_tokens(request: &mut ValidGenerateRequest, remaining_tokens: u32) {
+    let stopping_parameters = &mut request.stopping_parameters;
+    stopping_parameters.max_new_tokens = stopping_parameters.max_new_tokens.min(remaining_tokens);
+    stopping_parameters.max_total_new_tokens = remaining_tokens;
+}
+
+/// Split the text of a round into its complete characters and the character cut at its end
+///
+/// The bytes of a character can be spread over several tokens. When the round stops in the middle
+/// of one, the decoder renders the bytes generated so far as replacement characters. They are
+/// left out of the continued prompt, so that the next round generates the character in full
+/// instead of building on the replacement characters.
+fn split_incomplete_tail(text: &str) -> (&str, &str) {
+    let complete = text.trim_end_matches(char::REPLACEMENT_CHARACTER);
+    text.split_at(complete.len())
+}
+
+fn set_step_energy(top_tokens: &mut [Token], step_energy: Option<u64>) {
+    for top_token in top_tokens {
+        top_token.step_energy = step_energy;
+    }
+}
+
+/// Split the energy of concurrent sequences evenly between them
+///
+/// Every sequence of a `best_of` request measures the energy of the whole device over its own
+/// window, which includes the energy of the other sequences. Summing them would count the same
+/// energy several times, so each sequence is attributed an equal share of the batch energy.
+fn share_batch_energy(responses: &mut [InferResponse], batch_energy: Option<u64>) {
+    let sequences = responses.len().max(1) as u64;
+    let share = |energy: Option<u64>| energy.map(|energy| energy / sequences);
+    for response in responses {
+        response.energy_consumption = share(batch_energy);
+        response.tokens_per_joule = tokens_per_joule(
+            response.generated_text.generated_tokens,
+            response.energy_consumption,
+        );
+        response.prefill_energy = share(response.prefill_energy);
+        response.decode_energy = share(response.decode_energy);
+        for token in response.tokens.iter_mut() {
+            token.energy_consumption = share(token.energy_consumption);
+        }
+        for energy in response.token_energy_consumptions.iter_mut() {
+            *energy = share(*energy);
+        }
+        response.token_energy_stats = token_energy_stats(&response.token_energy_consumptions);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GeneratedText {
+    pub text: String,
+    pub generated_tokens: u32,
+    pub finish_reason: FinishReason,
+    pub seed: Option<u64>,
+    /// Limit the generation stopped at, set by the router on the `length` finish reason
+    pub length_limit: Option<LengthLimit>,
+}
+
+#[derive(Debug)]
+pub enum InferStreamResponse {
+    // Optional first message
+    Prefill {
+        tokens: Vec<PrefillToken>,
+        /// Energy consumed until the prefill is done, set by the router
+        prefill_energy_mj: Option<u64>,
+    },
+    // Intermediate messages
+    Intermediate {
+        token: Token,
+        top_tokens: Vec<Token>,
+        /// Ids and logprobs of the `top_logprobs` most likely tokens of the step
+        top_logprobs: Vec<(u32, f32)>,
         energy_consumption: Option<u64>,
+        /// Position of the token in the backend stream, if the backend tracks it
+        seq: Option<u32>,
+    },
+    // Last message
+    End {
+        token: Token,
+        top_tokens: Vec<Token>,
+        top_logprobs: Vec<(u32, f32)>,
+        generated_text: GeneratedText,
+        start: Instant,
+        queued: Instant,
+        energy_consumption: Option<u64>,
+        /// Energy consumed until the first token, set by the router
+        prefill_energy: Option<u64>,
+        /// Energy consumed after the first token, set by the router
+        decode_energy: Option<u64>,
+        /// Estimated prefill energy of the cached prefix, set by the router on prefix cache hits
+        prefill_energy_saved: Option<u64>,
+        seq: Option<u32>,
     },
 }
 
-#[derive(Debug)]
+impl InferStreamResponse {
+    /// Sequence number set by the backend, `None` for prefill and unsequenced backends
+    pub(crate) fn seq(&self) -> Option<u32> {
+        match self {
+            InferStreamResponse::Prefill { .. } => None,
+            InferStreamResponse::Intermediate { seq, .. }
+            | InferStreamResponse::End { seq, .. } => *seq,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub(crate) struct InferResponse {
     /// input_length is the input as perceived by the rust tokenizer in the
     /// validation pathway. It is redundant with prefill.len() but prefill
@@ -470,9 +1814,36 @@ pub(crate) struct InferResponse {
     pub(crate) generated_text: GeneratedText,
     pub(crate) queued: Instant,
     pub(crate) start: Instant,
+    /// Time spent waiting in the queue, from `queued` to `start`
+    pub(crate) queue_time_ms: u64,
+    /// Time spent generating, from `start` to the last token
+    pub(crate) inference_time_ms: u64,
     pub(crate) top_tokens: Vec<Vec<Token>>,
+    /// Ids and logprobs of the most likely tokens of each step, when `top_logprobs` is set
+    pub(crate) top_logprobs: Vec<Vec<(u32, f32)>>,
     pub(crate) energy_consumption: Option<u64>,
+    pub(crate) prefill_energy: Option<u64>,
+    pub(crate) decode_energy: Option<u64>,
+    /// Estimated prefill energy of the cached prefix of the input
+    pub(crate) prefill_energy_saved: Option<u64>,
+    /// Energy of all the sequences of a `best_of` or `n` request, measured once
+    pub(crate) batch_energy_consumption: Option<u64>,
+    /// Energy of the `best_of` sequences that were not returned, set on the best one
+    pub(crate) wasted_energy: Option<u64>,
+    /// Generated tokens per joule of `energy_consumption`
+    pub(crate) tokens_per_joule: Option<f64>,
+    /// Estimated emissions of `energy_consumption`, in grams of CO2
+    pub(crate) co2_grams: Option<f64>,
+    /// Energy consumed by each device, before subtracting the idle energy, only when the energy
+    /// is measured on several devices
+    pub(crate) per_device_energy: Vec<(u32, u64)>,
+    /// Summary of the energies of the generation steps
+    pub(crate) token_energy_stats: Option<TokenEnergyStats>,
+    /// Energy consumed since the start of the generation when each token was produced
     pub(crate) token_energy_consumptions: Vec<Option<u64>>,
+    /// Whether the energies of the tokens diverge from the energy of the request, a sign of a
+    /// measurement gap
+    pub(crate) energy_mismatch: bool,
 }
 
 #[derive(Debug, Error)]
@@ -489,14 +1860,28 @@ pub enum InferError {
     IncompleteGenerationStream,
     #[error("Template error: {0}")]
     TemplateError(#[from] minijinja::Error),
-    #[error("Missing template vatiable: {0}")]
-    MissingTemplateVariable(String),
+    #[error("Missing template variable: {0}")]
+    MissingTemplateVariable(String, #[source] minijinja::Error),
     #[error("Tool error: {0}")]
     ToolError(String),
+    #[error("Invalid schema for tool `{tool_name}`: {reason}")]
+    InvalidToolSchema { tool_name: String, reason: String },
     #[error("Stream event serialization error")]
     StreamSerializationError(String),
     #[error("Energy consumption error: {0}")]
     EnergyConsumptionError(String),
+    #[error("Token sequence error: {0}")]
+    TokenSequenceError(String),
+    #[error("Client did not read the response fast enough")]
+    SlowClient,
+    #[error("Unknown API key")]
+    UnknownApiKey,
+    /// Error of a generation that consumed energy before failing
+    #[error("{source}")]
+    WithEnergy {
+        source: Box<InferError>,
+        energy_consumption: u64,
+    },
 }
 
 impl InferError {
@@ -508,10 +1893,70 @@ impl InferError {
             InferError::IncompleteGeneration => "incomplete_generation",
             InferError::IncompleteGenerationStream => "incomplete_generation_stream",
             InferError::TemplateError(_) => "template_error",
-            InferError::MissingTemplateVariable(_) => "missing_template_variable",
+            InferError::MissingTemplateVariable(..) => "missing_template_variable",
             InferError::ToolError(_) => "tool_error",
+            InferError::InvalidToolSchema { .. } => "invalid_tool_schema",
             InferError::StreamSerializationError(_) => "stream_serialization_error",
             InferError::EnergyConsumptionError(_) => "energy_consumption_error",
+            InferError::TokenSequenceError(_) => "token_sequence_error",
+            InferError::SlowClient => "slow_client",
+            InferError::UnknownApiKey => "unknown_api_key",
+            InferError::WithEnergy { source, .. } => source.error_type(),
+        }
+    }
+
+    /// HTTP status code of the error, shared by the responses and the streamed error events
+    pub(crate) fn status_code(&self) -> u16 {
+        let status_code = match self {
+            InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
+            InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::IncompleteGenerationStream => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::MissingTemplateVariable(..) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::ToolError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::InvalidToolSchema { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::StreamSerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::EnergyConsumptionError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::TokenSequenceError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::SlowClient => StatusCode::REQUEST_TIMEOUT,
+            InferError::UnknownApiKey => StatusCode::UNAUTHORIZED,
+            InferError::WithEnergy { source, .. } => return source.status_code(),
+        };
+        status_code.as_u16()
+    }
+
+    /// Location and cause of a chat template failure, `None` for the other errors
+    pub(crate) fn template_error_detail(&self) -> Option<TemplateErrorDetail> {
+        match self {
+            InferError::TemplateError(err) => Some(template_error_detail(err, None)),
+            InferError::MissingTemplateVariable(variable, err) => {
+                Some(template_error_detail(err, Some(variable)))
+            }
+            InferError::WithEnergy { source, .. } => source.template_error_detail(),
+            _ => None,
+        }
+    }
+
+    /// Attach the energy consumed by the generation before it failed
+    pub(crate) fn with_energy(self, energy_consumption: Option<u64>) -> Self {
+        match (self, energy_consumption) {
+            (err @ InferError::WithEnergy { .. }, _) | (err, None) => err,
+            (err, Some(energy_consumption)) => InferError::WithEnergy {
+                source: Box::new(err),
+                energy_consumption,
+            },
+        }
+    }
+
+    /// Energy consumed by the generation before it failed, in millijoules
+    pub(crate) fn energy_consumption(&self) -> Option<u64> {
+        match self {
+            InferError::WithEnergy {
+                energy_consumption, ..
+            } => Some(*energy_consumption),
+            _ => None,
         }
     }
 
@@ -520,7 +1965,7 @@ impl InferError {
             .json_data(OpenaiErrorEvent {
                 error: APIError {
                     message: self.to_string(),
-                    http_status_code: 422,
+                    http_status_code: self.status_code(),
                 },
             })
             .unwrap()
@@ -530,10 +1975,2083 @@ impl InferError {
 #[derive(Serialize)]
 pub struct APIError {
     message: String,
-    http_status_code: usize,
+    http_status_code: u16,
 }
 
 #[derive(Serialize)]
 pub struct OpenaiErrorEvent {
     error: APIError,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::energy::EnergyMeter;
+    use super::*;
+    use crate::validation::ChunksToString;
+    use crate::{GenerateParameters, GrammarType, HubTokenizerConfig, Tokenizer};
+    use std::sync::atomic::{AtomicU32, AtomicU64};
+    use std::time::Duration;
+
+    const TEST_TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": {"[UNK]": 0, "hello": 1, "world": 2}, "unk_token": "[UNK]"}
+    }"#;
+
+    fn token(id: u32) -> Token {
+        Token {
+            id,
+            text: format!("t{id}"),
+            logprob: -1.0,
+            special: false,
+            energy_consumption: None,
+            telemetry: None,
+            continuation_boundary: false,
+            step_energy: None,
+            offsets: None,
+            inter_token_latency_ms: None,
+        }
+    }
+
+    /// Time every request of the mock backend waited in the queue
+    const QUEUE_TIME: Duration = Duration::from_millis(50);
+
+    /// Backend generating the same number of tokens for every scheduled request
+    ///
+    /// The first `segments - 1` requests stop on the length limit so that the router continues
+    /// the generation.
+    pub(crate) struct MockBackend {
+        tokens: u32,
+        segments: u32,
+        scheduled: AtomicU32,
+        cancellations: Arc<std::sync::Mutex<Vec<CancellationToken>>>,
+        /// Seed of every scheduled request
+        seeds: Arc<std::sync::Mutex<Vec<u64>>>,
+        /// Temperature of every scheduled request
+        temperatures: Arc<std::sync::Mutex<Vec<f32>>>,
+        /// Optional features reported by `supports`
+        features: Vec<BackendFeature>,
+    }
+
+    impl MockBackend {
+        pub(crate) fn new(tokens: u32, segments: u32) -> Self {
+            Self {
+                tokens,
+                segments,
+                scheduled: AtomicU32::new(0),
+                cancellations: Default::default(),
+                seeds: Default::default(),
+                temperatures: Default::default(),
+                features: Vec::new(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Backend for MockBackend {
+        fn schedule(
+            &self,
+            request: ValidGenerateRequest,
+            cancellation: CancellationToken,
+        ) -> Result<GenerationStream, InferError> {
+            self.cancellations.lock().unwrap().push(cancellation);
+            self.seeds.lock().unwrap().push(request.parameters.seed);
+            self.temperatures
+                .lock()
+                .unwrap()
+                .push(request.parameters.temperature);
+            let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER_SIZE);
+            let (top_n_tokens, top_logprobs) = (request.top_n_tokens, request.top_logprobs);
+            let top_tokens = move || (0..top_n_tokens).map(token).collect::<Vec<_>>();
+            let top_logprobs = move || {
+                (0..top_logprobs)
+                    .map(|id| (id, -(id as f32)))
+                    .collect::<Vec<_>>()
+            };
+            // Rounds stop at the token limit of the request, like the real backends
+            let tokens = self.tokens.min(request.stopping_parameters.max_new_tokens);
+            let last_segment = self.scheduled.fetch_add(1, Ordering::SeqCst) + 1 >= self.segments;
+            let finish_reason = if tokens < self.tokens || !last_segment {
+                FinishReason::Length
+            } else {
+                FinishReason::EndOfSequenceToken
+            };
+            let decoder_input_details = request.decoder_input_details;
+            tokio::spawn(async move {
+                if decoder_input_details {
+                    let _ = sender
+                        .send(Ok(InferStreamResponse::Prefill {
+                            tokens: vec![PrefillToken {
+                                id: 1,
+                                text: "hello".to_string(),
+                                logprob: f32::NAN,
+                            }],
+                            prefill_energy_mj: None,
+                        }))
+                        .await;
+                }
+                for id in 0..tokens - 1 {
+                    let _ = sender
+                        .send(Ok(InferStreamResponse::Intermediate {
+                            token: token(id),
+                            top_tokens: top_tokens(),
+                            top_logprobs: top_logprobs(),
+                            energy_consumption: None,
+                            seq: None,
+                        }))
+                        .await;
+                }
+                let start = Instant::now();
+                let _ = sender
+                    .send(Ok(InferStreamResponse::End {
+                        token: token(tokens - 1),
+                        top_tokens: top_tokens(),
+                        top_logprobs: top_logprobs(),
+                        generated_text: GeneratedText {
+                            text: "mock".to_string(),
+                            generated_tokens: tokens,
+                            finish_reason,
+                            seed: None,
+                            length_limit: None,
+                        },
+                        start,
+                        queued: start - QUEUE_TIME,
+                        energy_consumption: None,
+                        prefill_energy: None,
+                        decode_energy: None,
+                        prefill_energy_saved: None,
+                        seq: None,
+                    }))
+                    .await;
+            });
+            Ok(ReceiverStream::new(receiver))
+        }
+
+        async fn health(&self, current_health: bool) -> bool {
+            current_health
+        }
+
+        fn start_health(&self) -> bool {
+            true
+        }
+
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn supports(&self, feature: BackendFeature) -> bool {
+            self.features.contains(&feature)
+        }
+    }
+
+    /// Backend whose streams fail before the first token, or end without an `End` message
+    struct BrokenBackend {
+        error: bool,
+    }
+
+    #[async_trait]
+    impl Backend for BrokenBackend {
+        fn schedule(
+            &self,
+            _request: ValidGenerateRequest,
+            _cancellation: CancellationToken,
+        ) -> Result<GenerationStream, InferError> {
+            let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER_SIZE);
+            if self.error {
+                let _ = sender.try_send(Err(InferError::GenerationError("CUDA OOM".to_string())));
+            } else {
+                let _ = sender.try_send(Ok(InferStreamResponse::Intermediate {
+                    token: token(0),
+                    top_tokens: vec![],
+                    top_logprobs: vec![],
+                    energy_consumption: None,
+                    seq: None,
+                }));
+            }
+            Ok(ReceiverStream::new(receiver))
+        }
+
+        async fn health(&self, current_health: bool) -> bool {
+            current_health
+        }
+
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+    }
+
+    /// Backend generating a single token and then hanging until the generation is cancelled
+    struct StalledBackend;
+
+    #[async_trait]
+    impl Backend for StalledBackend {
+        fn schedule(
+            &self,
+            _request: ValidGenerateRequest,
+            cancellation: CancellationToken,
+        ) -> Result<GenerationStream, InferError> {
+            let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER_SIZE);
+            let _ = sender.try_send(Ok(InferStreamResponse::Intermediate {
+                token: token(0),
+                top_tokens: vec![],
+                top_logprobs: vec![],
+                energy_consumption: None,
+                seq: None,
+            }));
+            tokio::spawn(async move {
+                cancellation.cancelled().await;
+                drop(sender);
+            });
+            Ok(ReceiverStream::new(receiver))
+        }
+
+        async fn health(&self, current_health: bool) -> bool {
+            current_health
+        }
+
+        fn name(&self) -> &'static str {
+            "stalled"
+        }
+    }
+
+    /// Backend generating `tokens` tokens, one every `interval`
+    struct PacedBackend {
+        tokens: u32,
+        interval: Duration,
+    }
+
+    #[async_trait]
+    impl Backend for PacedBackend {
+        fn schedule(
+            &self,
+            _request: ValidGenerateRequest,
+            _cancellation: CancellationToken,
+        ) -> Result<GenerationStream, InferError> {
+            let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER_SIZE);
+            let (tokens, interval) = (self.tokens, self.interval);
+            tokio::spawn(async move {
+                for id in 0..tokens - 1 {
+                    tokio::time::sleep(interval).await;
+                    let _ = sender
+                        .send(Ok(InferStreamResponse::Intermediate {
+                            token: token(id),
+                            top_tokens: vec![],
+                            top_logprobs: vec![],
+                            energy_consumption: None,
+                            seq: None,
+                        }))
+                        .await;
+                }
+                tokio::time::sleep(interval).await;
+                let start = Instant::now();
+                let _ = sender
+                    .send(Ok(InferStreamResponse::End {
+                        token: token(tokens - 1),
+                        top_tokens: vec![],
+                        top_logprobs: vec![],
+                        generated_text: GeneratedText {
+                            text: "paced".to_string(),
+                            generated_tokens: tokens,
+                            finish_reason: FinishReason::EndOfSequenceToken,
+                            seed: None,
+                            length_limit: None,
+                        },
+                        start,
+                        queued: start,
+                        energy_consumption: None,
+                        prefill_energy: None,
+                        decode_energy: None,
+                        prefill_energy_saved: None,
+                        seq: None,
+                    }))
+                    .await;
+            });
+            Ok(ReceiverStream::new(receiver))
+        }
+
+        async fn health(&self, current_health: bool) -> bool {
+            current_health
+        }
+
+        fn name(&self) -> &'static str {
+            "paced"
+        }
+    }
+
+    /// Meter whose counter grows by 10mJ on every read
+    struct MockMeter {
+        devices: u32,
+        energy: AtomicU64,
+    }
+
+    pub(crate) fn mock_meter(devices: u32) -> Arc<dyn EnergyMeter> {
+        Arc::new(MockMeter {
+            devices,
+            energy: AtomicU64::new(0),
+        })
+    }
+
+    impl EnergyMeter for MockMeter {
+        fn device_count(&self) -> Result<u32, InferError> {
+            Ok(self.devices)
+        }
+
+        fn total_energy_consumption(&self, device_index: u32) -> Result<u64, InferError> {
+            if device_index >= self.devices {
+                return Err(InferError::EnergyConsumptionError(format!(
+                    "no device at index {device_index}"
+                )));
+            }
+            Ok(self.energy.fetch_add(10, Ordering::SeqCst) + 10)
+        }
+
+        fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+            Ok(150_000)
+        }
+    }
+
+    /// Meter whose counter grows by 10mJ on every read, and by 1000mJ more from its
+    /// `spike_at`th read on
+    struct SpikeMeter {
+        readings: AtomicU64,
+        spike_at: u64,
+    }
+
+    impl EnergyMeter for SpikeMeter {
+        fn device_count(&self) -> Result<u32, InferError> {
+            Ok(1)
+        }
+
+        fn total_energy_consumption(&self, _device_index: u32) -> Result<u64, InferError> {
+            let reading = self.readings.fetch_add(1, Ordering::SeqCst) + 1;
+            let spike = if reading >= self.spike_at { 1000 } else { 0 };
+            Ok(reading * 10 + spike)
+        }
+
+        fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+            Ok(150_000)
+        }
+
+        // Not probed with a reading, so that the readings are only the ones of the requests
+        fn energy_counter_supported(&self, _device_index: u32) -> bool {
+            true
+        }
+    }
+
+    /// Meter whose next `failures` readings fail
+    struct StutteringMeter {
+        failures: AtomicU32,
+        energy: AtomicU64,
+    }
+
+    impl EnergyMeter for StutteringMeter {
+        fn device_count(&self) -> Result<u32, InferError> {
+            Ok(1)
+        }
+
+        fn total_energy_consumption(&self, _device_index: u32) -> Result<u64, InferError> {
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |f| f.checked_sub(1))
+                .is_ok()
+            {
+                return Err(InferError::EnergyConsumptionError("Timeout".to_string()));
+            }
+            Ok(self.energy.fetch_add(10, Ordering::SeqCst) + 10)
+        }
+
+        fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+            Ok(150_000)
+        }
+    }
+
+    /// Meter that stops answering once `responsive` is cleared
+    struct FlakyMeter {
+        responsive: AtomicBool,
+    }
+
+    impl EnergyMeter for FlakyMeter {
+        fn device_count(&self) -> Result<u32, InferError> {
+            Ok(1)
+        }
+
+        fn total_energy_consumption(&self, _device_index: u32) -> Result<u64, InferError> {
+            match self.responsive.load(Ordering::SeqCst) {
+                true => Ok(0),
+                false => Err(InferError::EnergyConsumptionError("Timeout".to_string())),
+            }
+        }
+
+        fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+            Ok(150_000)
+        }
+    }
+
+    /// Meter answering its first `readings` readings only
+    struct ExhaustedMeter {
+        readings: AtomicU32,
+    }
+
+    impl EnergyMeter for ExhaustedMeter {
+        fn device_count(&self) -> Result<u32, InferError> {
+            Ok(1)
+        }
+
+        fn total_energy_consumption(&self, _device_index: u32) -> Result<u64, InferError> {
+            self.readings
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |r| r.checked_sub(1))
+                .map(|_| 0)
+                .map_err(|_| InferError::EnergyConsumptionError("Timeout".to_string()))
+        }
+
+        fn power_usage(&self, _device_index: u32) -> Result<u32, InferError> {
+            Ok(150_000)
+        }
+    }
+
+    fn validation() -> Validation {
+        let tokenizer: tokenizers::Tokenizer = TEST_TOKENIZER.parse().unwrap();
+        Validation::new(
+            1,
+            Tokenizer::Rust(tokenizer),
+            None,
+            None,
+            4,
+            4,
+            5,
+            32,
+            64,
+            false,
+        )
+    }
+
+    /// Builder of the [`Infer`] of the tests, with small limits and caches
+    fn builder(backend: MockBackend) -> InferBuilder {
+        Infer::builder(backend, validation())
+            .max_concurrent_requests(4)
+            .idempotency_cache_size(2)
+            .idempotency_ttl(Duration::from_secs(60))
+    }
+
+    pub(crate) fn infer(backend: MockBackend, energy_meter: Option<Arc<dyn EnergyMeter>>) -> Infer {
+        builder(backend).energy_meter(energy_meter).build()
+    }
+
+    fn request() -> GenerateRequest {
+        GenerateRequest {
+            inputs: "hello world".to_string(),
+            parameters: GenerateParameters {
+                max_new_tokens: Some(8),
+                ..crate::default_parameters()
+            },
+            add_special_tokens: true,
+            metadata: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_energy_tracking_disabled_without_devices() {
+        let infer = infer(MockBackend::new(3, 1), Some(mock_meter(0)));
+        assert!(infer.energy_devices().is_empty());
+        assert!(infer.device_telemetry().unwrap().is_empty());
+        assert!(infer.list_devices().unwrap().is_empty());
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.generated_text.text, "mock");
+        assert_eq!(response.tokens.len(), 3);
+        assert!(response.energy_consumption.is_none());
+        assert!(response
+            .token_energy_consumptions
+            .iter()
+            .all(Option::is_none));
+    }
+
+    #[tokio::test]
+    async fn test_power_usage() {
+        let without_meter = infer(MockBackend::new(3, 1), None);
+        assert!(without_meter.current_power_usage().unwrap().is_empty());
+        assert!(without_meter.energy_devices().is_empty());
+
+        let with_meter = builder(MockBackend::new(3, 1))
+            .energy_meter(Some(mock_meter(2)))
+            .energy_devices(vec![0, 1])
+            .build();
+        assert_eq!(
+            with_meter.current_power_usage().unwrap(),
+            vec![150_000, 150_000]
+        );
+        assert_eq!(with_meter.energy_devices(), &[0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_energy_tracked_with_devices() {
+        let infer = infer(MockBackend::new(3, 1), energy_meter);
+        let response = infer.generate(request()).await.unwrap();
+        assert!(response.energy_consumption.is_some());
+        assert!(response.token_energy_consumptions[0].is_some());
+        // The mock counter grows by 10mJ on every read, once per token
+        assert_eq!(response.prefill_energy, Some(10));
+        assert_eq!(response.decode_energy, Some(20));
+        let energy = response.energy_consumption.unwrap() as f64;
+        assert_eq!(response.tokens_per_joule, Some(3.0 / (energy / 1000.0)));
+        // No carbon intensity configured
+        assert_eq!(response.co2_grams, None);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_energy() {
+        // One reading at the start of the request, then one per token
+        let meter = energy::MockEnergyMeter::new(vec![1000, 1040, 1050, 1075]);
+        let infer = infer(MockBackend::new(3, 1), Some(Arc::new(meter)));
+        let response = infer.generate(request()).await.unwrap();
+        // Energy consumed since the start of the request, after each token
+        assert_eq!(
+            response.token_energy_consumptions,
+            [Some(40), Some(50), Some(75)]
+        );
+        assert_eq!(response.energy_consumption, Some(75));
+        assert_eq!(response.prefill_energy, Some(40));
+        assert_eq!(response.decode_energy, Some(35));
+        assert_eq!(response.tokens_per_joule, Some(40.0));
+    }
+
+    #[tokio::test]
+    async fn test_stream_and_generate_energy_match() {
+        let mut seeded_request = request();
+        seeded_request.parameters.seed = Some(42);
+
+        let stream_infer = infer(MockBackend::new(4, 1), Some(mock_meter(1)));
+        let (_permit, _input_length, stream) = stream_infer
+            .generate_stream(seeded_request.clone())
+            .await
+            .unwrap();
+        let mut stream = Box::pin(stream);
+        let mut stream_energy = None;
+        while let Some(response) = stream.next().await {
+            if let InferStreamResponse::End {
+                energy_consumption, ..
+            } = response.unwrap()
+            {
+                stream_energy = energy_consumption;
+            }
+        }
+
+        let generate_infer = infer(MockBackend::new(4, 1), Some(mock_meter(1)));
+        let response = generate_infer.generate(seeded_request).await.unwrap();
+        assert!(stream_energy.is_some());
+        assert_eq!(response.energy_consumption, stream_energy);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_seed_replay() {
+        async fn stream_text(infer: &Infer, request: GenerateRequest) -> (String, Option<u64>) {
+            let (_permit, _input_length, stream) = infer.generate_stream(request).await.unwrap();
+            let mut stream = Box::pin(stream);
+            let mut text = String::new();
+            let mut seed = None;
+            while let Some(response) = stream.next().await {
+                match response.unwrap() {
+                    InferStreamResponse::Intermediate { token, .. } => text.push_str(&token.text),
+                    InferStreamResponse::End {
+                        token,
+                        generated_text,
+                        ..
+                    } => {
+                        text.push_str(&token.text);
+                        seed = generated_text.seed;
+                    }
+                    InferStreamResponse::Prefill { .. } => {}
+                }
+            }
+            (text, seed)
+        }
+
+        let backend = MockBackend::new(3, 1);
+        let seeds = backend.seeds.clone();
+        let infer = infer(backend, None);
+        let mut sampled = request();
+
+        // The mock backend does not report the seed, the resolved one is streamed anyway
+        let (text, seed) = stream_text(&infer, sampled.clone()).await;
+        let seed = seed.expect("the resolved seed is streamed");
+        sampled.parameters.seed = Some(seed);
+        let (replayed_text, replayed_seed) = stream_text(&infer, sampled).await;
+        assert_eq!(replayed_seed, Some(seed));
+        assert_eq!(replayed_text, text);
+        assert_eq!(*seeds.lock().unwrap(), vec![seed, seed]);
+
+        // Greedy generations do not depend on a seed
+        let mut greedy = request();
+        greedy.parameters.do_sample = false;
+        let (_, seed) = stream_text(&infer, greedy).await;
+        assert_eq!(seed, None);
+    }
+
+    #[tokio::test]
+    async fn test_per_device_energy() {
+        let sharded_infer = builder(MockBackend::new(3, 1))
+            .energy_meter(Some(mock_meter(2)))
+            .energy_devices(vec![0, 1])
+            .build();
+        let response = sharded_infer.generate(request()).await.unwrap();
+        // The devices share the mock counter, which grows by 10mJ on every read of any device.
+        // The devices are read around the stream, so they add up to more than its energy.
+        assert_eq!(response.energy_consumption, Some(120));
+        assert_eq!(response.per_device_energy, vec![(0, 100), (1, 100)]);
+
+        let infer = builder(MockBackend::new(3, 1))
+            .energy_meter(Some(mock_meter(2)))
+            .device_index(1)
+            .build();
+        let response = infer.generate(request()).await.unwrap();
+        assert!(response.per_device_energy.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prefill_energy() {
+        let prefill_infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        let mut prefill_request = request();
+        prefill_request.parameters.decoder_input_details = true;
+        let (_permit, _input_length, stream) = prefill_infer
+            .generate_stream(prefill_request)
+            .await
+            .unwrap();
+        let mut stream = Box::pin(stream);
+        let mut prefill_energy_mj = None;
+        let mut end_prefill_energy = None;
+        while let Some(response) = stream.next().await {
+            match response.unwrap() {
+                InferStreamResponse::Prefill {
+                    tokens,
+                    prefill_energy_mj: energy,
+                } => {
+                    assert_eq!(tokens.len(), 1);
+                    prefill_energy_mj = energy;
+                }
+                InferStreamResponse::End { prefill_energy, .. } => {
+                    end_prefill_energy = prefill_energy;
+                }
+                InferStreamResponse::Intermediate { .. } => {}
+            }
+        }
+        // The mock counter grows by 10mJ between the start and the prefill
+        assert_eq!(prefill_energy_mj, Some(10));
+        assert_eq!(end_prefill_energy, prefill_energy_mj);
+    }
+
+    #[tokio::test]
+    async fn test_prefix_cache_energy_saved() {
+        // 20 tokens, the first 16 form a block
+        let mut shared_prefix = request();
+        shared_prefix.inputs = "hello world ".repeat(10);
+
+        // Nothing is saved when the backend prefills the hits in full
+        let mut prefix_infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        prefix_infer.prefix_cache = Some(Arc::new(PrefixCache::new(64)));
+        for _ in 0..2 {
+            let response = prefix_infer.generate(shared_prefix.clone()).await.unwrap();
+            assert_eq!(response.prefill_energy_saved, None);
+        }
+
+        let backend = MockBackend {
+            features: vec![BackendFeature::PrefixCache],
+            ..MockBackend::new(3, 1)
+        };
+        let mut prefix_infer = infer(backend, Some(mock_meter(1)));
+        prefix_infer.prefix_cache = Some(Arc::new(PrefixCache::new(64)));
+
+        // The first request is prefilled in full and gives the energy per token
+        let response = prefix_infer.generate(shared_prefix.clone()).await.unwrap();
+        assert_eq!(response.prefill_energy_saved, None);
+        let prefill_energy = response.prefill_energy.unwrap();
+
+        let response = prefix_infer.generate(shared_prefix).await.unwrap();
+        let expected = (prefill_energy as f64 * 16.0 / 20.0).round() as u64;
+        assert_eq!(response.prefill_energy_saved, Some(expected));
+
+        // Too short to share a block
+        let response = prefix_infer.generate(request()).await.unwrap();
+        assert_eq!(response.prefill_energy_saved, None);
+    }
+
+    #[tokio::test]
+    async fn test_grammar_gated_on_backend_support() {
+        let mut grammar_request = request();
+        grammar_request.parameters.grammar = Some(GrammarType::Regex("[a-z]+".to_string()));
+
+        let unsupported = infer(MockBackend::new(3, 1), None);
+        assert!(matches!(
+            unsupported.generate(grammar_request.clone()).await,
+            Err(InferError::ValidationError(ValidationError::Grammar))
+        ));
+
+        let backend = MockBackend {
+            features: vec![BackendFeature::Grammar],
+            ..MockBackend::new(3, 1)
+        };
+        let supported = infer(backend, None);
+        let response = supported.generate(grammar_request).await.unwrap();
+        assert_eq!(response.tokens.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_energy_separately() {
+        let unmeasured = infer(MockBackend::new(3, 1), None);
+        let health = unmeasured.health().await;
+        assert!(health.backend);
+        assert_eq!(health.energy, EnergyHealth::Disabled);
+
+        let meter = Arc::new(FlakyMeter {
+            responsive: AtomicBool::new(true),
+        });
+        let measured = infer(MockBackend::new(3, 1), Some(meter.clone()));
+        assert_eq!(measured.health().await.energy, EnergyHealth::Healthy);
+
+        // The model is still up when the energy counters stop answering
+        meter.responsive.store(false, Ordering::SeqCst);
+        let health = measured.health().await;
+        assert!(health.backend);
+        assert_eq!(health.energy, EnergyHealth::Degraded);
+    }
+
+    #[tokio::test]
+    async fn test_top_logprobs() {
+        let logprobs_infer = infer(MockBackend::new(3, 1), None);
+        let mut logprobs_request = request();
+        logprobs_request.parameters.top_logprobs = Some(2);
+        let response = logprobs_infer
+            .generate(logprobs_request.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.top_logprobs, vec![vec![(0, 0.0), (1, -1.0)]; 3]);
+        // Independent of the decoded top tokens
+        assert!(response.top_tokens.is_empty());
+
+        logprobs_request.parameters.top_logprobs = Some(6);
+        assert!(matches!(
+            logprobs_infer.generate(logprobs_request).await,
+            Err(InferError::ValidationError(ValidationError::TopLogprobs(
+                5, 6
+            )))
+        ));
+
+        // Nothing is collected unless requested
+        let response = logprobs_infer.generate(request()).await.unwrap();
+        assert!(response.top_logprobs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_top_tokens_step_energy() {
+        let top_n_infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        let mut top_n_request = request();
+        top_n_request.parameters.top_n_tokens = Some(2);
+        let response = top_n_infer.generate(top_n_request).await.unwrap();
+        assert_eq!(response.top_tokens.len(), 3);
+        for top_tokens in &response.top_tokens {
+            assert_eq!(top_tokens.len(), 2);
+            assert!(top_tokens.iter().all(|t| t.step_energy == Some(10)));
+        }
+
+        // Without top_n_tokens no step energy is attributed
+        let infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        let response = infer.generate(request()).await.unwrap();
+        assert!(response.top_tokens.is_empty());
+        assert!(response.tokens.iter().all(|t| t.step_energy.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_energy_sampling_interval() {
+        let meter = Arc::new(MockMeter {
+            devices: 1,
+            energy: AtomicU64::new(0),
+        });
+        let sampled_infer = Infer {
+            energy_sampling_interval: 2,
+            ..infer(MockBackend::new(5, 1), Some(meter.clone()))
+        };
+        // Ignore the reading done to probe the counter
+        meter.energy.store(0, Ordering::SeqCst);
+        let mut sampled_request = request();
+        sampled_request.parameters.top_n_tokens = Some(1);
+        let response = sampled_infer.generate(sampled_request).await.unwrap();
+
+        // The counter is read at the start, on the first token, then every other token and on
+        // the last one
+        assert_eq!(meter.energy.load(Ordering::SeqCst), 40);
+        assert_eq!(response.energy_consumption, Some(30));
+        let step_energies: Vec<_> = response
+            .top_tokens
+            .iter()
+            .map(|top_tokens| top_tokens[0].step_energy)
+            .collect();
+        assert_eq!(step_energies, vec![Some(10), None, Some(5), None, Some(5)]);
+    }
+
+    #[tokio::test]
+    async fn test_subtract_idle() {
+        let meter_infer = || infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        let mut net_request = request();
+        net_request.parameters.subtract_idle = true;
+
+        // Nothing to subtract before the idle power is measured
+        let infer = meter_infer();
+        let response = infer.generate(net_request.clone()).await.unwrap();
+        assert_eq!(response.energy_consumption, Some(30));
+        // The mock counter grows by 10mJ over the window
+        infer.measure_idle_power(Duration::from_millis(20)).await;
+        let idle_power_mw = infer.idle_power_mw().unwrap();
+        assert!(idle_power_mw > 0 && idle_power_mw <= 500);
+
+        // Devices drawing more when idle than what the mock counter reports
+        let infer = Infer {
+            idle_power_mw: Arc::new(OnceLock::from(u32::MAX)),
+            ..meter_infer()
+        };
+        let response = infer.generate(net_request).await.unwrap();
+        assert_eq!(response.energy_consumption, Some(0));
+        assert!(response
+            .token_energy_consumptions
+            .iter()
+            .all(|energy| *energy == Some(0)));
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.energy_consumption, Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_energy_summary() {
+        let infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        infer.generate(request()).await.unwrap();
+        infer.generate(request()).await.unwrap();
+        let summary = infer.energy_summary();
+        assert_eq!(summary.requests, 2);
+        assert_eq!(summary.generated_tokens, 6);
+        // 10mJ per token
+        assert_eq!(summary.energy_joules, 0.06);
+        assert_eq!(summary.tokens_per_joule, Some(100.0));
+        assert_eq!(summary.power_watts, Some(150.0));
+    }
+
+    /// Layer keeping the fields recorded on the spans
+    #[derive(Clone, Default)]
+    struct RecordedFields(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl tracing::field::Visit for RecordedFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}={value:?}", field.name()));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for RecordedFields {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            attrs.record(&mut self.clone());
+        }
+
+        fn on_record(
+            &self,
+            _id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_span_records_energy() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let fields = RecordedFields::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(fields.clone()));
+
+        let infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        let mut request = request();
+        request.parameters.seed = Some(42);
+        infer.generate(request).await.unwrap();
+        let fields = fields.0.lock().unwrap();
+        for field in ["seed=42", "input_length=2", "energy_mj=30"] {
+            assert!(
+                fields.iter().any(|f| f == field),
+                "{field} not in {fields:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_error_before_first_token() {
+        // Without energy tracking the error is returned as is
+        let mut untracked = infer(MockBackend::new(3, 1), None);
+        untracked.backend = Arc::new(BrokenBackend { error: true });
+        assert!(matches!(
+            untracked.generate(request()).await,
+            Err(InferError::GenerationError(_))
+        ));
+
+        let mut infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        infer.backend = Arc::new(BrokenBackend { error: true });
+        // The energy consumed until the failure is attached to the error
+        let err = infer.generate(request()).await.unwrap_err();
+        assert!(matches!(
+            &err,
+            InferError::WithEnergy { source, energy_consumption: 10 }
+                if matches!(**source, InferError::GenerationError(_))
+        ));
+        assert_eq!(err.error_type(), "generation");
+        assert_eq!(err.status_code(), 424);
+        assert_eq!(err.energy_consumption(), Some(10));
+        assert!(!infer.backend_health.load(Ordering::SeqCst));
+
+        let (_permit, _input_length, stream) = infer.generate_stream(request()).await.unwrap();
+        let responses: Vec<_> = stream.collect().await;
+        assert!(matches!(
+            &responses[..],
+            [Err(InferError::WithEnergy { source, .. })]
+                if matches!(**source, InferError::GenerationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_backend_stream_without_end() {
+        let mut infer = infer(MockBackend::new(3, 1), None);
+        infer.backend = Arc::new(BrokenBackend { error: false });
+        assert!(matches!(
+            infer.generate(continued_request(8)).await,
+            Err(InferError::IncompleteGeneration)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_cancels_generation() {
+        let backend = MockBackend::new(3, 1);
+        let cancellations = backend.cancellations.clone();
+        let infer = infer(backend, None);
+
+        let (permit, _input_length, stream) = infer.generate_stream(request()).await.unwrap();
+        let mut stream = Box::pin(stream);
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(!cancellations.lock().unwrap()[0].is_cancelled());
+        // The client disconnects
+        drop(stream);
+        drop(permit);
+        assert!(cancellations.lock().unwrap()[0].is_cancelled());
+        // The permit of the request is available again
+        let _permits: Vec<_> = (0..4)
+            .map(|_| {
+                infer
+                    .limit_concurrent_requests
+                    .try_acquire(&request().parameters)
+                    .unwrap()
+            })
+            .collect();
+    }
+
+    #[tokio::test]
+    async fn test_finished_stream_not_cancelled() {
+        let backend = MockBackend::new(3, 2);
+        let cancellations = backend.cancellations.clone();
+        let infer = infer(backend, None);
+        infer.generate(continued_request(32)).await.unwrap();
+        let cancellations = cancellations.lock().unwrap();
+        assert_eq!(cancellations.len(), 2);
+        assert!(cancellations.iter().all(|c| !c.is_cancelled()));
+    }
+
+    #[tokio::test]
+    async fn test_durations_reported() {
+        let infer = infer(MockBackend::new(3, 1), None);
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.queue_time_ms, QUEUE_TIME.as_millis() as u64);
+        assert!(response.inference_time_ms < 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_energy_budget_stops_generation() {
+        let infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        let mut request = request();
+        request.parameters.max_energy_millijoules = Some(15);
+
+        // The mock counter grows by 10mJ per token, the second token exceeds the budget
+        let response = infer.generate(request).await.unwrap();
+        assert_eq!(response.tokens.len(), 2);
+        assert_eq!(response.generated_text.generated_tokens, 2);
+        assert_eq!(response.generated_text.text, "t0t1");
+        assert!(matches!(
+            response.generated_text.finish_reason,
+            FinishReason::EnergyBudget
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_energy_budget_requires_energy_tracking() {
+        let infer = infer(MockBackend::new(3, 1), None);
+        let mut request = request();
+        request.parameters.max_energy_millijoules = Some(15);
+        assert!(matches!(
+            infer.generate(request).await,
+            Err(InferError::ValidationError(
+                ValidationError::EnergyBudgetUnavailable
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_time_limit_stops_generation() {
+        let mut stalled = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        stalled.backend = Arc::new(StalledBackend);
+        let mut request = request();
+        request.parameters.max_time_ms = Some(50);
+
+        let response = stalled.generate(request).await.unwrap();
+        assert_eq!(response.generated_text.generated_tokens, 1);
+        assert_eq!(response.generated_text.text, "t0");
+        assert!(matches!(
+            response.generated_text.finish_reason,
+            FinishReason::TimeLimit
+        ));
+        // The last token only marks the end of the generation
+        assert!(response.tokens[1].special);
+        assert!(response.tokens[1].text.is_empty());
+        // The energy is measured up to the time limit
+        assert!(response.energy_consumption.is_some());
+        assert_eq!(stalled.limit_concurrent_requests.running(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests_do_not_take_permits() {
+        let mut limited = infer(MockBackend::new(3, 1), None);
+        limited.limit_concurrent_requests = ConcurrencyLimits::new(1, None, None, None);
+
+        let mut invalid = request();
+        invalid.parameters.temperature = Some(0.0);
+        for _ in 0..16 {
+            assert!(matches!(
+                limited.generate(invalid.clone()).await,
+                Err(InferError::ValidationError(ValidationError::Temperature))
+            ));
+        }
+
+        // Invalid requests are rejected before being limited
+        let running = limited.generate_stream(request()).await.unwrap();
+        assert!(matches!(
+            limited.generate(invalid).await,
+            Err(InferError::ValidationError(ValidationError::Temperature))
+        ));
+        assert!(matches!(
+            limited.generate(request()).await,
+            Err(InferError::Overloaded(_))
+        ));
+        drop(running);
+        limited.generate(request()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_validate_only() {
+        let backend = MockBackend::new(3, 1);
+        let seeds = backend.seeds.clone();
+        let mut limited = infer(backend, None);
+        limited.limit_concurrent_requests = ConcurrencyLimits::new(1, None, None, None);
+        // Validation does not need a permit
+        let _running = limited.generate_stream(request()).await.unwrap();
+
+        let report = limited.validate_only(request()).await.unwrap();
+        assert!(report.accepted);
+        assert!(report.error.is_none());
+        assert!(report.input_length.unwrap() > 0);
+        // Energy is only estimated once enough requests were measured
+        assert_eq!(report.estimated_energy, None);
+        let parameters = report.parameters.unwrap();
+        assert_eq!(parameters.temperature, 1.0);
+        assert_eq!(parameters.max_new_tokens, 8);
+
+        // 8 tokens at 20 tokens per joule
+        for _ in 0..10 {
+            limited.energy_stats.record(40, Some(2_000));
+        }
+        let report = limited.validate_only(request()).await.unwrap();
+        assert_eq!(report.estimated_energy, Some(400));
+
+        let mut invalid = request();
+        invalid.parameters.temperature = Some(0.0);
+        let report = limited.validate_only(invalid).await.unwrap();
+        assert!(!report.accepted);
+        assert_eq!(
+            report.error.as_deref(),
+            Some("`temperature` must be strictly positive")
+        );
+        assert!(report.parameters.is_none());
+        // Only the running request reached the backend
+        assert_eq!(seeds.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_benchmark() {
+        let backend = MockBackend::new(3, 1);
+        let seeds = backend.seeds.clone();
+        let benchmarked = infer(backend, Some(mock_meter(1)));
+        let benchmark_request = |iterations| BenchmarkRequest {
+            prompt: "hello world".to_string(),
+            iterations,
+            max_new_tokens: 2,
+        };
+
+        let report = benchmarked.benchmark(benchmark_request(4)).await.unwrap();
+        assert_eq!(report.iterations, 4);
+        assert_eq!(report.generated_tokens, 8);
+        assert_eq!(seeds.lock().unwrap().len(), 4);
+        assert!(report.latency_ms.p50 <= report.latency_ms.p95);
+        let millijoules_per_token = report.millijoules_per_token.unwrap();
+        assert!(millijoules_per_token.mean > 0.0);
+
+        // Not reported when energy is not tracked
+        let untracked = infer(MockBackend::new(3, 1), None);
+        let report = untracked.benchmark(benchmark_request(1)).await.unwrap();
+        assert!(report.millijoules_per_token.is_none());
+
+        for iterations in [0, benchmark::MAX_ITERATIONS + 1] {
+            assert!(matches!(
+                untracked.benchmark(benchmark_request(iterations)).await,
+                Err(InferError::ValidationError(
+                    ValidationError::BenchmarkIterations(_, _)
+                ))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_input_bytes() {
+        let backend = MockBackend::new(3, 1);
+        let seeds = backend.seeds.clone();
+        let mut limited = infer(backend, None);
+        limited.limit_concurrent_requests = ConcurrencyLimits::new(1, None, None, None);
+        limited.max_input_bytes = "hello world".len();
+        let mut oversized = request();
+        oversized.inputs.push('!');
+
+        // Rejected without waiting for the permit held by the running request
+        let _running = limited.generate_stream(request()).await.unwrap();
+        let err = limited.generate(oversized.clone()).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                InferError::ValidationError(ValidationError::InputBytes(11, 12))
+            ),
+            "{err}"
+        );
+
+        let report = limited.validate_only(oversized).await.unwrap();
+        assert!(!report.accepted);
+        assert_eq!(
+            report.error.as_deref(),
+            Some("`inputs` must be at most 11 bytes. Given: 12")
+        );
+        assert_eq!(seeds.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key() {
+        let backend = MockBackend::new(3, 1);
+        let seeds = backend.seeds.clone();
+        let mut infer = infer(backend, None);
+
+        let (first, replayed) = infer
+            .generate_idempotent(request(), Some("retry"))
+            .await
+            .unwrap();
+        assert!(!replayed);
+        let (second, replayed) = infer
+            .generate_idempotent(request(), Some("retry"))
+            .await
+            .unwrap();
+        assert!(replayed);
+        assert_eq!(second.generated_text.seed, first.generated_text.seed);
+        assert_eq!(seeds.lock().unwrap().len(), 1);
+
+        // The key of another tenant is another key, and a key is not reused for another request
+        let mut other_tenant = request();
+        other_tenant.parameters.api_key = Some("other-tenant".to_string());
+        let (_, replayed) = infer
+            .generate_idempotent(other_tenant, Some("retry"))
+            .await
+            .unwrap();
+        assert!(!replayed);
+        let mut other_request = request();
+        other_request.inputs = "other request".to_string();
+        let err = infer
+            .generate_idempotent(other_request, Some("retry"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InferError::ValidationError(ValidationError::IdempotencyKeyReused)
+        ));
+        assert_eq!(err.status_code(), 422);
+        assert_eq!(seeds.lock().unwrap().len(), 2);
+
+        // Other keys and requests without a key are generated
+        let (_, replayed) = infer
+            .generate_idempotent(request(), Some("other"))
+            .await
+            .unwrap();
+        assert!(!replayed);
+        let (_, replayed) = infer.generate_idempotent(request(), None).await.unwrap();
+        assert!(!replayed);
+        assert_eq!(seeds.lock().unwrap().len(), 4);
+
+        // Expired responses are generated again
+        infer.idempotency_cache = Arc::new(IdempotencyCache::new(2, Duration::ZERO));
+        for _ in 0..2 {
+            let (_, replayed) = infer
+                .generate_idempotent(request(), Some("retry"))
+                .await
+                .unwrap();
+            assert!(!replayed);
+        }
+        assert_eq!(seeds.lock().unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_in_flight() {
+        let mut infer = infer(MockBackend::new(3, 1), None);
+        infer.backend = Arc::new(PacedBackend {
+            tokens: 3,
+            interval: Duration::from_millis(20),
+        });
+
+        // A retry arriving while the first attempt runs waits for its response
+        let (first, retry) = tokio::join!(
+            infer.generate_idempotent(request(), Some("retry")),
+            infer.generate_idempotent(request(), Some("retry")),
+        );
+        let (first, replayed) = first.unwrap();
+        assert!(!replayed);
+        let (retry, replayed) = retry.unwrap();
+        assert!(replayed);
+        assert_eq!(retry.generated_text.text, first.generated_text.text);
+
+        // A cancelled attempt leaves the key to the next one
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(10),
+            infer.generate_idempotent(request(), Some("cancelled")),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        let (_, replayed) = infer
+            .generate_idempotent(request(), Some("cancelled"))
+            .await
+            .unwrap();
+        assert!(!replayed);
+    }
+
+    #[tokio::test]
+    async fn test_efficiency_floor() {
+        let mut infer = infer(MockBackend::new(3, 1), None);
+        infer.efficiency_floor = Some(1.0);
+        // Nothing is shed before the efficiency is known
+        let running = infer.generate_stream(request()).await.unwrap();
+        assert!(infer.generate_stream(request()).await.is_ok());
+
+        // 10 tokens for 1 kJ, far below the floor
+        infer.energy_stats.record(10, Some(1_000_000));
+        let err = infer.generate_stream(request()).await.err().unwrap();
+        assert!(matches!(err, InferError::Overloaded(_)), "{err}");
+
+        // Requests are admitted again once the running ones are done
+        drop(running);
+        assert_eq!(infer.limit_concurrent_requests.running(), 0);
+        assert!(infer.generate(request()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_energy_read_failures() {
+        let meter = Arc::new(StutteringMeter {
+            failures: AtomicU32::new(0),
+            energy: AtomicU64::new(0),
+        });
+        let infer = infer(MockBackend::new(3, 1), Some(meter.clone()));
+
+        // Transient failures are retried
+        meter.failures.store(2, Ordering::SeqCst);
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(meter.failures.load(Ordering::SeqCst), 0);
+        assert_eq!(response.energy_consumption, Some(30));
+
+        // The generation goes on without its energy when the readings keep failing
+        meter.failures.store(4, Ordering::SeqCst);
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.tokens.len(), 3);
+        assert_eq!(response.energy_consumption, None);
+        assert_eq!(response.prefill_energy, None);
+
+        meter.failures.store(u32::MAX, Ordering::SeqCst);
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.tokens.len(), 3);
+        assert_eq!(response.energy_consumption, None);
+    }
+
+    #[tokio::test]
+    async fn test_final_energy_read_failure() {
+        // Only the reading before the generation succeeds
+        let meter = Arc::new(ExhaustedMeter {
+            readings: AtomicU32::new(1),
+        });
+        let exhausted = infer(MockBackend::new(3, 1), Some(meter));
+
+        let response = exhausted.generate(request()).await.unwrap();
+        assert_eq!(response.generated_text.text, "mock");
+        assert_eq!(response.generated_text.generated_tokens, 3);
+        assert_eq!(response.energy_consumption, None);
+        assert_eq!(response.tokens_per_joule, None);
+        assert!(exhausted.backend_health.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_energy_measurement_disabled_per_request() {
+        let meter = Arc::new(MockMeter {
+            devices: 1,
+            energy: AtomicU64::new(0),
+        });
+        let infer = infer(MockBackend::new(3, 1), Some(meter.clone()));
+        let reads = meter.energy.load(Ordering::SeqCst);
+        let mut unmeasured = request();
+        unmeasured.parameters.measure_energy = false;
+        let response = infer.generate(unmeasured.clone()).await.unwrap();
+        assert_eq!(response.energy_consumption, None);
+        assert!(response
+            .token_energy_consumptions
+            .iter()
+            .all(|energy| energy.is_none()));
+        // The counter was not read at all
+        assert_eq!(meter.energy.load(Ordering::SeqCst), reads);
+
+        unmeasured.parameters.max_energy_millijoules = Some(15);
+        assert!(matches!(
+            infer.generate(unmeasured).await,
+            Err(InferError::ValidationError(
+                ValidationError::EnergyBudgetUnavailable
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sampling_hook() {
+        let backend = MockBackend::new(3, 1);
+        let temperatures = backend.temperatures.clone();
+        let hook: SamplingHook = Arc::new(|state: &GenerationState| {
+            (state.generated_tokens >= 2).then(|| SamplingOverride {
+                temperature: Some(0.5),
+                ..Default::default()
+            })
+        });
+        let infer = Infer::builder(backend, validation())
+            .sampling_hook(Some(hook))
+            .build();
+        let response = infer.generate(request()).await.unwrap();
+        // The second token stops the first round, the rest runs with the new temperature
+        assert_eq!(*temperatures.lock().unwrap(), vec![1.0, 0.5]);
+        assert_eq!(response.tokens.len(), 5);
+        assert!(response.tokens[1].continuation_boundary);
+        assert_eq!(response.generated_text.text, "t0t1mock");
+    }
+
+    #[tokio::test]
+    async fn test_builder_energy_replay() {
+        let infer = Infer::builder(MockBackend::new(3, 1), validation())
+            .energy_source(energy::EnergySource::Replay)
+            .energy_replay_readings(vec![100, 150])
+            .energy_devices(vec![1])
+            .build();
+        // Replayed readings come from a single device
+        assert_eq!(infer.energy_devices(), &[0]);
+        let response = infer.generate(request()).await.unwrap();
+        assert!(response.energy_consumption.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_named_chat_templates() {
+        let tokenizer_config: HubTokenizerConfig = serde_json::from_value(serde_json::json!({
+            "chat_template": [
+                {"name": "default", "template": "{% for m in messages %}{{ m.content }}{% endfor %}"},
+                {"name": "tool_use", "template": "tools: {% for m in messages %}{{ m.content }}{% endfor %}"},
+            ]
+        }))
+        .unwrap();
+        let infer = Infer::builder(MockBackend::new(3, 1), validation())
+            .tokenizer_config(tokenizer_config)
+            .build();
+        assert!(infer.has_chat_template());
+        assert!(!Infer::builder(MockBackend::new(3, 1), validation())
+            .build()
+            .has_chat_template());
+        let messages = || -> Vec<Message> {
+            serde_json::from_value(serde_json::json!([{"role": "user", "content": "Hi"}])).unwrap()
+        };
+
+        let applied = infer
+            .apply_chat_template(messages(), None, None, None)
+            .unwrap();
+        assert_eq!(applied, ("Hi".to_string(), Some("default".to_string())));
+        let applied = infer
+            .apply_chat_template(messages(), None, None, Some("tool_use"))
+            .unwrap();
+        assert_eq!(
+            applied,
+            ("tools: Hi".to_string(), Some("tool_use".to_string()))
+        );
+
+        // A template sent in the request wins over the templates of the model
+        let applied = infer
+            .apply_chat_template(
+                messages(),
+                None,
+                Some("[{{ messages | length }}]"),
+                Some("tool_use"),
+            )
+            .unwrap();
+        assert_eq!(applied, ("[1]".to_string(), None));
+
+        let err = infer
+            .apply_chat_template(messages(), None, None, Some("missing"))
+            .unwrap_err();
+        assert!(err.to_string().contains("missing"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_generate_with_or_without_nvml() {
+        // Must not panic on machines without NVIDIA drivers
+        let infer = Infer::builder(MockBackend::new(3, 1), validation())
+            .max_concurrent_requests(4)
+            .energy_source(energy::EnergySource::Nvml)
+            .build();
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.tokens.len(), 3);
+        if Nvml::init().is_err() {
+            assert!(response.energy_consumption.is_none());
+        }
+    }
+
+    fn continued_request(max_new_tokens: u32) -> GenerateRequest {
+        let mut request = request();
+        request.parameters.max_new_tokens = Some(max_new_tokens);
+        request.parameters.continue_on_length = true;
+        request
+    }
+
+    #[tokio::test]
+    async fn test_continuation_disabled_by_default() {
+        let infer = infer(MockBackend::new(3, 2), None);
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.tokens.len(), 3);
+        assert_eq!(response.generated_text.text, "mock");
+        assert!(matches!(
+            response.generated_text.finish_reason,
+            FinishReason::Length
+        ));
+        assert!(!response
+            .tokens
+            .iter()
+            .any(|token| token.continuation_boundary));
+    }
+
+    #[tokio::test]
+    async fn test_continuation_rounds_bounded() {
+        let infer = infer(MockBackend::new(1, u32::MAX), None);
+        let response = infer.generate(continued_request(32)).await.unwrap();
+        assert_eq!(response.tokens.len() as u32, MAX_CONTINUATION_ROUNDS + 1);
+        assert!(matches!(
+            response.generated_text.finish_reason,
+            FinishReason::Length
+        ));
+        assert_eq!(
+            response.generated_text.length_limit,
+            Some(LengthLimit::ContinuationRounds)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_length_limits() {
+        // A single generation stops at `max_new_tokens`
+        let infer = infer(MockBackend::new(100, u32::MAX), None);
+        let response = infer.generate(request()).await.unwrap();
+        assert_eq!(response.generated_text.generated_tokens, 8);
+        assert_eq!(
+            response.generated_text.length_limit,
+            Some(LengthLimit::MaxNewTokens)
+        );
+
+        // Rounds of 2 tokens continued twice, then stopped by the total of 5
+        let mut budgeted = continued_request(2);
+        budgeted.parameters.max_total_new_tokens = Some(5);
+        let response = infer.generate(budgeted).await.unwrap();
+        assert_eq!(response.generated_text.generated_tokens, 5);
+        let boundaries: Vec<_> = response
+            .tokens
+            .iter()
+            .map(|token| token.continuation_boundary)
+            .collect();
+        assert_eq!(boundaries, [false, true, false, true, false]);
+        assert_eq!(
+            response.generated_text.length_limit,
+            Some(LengthLimit::MaxTotalNewTokens)
+        );
+
+        // Without `max_total_new_tokens`, `max_new_tokens` is the budget of all the rounds
+        let response = infer.generate(continued_request(5)).await.unwrap();
+        assert_eq!(response.generated_text.generated_tokens, 5);
+        assert_eq!(
+            response.generated_text.length_limit,
+            Some(LengthLimit::MaxNewTokens)
+        );
+
+        // No limit is reported when the generation ends on its own
+        let infer = self::infer(MockBackend::new(3, 1), None);
+        let ended = infer.generate(request()).await.unwrap();
+        assert!(matches!(
+            ended.generated_text.finish_reason,
+            FinishReason::EndOfSequenceToken
+        ));
+        assert_eq!(ended.generated_text.length_limit, None);
+    }
+
+    #[tokio::test]
+    async fn test_return_offsets() {
+        let response = infer(MockBackend::new(3, 1), None)
+            .generate(request())
+            .await
+            .unwrap();
+        assert!(response.tokens.iter().all(|token| token.offsets.is_none()));
+
+        // Rounds of tokens `t0`, `t1` and `t2`, stopped in the first token of the second round
+        let mut with_offsets = continued_request(8);
+        with_offsets.parameters.return_offsets = true;
+        with_offsets.parameters.stop = vec!["t2t".to_string()];
+        let response = infer(MockBackend::new(3, 2), None)
+            .generate(with_offsets)
+            .await
+            .unwrap();
+        let offsets: Vec<_> = response
+            .tokens
+            .iter()
+            .map(|token| token.offsets.map(|offsets| (offsets.start, offsets.stop)))
+            .collect();
+        assert_eq!(
+            offsets,
+            vec![Some((0, 2)), Some((2, 4)), Some((4, 6)), Some((6, 7))]
+        );
+    }
+
+    #[test]
+    fn test_send_response_keeps_terminal_slot() {
+        let intermediate = |id| {
+            Ok(InferStreamResponse::Intermediate {
+                token: token(id),
+                top_tokens: vec![],
+                top_logprobs: vec![],
+                energy_consumption: None,
+                seq: None,
+            })
+        };
+
+        // Intermediate responses leave the last slot free, the slow client gets an error there
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(3);
+        assert_eq!(send_response(&sender, intermediate(0)), Ok(()));
+        assert_eq!(send_response(&sender, intermediate(1)), Ok(()));
+        assert_eq!(
+            send_response(&sender, intermediate(2)),
+            Err(StreamSendError::SlowClient)
+        );
+        assert!(receiver.try_recv().unwrap().is_ok());
+        assert!(receiver.try_recv().unwrap().is_ok());
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            Err(InferError::SlowClient)
+        ));
+
+        // Errors take the last slot without waiting for the client
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        assert_eq!(send_response(&sender, intermediate(0)), Ok(()));
+        let error = Err(InferError::GenerationError("failed".to_string()));
+        assert_eq!(send_response(&sender, error), Ok(()));
+        assert!(receiver.try_recv().unwrap().is_ok());
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            Err(InferError::GenerationError(_))
+        ));
+
+        drop(receiver);
+        assert_eq!(
+            send_response(&sender, intermediate(1)),
+            Err(StreamSendError::Closed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inter_token_latency() {
+        let mut paced = infer(MockBackend::new(3, 1), None);
+        paced.backend = Arc::new(PacedBackend {
+            tokens: 3,
+            interval: Duration::from_millis(20),
+        });
+        let response = paced.generate(request()).await.unwrap();
+        assert!(response
+            .tokens
+            .iter()
+            .all(|token| token.inter_token_latency_ms.is_none()));
+
+        let mut with_latency = request();
+        with_latency.parameters.return_inter_token_latency = true;
+        let response = paced.generate(with_latency).await.unwrap();
+        assert_eq!(response.tokens.len(), 3);
+        for token in &response.tokens {
+            let latency = token.inter_token_latency_ms.unwrap();
+            assert!(latency >= 20.0, "{latency}ms between tokens");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_sequence_across_tokens() {
+        // Tokens `t0`, `t1`, `t2`...: split over two tokens, then over three
+        for stop in ["1t", "0t1t"] {
+            let mut stopped = request();
+            stopped.parameters.stop = vec![stop.to_string()];
+            stopped.parameters.do_sample = false;
+            let response = infer(MockBackend::new(5, 1), None)
+                .generate(stopped)
+                .await
+                .unwrap();
+            assert_eq!(response.generated_text.text, "t0t1t");
+            assert_eq!(response.generated_text.generated_tokens, 3);
+            assert!(matches!(
+                response.generated_text.finish_reason,
+                FinishReason::StopSequence
+            ));
+            // Greedy requests do not report a seed
+            assert_eq!(response.generated_text.seed, None);
+            // The text of the last token is trimmed at the end of the stop sequence
+            assert_eq!(response.tokens.last().unwrap().text, "t");
+        }
+
+        // Sampled requests report the seed they were generated with
+        let mut sampled = request();
+        sampled.parameters.stop = vec!["1t".to_string()];
+        sampled.parameters.seed = Some(42);
+        let response = infer(MockBackend::new(5, 1), None)
+            .generate(sampled)
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.seed, Some(42));
+
+        // Completed by the last token of the backend
+        let mut stopped = request();
+        stopped.parameters.stop = vec!["3t".to_string()];
+        let response = infer(MockBackend::new(5, 1), None)
+            .generate(stopped)
+            .await
+            .unwrap();
+        assert_eq!(response.tokens.len(), 5);
+        assert_eq!(response.tokens.last().unwrap().text, "t");
+        assert!(matches!(
+            response.generated_text.finish_reason,
+            FinishReason::StopSequence
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_multiple_eos_tokens() {
+        // The backend stops at its own EOS token, the router at the other ones
+        let mut eos_infer = infer(MockBackend::new(5, 1), None);
+        eos_infer.eos_tokens = Arc::new(["</s>".to_string(), "t2".to_string()]);
+        let response = eos_infer.generate(request()).await.unwrap();
+        assert_eq!(response.tokens.len(), 3);
+        assert_eq!(response.generated_text.text, "t0t1");
+        assert!(matches!(
+            response.generated_text.finish_reason,
+            FinishReason::EndOfSequenceToken
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_continuation_token_budget() {
+        // Rounds of 3 tokens within a budget of 5: the second round only gets the 2 tokens left,
+        // although the continued request is validated again with a budget of 5
+        let budgeted = infer(MockBackend::new(3, u32::MAX), None);
+        let response = budgeted.generate(continued_request(5)).await.unwrap();
+        assert_eq!(response.tokens.len(), 5);
+        assert_eq!(response.generated_text.generated_tokens, 5);
+        assert!(matches!(
+            response.generated_text.finish_reason,
+            FinishReason::Length
+        ));
+
+        // Same over the stream, with a budget that is not a multiple of the round length
+        let (_permit, _input_length, stream) = budgeted
+            .generate_stream(continued_request(7))
+            .await
+            .unwrap();
+        let tokens: Vec<_> = stream
+            .map(|response| match response.unwrap() {
+                InferStreamResponse::Prefill { .. } => 0,
+                _ => 1,
+            })
+            .collect()
+            .await;
+        assert_eq!(tokens.iter().sum::<u32>(), 7);
+    }
+
+    /// Backend generating one round of text per schedule, recording the prompt of each round
+    struct RoundsBackend {
+        rounds: Vec<&'static str>,
+        inputs: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Backend for RoundsBackend {
+        fn schedule(
+            &self,
+            request: ValidGenerateRequest,
+            _cancellation: CancellationToken,
+        ) -> Result<GenerationStream, InferError> {
+            let mut inputs = self.inputs.lock().unwrap();
+            inputs.push(request.inputs.chunks_to_string());
+            let round = inputs.len() - 1;
+            let finish_reason = if round + 1 < self.rounds.len() {
+                FinishReason::Length
+            } else {
+                FinishReason::EndOfSequenceToken
+            };
+            let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER_SIZE);
+            let start = Instant::now();
+            let _ = sender.try_send(Ok(InferStreamResponse::End {
+                token: token(0),
+                top_tokens: vec![],
+                top_logprobs: vec![],
+                generated_text: GeneratedText {
+                    text: self.rounds[round].to_string(),
+                    generated_tokens: 1,
+                    finish_reason,
+                    seed: None,
+                    length_limit: None,
+                },
+                start,
+                queued: start,
+                energy_consumption: None,
+                prefill_energy: None,
+                decode_energy: None,
+                prefill_energy_saved: None,
+                seq: None,
+            }));
+            Ok(ReceiverStream::new(receiver))
+        }
+
+        async fn health(&self, current_health: bool) -> bool {
+            current_health
+        }
+
+        fn name(&self) -> &'static str {
+            "rounds"
+        }
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        let errors = [
+            (InferError::GenerationError("oom".to_string()), 424),
+            (InferError::Overloaded(TryAcquireError::NoPermits), 429),
+            (
+                InferError::ValidationError(ValidationError::Temperature),
+                422,
+            ),
+            (InferError::IncompleteGeneration, 500),
+            (InferError::IncompleteGenerationStream, 500),
+            (
+                InferError::TemplateError(minijinja::Error::new(ErrorKind::SyntaxError, "x")),
+                422,
+            ),
+            (
+                InferError::MissingTemplateVariable(
+                    "x".to_string(),
+                    minijinja::Error::new(ErrorKind::UndefinedError, "x"),
+                ),
+                422,
+            ),
+            (InferError::ToolError("x".to_string()), 422),
+            (
+                InferError::InvalidToolSchema {
+                    tool_name: "x".to_string(),
+                    reason: "x".to_string(),
+                },
+                422,
+            ),
+            (InferError::StreamSerializationError("x".to_string()), 500),
+            (InferError::EnergyConsumptionError("x".to_string()), 500),
+            (InferError::TokenSequenceError("x".to_string()), 500),
+            (InferError::SlowClient, 408),
+            (InferError::UnknownApiKey, 401),
+        ];
+        for (error, status_code) in errors {
+            assert_eq!(error.status_code(), status_code, "{error}");
+        }
+    }
+
+    #[test]
+    fn test_split_incomplete_tail() {
+        assert_eq!(split_incomplete_tail("你好"), ("你好", ""));
+        assert_eq!(split_incomplete_tail("你\u{FFFD}"), ("你", "\u{FFFD}"));
+        assert_eq!(
+            split_incomplete_tail("a \u{FFFD}\u{FFFD}\u{FFFD}"),
+            ("a ", "\u{FFFD}\u{FFFD}\u{FFFD}")
+        );
+        assert_eq!(split_incomplete_tail(""), ("", ""));
+    }
+
+    #[tokio::test]
+    async fn test_continuation_characters_split_across_rounds() {
+        // The second and third rounds end in the middle of a CJK character and of an emoji
+        let inputs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut rounds_infer = infer(MockBackend::new(1, 1), None);
+        rounds_infer.backend = Arc::new(RoundsBackend {
+            rounds: vec!["你好\u{FFFD}", "世界 \u{FFFD}\u{FFFD}", "😀"],
+            inputs: inputs.clone(),
+        });
+        let mut continued = request();
+        continued.parameters.continue_on_length = true;
+        let response = rounds_infer.generate(continued).await.unwrap();
+
+        // No replacement character reaches the prompt of the next rounds nor the response
+        assert_eq!(
+            *inputs.lock().unwrap(),
+            vec!["hello world", "hello world你好", "hello world你好世界 "]
+        );
+        assert_eq!(response.generated_text.text, "你好世界 😀");
+    }
+
+    #[tokio::test]
+    async fn test_continuation_seed_per_round() {
+        let backend = MockBackend::new(2, 3);
+        let seeds = backend.seeds.clone();
+        let infer = infer(backend, None);
+        let mut request = continued_request(16);
+        request.parameters.seed = Some(u64::MAX);
+        let response = infer.generate(request).await.unwrap();
+        assert_eq!(response.generated_text.text, "mockmockmock");
+        // Every round draws from its own seed, derived from the request seed
+        assert_eq!(*seeds.lock().unwrap(), vec![u64::MAX, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_continuation_boundary_flagged() {
+        let infer = infer(MockBackend::new(3, 2), None);
+        let response = infer.generate(continued_request(8)).await.unwrap();
+        assert_eq!(response.tokens.len(), 6);
+        assert_eq!(response.generated_text.text, "mockmock");
+
+        let boundaries: Vec<usize> = response
+            .tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, token)| token.continuation_boundary)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(boundaries, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_continuation_boundary_energy() {
+        let infer = infer(MockBackend::new(3, 2), Some(mock_meter(1)));
+        let mut request = continued_request(8);
+        request.parameters.top_n_tokens = Some(2);
+        let response = infer.generate(request).await.unwrap();
+        assert!(response.tokens[2].continuation_boundary);
+
+        // The boundary token is measured, not given the energy reported by the backend
+        let energies: Vec<u64> = response
+            .tokens
+            .iter()
+            .map(|token| token.energy_consumption.unwrap())
+            .collect();
+        assert!(energies.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(response.energy_consumption, energies.last().copied());
+        assert!(response.top_tokens[2]
+            .iter()
+            .all(|token| token.step_energy == Some(10)));
+        assert!(!response.energy_mismatch);
+    }
+
+    /// Response of a sequence that measured the energy of the whole device during its window
+    fn overlapping_response(energy: u64) -> InferResponse {
+        let mut token = token(0);
+        token.energy_consumption = Some(energy);
+        InferResponse {
+            _input_length: 2,
+            prefill: vec![],
+            tokens: vec![token],
+            generated_text: GeneratedText {
+                text: "mock".to_string(),
+                generated_tokens: 1,
+                finish_reason: FinishReason::Length,
+                seed: None,
+                length_limit: None,
+            },
+            queued: Instant::now(),
+            start: Instant::now(),
+            queue_time_ms: 0,
+            inference_time_ms: 0,
+            top_tokens: vec![],
+            top_logprobs: vec![],
+            energy_consumption: Some(energy),
+            prefill_energy: Some(energy / 2),
+            decode_energy: Some(energy / 2),
+            prefill_energy_saved: None,
+            batch_energy_consumption: None,
+            wasted_energy: None,
+            tokens_per_joule: None,
+            co2_grams: None,
+            per_device_energy: Vec::new(),
+            token_energy_stats: None,
+            token_energy_consumptions: vec![Some(energy)],
+            energy_mismatch: false,
+        }
+    }
+
+    #[test]
+    fn test_best_of_energy_not_double_counted() {
+        // Four sequences ran together while the device consumed 400mJ
+        let mut responses: Vec<_> = (0..4).map(|_| overlapping_response(400)).collect();
+        share_batch_energy(&mut responses, Some(400));
+
+        let aggregate: u64 = responses
+            .iter()
+            .map(|response| response.energy_consumption.unwrap())
+            .sum();
+        assert_eq!(aggregate, 400);
+        for response in &responses {
+            assert_eq!(response.energy_consumption, Some(100));
+            assert_eq!(response.prefill_energy, Some(50));
+            assert_eq!(response.tokens[0].energy_consumption, Some(100));
+            assert_eq!(response.token_energy_consumptions, vec![Some(100)]);
+        }
+
+        let mut responses = vec![overlapping_response(400)];
+        share_batch_energy(&mut responses, None);
+        assert_eq!(responses[0].energy_consumption, None);
+    }
+
+    #[tokio::test]
+    async fn test_best_of_batch_energy() {
+        // Nothing is wasted, or known to be, without energy tracking
+        let untracked = infer(MockBackend::new(3, 1), None);
+        let (best, _) = untracked.generate_best_of(request(), 2).await.unwrap();
+        assert_eq!(best.wasted_energy, None);
+
+        let infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        let (best, others) = infer.generate_best_of(request(), 4).await.unwrap();
+        assert_eq!(others.len(), 3);
+
+        let batch_energy = best.batch_energy_consumption.unwrap();
+        let shares: Vec<u64> = std::iter::once(&best)
+            .chain(&others)
+            .map(|response| response.energy_consumption.unwrap())
+            .collect();
+        assert_eq!(shares, vec![batch_energy / 4; 4]);
+        assert!(others
+            .iter()
+            .all(|response| response.batch_energy_consumption.is_none()));
+        assert_eq!(best.wasted_energy, Some(shares[1..].iter().sum()));
+        assert!(others
+            .iter()
+            .all(|response| response.wasted_energy.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_generate_n() {
+        let infer = infer(MockBackend::new(3, 1), Some(mock_meter(1)));
+        // Unlike best_of, n does not require sampling
+        let responses = infer.generate_n(request(), 3).await.unwrap();
+        assert_eq!(responses.len(), 3);
+        let batch_energy = responses[0].batch_energy_consumption.unwrap();
+        for response in &responses {
+            assert_eq!(response.generated_text.text, "mock");
+            assert_eq!(response.batch_energy_consumption, Some(batch_energy));
+            assert_eq!(response.energy_consumption, Some(batch_energy / 3));
+        }
+
+        for n in [0, 5] {
+            assert!(matches!(
+                infer.generate_n(request(), n).await,
+                Err(InferError::ValidationError(ValidationError::N(4, _)))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_energy_reconciliation() {
+        let mut long_request = request();
+        long_request.parameters.max_new_tokens = Some(20);
+
+        // 10mJ per token: the last token is a small part of the request
+        let response = infer(MockBackend::new(20, 1), Some(mock_meter(1)))
+            .generate(long_request.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.energy_consumption, Some(200));
+        assert!(!response.energy_mismatch);
+
+        // The last step consumes far more than what the tokens account for
+        let spike_meter = Arc::new(SpikeMeter {
+            readings: AtomicU64::new(0),
+            spike_at: 21,
+        });
+        let response = infer(MockBackend::new(20, 1), Some(spike_meter))
+            .generate(long_request)
+            .await
+            .unwrap();
+        assert_eq!(response.energy_consumption, Some(1200));
+        assert!(response.energy_mismatch);
+
+        // A single token has nothing to be compared with
+        let mut short_request = request();
+        short_request.parameters.max_new_tokens = Some(1);
+        let response = infer(MockBackend::new(1, 1), Some(mock_meter(1)))
+            .generate(short_request)
+            .await
+            .unwrap();
+        assert!(!response.energy_mismatch);
+
+        // Without energy tracking
+        let response = infer(MockBackend::new(3, 1), None)
+            .generate(request())
+            .await
+            .unwrap();
+        assert!(!response.energy_mismatch);
+    }
+
+    #[test]
+    fn test_top_tokens_not_collected_when_unused() {
+        let mut result_top_tokens = Vec::new();
+        for id in 0..64 {
+            collect_top_tokens(&mut result_top_tokens, vec![token(id)], false);
+        }
+        assert!(result_top_tokens.is_empty());
+        assert_eq!(result_top_tokens.capacity(), 0);
+    }
+
+    #[test]
+    fn test_top_tokens_collected_when_requested() {
+        let mut result_top_tokens = Vec::new();
+        collect_top_tokens(&mut result_top_tokens, vec![token(0), token(1)], true);
+        collect_top_tokens(&mut result_top_tokens, vec![token(2), token(3)], true);
+        assert_eq!(result_top_tokens.len(), 2);
+        assert_eq!(result_top_tokens[1][0].id, 2);
+    }
+}
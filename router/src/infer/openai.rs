@@ -0,0 +1,552 @@
+use crate::infer::{
+    Backend, GeneratedText, GenerationStream, InferError, InferStreamResponse, STREAM_BUFFER_SIZE,
+};
+use crate::validation::{ChunksToString, ValidGenerateRequest};
+use crate::{FinishReason, Token};
+use async_trait::async_trait;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+
+/// Backend forwarding the requests to a remote OpenAI compatible server
+///
+/// The generation runs on the remote server (vLLM, llama.cpp server, ...) through its streaming
+/// `/v1/completions` route, while the router keeps measuring the energy of the local devices.
+/// The token ids are asked with the `return_tokens_as_token_ids` extension of vLLM, the tokens of
+/// the servers that do not report them have the id 0.
+pub struct OpenAiProxyBackend {
+    client: reqwest::Client,
+    base_url: String,
+    model: String,
+}
+
+impl OpenAiProxyBackend {
+    /// Proxy to the server at `base_url`, e.g. `http://localhost:8000`, serving `model`
+    pub fn new(base_url: impl Into<String>, model: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            model: model.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for OpenAiProxyBackend {
+    fn schedule(
+        &self,
+        request: ValidGenerateRequest,
+        cancellation: CancellationToken,
+    ) -> Result<GenerationStream, InferError> {
+        let body = serde_json::to_string(&CompletionRequest::new(&self.model, &request))
+            .map_err(|err| InferError::GenerationError(err.to_string()))?;
+        let upstream_request = self
+            .client
+            .post(format!("{}/v1/completions", self.base_url))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+
+        let (sender, receiver) = channel(STREAM_BUFFER_SIZE);
+        tokio::spawn(async move {
+            tokio::select! {
+                result = forward(upstream_request, &sender) => {
+                    if let Err(err) = result {
+                        tracing::error!("{err}");
+                        let _ = sender.send(Err(err)).await;
+                    }
+                }
+                // Dropping the upstream request closes its connection
+                _ = cancellation.cancelled() => tracing::debug!("Upstream request cancelled"),
+            }
+        });
+        Ok(ReceiverStream::new(receiver))
+    }
+
+    async fn health(&self, _current_health: bool) -> bool {
+        match self
+            .client
+            .get(format!("{}/health", self.base_url))
+            .send()
+            .await
+        {
+            Ok(response) => response.status().is_success(),
+            Err(err) => {
+                tracing::warn!("Upstream health check failed: {err}");
+                false
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "openai-proxy"
+    }
+}
+
+/// Body of a streaming `/v1/completions` request
+#[derive(Debug, Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    prompt: String,
+    max_tokens: u32,
+    temperature: f32,
+    top_p: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repetition_penalty: Option<f32>,
+    frequency_penalty: f32,
+    seed: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    stream: bool,
+    /// Only the logprob of the generated token
+    logprobs: u32,
+    /// Extension of vLLM reporting the tokens of the logprobs as `token_id:<id>`
+    return_tokens_as_token_ids: bool,
+}
+
+impl<'a> CompletionRequest<'a> {
+    fn new(model: &'a str, request: &ValidGenerateRequest) -> Self {
+        let parameters = &request.parameters;
+        Self {
+            model,
+            prompt: request.inputs.chunks_to_string(),
+            max_tokens: request.stopping_parameters.max_new_tokens,
+            // Greedy decoding is expressed with a null temperature
+            temperature: if parameters.do_sample {
+                parameters.temperature
+            } else {
+                0.0
+            },
+            top_p: parameters.top_p,
+            // Extensions of vLLM, only sent when they differ from the defaults
+            top_k: (parameters.top_k > 0).then_some(parameters.top_k),
+            repetition_penalty: (parameters.repetition_penalty != 1.0)
+                .then_some(parameters.repetition_penalty),
+            frequency_penalty: parameters.frequency_penalty,
+            seed: parameters.seed,
+            stop: request.stopping_parameters.stop_sequences.clone(),
+            stream: true,
+            logprobs: 0,
+            return_tokens_as_token_ids: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChoice>,
+    #[serde(default)]
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionUsage {
+    completion_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChoice {
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    logprobs: Option<CompletionLogprobs>,
+    finish_reason: Option<String>,
+    /// Extension of vLLM, what stopped the generation when `finish_reason` is `stop`
+    #[serde(default)]
+    stop_reason: Option<StopReason>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StopReason {
+    /// One of the stop sequences of the request
+    Sequence(String),
+    /// A stop token, given by its id
+    Token(u32),
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionLogprobs {
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    token_logprobs: Vec<Option<f32>>,
+}
+
+impl CompletionLogprobs {
+    /// Id of the first token of the chunk, when the server reports it
+    fn token_id(&self) -> Option<u32> {
+        self.tokens.first()?.strip_prefix("token_id:")?.parse().ok()
+    }
+}
+
+/// Map an upstream finish reason to ours
+///
+/// OpenAI servers report both end of sequence tokens and stop sequences as `stop`, the
+/// `stop_reason` of the servers that report it tells them apart.
+fn finish_reason(reason: &str, stop_reason: Option<&StopReason>) -> FinishReason {
+    match (reason, stop_reason) {
+        ("length", _) => FinishReason::Length,
+        ("stop", Some(StopReason::Sequence(_))) => FinishReason::StopSequence,
+        _ => FinishReason::EndOfSequenceToken,
+    }
+}
+
+/// Split a server-sent events body into the payloads of its `data` fields
+#[derive(Debug, Default)]
+struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    /// Add bytes of the body and return the payloads of the lines that are now complete
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut payloads = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(data) = line.trim_end().strip_prefix("data:") {
+                payloads.push(data.trim_start().to_string());
+            }
+        }
+        payloads
+    }
+}
+
+fn upstream_error(err: impl std::fmt::Display) -> InferError {
+    InferError::GenerationError(format!("upstream server error: {err}"))
+}
+
+/// Send the request upstream and adapt its event stream into our responses
+///
+/// The last token is only sent once the finish reason is known, so that it can be sent as `End`.
+/// A chunk can hold several tokens, it is sent as a single token but each of them is counted.
+/// The upstream stream is not read while the client is slow, so the backpressure reaches the
+/// upstream server.
+async fn forward(
+    request: reqwest::RequestBuilder,
+    sender: &Sender<Result<InferStreamResponse, InferError>>,
+) -> Result<(), InferError> {
+    let queued = Instant::now();
+    let mut response = request.send().await.map_err(upstream_error)?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(upstream_error(format!("{status}: {body}")));
+    }
+    let start = Instant::now();
+
+    let mut decoder = SseDecoder::default();
+    let mut text = String::new();
+    let mut generated_tokens = 0;
+    let mut pending: Option<Token> = None;
+    'stream: while let Some(bytes) = response.chunk().await.map_err(upstream_error)? {
+        for data in decoder.push(&bytes) {
+            // `[DONE]` ends the upstream stream. The generation returns as soon as it gets a
+            // finish reason, reaching it means that the finish reason is missing.
+            if data == "[DONE]" {
+                break 'stream;
+            }
+            let CompletionChunk { choices, usage } =
+                serde_json::from_str(&data).map_err(upstream_error)?;
+            let Some(choice) = choices.into_iter().next() else {
+                continue;
+            };
+
+            if !choice.text.is_empty() {
+                let id = choice
+                    .logprobs
+                    .as_ref()
+                    .and_then(CompletionLogprobs::token_id)
+                    .unwrap_or(0);
+                // The logprobs list every token of the chunk
+                generated_tokens += choice
+                    .logprobs
+                    .as_ref()
+                    .map_or(1, |logprobs| logprobs.tokens.len().max(1) as u32);
+                let logprob = choice
+                    .logprobs
+                    .and_then(|logprobs| logprobs.token_logprobs.into_iter().next().flatten())
+                    .unwrap_or(0.0);
+                text.push_str(&choice.text);
+                let token = Token {
+                    id,
+                    text: choice.text,
+                    logprob,
+                    special: false,
+                    energy_consumption: None,
+                    telemetry: None,
+                    continuation_boundary: false,
+                    step_energy: None,
+                    offsets: None,
+                    inter_token_latency_ms: None,
+                };
+                if let Some(token) = pending.replace(token) {
+                    let response = InferStreamResponse::Intermediate {
+                        token,
+                        top_tokens: vec![],
+                        top_logprobs: vec![],
+                        energy_consumption: None,
+                        seq: None,
+                    };
+                    if sender.send(Ok(response)).await.is_err() {
+                        // The request was cancelled, dropping the response closes the connection
+                        return Ok(());
+                    }
+                }
+            }
+
+            if let Some(reason) = choice.finish_reason {
+                let finish_reason = finish_reason(&reason, choice.stop_reason.as_ref());
+                // The usage, when reported, is the count of the server
+                if let Some(usage) = usage {
+                    generated_tokens = usage.completion_tokens;
+                }
+                let token = pending.take().unwrap_or_else(|| Token {
+                    id: 0,
+                    text: String::new(),
+                    logprob: 0.0,
+                    special: true,
+                    energy_consumption: None,
+                    telemetry: None,
+                    continuation_boundary: false,
+                    step_energy: None,
+                    offsets: None,
+                    inter_token_latency_ms: None,
+                });
+                let _ = sender
+                    .send(Ok(InferStreamResponse::End {
+                        token,
+                        top_tokens: vec![],
+                        top_logprobs: vec![],
+                        generated_text: GeneratedText {
+                            text,
+                            generated_tokens,
+                            finish_reason,
+                            seed: None,
+                            length_limit: None,
+                        },
+                        start,
+                        queued,
+                        energy_consumption: None,
+                        prefill_energy: None,
+                        decode_energy: None,
+                        prefill_energy_saved: None,
+                        seq: None,
+                    }))
+                    .await;
+                return Ok(());
+            }
+        }
+    }
+    Err(upstream_error("stream ended without a finish reason"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{Chunk, ValidParameters, ValidStoppingParameters};
+    use crate::EnergyPriority;
+    use axum::http::header;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use tokio_stream::StreamExt;
+
+    fn request(stop_sequences: Vec<String>) -> ValidGenerateRequest {
+        ValidGenerateRequest {
+            inputs: vec![Chunk::Text("What is Deep Learning?".to_string())],
+            input_ids: None,
+            input_length: 5,
+            truncate: 0,
+            add_special_tokens: true,
+            decoder_input_details: false,
+            measure_energy: true,
+            energy_priority: EnergyPriority::Throughput,
+            parameters: ValidParameters {
+                temperature: 1.0,
+                top_k: 0,
+                top_p: 1.0,
+                typical_p: 1.0,
+                do_sample: false,
+                seed: 42,
+                repetition_penalty: 1.0,
+                frequency_penalty: 0.0,
+                watermark: false,
+                grammar: None,
+            },
+            stopping_parameters: ValidStoppingParameters {
+                max_new_tokens: 3,
+                max_total_new_tokens: 3,
+                stop_sequences,
+                ignore_eos_token: false,
+            },
+            top_n_tokens: 0,
+            top_logprobs: 0,
+            adapter_id: None,
+        }
+    }
+
+    /// Serve a fixed completion stream and return the base url of the server
+    async fn upstream(events: &'static str) -> String {
+        let app = Router::new()
+            .route(
+                "/v1/completions",
+                post(
+                    move || async move { ([(header::CONTENT_TYPE, "text/event-stream")], events) },
+                ),
+            )
+            .route("/health", get(|| async {}));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}/")
+    }
+
+    #[test]
+    fn test_completion_request() {
+        let request = CompletionRequest::new("model", &request(vec!["\n".to_string()]));
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["prompt"], "What is Deep Learning?");
+        assert_eq!(body["max_tokens"], 3);
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["stop"], serde_json::json!(["\n"]));
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["logprobs"], 0);
+        assert_eq!(body["return_tokens_as_token_ids"], true);
+        assert!(body.get("top_k").is_none());
+    }
+
+    #[test]
+    fn test_sse_decoder() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b"data: {\"a\"").is_empty());
+        assert_eq!(
+            decoder.push(b": 1}\n\n: comment\ndata:[DONE]\n"),
+            ["{\"a\": 1}", "[DONE]"]
+        );
+    }
+
+    #[test]
+    fn test_finish_reason() {
+        let sequence = StopReason::Sequence("###".to_string());
+        assert!(matches!(
+            finish_reason("length", None),
+            FinishReason::Length
+        ));
+        assert!(matches!(
+            finish_reason("stop", Some(&sequence)),
+            FinishReason::StopSequence
+        ));
+        assert!(matches!(
+            finish_reason("stop", Some(&StopReason::Token(2))),
+            FinishReason::EndOfSequenceToken
+        ));
+        assert!(matches!(
+            finish_reason("stop", None),
+            FinishReason::EndOfSequenceToken
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_stream() {
+        let url = upstream(concat!(
+            "data: {\"choices\": [{\"text\": \" Deep\", \"finish_reason\": null}]}\n\n",
+            "data: {\"choices\": [{\"text\": \" learning\", \"finish_reason\": null, ",
+            "\"logprobs\": {\"tokens\": [\"token_id:4673\", \"token_id:11\"], ",
+            "\"token_logprobs\": [-0.5, -0.1]}}]}\n\n",
+            "data: {\"choices\": [{\"text\": \" is\", \"finish_reason\": \"length\"}]}\n\n",
+            "data: [DONE]\n\n",
+        ))
+        .await;
+        let backend = OpenAiProxyBackend::new(url, "model");
+        assert!(backend.health(false).await);
+
+        let responses: Vec<_> = backend
+            .schedule(request(vec![]), CancellationToken::new())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 3);
+        assert!(matches!(
+            &responses[0],
+            InferStreamResponse::Intermediate { token, .. } if token.id == 0
+        ));
+        assert!(matches!(
+            &responses[1],
+            InferStreamResponse::Intermediate { token, .. }
+                if token.text == " learning" && token.id == 4673 && token.logprob == -0.5
+        ));
+        let InferStreamResponse::End {
+            token,
+            generated_text,
+            ..
+        } = &responses[2]
+        else {
+            panic!("The last response must be the end of the stream");
+        };
+        assert_eq!(token.text, " is");
+        assert_eq!(generated_text.text, " Deep learning is");
+        // The second chunk holds two tokens
+        assert_eq!(generated_text.generated_tokens, 4);
+        assert!(matches!(generated_text.finish_reason, FinishReason::Length));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_stream_stop_reason() {
+        let url = upstream(concat!(
+            "data: {\"choices\": [{\"text\": \" Deep\", \"finish_reason\": null}]}\n\n",
+            "data: {\"choices\": [{\"text\": \"\", \"finish_reason\": \"stop\", ",
+            "\"stop_reason\": \"###\"}], \"usage\": {\"completion_tokens\": 2}}\n\n",
+            "data: [DONE]\n\n",
+        ))
+        .await;
+        let backend = OpenAiProxyBackend::new(url, "model");
+        let responses: Vec<_> = backend
+            .schedule(request(vec!["###".to_string()]), CancellationToken::new())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .await
+            .unwrap();
+        let [InferStreamResponse::End {
+            token,
+            generated_text,
+            ..
+        }] = &responses[..]
+        else {
+            panic!("The stream must hold the last token only");
+        };
+        assert_eq!(token.text, " Deep");
+        assert!(matches!(
+            generated_text.finish_reason,
+            FinishReason::StopSequence
+        ));
+        // The usage reported by the server wins over the tokens counted in the stream
+        assert_eq!(generated_text.generated_tokens, 2);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_stream_without_finish_reason() {
+        // Events after `[DONE]` are ignored
+        let url = upstream(concat!(
+            "data: {\"choices\": [{\"text\": \" Deep\"}]}\n\ndata: [DONE]\n\n",
+            "data: {\"choices\": [{\"text\": \" is\", \"finish_reason\": \"length\"}]}\n\n",
+        ))
+        .await;
+        let backend = OpenAiProxyBackend::new(url, "model");
+        let responses: Vec<_> = backend
+            .schedule(request(vec![]), CancellationToken::new())
+            .unwrap()
+            .collect()
+            .await;
+        assert!(matches!(
+            responses[..],
+            [Err(InferError::GenerationError(_))]
+        ));
+    }
+}
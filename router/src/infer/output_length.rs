@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Number of completed requests kept to compute the rolling average
+const WINDOW_SIZE: usize = 256;
+/// Number of completed requests required before flagging anything
+const MIN_SAMPLES: usize = 16;
+
+/// Flags generations that are much longer than the recent ones
+///
+/// This is only used for reporting: a flagged request is never stopped.
+#[derive(Debug)]
+pub(crate) struct OutputLengthMonitor {
+    /// A request is flagged when its length exceeds `penalty` times the rolling average
+    penalty: f32,
+    lengths: Mutex<RollingLengths>,
+}
+
+#[derive(Debug, Default)]
+struct RollingLengths {
+    values: VecDeque<u32>,
+    sum: u64,
+}
+
+impl OutputLengthMonitor {
+    pub(crate) fn new(penalty: f32) -> Self {
+        Self {
+            penalty,
+            lengths: Mutex::new(RollingLengths::default()),
+        }
+    }
+
+    /// Record the length of a finished generation and return whether it is abnormally long
+    pub(crate) fn observe(&self, generated_tokens: u32) -> bool {
+        let mut lengths = self.lengths.lock().expect("output length lock poisoned");
+
+        let is_long = lengths.values.len() >= MIN_SAMPLES && {
+            let average = lengths.sum as f32 / lengths.values.len() as f32;
+            generated_tokens as f32 > average * self.penalty
+        };
+
+        if lengths.values.len() == WINDOW_SIZE {
+            let evicted = lengths.values.pop_front().unwrap_or_default();
+            lengths.sum -= evicted as u64;
+        }
+        lengths.values.push_back(generated_tokens);
+        lengths.sum += generated_tokens as u64;
+
+        is_long
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_output_flagged() {
+        let monitor = OutputLengthMonitor::new(4.0);
+        for i in 0..MIN_SAMPLES as u32 {
+            assert!(!monitor.observe(40 + i % 20));
+        }
+        // Abnormally long mock generation
+        assert!(monitor.observe(2048));
+        assert!(!monitor.observe(60));
+    }
+
+    #[test]
+    fn test_no_flag_before_warmup() {
+        let monitor = OutputLengthMonitor::new(2.0);
+        assert!(!monitor.observe(1));
+        assert!(!monitor.observe(4096));
+    }
+
+    #[test]
+    fn test_rolling_window_evicts_old_lengths() {
+        let monitor = OutputLengthMonitor::new(2.0);
+        for _ in 0..WINDOW_SIZE {
+            monitor.observe(10);
+        }
+        assert!(monitor.observe(100));
+        // Once the window only holds long generations they become the norm
+        for _ in 0..WINDOW_SIZE {
+            monitor.observe(100);
+        }
+        assert!(!monitor.observe(150));
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// Number of tokens hashed together, prefixes are matched in whole blocks
+pub(crate) const PREFIX_BLOCK_SIZE: usize = 16;
+
+/// Smoothing factor of the moving average of the prefill energy per token
+const PREFILL_EWMA_ALPHA: f64 = 0.1;
+
+/// Longest prefix of a request input that was already in a recent request
+///
+/// Backends with a KV cache can reuse the prefill of these tokens, see
+/// [`Backend::schedule_with_cache`](super::Backend::schedule_with_cache).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrefixCacheHint {
+    /// Hash of the token ids of the prefix
+    pub hash: u64,
+    /// Length of the prefix, in tokens
+    pub cached_tokens: usize,
+}
+
+/// Hashes of the prefixes of the recent inputs, by blocks of [`PREFIX_BLOCK_SIZE`] tokens
+///
+/// The hash of a block covers all the tokens before it, so a hit on a block is a hit on the
+/// whole prefix ending with it. The least recently used blocks are evicted first.
+#[derive(Debug)]
+pub(crate) struct PrefixCache {
+    capacity: usize,
+    state: Mutex<PrefixState>,
+}
+
+#[derive(Debug, Default)]
+struct PrefixState {
+    /// Last use of each block hash
+    blocks: HashMap<u64, u64>,
+    /// Block hashes by last use, the least recently used first
+    order: BTreeMap<u64, u64>,
+    clock: u64,
+    lookups: u64,
+    hits: u64,
+    /// Moving average of the prefill energy per input token of the misses, in millijoules
+    prefill_mj_per_token: Option<f64>,
+}
+
+impl PrefixCache {
+    /// Cache holding at most `capacity` blocks
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(PrefixState::default()),
+        }
+    }
+
+    /// Look up the longest cached prefix of `input_ids` and cache all of its prefixes
+    pub(crate) fn lookup(&self, input_ids: &[u32]) -> Option<PrefixCacheHint> {
+        let mut state = self.state.lock().unwrap();
+        let mut hint = None;
+        let mut hasher = DefaultHasher::new();
+        for (index, block) in input_ids.chunks_exact(PREFIX_BLOCK_SIZE).enumerate() {
+            block.hash(&mut hasher);
+            let hash = hasher.finish();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(last_use) = state.blocks.insert(hash, clock) {
+                state.order.remove(&last_use);
+                hint = Some(PrefixCacheHint {
+                    hash,
+                    cached_tokens: (index + 1) * PREFIX_BLOCK_SIZE,
+                });
+            }
+            state.order.insert(clock, hash);
+        }
+        while state.blocks.len() > self.capacity {
+            let Some((_, hash)) = state.order.pop_first() else {
+                break;
+            };
+            state.blocks.remove(&hash);
+        }
+
+        state.lookups += 1;
+        let result = match hint {
+            Some(_) => {
+                state.hits += 1;
+                "hit"
+            }
+            None => "miss",
+        };
+        metrics::counter!("tgi_prefix_cache_lookups", "result" => result).increment(1);
+        metrics::gauge!("tgi_prefix_cache_hit_rate").set(state.hits as f64 / state.lookups as f64);
+        hint
+    }
+
+    /// Learn the prefill energy per token from a request that did not hit the cache
+    pub(crate) fn record_prefill(&self, input_length: u32, prefill_energy: Option<u64>) {
+        let Some(prefill_energy) = prefill_energy.filter(|_| input_length > 0) else {
+            return;
+        };
+        let per_token = prefill_energy as f64 / input_length as f64;
+        let mut state = self.state.lock().unwrap();
+        state.prefill_mj_per_token = Some(match state.prefill_mj_per_token {
+            Some(average) => average + PREFILL_EWMA_ALPHA * (per_token - average),
+            None => per_token,
+        });
+    }
+
+    /// Prefill energy of the cached tokens, `None` until the energy of a miss was recorded
+    pub(crate) fn saved_energy_estimate(&self, hint: &PrefixCacheHint) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .prefill_mj_per_token
+            .map(|per_token| (per_token * hint.cached_tokens as f64).round() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(first: u32, blocks: usize) -> Vec<u32> {
+        (first..first + (blocks * PREFIX_BLOCK_SIZE) as u32).collect()
+    }
+
+    #[test]
+    fn test_longest_prefix() {
+        let cache = PrefixCache::new(64);
+        assert_eq!(cache.lookup(&input(0, 2)), None);
+
+        // The first two blocks are shared, the partial block at the end is never cached
+        let mut longer = input(0, 3);
+        longer.push(7);
+        let hint = cache.lookup(&longer).unwrap();
+        assert_eq!(hint.cached_tokens, 2 * PREFIX_BLOCK_SIZE);
+        assert_eq!(
+            cache.lookup(&longer).unwrap().cached_tokens,
+            3 * PREFIX_BLOCK_SIZE
+        );
+
+        // Same block after a different prefix
+        let mut other = input(1000, 1);
+        other.extend(&input(0, 1));
+        assert_eq!(cache.lookup(&other), None);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = PrefixCache::new(2);
+        cache.lookup(&input(0, 1));
+        cache.lookup(&input(100, 1));
+        cache.lookup(&input(0, 1));
+        // Evicts the least recently used block
+        cache.lookup(&input(200, 1));
+        assert!(cache.lookup(&input(0, 1)).is_some());
+        assert_eq!(cache.lookup(&input(100, 1)), None);
+    }
+
+    #[test]
+    fn test_saved_energy_estimate() {
+        let cache = PrefixCache::new(64);
+        let hint = PrefixCacheHint {
+            hash: 0,
+            cached_tokens: 32,
+        };
+        assert_eq!(cache.saved_energy_estimate(&hint), None);
+        cache.record_prefill(64, None);
+        cache.record_prefill(0, Some(10));
+        assert_eq!(cache.saved_energy_estimate(&hint), None);
+        cache.record_prefill(64, Some(128));
+        assert_eq!(cache.saved_energy_estimate(&hint), Some(64));
+    }
+}
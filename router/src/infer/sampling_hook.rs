@@ -0,0 +1,77 @@
+use crate::{GenerateParameters, Token};
+use std::sync::Arc;
+
+/// Called after every generated token, returns the sampling parameters of the rest of the
+/// generation or `None` to keep the current ones
+///
+/// New parameters are applied by scheduling the rest of the generation again, like a
+/// continuation of the request, so they are only followed up to the continuation limit.
+pub type SamplingHook = Arc<dyn Fn(&GenerationState) -> Option<SamplingOverride> + Send + Sync>;
+
+/// Progress of a generation, given to the [`SamplingHook`]
+#[derive(Debug)]
+pub struct GenerationState<'a> {
+    /// Tokens generated so far, over all the rounds
+    pub generated_tokens: u32,
+    /// Number of times the request was scheduled again
+    pub continuation_rounds: u32,
+    /// Last generated token
+    pub token: &'a Token,
+    /// Text generated since the request was last scheduled
+    pub round_text: &'a str,
+    /// Energy consumed so far in millijoules, `None` when energy is not tracked
+    pub energy_consumption: Option<u64>,
+    /// Sampling parameters of the current round, unset ones use the defaults of the backend
+    pub sampling: SamplingOverride,
+}
+
+/// Sampling parameters of a generation, `None` keeps the current value
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SamplingOverride {
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+impl SamplingOverride {
+    /// Sampling parameters of `parameters`
+    pub(crate) fn current(parameters: &GenerateParameters) -> Self {
+        Self {
+            temperature: parameters.temperature,
+            top_k: parameters.top_k,
+            top_p: parameters.top_p,
+            typical_p: parameters.typical_p,
+            repetition_penalty: parameters.repetition_penalty,
+            frequency_penalty: parameters.frequency_penalty,
+        }
+    }
+
+    /// Whether applying the override would change `parameters`
+    pub(crate) fn changes(&self, parameters: &GenerateParameters) -> bool {
+        self.merged(parameters) != Self::current(parameters)
+    }
+
+    pub(crate) fn apply(&self, parameters: &mut GenerateParameters) {
+        let merged = self.merged(parameters);
+        parameters.temperature = merged.temperature;
+        parameters.top_k = merged.top_k;
+        parameters.top_p = merged.top_p;
+        parameters.typical_p = merged.typical_p;
+        parameters.repetition_penalty = merged.repetition_penalty;
+        parameters.frequency_penalty = merged.frequency_penalty;
+    }
+
+    fn merged(&self, parameters: &GenerateParameters) -> Self {
+        Self {
+            temperature: self.temperature.or(parameters.temperature),
+            top_k: self.top_k.or(parameters.top_k),
+            top_p: self.top_p.or(parameters.top_p),
+            typical_p: self.typical_p.or(parameters.typical_p),
+            repetition_penalty: self.repetition_penalty.or(parameters.repetition_penalty),
+            frequency_penalty: self.frequency_penalty.or(parameters.frequency_penalty),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::infer::{InferError, InferStreamResponse};
+use std::collections::BTreeMap;
+
+/// Maximum number of out of order tokens buffered before a gap is considered fatal
+pub(crate) const SEQUENCE_REORDER_WINDOW: usize = 16;
+
+/// Restore the order of the tokens emitted by a backend using their sequence number
+///
+/// Backends that do not set `seq` are assumed to emit tokens in order and are passed through.
+#[derive(Debug)]
+pub(crate) struct TokenSequencer {
+    next_seq: u32,
+    window: usize,
+    pending: BTreeMap<u32, InferStreamResponse>,
+}
+
+impl TokenSequencer {
+    pub(crate) fn new(window: usize) -> Self {
+        Self {
+            next_seq: 0,
+            window,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Push a response coming from the backend and return the responses that can be forwarded
+    pub(crate) fn push(
+        &mut self,
+        response: InferStreamResponse,
+    ) -> Result<Vec<InferStreamResponse>, InferError> {
+        let seq = match response.seq() {
+            Some(seq) => seq,
+            None => return Ok(vec![response]),
+        };
+
+        if seq < self.next_seq || self.pending.contains_key(&seq) {
+            return Err(InferError::TokenSequenceError(format!(
+                "duplicated token with sequence number {seq}, expected {}",
+                self.next_seq
+            )));
+        }
+
+        if seq > self.next_seq {
+            if self.pending.len() >= self.window {
+                return Err(InferError::TokenSequenceError(format!(
+                    "gap in token sequence: expected {}, buffered {} tokens up to {seq}",
+                    self.next_seq,
+                    self.pending.len()
+                )));
+            }
+            self.pending.insert(seq, response);
+            return Ok(Vec::new());
+        }
+
+        // The expected token arrived, flush it along with all the contiguous buffered tokens
+        let mut ready = vec![response];
+        self.next_seq += 1;
+        while let Some(response) = self.pending.remove(&self.next_seq) {
+            ready.push(response);
+            self.next_seq += 1;
+        }
+        Ok(ready)
+    }
+
+    /// Check that no token is still waiting for a missing predecessor
+    pub(crate) fn finish(&self) -> Result<(), InferError> {
+        match self.pending.keys().next() {
+            Some(seq) => Err(InferError::TokenSequenceError(format!(
+                "backend stream ended with a gap: expected {}, next buffered token is {seq}",
+                self.next_seq
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::GeneratedText;
+    use crate::{FinishReason, Token};
+    use tokio::time::Instant;
+
+    fn token(id: u32) -> Token {
+        Token {
+            id,
+            text: format!("t{id}"),
+            logprob: 0.0,
+            special: false,
+            energy_consumption: None,
+            telemetry: None,
+            continuation_boundary: false,
+            step_energy: None,
+            offsets: None,
+            inter_token_latency_ms: None,
+        }
+    }
+
+    fn intermediate(seq: u32) -> InferStreamResponse {
+        InferStreamResponse::Intermediate {
+            token: token(seq),
+            top_tokens: vec![],
+            top_logprobs: vec![],
+            energy_consumption: None,
+            seq: Some(seq),
+        }
+    }
+
+    fn end(seq: u32) -> InferStreamResponse {
+        InferStreamResponse::End {
+            token: token(seq),
+            top_tokens: vec![],
+            top_logprobs: vec![],
+            generated_text: GeneratedText {
+                text: "done".to_string(),
+                generated_tokens: seq + 1,
+                finish_reason: FinishReason::Length,
+                seed: None,
+                length_limit: None,
+            },
+            start: Instant::now(),
+            queued: Instant::now(),
+            energy_consumption: None,
+            prefill_energy: None,
+            decode_energy: None,
+            prefill_energy_saved: None,
+            seq: Some(seq),
+        }
+    }
+
+    fn ids(responses: &[InferStreamResponse]) -> Vec<u32> {
+        responses
+            .iter()
+            .map(|response| match response {
+                InferStreamResponse::Intermediate { token, .. }
+                | InferStreamResponse::End { token, .. } => token.id,
+                InferStreamResponse::Prefill { .. } => panic!("Unexpected prefill"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sequencer_reorders_out_of_order_tokens() {
+        let mut sequencer = TokenSequencer::new(SEQUENCE_REORDER_WINDOW);
+        let mut output = vec![];
+        for response in [intermediate(2), intermediate(0), intermediate(1), end(3)] {
+            output.extend(sequencer.push(response).unwrap());
+        }
+        assert_eq!(ids(&output), vec![0, 1, 2, 3]);
+        assert!(matches!(output[3], InferStreamResponse::End { .. }));
+        sequencer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_sequencer_passes_through_unsequenced_tokens() {
+        let mut sequencer = TokenSequencer::new(SEQUENCE_REORDER_WINDOW);
+        let response = InferStreamResponse::Intermediate {
+            token: token(7),
+            top_tokens: vec![],
+            top_logprobs: vec![],
+            energy_consumption: None,
+            seq: None,
+        };
+        assert_eq!(ids(&sequencer.push(response).unwrap()), vec![7]);
+        let prefill = sequencer
+            .push(InferStreamResponse::Prefill {
+                tokens: vec![],
+                prefill_energy_mj: None,
+            })
+            .unwrap();
+        assert!(matches!(prefill[..], [InferStreamResponse::Prefill { .. }]));
+    }
+
+    #[test]
+    fn test_sequencer_gap_larger_than_window() {
+        let mut sequencer = TokenSequencer::new(2);
+        assert!(sequencer.push(intermediate(1)).unwrap().is_empty());
+        assert!(sequencer.push(intermediate(2)).unwrap().is_empty());
+        assert!(matches!(
+            sequencer.push(intermediate(3)),
+            Err(InferError::TokenSequenceError(_))
+        ));
+    }
+
+    #[test]
+    fn test_sequencer_gap_at_end_of_stream() {
+        let mut sequencer = TokenSequencer::new(SEQUENCE_REORDER_WINDOW);
+        assert_eq!(ids(&sequencer.push(intermediate(0)).unwrap()), vec![0]);
+        assert!(sequencer.push(end(2)).unwrap().is_empty());
+        assert!(matches!(
+            sequencer.finish(),
+            Err(InferError::TokenSequenceError(_))
+        ));
+    }
+
+    #[test]
+    fn test_sequencer_duplicated_token() {
+        let mut sequencer = TokenSequencer::new(SEQUENCE_REORDER_WINDOW);
+        sequencer.push(intermediate(0)).unwrap();
+        assert!(matches!(
+            sequencer.push(intermediate(0)),
+            Err(InferError::TokenSequenceError(_))
+        ));
+    }
+}
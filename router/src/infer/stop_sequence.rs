@@ -0,0 +1,98 @@
+/// Detect the stop sequences in the text of the generated tokens
+///
+/// A stop sequence can be split over several tokens, the end of the text seen so far is kept
+/// so that a sequence completed by a token is found even when it started in earlier ones.
+#[derive(Debug)]
+pub(crate) struct StopSequenceMatcher {
+    stop_sequences: Vec<String>,
+    /// End of the generated text, one byte shorter than the longest stop sequence
+    tail: String,
+    tail_len: usize,
+}
+
+impl StopSequenceMatcher {
+    /// `None` when there is no stop sequence to look for
+    pub(crate) fn new(stop_sequences: &[String]) -> Option<Self> {
+        let stop_sequences: Vec<String> = stop_sequences
+            .iter()
+            .filter(|stop| !stop.is_empty())
+            .cloned()
+            .collect();
+        let tail_len = stop_sequences.iter().map(String::len).max()? - 1;
+        Some(Self {
+            stop_sequences,
+            tail: String::new(),
+            tail_len,
+        })
+    }
+
+    /// Add the text of a token, returns the length in bytes of the part of `text` up to the end
+    /// of the first stop sequence it completes
+    pub(crate) fn push(&mut self, text: &str) -> Option<usize> {
+        let start = self.tail.len();
+        self.tail.push_str(text);
+        // Matches ending before `start` were found by the previous tokens
+        let stop_end = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop| {
+                self.tail
+                    .match_indices(stop.as_str())
+                    .map(|(index, _)| index + stop.len())
+                    .find(|end| *end > start)
+            })
+            .min();
+
+        if self.tail.len() > self.tail_len {
+            let mut cut = self.tail.len() - self.tail_len;
+            while !self.tail.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.tail.drain(..cut);
+        }
+        stop_end.map(|end| end - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(stop_sequences: &[&str]) -> StopSequenceMatcher {
+        let stop_sequences: Vec<_> = stop_sequences.iter().map(|s| s.to_string()).collect();
+        StopSequenceMatcher::new(&stop_sequences).unwrap()
+    }
+
+    #[test]
+    fn test_stop_sequence_in_one_token() {
+        assert!(StopSequenceMatcher::new(&[]).is_none());
+        assert!(StopSequenceMatcher::new(&[String::new()]).is_none());
+
+        let mut matcher = matcher(&["###"]);
+        assert_eq!(matcher.push("Hello"), None);
+        // The text after the stop sequence is trimmed
+        assert_eq!(matcher.push(" ### world"), Some(4));
+    }
+
+    #[test]
+    fn test_stop_sequence_split_over_two_tokens() {
+        let mut matcher = matcher(&["\nUser:"]);
+        assert_eq!(matcher.push("Sure.\nUs"), None);
+        assert_eq!(matcher.push("er: more"), Some(3));
+    }
+
+    #[test]
+    fn test_stop_sequence_split_over_three_tokens() {
+        let mut matcher = matcher(&["never", "<|end|>"]);
+        assert_eq!(matcher.push("done <|"), None);
+        assert_eq!(matcher.push("en"), None);
+        assert_eq!(matcher.push("d|>"), Some(3));
+    }
+
+    #[test]
+    fn test_stop_sequence_with_multibyte_characters() {
+        let mut matcher = matcher(&["éé"]);
+        assert_eq!(matcher.push("aé"), None);
+        assert_eq!(matcher.push("ébé"), Some(2));
+    }
+}
@@ -0,0 +1,342 @@
+use crate::infer::energy::EnergyMeter;
+use crate::infer::InferError;
+use crate::Token;
+use nvml_wrapper::bitmasks::device::ThrottleReasons;
+use nvml_wrapper::enum_wrappers::device::{Clock, TemperatureSensor};
+use nvml_wrapper::enums::device::SampleValue;
+use nvml_wrapper::structs::device::FieldId;
+use nvml_wrapper::sys_exports::field_id::{
+    NVML_FI_DEV_MEMORY_TEMP, NVML_FI_DEV_POWER_AVERAGE, NVML_FI_DEV_POWER_INSTANT,
+};
+use nvml_wrapper::Nvml;
+use serde::Serialize;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// GPU metrics that can be sampled alongside energy for every generated token
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TelemetryField {
+    /// Graphics clock in MHz
+    GraphicsClock,
+    /// Streaming multiprocessor clock in MHz
+    SmClock,
+    /// Memory clock in MHz
+    MemoryClock,
+    /// Percentage of time a kernel was running on the GPU
+    GpuUtilization,
+    /// Percentage of time the device memory was read or written
+    MemoryUtilization,
+    /// GPU die temperature in degrees Celsius
+    GpuTemperature,
+    /// Memory temperature in degrees Celsius
+    MemoryTemperature,
+    /// Instantaneous power draw in milliwatts
+    PowerInstant,
+    /// Power draw averaged by the driver in milliwatts
+    PowerAverage,
+}
+
+impl TelemetryField {
+    /// NVML field id when the metric is exposed through the field values API
+    fn field_id(&self) -> Option<FieldId> {
+        match self {
+            TelemetryField::MemoryTemperature => Some(FieldId(NVML_FI_DEV_MEMORY_TEMP)),
+            TelemetryField::PowerInstant => Some(FieldId(NVML_FI_DEV_POWER_INSTANT)),
+            TelemetryField::PowerAverage => Some(FieldId(NVML_FI_DEV_POWER_AVERAGE)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct TelemetryValue {
+    pub field: TelemetryField,
+    #[schema(example = 1410.0)]
+    pub value: f64,
+}
+
+/// Source of the extended per token telemetry
+pub(crate) trait TelemetrySource: Send + Sync {
+    /// Sample the given fields. Fields that could not be read are left out.
+    fn sample(&self, fields: &[TelemetryField]) -> Vec<TelemetryValue>;
+}
+
+/// Telemetry read from a GPU through NVML
+pub(crate) struct NvmlTelemetry {
+    nvml: Arc<Nvml>,
+    device_index: u32,
+}
+
+impl NvmlTelemetry {
+    pub(crate) fn new(nvml: Arc<Nvml>, device_index: u32) -> Self {
+        Self { nvml, device_index }
+    }
+}
+
+impl TelemetrySource for NvmlTelemetry {
+    fn sample(&self, fields: &[TelemetryField]) -> Vec<TelemetryValue> {
+        let device = match self.nvml.device_by_index(self.device_index) {
+            Ok(device) => device,
+            Err(err) => {
+                tracing::debug!("Could not get device for telemetry: {err}");
+                return Vec::new();
+            }
+        };
+
+        // Fields exposed by the field values API are read with a single driver call
+        let (batched, individual): (Vec<TelemetryField>, Vec<TelemetryField>) = fields
+            .iter()
+            .copied()
+            .partition(|field| field.field_id().is_some());
+        let mut values = Vec::with_capacity(fields.len());
+        if !batched.is_empty() {
+            let ids: Vec<FieldId> = batched.iter().filter_map(|f| f.field_id()).collect();
+            match device.field_values_for(&ids) {
+                Ok(samples) => {
+                    for (field, sample) in batched.iter().zip(samples) {
+                        let value =
+                            sample
+                                .and_then(|sample| sample.value)
+                                .map(|value| match value {
+                                    SampleValue::F64(v) => v,
+                                    SampleValue::U32(v) => v as f64,
+                                    SampleValue::U64(v) => v as f64,
+                                    SampleValue::I64(v) => v as f64,
+                                });
+                        if let Ok(value) = value {
+                            values.push(TelemetryValue {
+                                field: *field,
+                                value,
+                            });
+                        }
+                    }
+                }
+                Err(err) => tracing::debug!("Could not read NVML field values: {err}"),
+            }
+        }
+
+        for field in individual {
+            let value = match field {
+                TelemetryField::GraphicsClock => device.clock_info(Clock::Graphics),
+                TelemetryField::SmClock => device.clock_info(Clock::SM),
+                TelemetryField::MemoryClock => device.clock_info(Clock::Memory),
+                TelemetryField::GpuUtilization => device.utilization_rates().map(|u| u.gpu),
+                TelemetryField::MemoryUtilization => device.utilization_rates().map(|u| u.memory),
+                TelemetryField::GpuTemperature => device.temperature(TemperatureSensor::Gpu),
+                TelemetryField::MemoryTemperature
+                | TelemetryField::PowerInstant
+                | TelemetryField::PowerAverage => continue,
+            };
+            match value {
+                Ok(value) => values.push(TelemetryValue {
+                    field,
+                    value: value as f64,
+                }),
+                Err(err) => tracing::debug!("Could not read {field:?}: {err}"),
+            }
+        }
+        values
+    }
+}
+
+/// Thermal state of a device, to tell whether its clocks are being lowered
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct DeviceTelemetry {
+    #[schema(example = 0)]
+    pub device: u32,
+    /// GPU die temperature in degrees Celsius
+    #[schema(nullable = true, example = 65)]
+    pub temperature: Option<u32>,
+    /// Instantaneous power draw in milliwatts
+    #[schema(nullable = true, example = 250000)]
+    pub power_usage: Option<u32>,
+    /// Reasons the clocks are currently lowered, empty when running at full speed
+    #[schema(example = json!(["sw_thermal_slowdown"]))]
+    pub throttle_reasons: Vec<String>,
+}
+
+/// Names of the throttle reasons reported by NVML
+const THROTTLE_REASONS: [(ThrottleReasons, &str); 9] = [
+    (ThrottleReasons::GPU_IDLE, "gpu_idle"),
+    (
+        ThrottleReasons::APPLICATIONS_CLOCKS_SETTING,
+        "applications_clocks_setting",
+    ),
+    (ThrottleReasons::SW_POWER_CAP, "sw_power_cap"),
+    (ThrottleReasons::HW_SLOWDOWN, "hw_slowdown"),
+    (ThrottleReasons::SYNC_BOOST, "sync_boost"),
+    (ThrottleReasons::SW_THERMAL_SLOWDOWN, "sw_thermal_slowdown"),
+    (ThrottleReasons::HW_THERMAL_SLOWDOWN, "hw_thermal_slowdown"),
+    (
+        ThrottleReasons::HW_POWER_BRAKE_SLOWDOWN,
+        "hw_power_brake_slowdown",
+    ),
+    (
+        ThrottleReasons::DISPLAY_CLOCK_SETTING,
+        "display_clock_setting",
+    ),
+];
+
+fn throttle_reason_names(reasons: ThrottleReasons) -> Vec<String> {
+    THROTTLE_REASONS
+        .iter()
+        .filter(|(reason, _)| reasons.contains(*reason))
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+/// Read the thermal state of a device
+///
+/// Metrics the device does not support are left empty.
+pub(crate) fn device_telemetry(
+    nvml: &Nvml,
+    device_index: u32,
+) -> Result<DeviceTelemetry, InferError> {
+    let device = nvml
+        .device_by_index(device_index)
+        .map_err(|e| InferError::EnergyConsumptionError(e.to_string()))?;
+    let temperature = device
+        .temperature(TemperatureSensor::Gpu)
+        .inspect_err(|err| tracing::debug!("Could not read the temperature: {err}"))
+        .ok();
+    let power_usage = device
+        .power_usage()
+        .inspect_err(|err| tracing::debug!("Could not read the power draw: {err}"))
+        .ok();
+    let throttle_reasons = device
+        .current_throttle_reasons()
+        .inspect_err(|err| tracing::debug!("Could not read the throttle reasons: {err}"))
+        .map(throttle_reason_names)
+        .unwrap_or_default();
+    Ok(DeviceTelemetry {
+        device: device_index,
+        temperature,
+        power_usage,
+        throttle_reasons,
+    })
+}
+
+/// Device that energy can be measured on
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct DeviceInfo {
+    /// Index to pass to `--energy-devices`
+    #[schema(example = 0)]
+    pub index: u32,
+    #[schema(example = "NVIDIA A100-SXM4-80GB")]
+    pub name: String,
+    /// Total memory in bytes
+    #[schema(example = 85899345920u64)]
+    pub memory_total: u64,
+    /// Whether the device has an energy counter, its energy is estimated from its power draw
+    /// otherwise
+    #[schema(example = true)]
+    pub energy_counter_supported: bool,
+}
+
+/// List all the devices NVML can see, whether energy is measured on them or not
+pub(crate) fn list_devices(nvml: &Nvml) -> Result<Vec<DeviceInfo>, InferError> {
+    let nvml_error =
+        |e: nvml_wrapper::error::NvmlError| InferError::EnergyConsumptionError(e.to_string());
+    let count = nvml.device_count().map_err(nvml_error)?;
+    (0..count)
+        .map(|index| {
+            let device = nvml.device_by_index(index).map_err(nvml_error)?;
+            Ok(DeviceInfo {
+                index,
+                name: device.name().map_err(nvml_error)?,
+                memory_total: device.memory_info().map_err(nvml_error)?.total,
+                energy_counter_supported: nvml.energy_counter_supported(index),
+            })
+        })
+        .collect()
+}
+
+/// Attach the sampled telemetry to a generated token
+pub(crate) fn attach_telemetry(
+    token: &mut Token,
+    source: Option<&dyn TelemetrySource>,
+    fields: &[TelemetryField],
+) {
+    if let Some(source) = source.filter(|_| !fields.is_empty()) {
+        token.telemetry = Some(source.sample(fields));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a fixed value for every requested field
+    struct FakeTelemetry;
+
+    impl TelemetrySource for FakeTelemetry {
+        fn sample(&self, fields: &[TelemetryField]) -> Vec<TelemetryValue> {
+            fields
+                .iter()
+                .map(|&field| TelemetryValue { field, value: 42.0 })
+                .collect()
+        }
+    }
+
+    fn token() -> Token {
+        Token {
+            id: 0,
+            text: "a".to_string(),
+            logprob: 0.0,
+            special: false,
+            energy_consumption: None,
+            telemetry: None,
+            continuation_boundary: false,
+            step_energy: None,
+            offsets: None,
+            inter_token_latency_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_selected_fields_are_attached() {
+        let fields = [TelemetryField::SmClock, TelemetryField::GpuTemperature];
+        let mut token = token();
+        attach_telemetry(&mut token, Some(&FakeTelemetry), &fields);
+
+        let telemetry = token.telemetry.as_ref().unwrap();
+        let sampled: Vec<_> = telemetry.iter().map(|value| value.field).collect();
+        assert_eq!(sampled, fields);
+
+        let serialized = serde_json::to_value(&token).unwrap();
+        assert_eq!(serialized["telemetry"][0]["field"], "sm_clock");
+        assert_eq!(serialized["telemetry"][1]["field"], "gpu_temperature");
+        assert_eq!(serialized["telemetry"][1]["value"], 42.0);
+    }
+
+    #[test]
+    fn test_throttle_reason_names() {
+        assert!(throttle_reason_names(ThrottleReasons::NONE).is_empty());
+        assert_eq!(
+            throttle_reason_names(
+                ThrottleReasons::SW_POWER_CAP | ThrottleReasons::HW_THERMAL_SLOWDOWN
+            ),
+            vec!["sw_power_cap", "hw_thermal_slowdown"]
+        );
+    }
+
+    #[test]
+    fn test_telemetry_disabled_by_default() {
+        let mut token = token();
+        attach_telemetry(&mut token, Some(&FakeTelemetry), &[]);
+        assert!(token.telemetry.is_none());
+        attach_telemetry(&mut token, None, &[TelemetryField::SmClock]);
+        assert!(token.telemetry.is_none());
+
+        let serialized = serde_json::to_value(&token).unwrap();
+        assert!(serialized.get("telemetry").is_none());
+    }
+
+    #[test]
+    fn test_batched_fields() {
+        assert!(TelemetryField::PowerInstant.field_id().is_some());
+        assert!(TelemetryField::MemoryTemperature.field_id().is_some());
+        assert!(TelemetryField::SmClock.field_id().is_none());
+    }
+}
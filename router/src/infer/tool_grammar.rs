@@ -3,7 +3,7 @@ use crate::{
     FunctionDefinition, FunctionRef, FunctionsMap, JsonSchemaTool, Properties, Tool, ToolChoice,
 };
 use serde_json::{json, Map, Value};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub(crate) struct ToolGrammar {}
 
@@ -17,10 +17,72 @@ impl ToolGrammar {
             .ok_or_else(|| InferError::ToolError(format!("Tool with name {} not found", name)))
     }
 
+    /// Check the definitions of the tools before they are turned into a grammar
+    pub fn validate(tools: &[Tool]) -> Result<(), InferError> {
+        let mut names = HashSet::new();
+        for tool in tools {
+            let name = &tool.function.name;
+            let invalid = |reason: String| InferError::InvalidToolSchema {
+                tool_name: name.clone(),
+                reason,
+            };
+
+            if tool.r#type != "function" {
+                return Err(invalid(format!(
+                    "unsupported tool type `{}`, only `function` is supported",
+                    tool.r#type
+                )));
+            }
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(invalid(
+                    "the name must be made of letters, digits, underscores and dashes".to_string(),
+                ));
+            }
+            if !names.insert(name.as_str()) {
+                return Err(invalid("the name is used by several tools".to_string()));
+            }
+
+            let parameters = match &tool.function.arguments {
+                Value::Null => continue,
+                // Parameters sent as a string must hold a JSON object
+                Value::String(s) => serde_json::from_str(s)
+                    .map_err(|e| invalid(format!("parameters are not valid JSON: {e}")))?,
+                parameters => parameters.clone(),
+            };
+            let Value::Object(object) = &parameters else {
+                return Err(invalid("parameters must be a JSON object".to_string()));
+            };
+            jsonschema::draft202012::meta::validate(&parameters)
+                .map_err(|e| invalid(format!("parameters are not a valid JSON schema: {e}")))?;
+
+            let properties = match object.get("properties") {
+                Some(Value::Object(properties)) => Some(properties),
+                _ => None,
+            };
+            if let Some(Value::Array(required)) = object.get("required") {
+                for field in required {
+                    let field = field.as_str().unwrap_or_default();
+                    if !properties.is_some_and(|properties| properties.contains_key(field)) {
+                        return Err(invalid(format!(
+                            "required parameter `{field}` is not in the properties"
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn apply(
         tools: Vec<Tool>,
         tool_choice: ToolChoice,
     ) -> Result<Option<(Vec<Tool>, JsonSchemaTool)>, InferError> {
+        Self::validate(&tools)?;
+
         let tools_to_use = match tool_choice {
             ToolChoice::Function(function) => {
                 vec![Self::find_tool_by_name(&tools, &function.name)?]
@@ -121,3 +183,83 @@ impl ToolGrammar {
         Ok(Some((tools_to_use, tool_schema)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool(name: &str, arguments: Value) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: FunctionDefinition {
+                description: None,
+                name: name.to_string(),
+                arguments,
+            },
+        }
+    }
+
+    fn weather() -> Tool {
+        tool(
+            "get_weather",
+            json!({
+                "type": "object",
+                "properties": {
+                    "location": {"type": "string"},
+                    "days": {"type": "integer"}
+                },
+                "required": ["location"]
+            }),
+        )
+    }
+
+    fn invalid_schema(tools: &[Tool]) -> (String, String) {
+        match ToolGrammar::validate(tools) {
+            Err(InferError::InvalidToolSchema { tool_name, reason }) => (tool_name, reason),
+            other => panic!("Expected an invalid tool schema, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_valid_tools() {
+        let tools = [
+            weather(),
+            tool("no_parameters", Value::Null),
+            tool("as_string", json!(r#"{"type": "object"}"#)),
+        ];
+        ToolGrammar::validate(&tools).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_tools() {
+        let (name, reason) = invalid_schema(&[tool("get_weather", json!({"type": "strnig"}))]);
+        assert_eq!(name, "get_weather");
+        assert!(reason.starts_with("parameters are not a valid JSON schema"));
+
+        let (_, reason) = invalid_schema(&[tool("get_weather", json!(["location"]))]);
+        assert_eq!(reason, "parameters must be a JSON object");
+
+        let mut missing = weather();
+        missing.function.arguments["required"] = json!(["location", "unit"]);
+        let (_, reason) = invalid_schema(&[missing]);
+        assert_eq!(reason, "required parameter `unit` is not in the properties");
+
+        let (_, reason) = invalid_schema(&[weather(), weather()]);
+        assert_eq!(reason, "the name is used by several tools");
+
+        let (name, _) = invalid_schema(&[tool("get weather", Value::Null)]);
+        assert_eq!(name, "get weather");
+
+        let mut retrieval = weather();
+        retrieval.r#type = "retrieval".to_string();
+        assert!(ToolGrammar::validate(&[retrieval]).is_err());
+    }
+
+    #[test]
+    fn test_apply_validates_tools() {
+        assert!(matches!(
+            ToolGrammar::apply(vec![tool("", Value::Null)], ToolChoice::Auto),
+            Err(InferError::InvalidToolSchema { .. })
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::infer::Infer;
 use crate::{
     default_parameters,
-    server::{generate_internal, ComputeType},
+    server::{api_key, generate_internal, ComputeType},
     Deserialize, ErrorResponse, GenerateParameters, GenerateRequest, Serialize, ToSchema,
 };
 use axum::extract::{Extension, Path};
@@ -169,6 +169,7 @@ pub async fn kserve_model_metadata_ready(
 pub async fn kserve_model_infer(
     infer: Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
+    headers: HeaderMap,
     Json(payload): Json<InferenceRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
     let id = payload.id.clone();
@@ -182,6 +183,8 @@ pub async fn kserve_model_infer(
                     Json(ErrorResponse {
                         error: e.to_string(),
                         error_type: "utf8".to_string(),
+                        energy_consumption: None,
+                        template_error: None,
                     }),
                 )
             })
@@ -194,6 +197,8 @@ pub async fn kserve_model_infer(
             Json(ErrorResponse {
                 error: "Inputs and outputs length mismatch".to_string(),
                 error_type: "length mismatch".to_string(),
+                energy_consumption: None,
+                template_error: None,
             }),
         ));
     }
@@ -204,14 +209,18 @@ pub async fn kserve_model_infer(
         .map(|(str_input, output)| {
             let generate_request = GenerateRequest {
                 inputs: str_input.to_string(),
-                parameters: payload.parameters.clone(),
+                parameters: GenerateParameters {
+                    api_key: api_key(&headers),
+                    ..payload.parameters.clone()
+                },
                 add_special_tokens: true,
+                metadata: None,
             };
             let infer = infer.clone();
             let compute_type = compute_type.clone();
             let span = tracing::Span::current();
             async move {
-                generate_internal(infer, compute_type, Json(generate_request), span)
+                generate_internal(infer, compute_type, Json(generate_request), span, None)
                     .await
                     .map(|(_, _, Json(generation))| {
                         let generation_as_bytes = generation.generated_text.as_bytes().to_vec();
@@ -228,6 +237,8 @@ pub async fn kserve_model_infer(
                             Json(ErrorResponse {
                                 error: "Incomplete generation".into(),
                                 error_type: "Incomplete generation".into(),
+                                energy_consumption: None,
+                                template_error: None,
                             }),
                         )
                     })
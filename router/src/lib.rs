@@ -1,5 +1,6 @@
 /// Text Generation Inference Webserver
 pub mod config;
+pub mod energy_trace;
 pub mod infer;
 pub mod server;
 pub mod validation;
@@ -9,12 +10,18 @@ mod kserve;
 pub mod logging;
 
 mod chat;
+mod chunking;
+mod compression;
+mod grpc;
 mod sagemaker;
 pub mod usage_stats;
 mod vertex;
 
+use crate::energy_trace::EnergyReport;
+use crate::infer::energy::EnergyUnit;
+use crate::infer::telemetry::TelemetryValue;
 use crate::infer::tool_grammar::ToolGrammar;
-use crate::infer::{Infer, InferError};
+use crate::infer::{Infer, InferError, RequestClass};
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
 use serde::{Deserialize, Serialize};
@@ -138,7 +145,13 @@ pub struct HubTokenizerConfig {
     pub chat_template: Option<ChatTemplateVersions>,
     pub completion_template: Option<String>,
     pub bos_token: Option<TokenizerConfigToken>,
-    pub eos_token: Option<TokenizerConfigToken>,
+    /// End of sequence tokens, the first one is the `eos_token` of the chat templates
+    #[serde(default, rename = "eos_token", deserialize_with = "one_or_many_tokens")]
+    pub eos_tokens: Vec<TokenizerConfigToken>,
+    pub pad_token: Option<TokenizerConfigToken>,
+    pub unk_token: Option<TokenizerConfigToken>,
+    #[serde(default)]
+    pub additional_special_tokens: Vec<TokenizerConfigToken>,
     pub tokenizer_class: Option<String>,
     pub add_bos_token: Option<bool>,
     pub add_eos_token: Option<bool>,
@@ -152,6 +165,25 @@ impl HubTokenizerConfig {
     }
 }
 
+/// Models with several end of turn tokens list them all, e.g. `["<|eot_id|>", "</s>"]`
+fn one_or_many_tokens<'de, D>(deserializer: D) -> Result<Vec<TokenizerConfigToken>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(TokenizerConfigToken),
+        Many(Vec<TokenizerConfigToken>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(OneOrMany::One(token)) => vec![token],
+        Some(OneOrMany::Many(tokens)) => tokens,
+    })
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ChatTemplateStandalone {
     pub chat_template: ChatTemplateVersions,
@@ -173,6 +205,271 @@ impl TokenizerConfigToken {
     }
 }
 
+#[derive(Clone, Debug, Serialize, ToSchema, PartialEq)]
+pub(crate) struct SpecialToken {
+    #[schema(example = "</s>")]
+    pub content: String,
+    /// Id of the token, when the tokenizer maps it to a single id
+    #[schema(nullable = true, example = 2)]
+    pub id: Option<u32>,
+}
+
+impl From<&TokenizerConfigToken> for SpecialToken {
+    fn from(token: &TokenizerConfigToken) -> Self {
+        Self {
+            content: token.as_str().to_string(),
+            id: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, ToSchema, PartialEq)]
+pub(crate) struct SpecialTokensResponse {
+    #[schema(nullable = true)]
+    pub bos_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub eos_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub pad_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub unk_token: Option<SpecialToken>,
+    pub additional_special_tokens: Vec<SpecialToken>,
+}
+
+impl From<&HubTokenizerConfig> for SpecialTokensResponse {
+    fn from(config: &HubTokenizerConfig) -> Self {
+        Self {
+            bos_token: config.bos_token.as_ref().map(SpecialToken::from),
+            eos_token: config.eos_tokens.first().map(SpecialToken::from),
+            pad_token: config.pad_token.as_ref().map(SpecialToken::from),
+            unk_token: config.unk_token.as_ref().map(SpecialToken::from),
+            additional_special_tokens: config
+                .additional_special_tokens
+                .iter()
+                .map(SpecialToken::from)
+                .collect(),
+        }
+    }
+}
+
+impl SpecialTokensResponse {
+    pub(crate) fn tokens_mut(&mut self) -> impl Iterator<Item = &mut SpecialToken> {
+        [
+            self.bos_token.as_mut(),
+            self.eos_token.as_mut(),
+            self.pad_token.as_mut(),
+            self.unk_token.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.additional_special_tokens.iter_mut())
+    }
+}
+
+/// State of the energy measurement
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EnergyHealth {
+    /// The energy counters answer
+    Healthy,
+    /// The energy counters stopped answering, requests are still served without energy
+    Degraded,
+    /// Energy is not measured
+    Disabled,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct HealthResponse {
+    /// Whether the model can serve requests
+    #[schema(example = true)]
+    pub backend: bool,
+    #[schema(example = "healthy")]
+    pub energy: EnergyHealth,
+}
+
+/// Outcome of validating a request without generating it
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ValidationReport {
+    /// Whether the request would be accepted
+    #[schema(example = true)]
+    pub accepted: bool,
+    /// Why the request would be rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub error: Option<String>,
+    /// Number of tokens of the inputs, after truncation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 14)]
+    pub input_length: Option<u32>,
+    /// Predicted energy of the request in millijoules, from its token budget and the recent
+    /// tokens per joule, once enough requests were measured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 4200)]
+    pub estimated_energy: Option<u64>,
+    /// Estimated emissions of `estimated_energy`, in grams of CO2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.0005)]
+    pub estimated_co2_grams: Option<f64>,
+    /// Parameters the request would be generated with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub parameters: Option<EffectiveParameters>,
+}
+
+/// Parameters of a request once the defaults are applied
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct EffectiveParameters {
+    #[schema(example = 1.0)]
+    pub temperature: f32,
+    #[schema(example = 0)]
+    pub top_k: u32,
+    #[schema(example = 1.0)]
+    pub top_p: f32,
+    #[schema(example = 1.0)]
+    pub typical_p: f32,
+    #[schema(example = false)]
+    pub do_sample: bool,
+    /// Seed the request would be sampled with, drawn at random when the request has none
+    #[schema(example = 42)]
+    pub seed: u64,
+    #[schema(example = 1.0)]
+    pub repetition_penalty: f32,
+    #[schema(example = 0.0)]
+    pub frequency_penalty: f32,
+    #[schema(example = false)]
+    pub watermark: bool,
+    /// Whether the generation is constrained by a grammar
+    #[schema(example = false)]
+    pub grammar: bool,
+    #[schema(example = 20)]
+    pub max_new_tokens: u32,
+    /// Maximum number of generated tokens over all the continuations of the request
+    #[schema(example = 20)]
+    pub max_total_new_tokens: u32,
+    #[schema(example = json!(["photographer"]))]
+    pub stop: Vec<String>,
+    #[schema(example = 1024)]
+    pub truncate: u32,
+    #[schema(example = 0)]
+    pub top_n_tokens: u32,
+}
+
+/// Benchmark generating the same prompt several times in a row
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct BenchmarkRequest {
+    /// Prompt generated by every iteration
+    #[serde(default = "default_benchmark_prompt")]
+    #[schema(example = "Write a short story about a robot learning to paint.")]
+    pub prompt: String,
+    /// Number of generations, run one after the other
+    #[serde(default = "default_benchmark_iterations")]
+    #[schema(minimum = 1, example = 5)]
+    pub iterations: u32,
+    /// Tokens generated by every iteration, greedily
+    #[serde(default = "default_benchmark_max_new_tokens")]
+    #[schema(minimum = 1, example = 64)]
+    pub max_new_tokens: u32,
+}
+
+fn default_benchmark_prompt() -> String {
+    infer::benchmark::DEFAULT_PROMPT.to_string()
+}
+
+fn default_benchmark_iterations() -> u32 {
+    infer::benchmark::DEFAULT_ITERATIONS
+}
+
+fn default_benchmark_max_new_tokens() -> u32 {
+    infer::benchmark::DEFAULT_MAX_NEW_TOKENS
+}
+
+/// Aggregate of the generations of a benchmark
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct BenchmarkReport {
+    #[schema(example = 5)]
+    pub iterations: u32,
+    /// Tokens generated over all the iterations
+    #[schema(example = 320)]
+    pub generated_tokens: u32,
+    /// Duration of each generation, from the request to the last token
+    pub latency_ms: BenchmarkStats,
+    pub tokens_per_second: BenchmarkStats,
+    /// Always in millijoules, whatever the energy unit of the server. Not set when energy is
+    /// not tracked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub millijoules_per_token: Option<BenchmarkStats>,
+}
+
+/// Distribution of a measure over the iterations of a benchmark, percentiles are nearest ranks
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct BenchmarkStats {
+    #[schema(example = 812.5)]
+    pub mean: f64,
+    #[schema(example = 805.0)]
+    pub p50: f64,
+    #[schema(example = 870.0)]
+    pub p95: f64,
+}
+
+/// Properties of the served model that clients adapt their requests to
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ModelMetadata {
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model_id: String,
+    #[schema(nullable = true, example = "e985a63cdc139290c5f700ff1929f0b5942cced2")]
+    pub model_sha: Option<String>,
+    #[schema(example = "1024")]
+    pub max_input_tokens: usize,
+    #[schema(example = "2048")]
+    pub max_total_tokens: usize,
+    /// Whether the model has a chat template, `/v1/chat/completions` needs one
+    #[schema(example = true)]
+    pub chat_template: bool,
+    /// Whether the energy of the requests is measured on this deployment
+    #[schema(example = true)]
+    pub energy_measurement: bool,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct PowerResponse {
+    /// Indices of the devices energy is measured on
+    #[schema(example = json!([0]))]
+    pub devices: Vec<u32>,
+    /// Instantaneous power draw of each device in milliwatts
+    #[schema(example = json!([250000]))]
+    pub power_usage: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct ConcurrencyLimit {
+    /// Maximum number of requests running at the same time
+    #[schema(example = 128)]
+    pub limit: usize,
+}
+
+/// Aggregate energy of the requests served since the router started
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct EnergySummary {
+    /// Finished requests
+    #[schema(example = 120)]
+    pub requests: u64,
+    #[schema(example = 9600)]
+    pub generated_tokens: u64,
+    /// Energy consumed by the requests whose energy was measured
+    #[schema(example = 480.5)]
+    pub energy_joules: f64,
+    /// Generated tokens per joule of the requests whose energy was measured
+    #[schema(nullable = true, example = 19.9)]
+    pub tokens_per_joule: Option<f64>,
+    /// Current power draw of all the devices, `null` when it cannot be read
+    #[schema(nullable = true, example = 250.0)]
+    pub power_watts: Option<f64>,
+    /// Moving average of the tokens per joule of the finished requests
+    #[schema(nullable = true, example = 20.4)]
+    pub efficiency_ewma: Option<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "processor_class")]
 pub enum HubPreprocessorConfig {
@@ -222,6 +519,32 @@ impl HubProcessorConfig {
     }
 }
 
+/// Scheduling hint given to the backend
+///
+/// Backends are free to ignore it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EnergyPriority {
+    /// Maximize the throughput, the current behavior
+    #[default]
+    Throughput,
+    /// Favor the energy efficiency, for example with smaller batches, at the cost of latency
+    EnergyEfficient,
+}
+
+/// How the streamed tokens are grouped into events
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Chunking {
+    /// One event per token
+    #[default]
+    None,
+    /// One event per word, sent when a whitespace ends it
+    Word,
+    /// One event per sentence, sent when a `.`, `!`, `?` or a newline ends it
+    Sentence,
+}
+
 #[derive(Clone, Debug, Deserialize, ToSchema, Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 #[serde(tag = "type", content = "value")]
@@ -231,9 +554,14 @@ pub(crate) enum GrammarType {
     /// JSON Schema is a declarative language that allows to annotate JSON documents
     /// with types and descriptions.
     #[serde(rename = "json")]
-    #[serde(alias = "json_object")]
     #[schema(example = json ! ({"properties": {"location":{"type": "string"}}}))]
     Json(serde_json::Value),
+    /// OpenAI JSON mode: the output is constrained to a valid JSON object.
+    ///
+    /// A JSON Schema can still be given in `value`, in which case it behaves like `json`.
+    #[serde(rename = "json_object")]
+    #[schema(value_type = Option<Object>)]
+    JsonObject(Option<serde_json::Value>),
     #[serde(rename = "regex")]
     Regex(String),
 }
@@ -277,6 +605,12 @@ pub struct Info {
     pub sha: Option<&'static str>,
     #[schema(nullable = true, example = "null")]
     pub docker_label: Option<&'static str>,
+
+    /// Interval between the keep-alive comments of idle SSE streams, in milliseconds. Defaults
+    /// to 15 seconds, 0 disables the keep-alive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub sse_keep_alive_interval_ms: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema, Default)]
@@ -403,6 +737,12 @@ pub(crate) struct GenerateParameters {
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 5)]
     pub top_n_tokens: Option<u32>,
 
+    /// The number of most likely tokens to return at each step as raw `[id, logprob]` pairs,
+    /// without decoding their text. Independent of `top_n_tokens`.
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 5)]
+    pub top_logprobs: Option<u32>,
+
     /// Grammar constraints for the generation.
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
@@ -412,6 +752,116 @@ pub(crate) struct GenerateParameters {
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub adapter_id: Option<String>,
+
+    /// Class of the request used for admission control.
+    /// Inferred from `max_new_tokens` when not set.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "interactive")]
+    pub request_class: Option<RequestClass>,
+
+    /// API key of the tenant the request counts against, taken from the `x-api-key` header
+    #[serde(skip)]
+    pub api_key: Option<String>,
+
+    /// Whether to continue the generation when the length limit of a round is reached.
+    /// The generated text is appended to the inputs and scheduled again, at most
+    /// `MAX_CONTINUATION_ROUNDS` times. Each round is sampled with its own seed, derived from
+    /// `seed`, so the output differs from a single round of the same length.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub continue_on_length: bool,
+
+    /// Maximum number of tokens generated over all the rounds when `continue_on_length` is set.
+    /// `max_new_tokens` then bounds every round instead of the whole generation. Ignored when
+    /// the generation is not continued.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub max_total_new_tokens: Option<u32>,
+
+    /// Maximum energy the request may consume, in millijoules.
+    /// The generation stops with the `energy_budget` finish reason once it is exceeded.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub max_energy_millijoules: Option<u64>,
+
+    /// Maximum duration of the generation, in milliseconds from when the request is scheduled.
+    /// The generation stops with the `time_limit` finish reason once it is exceeded.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub max_time_ms: Option<u64>,
+
+    /// Whether to report the energy net of the idle draw of the devices, measured at startup.
+    /// The reported energy is then the energy spent on top of what the devices would have drawn
+    /// doing nothing. Has no effect when energy is not tracked.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub subtract_idle: bool,
+
+    /// Whether to measure the energy of the request. Disabling it saves the reads of the energy
+    /// counter on every token, the request is then returned without energy.
+    #[serde(default = "default_true")]
+    #[schema(default = "true", example = true)]
+    pub measure_energy: bool,
+
+    /// Hint for the backend on whether to favor throughput or energy efficiency when scheduling
+    /// the request. Backends are free to ignore it.
+    #[serde(default)]
+    #[schema(default = "throughput", example = "energy_efficient")]
+    pub energy_priority: EnergyPriority,
+
+    /// Unit of the energies in the response. Defaults to the unit configured on the server,
+    /// millijoules unless told otherwise.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "joules")]
+    pub energy_unit: Option<EnergyUnit>,
+
+    /// Whether to return the byte range of every generated token in the generated text.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub return_offsets: bool,
+
+    /// Whether to return the time elapsed since the previous token with every generated token.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub return_inter_token_latency: bool,
+
+    /// Whether to stream the generated tokens as their id, logprob and energy only, leaving
+    /// the decoding to the client. The last event is sent in full. Only applies to
+    /// `/generate_stream`.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub token_ids_only: bool,
+
+    /// Whether to group the streamed tokens into words or sentences, sending an event per group
+    /// instead of one per token. The tokens of a group are merged: their text is concatenated
+    /// and their energies are summed. The top tokens are not sent with the grouped events.
+    #[serde(default)]
+    #[schema(default = "none", example = "word")]
+    pub chunking: Chunking,
+
+    /// Priority of the request when the server is at its concurrency limit. Requests with a
+    /// priority wait for a slot instead of being rejected, for up to 30 seconds, the highest
+    /// priority being admitted first and requests of the same priority in their order of arrival.
+    /// Requests without a priority are rejected right away, also while requests with a priority
+    /// are waiting.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = 1)]
+    pub priority: Option<u8>,
 }
 
 fn default_parameters() -> GenerateParameters {
@@ -433,8 +883,24 @@ fn default_parameters() -> GenerateParameters {
         decoder_input_details: false,
         seed: None,
         top_n_tokens: None,
+        top_logprobs: None,
         grammar: None,
         adapter_id: None,
+        request_class: None,
+        api_key: None,
+        continue_on_length: false,
+        max_total_new_tokens: None,
+        max_energy_millijoules: None,
+        max_time_ms: None,
+        subtract_idle: false,
+        measure_energy: true,
+        energy_priority: EnergyPriority::Throughput,
+        energy_unit: None,
+        return_offsets: false,
+        return_inter_token_latency: false,
+        token_ids_only: false,
+        chunking: Chunking::None,
+        priority: None,
     }
 }
 
@@ -521,6 +987,12 @@ pub struct CompletionRequest {
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub stop: Option<Vec<String>>,
+
+    /// Echo back the prompt in addition to the completion. The logprobs of the prompt tokens are
+    /// then returned before the ones of the completion, the first prompt token has none.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub echo: bool,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -581,6 +1053,9 @@ pub(crate) struct ChatCompletionComplete {
     pub message: OutputMessage,
     pub logprobs: Option<ChatCompletionLogprobs>,
     pub finish_reason: String,
+    /// Share of the energy of the request attributed to this choice, when `n` > 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub energy_consumption: Option<u64>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
@@ -663,6 +1138,27 @@ pub(crate) struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    /// GPU energy consumed by the request in millijoules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 1000000)]
+    pub energy_consumption: Option<u64>,
+}
+
+impl Usage {
+    /// Usage of the generations of `reports`, without energy when none of them was measured
+    pub(crate) fn from_reports<'a>(reports: impl IntoIterator<Item = &'a EnergyReport>) -> Self {
+        reports
+            .into_iter()
+            .fold(Usage::default(), |usage, report| Usage {
+                prompt_tokens: usage.prompt_tokens + report.input_tokens,
+                completion_tokens: usage.completion_tokens + report.generated_tokens,
+                total_tokens: usage.total_tokens + report.input_tokens + report.generated_tokens,
+                energy_consumption: match (usage.energy_consumption, report.energy_mj) {
+                    (Some(total), Some(energy)) => Some(total + energy),
+                    (total, energy) => total.or(energy),
+                },
+            })
+    }
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -686,6 +1182,7 @@ impl ChatCompletion {
         return_logprobs: bool,
         tool_calls: Option<Vec<ToolCall>>,
         prompt_tokens: u32,
+        energy_consumption: Option<u64>,
     ) -> Self {
         let message = match (output, tool_calls) {
             (Some(content), None) => OutputMessage::ChatMessage(TextMessage {
@@ -725,14 +1222,40 @@ impl ChatCompletion {
                 logprobs: return_logprobs
                     .then(|| ChatCompletionLogprobs::from((details.tokens, details.top_tokens))),
                 finish_reason: details.finish_reason.format(true),
+                energy_consumption: None,
             }],
             usage: Usage {
                 prompt_tokens,
                 completion_tokens: details.generated_tokens,
                 total_tokens: prompt_tokens + details.generated_tokens,
+                energy_consumption,
             },
         }
     }
+
+    /// Add another choice generated from the same prompt, counted in the usage
+    pub(crate) fn push_choice(
+        &mut self,
+        content: String,
+        details: Details,
+        return_logprobs: bool,
+        energy_consumption: Option<u64>,
+    ) {
+        self.choices.push(ChatCompletionComplete {
+            index: self.choices.len() as u32,
+            message: OutputMessage::ChatMessage(TextMessage {
+                role: "assistant".into(),
+                content,
+                ..Default::default()
+            }),
+            logprobs: return_logprobs
+                .then(|| ChatCompletionLogprobs::from((details.tokens, details.top_tokens))),
+            finish_reason: details.finish_reason.format(true),
+            energy_consumption,
+        });
+        self.usage.completion_tokens += details.generated_tokens;
+        self.usage.total_tokens += details.generated_tokens;
+    }
 }
 #[derive(Clone, Serialize, ToSchema)]
 #[cfg_attr(test, derive(Debug))]
@@ -745,6 +1268,22 @@ pub(crate) struct ChatCompletionChunk {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionChoice>,
     pub usage: Option<Usage>,
+    /// Only sent when `stream_options.include_energy` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy: Option<ChunkEnergy>,
+}
+
+/// Energy of a streamed request so far
+#[derive(Clone, Serialize, ToSchema)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub(crate) struct ChunkEnergy {
+    /// GPU energy consumed since the start of the request in millijoules
+    #[schema(nullable = true, example = 120000)]
+    pub energy_consumption: Option<u64>,
+    /// Generated tokens per joule, only sent on the last chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 2.5)]
+    pub tokens_per_joule: Option<f64>,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -801,6 +1340,7 @@ impl ChatCompletionChunk {
             system_fingerprint,
             choices,
             usage,
+            energy: None,
         }
     }
 }
@@ -848,9 +1388,9 @@ pub(crate) struct ChatRequest {
     #[schema(default = "1024", example = "32")]
     pub max_tokens: Option<u32>,
 
-    /// UNUSED
     /// How many chat completion choices to generate for each input message. Note that you will be charged based on the
     /// number of generated tokens across all of the choices. Keep n as 1 to minimize costs.
+    /// Not supported when streaming or using tools.
     #[serde(default)]
     #[schema(nullable = true, example = "2")]
     pub n: Option<u32>,
@@ -921,10 +1461,27 @@ pub(crate) struct ChatRequest {
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub energy_consumption: Option<u64>,
+
+    /// Jinja chat template used instead of the template of the model for this request.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub chat_template: Option<String>,
+
+    /// Name of the template of the model to apply, for models shipping several templates.
+    /// The applied template is returned in the `x-chat-template` header.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "tool_use")]
+    pub template_name: Option<String>,
 }
 
 impl ChatRequest {
-    fn try_into_generate(self, infer: &Infer) -> Result<(GenerateRequest, bool), InferError> {
+    /// Build the generate request of the chat request
+    ///
+    /// Also returns whether tools are used and the name of the applied chat template.
+    fn try_into_generate(
+        self,
+        infer: &Infer,
+    ) -> Result<(GenerateRequest, bool, Option<String>), InferError> {
         let ChatRequest {
             model,
             max_tokens,
@@ -940,8 +1497,12 @@ impl ChatRequest {
             frequency_penalty,
             top_p,
             top_logprobs,
+            chat_template,
+            template_name,
             ..
         } = self;
+        let chat_template = chat_template.as_deref();
+        let template_name = template_name.as_deref();
 
         let repetition_penalty = presence_penalty.map(|x| x + 2.0);
         let max_new_tokens = max_tokens;
@@ -961,9 +1522,10 @@ impl ChatRequest {
             ));
         }
 
-        let (inputs, grammar, using_tools) = match response_format {
+        let ((inputs, applied_template), grammar, using_tools) = match response_format {
             Some(format) => {
-                let inputs = infer.apply_chat_template(messages, None)?;
+                let inputs =
+                    infer.apply_chat_template(messages, None, chat_template, template_name)?;
                 (inputs, Some(format), false)
             }
             None => {
@@ -971,21 +1533,29 @@ impl ChatRequest {
                     match ToolGrammar::apply(tools, tool_choice)? {
                         Some((updated_tools, tool_schema)) => {
                             let grammar = GrammarType::Json(serde_json::json!(tool_schema));
-                            let inputs: String = infer.apply_chat_template(
+                            let inputs = infer.apply_chat_template(
                                 messages,
                                 Some((updated_tools, tool_prompt)),
+                                chat_template,
+                                template_name,
                             )?;
                             (inputs, Some(grammar), true)
                         }
                         None => {
                             // same as if no response_format or tools are set
-                            let inputs = infer.apply_chat_template(messages, None)?;
+                            let inputs = infer.apply_chat_template(
+                                messages,
+                                None,
+                                chat_template,
+                                template_name,
+                            )?;
                             (inputs, None, false)
                         }
                     }
                 } else {
                     // if no response_format or tools are set simply apply the chat template to generate inputs
-                    let inputs = infer.apply_chat_template(messages, None)?;
+                    let inputs =
+                        infer.apply_chat_template(messages, None, chat_template, template_name)?;
                     (inputs, None, false)
                 }
             }
@@ -995,6 +1565,7 @@ impl ChatRequest {
             GenerateRequest {
                 inputs: inputs.to_string(),
                 add_special_tokens: false,
+                metadata: None,
                 parameters: GenerateParameters {
                     best_of: None,
                     temperature,
@@ -1013,11 +1584,28 @@ impl ChatRequest {
                     decoder_input_details: false,
                     seed,
                     top_n_tokens: top_logprobs,
+                    top_logprobs: None,
                     grammar,
                     adapter_id: model.filter(|m| *m != "tgi"),
+                    request_class: None,
+                    api_key: None,
+                    continue_on_length: false,
+                    max_total_new_tokens: None,
+                    max_energy_millijoules: None,
+                    max_time_ms: None,
+                    subtract_idle: false,
+                    measure_energy: true,
+                    energy_priority: EnergyPriority::Throughput,
+                    energy_unit: None,
+                    return_offsets: false,
+                    return_inter_token_latency: false,
+                    token_ids_only: false,
+                    chunking: Chunking::None,
+                    priority: None,
                 },
             },
             using_tools,
+            applied_template,
         ))
     }
 
@@ -1052,6 +1640,10 @@ struct StreamOptions {
     #[schema(example = "true")]
     #[serde(default)]
     include_usage: bool,
+    /// If set, every chunk carries an `energy` field with the energy consumed so far, and the last chunk with a `finish_reason` also carries the generated tokens per joule. Not part of the OpenAI API.
+    #[schema(example = "false")]
+    #[serde(default)]
+    include_energy: bool,
 }
 
 pub fn default_tool_prompt() -> String {
@@ -1196,11 +1788,57 @@ pub(crate) struct ChatTemplateInputs<'a> {
     tools: Option<Vec<Tool>>,
 }
 
-#[derive(Clone, Deserialize, Serialize, ToSchema, Default, Debug, PartialEq)]
+#[derive(Clone, Deserialize, ToSchema, Default, Debug, PartialEq)]
 pub struct ToolCall {
     pub id: String,
     pub r#type: String,
     pub function: FunctionDefinition,
+    /// The generation hit the token limit in the middle of the arguments,
+    /// which are then returned as an incomplete JSON string
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl Serialize for ToolCall {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("ToolCall", 3 + usize::from(self.truncated))?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("type", &self.r#type)?;
+        if self.truncated {
+            state.serialize_field("function", &PartialFunction(&self.function))?;
+            state.serialize_field("truncated", &true)?;
+        } else {
+            state.serialize_field("function", &self.function)?;
+        }
+        state.end()
+    }
+}
+
+/// Function of a truncated tool call, whose partial arguments are returned as generated
+struct PartialFunction<'a>(&'a FunctionDefinition);
+
+impl Serialize for PartialFunction<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let function = self.0;
+        let mut state = serializer.serialize_struct("FunctionDefinition", 3)?;
+        state.serialize_field("description", &function.description)?;
+        state.serialize_field("name", &function.name)?;
+        match &function.arguments {
+            serde_json::Value::String(arguments) => {
+                state.serialize_field("arguments", arguments)?
+            }
+            arguments => state.serialize_field("arguments", &arguments.to_string())?,
+        }
+        state.end()
+    }
 }
 
 #[derive(Clone, Deserialize, ToSchema, Serialize, Debug, PartialEq)]
@@ -1331,6 +1969,12 @@ pub(crate) struct GenerateRequest {
     /// we shouldn't add the special tokens.
     #[serde(default = "default_true", skip)]
     pub add_special_tokens: bool,
+
+    /// Opaque value echoed as is in the response and the energy log, e.g. to join the energy of
+    /// the request with the ids of the client
+    #[serde(default)]
+    #[schema(value_type = Option<Object>, nullable = true, example = json!({"experiment": "a"}))]
+    pub metadata: Option<serde_json::Value>,
 }
 
 fn default_true() -> bool {
@@ -1346,6 +1990,10 @@ pub(crate) struct CompatGenerateRequest {
     #[serde(default)]
     #[schema(default = "false")]
     pub stream: bool,
+    /// Opaque value echoed as is in the response
+    #[serde(default)]
+    #[schema(value_type = Option<Object>, nullable = true, example = json!({"experiment": "a"}))]
+    pub metadata: Option<serde_json::Value>,
 }
 
 impl From<CompatGenerateRequest> for GenerateRequest {
@@ -1354,11 +2002,12 @@ impl From<CompatGenerateRequest> for GenerateRequest {
             inputs: req.inputs,
             add_special_tokens: true,
             parameters: req.parameters,
+            metadata: req.metadata,
         }
     }
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct PrefillToken {
     #[schema(example = 0)]
     pub id: u32,
@@ -1380,6 +2029,36 @@ pub struct Token {
     pub special: bool,
     #[schema(nullable = true, example = 1000000)]
     pub energy_consumption: Option<u64>,
+    /// Extended GPU telemetry sampled with the token, when enabled on the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub telemetry: Option<Vec<TelemetryValue>>,
+    /// The generation stopped on this token because of the length limit and was continued
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[schema(example = "false")]
+    pub continuation_boundary: bool,
+    /// Energy consumed by the generation step that produced the token, set on the top tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub step_energy: Option<u64>,
+    /// Byte range of the token in the generated text, when `return_offsets` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub offsets: Option<TokenOffsets>,
+    /// Milliseconds since the previous token, or since the request was scheduled for the first
+    /// one, when `return_inter_token_latency` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 25.4)]
+    pub inter_token_latency_ms: Option<f64>,
+}
+
+/// Byte range of a token in the generated text, special tokens having an empty range
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+pub struct TokenOffsets {
+    #[schema(example = 0)]
+    pub start: usize,
+    #[schema(example = 4)]
+    pub stop: usize,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -1394,7 +2073,7 @@ pub struct SimpleToken {
     stop: usize,
 }
 
-#[derive(Debug, Serialize, ToSchema, Clone)]
+#[derive(Debug, Serialize, ToSchema, Clone, PartialEq)]
 #[serde(rename_all(serialize = "snake_case"))]
 #[schema(example = "Length")]
 pub enum FinishReason {
@@ -1405,6 +2084,22 @@ pub enum FinishReason {
     EndOfSequenceToken,
     #[schema(rename = "stop_sequence")]
     StopSequence,
+    #[schema(rename = "energy_budget")]
+    EnergyBudget,
+    #[schema(rename = "time_limit")]
+    TimeLimit,
+}
+
+/// Token limit that stopped a generation with the `length` finish reason
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthLimit {
+    /// `max_new_tokens`, which bounds a single round when the generation is continued
+    MaxNewTokens,
+    /// `max_total_new_tokens`, which bounds all the rounds of a continued generation
+    MaxTotalNewTokens,
+    /// The generation was continued as many times as allowed
+    ContinuationRounds,
 }
 
 impl std::fmt::Display for FinishReason {
@@ -1413,6 +2108,8 @@ impl std::fmt::Display for FinishReason {
             FinishReason::Length => write!(f, "length"),
             FinishReason::EndOfSequenceToken => write!(f, "eos_token"),
             FinishReason::StopSequence => write!(f, "stop_sequence"),
+            FinishReason::EnergyBudget => write!(f, "energy_budget"),
+            FinishReason::TimeLimit => write!(f, "time_limit"),
         }
     }
 }
@@ -1446,6 +2143,10 @@ pub(crate) struct BestOfSequence {
 pub(crate) struct Details {
     #[schema(example = "length")]
     pub finish_reason: FinishReason,
+    /// Limit the generation stopped at, when the finish reason is `length`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "max_new_tokens")]
+    pub length_limit: Option<LengthLimit>,
     #[schema(example = 1)]
     pub generated_tokens: u32,
     #[schema(nullable = true, example = 42)]
@@ -1456,6 +2157,22 @@ pub(crate) struct Details {
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub top_tokens: Vec<Vec<Token>>,
+    /// `[id, logprob]` pairs of the most likely tokens of each step, when `top_logprobs` is set
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schema(value_type = Vec<Vec<Vec<f32>>>, example = json!([[[1, -0.2], [7, -1.9]]]))]
+    pub top_logprobs: Vec<Vec<(u32, f32)>>,
+}
+
+impl Details {
+    /// Logprobs of the prompt tokens followed by the ones of the generated tokens, `NaN` for the
+    /// first prompt token
+    pub(crate) fn echo_logprobs(&self) -> Vec<f32> {
+        self.prefill
+            .iter()
+            .map(|token| token.logprob)
+            .chain(self.tokens.iter().map(|token| token.logprob))
+            .collect()
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1466,6 +2183,112 @@ pub(crate) struct GenerateResponse {
     pub details: Option<Details>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub energy_consumption: Option<u64>,
+    /// Energy consumed until the first token was generated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefill_energy: Option<u64>,
+    /// Energy consumed generating the following tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decode_energy: Option<u64>,
+    /// Estimated prefill energy saved by the cached prefix of the input
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefill_energy_saved_estimate: Option<u64>,
+    /// Energy consumed by all the `best_of` sequences together. `energy_consumption` is then the
+    /// share of this sequence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_energy_consumption: Option<u64>,
+    /// Energy consumed by the `best_of` sequences that were not returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wasted_energy: Option<u64>,
+    /// Summary of the energies of the generation steps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_energy_stats: Option<TokenEnergyStats>,
+    /// Generated tokens per joule consumed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_per_joule: Option<f64>,
+    /// Estimated emissions of the request in grams of CO2, when a carbon intensity is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub co2_grams: Option<f64>,
+    /// Energy consumed by each device, when the model runs on several devices
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub per_device_energy: Vec<DeviceEnergy>,
+    /// The output is far longer than the recent generations
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub long_output_warning: bool,
+    /// Unit of all the energies of the response
+    #[schema(example = "millijoules")]
+    pub energy_unit: EnergyUnit,
+    /// Metadata sent with the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>, nullable = true, example = json!({"experiment": "a"}))]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// Summary of the energies of the generation steps of a request, in millijoules
+///
+/// Steps between two energy readings are counted once, with the energy of all their tokens.
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct TokenEnergyStats {
+    #[schema(example = 30000)]
+    pub sum: u64,
+    #[schema(example = 1000.0)]
+    pub mean: f64,
+    #[schema(example = 12000)]
+    pub max: u64,
+    /// 95th percentile, the nearest rank
+    #[schema(example = 2500)]
+    pub p95: u64,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct DeviceEnergy {
+    #[schema(example = 0)]
+    pub device: u32,
+    /// Energy consumed by the device during the request in millijoules
+    #[schema(example = 250000)]
+    pub energy_consumption: u64,
+}
+
+impl From<(u32, u64)> for DeviceEnergy {
+    fn from((device, energy_consumption): (u32, u64)) -> Self {
+        Self {
+            device,
+            energy_consumption,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, ToSchema)]
+pub(crate) struct ApplyTemplateRequest {
+    pub messages: Vec<Message>,
+    /// Tools rendered along with the messages, as in a chat request
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tools: Option<Vec<Tool>>,
+    /// Prompt appended after the tools
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tool_prompt: Option<String>,
+    /// Template used instead of the template of the model
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub chat_template: Option<String>,
+    /// Name of the template of the model to apply
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub template_name: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ApplyTemplateResponse {
+    /// Prompt rendered by the chat template
+    #[schema(example = "<s>[INST] What is Deep Learning? [/INST]")]
+    pub prompt: String,
+    /// Number of tokens of the prompt
+    #[schema(example = 14)]
+    pub input_tokens: usize,
+    /// Name of the applied template of the model, `null` for a template sent in the request
+    #[schema(nullable = true, example = "default")]
+    pub template_name: Option<String>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1482,6 +2305,10 @@ pub(crate) struct TokenizeResponse(Vec<SimpleToken>);
 pub(crate) struct StreamDetails {
     #[schema(example = "length")]
     pub finish_reason: FinishReason,
+    /// Limit the generation stopped at, when the finish reason is `length`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "max_new_tokens")]
+    pub length_limit: Option<LengthLimit>,
     #[schema(example = 1)]
     pub generated_tokens: u32,
     #[schema(nullable = true, example = 42)]
@@ -1502,12 +2329,83 @@ pub(crate) struct StreamResponse {
     pub details: Option<StreamDetails>,
     #[schema(nullable = true, default = "null")]
     pub energy_consumption: Option<u64>,
+    /// Energy consumed until the first token was generated, sent with the last token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefill_energy: Option<u64>,
+    /// Energy consumed generating the following tokens, sent with the last token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decode_energy: Option<u64>,
+    /// Estimated prefill energy saved by the cached prefix of the input, sent with the last token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefill_energy_saved_estimate: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub long_output_warning: bool,
+    /// Unit of the energies of the event
+    #[schema(example = "millijoules")]
+    pub energy_unit: EnergyUnit,
+    /// Metadata sent with the request, on the last event only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>, nullable = true, example = json!({"experiment": "a"}))]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// Event of a generated token streamed without its text, when `token_ids_only` is set
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct StreamTokenId {
+    pub index: u32,
+    #[schema(example = 0)]
+    pub id: u32,
+    #[schema(nullable = true, example = -0.34)]
+    pub logprob: f32,
+    #[schema(nullable = true, example = 1000000)]
+    pub energy_consumption: Option<u64>,
+}
+
+impl From<&StreamResponse> for StreamTokenId {
+    fn from(response: &StreamResponse) -> Self {
+        Self {
+            index: response.index,
+            id: response.token.id,
+            logprob: response.token.logprob,
+            energy_consumption: response.energy_consumption,
+        }
+    }
 }
 
 #[derive(Serialize, ToSchema)]
 pub(crate) struct ErrorResponse {
     pub error: String,
     pub error_type: String,
+    /// Energy consumed by the generation before it failed, in millijoules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy_consumption: Option<u64>,
+    /// Where the chat template failed, set on the template errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template_error: Option<TemplateErrorDetail>,
+}
+
+/// Location and cause of a chat template failure, for the authors of custom templates
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct TemplateErrorDetail {
+    /// Kind of the minijinja error
+    #[schema(example = "UndefinedError")]
+    pub kind: String,
+    /// Line of the template the error is on, starting at 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 3)]
+    pub line: Option<usize>,
+    /// Column of the start of the failing expression on its line, starting at 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 12)]
+    pub column: Option<usize>,
+    /// Expression using an undefined variable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "system.prefix")]
+    pub undefined_variable: Option<String>,
+    /// Description of the error, without its location
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub detail: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -1574,10 +2472,10 @@ mod tests {
             ))
         );
         assert_eq!(
-            config.eos_token,
-            Some(TokenizerConfigToken::String(
+            config.eos_tokens,
+            [TokenizerConfigToken::String(
                 "<｜end▁of▁sentence｜>".to_string()
-            ))
+            )]
         );
 
         // in this case we expect the tokens to be encoded as structured tokens
@@ -1616,10 +2514,77 @@ mod tests {
             })
         );
         assert_eq!(
-            config.eos_token,
-            Some(TokenizerConfigToken::Object {
+            config.eos_tokens,
+            [TokenizerConfigToken::Object {
                 content: "<｜end▁of▁sentence｜>".to_string()
-            })
+            }]
+        );
+    }
+
+    #[test]
+    fn test_multiple_eos_tokens_tokenizer_config() {
+        let json_content = r#"{
+            "eos_token": ["<|eot_id|>", {"content": "</s>"}]
+        }"#;
+        let config: HubTokenizerConfig = serde_json::from_str(json_content).unwrap();
+        assert_eq!(
+            config.eos_tokens,
+            [
+                TokenizerConfigToken::String("<|eot_id|>".to_string()),
+                TokenizerConfigToken::Object {
+                    content: "</s>".to_string()
+                }
+            ]
+        );
+        // The first one is the EOS token of the model
+        assert_eq!(
+            SpecialTokensResponse::from(&config).eos_token.unwrap().content,
+            "<|eot_id|>"
+        );
+
+        let config: HubTokenizerConfig = serde_json::from_str(r#"{"eos_token": null}"#).unwrap();
+        assert!(config.eos_tokens.is_empty());
+    }
+
+    #[test]
+    fn test_special_tokens_from_tokenizer_config() {
+        let json_content = r#"{
+            "bos_token": {
+              "__type": "AddedToken",
+              "content": "<s>",
+              "lstrip": false,
+              "normalized": true,
+              "rstrip": false,
+              "single_word": false
+            },
+            "eos_token": "</s>",
+            "pad_token": "<pad>",
+            "additional_special_tokens": ["<|im_start|>", {"content": "<|im_end|>"}]
+        }"#;
+
+        let config: HubTokenizerConfig = serde_json::from_str(json_content).unwrap();
+        let special_tokens = SpecialTokensResponse::from(&config);
+
+        let token = |content: &str| SpecialToken {
+            content: content.to_string(),
+            id: None,
+        };
+        assert_eq!(
+            special_tokens,
+            SpecialTokensResponse {
+                bos_token: Some(token("<s>")),
+                eos_token: Some(token("</s>")),
+                pad_token: Some(token("<pad>")),
+                unk_token: None,
+                additional_special_tokens: vec![token("<|im_start|>"), token("<|im_end|>")],
+            }
+        );
+
+        // Missing tokens are simply absent
+        let config: HubTokenizerConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(
+            SpecialTokensResponse::from(&config),
+            SpecialTokensResponse::default()
         );
     }
 
@@ -1736,7 +2701,8 @@ mod tests {
         assert!(matches!(
             request.stream_options,
             StreamOptions {
-                include_usage: true
+                include_usage: true,
+                include_energy: false
             }
         ));
 
@@ -1752,11 +2718,48 @@ mod tests {
         assert!(matches!(
             request.stream_options,
             StreamOptions {
-                include_usage: false
+                include_usage: false,
+                include_energy: false
             }
         ));
     }
 
+    #[test]
+    fn test_energy_priority() {
+        let request: GenerateRequest = serde_json::from_value(json!({
+            "inputs": "Hello",
+            "parameters": {"energy_priority": "energy_efficient"}
+        }))
+        .unwrap();
+        assert_eq!(
+            request.parameters.energy_priority,
+            EnergyPriority::EnergyEfficient
+        );
+
+        // Requests without hint keep the current behavior
+        let request: GenerateRequest =
+            serde_json::from_value(json!({"inputs": "Hello", "parameters": {}})).unwrap();
+        assert_eq!(
+            request.parameters.energy_priority,
+            EnergyPriority::Throughput
+        );
+    }
+
+    #[test]
+    fn test_request_metadata() {
+        let metadata = json!({"experiment": "a", "tags": [1, 2]});
+        let request: GenerateRequest =
+            serde_json::from_value(json!({"inputs": "Hello", "metadata": metadata})).unwrap();
+        assert_eq!(request.metadata, Some(metadata.clone()));
+
+        let request: CompatGenerateRequest =
+            serde_json::from_value(json!({"inputs": "Hello", "metadata": metadata})).unwrap();
+        assert_eq!(GenerateRequest::from(request).metadata, Some(metadata));
+
+        let request: GenerateRequest = serde_json::from_value(json!({"inputs": "Hello"})).unwrap();
+        assert_eq!(request.metadata, None);
+    }
+
     #[test]
     fn openai_output() {
         let message = OutputMessage::ChatMessage(TextMessage {
@@ -1782,6 +2785,7 @@ mod tests {
                         "format": "csv"
                     }),
                 },
+                truncated: false,
             }],
         });
         let serialized = serde_json::to_string(&message).unwrap();
@@ -1791,6 +2795,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn tool_call_arguments() {
+        let mut tool_call = ToolCall {
+            id: "0".to_string(),
+            r#type: "function".to_string(),
+            function: FunctionDefinition {
+                description: None,
+                name: "myfn".to_string(),
+                arguments: json!("csv"),
+            },
+            truncated: false,
+        };
+        // Arguments of a complete tool call are always serialized as JSON
+        let serialized = serde_json::to_value(&tool_call).unwrap();
+        assert_eq!(serialized["function"]["arguments"], r#""csv""#);
+        assert!(serialized.get("truncated").is_none());
+
+        // Partial arguments are returned as they were generated
+        tool_call.function.arguments = json!(r#"{"format": "cs"#);
+        tool_call.truncated = true;
+        let serialized = serde_json::to_value(&tool_call).unwrap();
+        assert_eq!(serialized["function"]["arguments"], r#"{"format": "cs"#);
+        assert_eq!(serialized["truncated"], true);
+    }
+
     #[test]
     fn tool_choice_formats() {
         #[derive(Deserialize)]
@@ -1827,4 +2856,80 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_stream_token_id() {
+        let response = StreamResponse {
+            index: 3,
+            token: Token {
+                id: 42,
+                text: " hello".to_string(),
+                logprob: -0.5,
+                special: false,
+                energy_consumption: Some(1500),
+                telemetry: None,
+                continuation_boundary: false,
+                step_energy: None,
+                offsets: None,
+                inter_token_latency_ms: None,
+            },
+            top_tokens: vec![],
+            generated_text: None,
+            details: None,
+            energy_consumption: Some(1500),
+            prefill_energy: None,
+            decode_energy: None,
+            prefill_energy_saved_estimate: None,
+            long_output_warning: false,
+            energy_unit: EnergyUnit::Joules,
+            metadata: None,
+        };
+
+        // The energy is converted like in the full events, the text is left out
+        let event = EnergyUnit::Joules
+            .to_json(&StreamTokenId::from(&response))
+            .unwrap();
+        assert_eq!(
+            event,
+            json!({"index": 3, "id": 42, "logprob": -0.5, "energy_consumption": 1.5})
+        );
+    }
+
+    #[test]
+    fn test_echo_logprobs() {
+        let prefill_token = |id, logprob| PrefillToken {
+            id,
+            text: format!("p{id}"),
+            logprob,
+        };
+        let details = Details {
+            finish_reason: FinishReason::Length,
+            length_limit: Some(LengthLimit::MaxNewTokens),
+            generated_tokens: 1,
+            seed: None,
+            prefill: vec![prefill_token(1, f32::NAN), prefill_token(2, -1.5)],
+            tokens: vec![Token {
+                id: 3,
+                text: " t".to_string(),
+                logprob: -0.25,
+                special: false,
+                energy_consumption: None,
+                telemetry: None,
+                continuation_boundary: false,
+                step_energy: None,
+                offsets: None,
+                inter_token_latency_ms: None,
+            }],
+            best_of_sequences: None,
+            top_tokens: vec![],
+            top_logprobs: vec![],
+        };
+
+        let logprobs = details.echo_logprobs();
+        assert_eq!(logprobs.len(), 3);
+        assert!(logprobs[0].is_nan());
+        assert_eq!(logprobs[1..], [-1.5, -0.25]);
+        // The first prompt token has no logprob, like in the OpenAI API
+        assert_eq!(json!(logprobs), json!([null, -1.5, -0.25]));
+    }
 }
\ No newline at end of file
@@ -5,7 +5,7 @@ use crate::{
     CompletionFinal, CompletionRequest, ErrorResponse, GenerateResponse, Info, StreamResponse,
 };
 use axum::extract::Extension;
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::Response;
 use axum::Json;
 use serde::{Deserialize, Serialize};
@@ -21,7 +21,7 @@ pub(crate) enum SagemakerRequest {
 }
 
 // Used for OpenAPI specs
-#[allow(dead_code)]
+#[allow(dead_code, clippy::large_enum_variant)]
 #[derive(Serialize, ToSchema)]
 #[serde(untagged)]
 pub(crate) enum SagemakerResponse {
@@ -68,15 +68,26 @@ pub(crate) async fn sagemaker_compatibility(
     infer: Extension<Infer>,
     compute_type: Extension<ComputeType>,
     info: Extension<Info>,
+    headers: HeaderMap,
     Json(req): Json<SagemakerRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     match req {
         SagemakerRequest::Generate(req) => {
-            compat_generate(default_return_full_text, infer, compute_type, Json(req)).await
+            compat_generate(
+                default_return_full_text,
+                infer,
+                compute_type,
+                info,
+                headers,
+                Json(req),
+            )
+            .await
+        }
+        SagemakerRequest::Chat(req) => {
+            chat_completions(infer, compute_type, info, headers, Json(req)).await
         }
-        SagemakerRequest::Chat(req) => chat_completions(infer, compute_type, info, Json(req)).await,
         SagemakerRequest::Completion(req) => {
-            completions(infer, compute_type, info, Json(req)).await
+            completions(infer, compute_type, info, headers, Json(req)).await
         }
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,16 @@
 
 use crate::chat::{ChatChoice, ChatEvent, ChatState};
+use crate::chunking::TokenChunker;
+use crate::energy_trace::EnergyReport;
 /// HTTP Server logic
 use crate::config::Config;
-use crate::infer::{Backend, Infer, InferError, InferResponse, InferStreamResponse};
+use crate::infer::energy::{EnergyLogFormat, EnergySource, EnergyUnit};
+use crate::infer::sampling_hook::SamplingHook;
+use crate::infer::telemetry::{DeviceInfo, DeviceTelemetry, TelemetryField, TelemetryValue};
+use crate::infer::{
+    Backend, Infer, InferError, InferResponse, InferStreamResponse, RequestClass, TenantQuota,
+    IDLE_POWER_WINDOW, MAX_CONTINUATION_ROUNDS, STREAM_BUFFER_SIZE,
+};
 #[cfg(feature = "kserve")]
 use crate::kserve::{
     kerve_server_metadata, kserve_health_live, kserve_health_ready, kserve_model_infer,
@@ -14,25 +22,32 @@ use crate::sagemaker::{
 };
 use crate::validation::ValidationError;
 use crate::vertex::vertex_compatibility;
+use crate::infer::tool_grammar::ToolGrammar;
 use crate::ChatTokenizeResponse;
+use crate::{ApplyTemplateRequest, ApplyTemplateResponse};
 use crate::{
-    usage_stats, BestOfSequence, Details, ErrorResponse, FinishReason, FunctionName,
-    GenerateParameters, GenerateRequest, GenerateResponse, GrammarType, HubModelInfo,
-    HubProcessorConfig, HubTokenizerConfig, Info, Message, MessageChunk, MessageContent,
-    OutputMessage, PrefillToken, SimpleToken, StreamDetails, StreamOptions, StreamResponse,
-    TextMessage, Token, TokenizeResponse, Tokenizer, ToolCallDelta, ToolCallMessage, Url, Usage,
-    Validation,
+    usage_stats, BestOfSequence, Chunking, Details, EnergyPriority, ErrorResponse, FinishReason,
+    FunctionName, GenerateParameters, GenerateRequest, GenerateResponse, GrammarType, HubModelInfo,
+    HubProcessorConfig, HubTokenizerConfig, Info, LengthLimit, Message, MessageChunk,
+    MessageContent, OutputMessage, PrefillToken, SimpleToken, StreamDetails, StreamOptions,
+    StreamResponse, TemplateErrorDetail, TextMessage, Token, TokenizeResponse, Tokenizer,
+    ToolCallDelta, ToolCallMessage, Url, Usage, Validation,
 };
 use crate::{
     ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionComplete,
     ChatCompletionDelta, ChatCompletionLogprob, ChatCompletionLogprobs, ChatCompletionTopLogprob,
-    ChatRequest, Chunk, CompatGenerateRequest, Completion, CompletionComplete, CompletionFinal,
-    CompletionRequest, CompletionType, DeltaToolCall, Function, Prompt, Tool,
+    ChatRequest, Chunk, ChunkEnergy, CompatGenerateRequest, Completion, CompletionComplete,
+    CompletionFinal, CompletionRequest, CompletionType, DeltaToolCall, Function, Prompt, Tool,
 };
 use crate::{FunctionDefinition, HubPreprocessorConfig, ToolCall, ToolChoice};
-use crate::{MessageBody, ModelInfo, ModelsInfo};
+use crate::{
+    BenchmarkReport, BenchmarkRequest, BenchmarkStats, ConcurrencyLimit, DeviceEnergy,
+    EffectiveParameters, EnergyHealth, EnergySummary, HealthResponse, MessageBody, ModelInfo,
+    ModelMetadata, ModelsInfo, PowerResponse, SpecialToken, SpecialTokensResponse, StreamTokenId,
+    TokenEnergyStats, TokenOffsets, ValidationReport,
+};
 use async_stream::__private::AsyncStream;
-use axum::extract::{DefaultBodyLimit, Extension};
+use axum::extract::{DefaultBodyLimit, Extension, State};
 use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
@@ -121,11 +136,13 @@ example = json ! ({"error": "Input validation error"})),
 example = json ! ({"error": "Incomplete generation"})),
 )
 )]
-#[instrument(skip(infer, req))]
+#[instrument(skip(infer, info, req))]
 pub(crate) async fn compat_generate(
     Extension(default_return_full_text): Extension<bool>,
     infer: Extension<Infer>,
     compute_type: Extension<ComputeType>,
+    info: Extension<Info>,
+    headers: HeaderMap,
     Json(mut req): Json<CompatGenerateRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     // default return_full_text given the pipeline_tag
@@ -135,11 +152,14 @@ pub(crate) async fn compat_generate(
 
     // switch on stream
     if req.stream {
-        Ok(generate_stream(infer, compute_type, Json(req.into()))
-            .await
-            .into_response())
+        Ok(
+            generate_stream(infer, compute_type, info, headers, Json(req.into()))
+                .await
+                .into_response(),
+        )
     } else {
-        let (headers, Json(generation)) = generate(infer, compute_type, Json(req.into())).await?;
+        let (headers, Json(generation)) =
+            generate(infer, compute_type, headers, Json(req.into())).await?;
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(vec![generation])).into_response())
     }
@@ -157,6 +177,28 @@ async fn get_model_info(info: Extension<Info>) -> Json<Info> {
     Json(info.0)
 }
 
+/// Served model properties, along with whether energy is measured
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/model-info",
+responses((status = 200, description = "Served model properties", body = ModelMetadata))
+)]
+#[instrument(skip_all)]
+async fn model_metadata(
+    Extension(info): Extension<Info>,
+    Extension(infer): Extension<Infer>,
+) -> Json<ModelMetadata> {
+    Json(ModelMetadata {
+        model_id: info.model_id,
+        model_sha: info.model_sha,
+        max_input_tokens: info.max_input_tokens,
+        max_total_tokens: info.max_total_tokens,
+        chat_template: infer.has_chat_template(),
+        energy_measurement: !infer.energy_devices().is_empty(),
+    })
+}
+
 #[utoipa::path(
 get,
 tag = "Text Generation Inference",
@@ -210,29 +252,77 @@ async fn get_chat_tokenize(
     Ok((HeaderMap::new(), Json(resp)))
 }
 
+/// Render the chat template without running inference
+#[utoipa::path(
+    post,
+    tag = "Text Generation Inference",
+    path = "/apply_template",
+    request_body = ApplyTemplateRequest,
+    responses(
+    (status = 200, description = "Rendered prompt", body = ApplyTemplateResponse),
+    (status = 422, description = "The template could not be rendered", body = ErrorResponse,
+    example = json ! ({"error": "Missing template variable: message.name", "error_type": "missing_template_variable"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn apply_template(
+    Extension(infer): Extension<Infer>,
+    Json(req): Json<ApplyTemplateRequest>,
+) -> Result<Json<ApplyTemplateResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ApplyTemplateRequest {
+        messages,
+        tools,
+        tool_prompt,
+        chat_template,
+        template_name,
+    } = req;
+    let tools_and_prompt = match tools {
+        Some(tools) => ToolGrammar::apply(tools, ToolChoice::default())?.map(|(tools, _)| {
+            (tools, tool_prompt.unwrap_or_else(crate::default_tool_prompt))
+        }),
+        None => None,
+    };
+    let (prompt, template_name) = infer.apply_chat_template(
+        messages,
+        tools_and_prompt,
+        chat_template.as_deref(),
+        template_name.as_deref(),
+    )?;
+
+    let encoding = infer
+        .tokenize(GenerateRequest {
+            inputs: prompt.clone(),
+            add_special_tokens: false,
+            metadata: None,
+            parameters: crate::default_parameters(),
+        })
+        .await?;
+    Ok(Json(ApplyTemplateResponse {
+        prompt,
+        input_tokens: encoding.len(),
+        template_name,
+    }))
+}
+
 #[utoipa::path(
 get,
 tag = "Text Generation Inference",
 path = "/health",
 responses(
-(status = 200, description = "Everything is working fine"),
-(status = 503, description = "Text generation inference is down", body = ErrorResponse,
-example = json ! ({"error": "unhealthy", "error_type": "healthcheck"})),
+(status = 200, description = "The model is serving requests, energy may still be degraded", body = HealthResponse),
+(status = 503, description = "Text generation inference is down", body = HealthResponse,
+example = json ! ({"backend": false, "energy": "healthy"})),
 )
 )]
 #[instrument(skip(infer))]
 /// Health check method
-async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
-    match infer.health().await {
-        true => Ok(()),
-        false => Err((
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(ErrorResponse {
-                error: "unhealthy".to_string(),
-                error_type: "healthcheck".to_string(),
-            }),
-        )),
-    }
+async fn health(infer: Extension<Infer>) -> (StatusCode, Json<HealthResponse>) {
+    let health = infer.health().await;
+    let status = match health.backend {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(health))
 }
 
 /// Generate tokens
@@ -268,12 +358,29 @@ seed,
 async fn generate(
     infer: Extension<Infer>,
     Extension(ComputeType(compute_type)): Extension<ComputeType>,
-    Json(req): Json<GenerateRequest>,
-) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
+    request_headers: HeaderMap,
+    Json(mut req): Json<GenerateRequest>,
+) -> Result<(HeaderMap, Json<serde_json::Value>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
-    let (headers, _, response) =
-        generate_internal(infer, ComputeType(compute_type), Json(req), span).await?;
-    Ok((headers, response))
+    req.parameters.api_key = api_key(&request_headers);
+    // Retried requests get the response of the first attempt
+    let idempotency_key = request_headers
+        .get("idempotency-key")
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_string);
+    let (headers, _, Json(response)) = generate_internal(
+        infer,
+        ComputeType(compute_type),
+        Json(req),
+        span,
+        idempotency_key,
+    )
+    .await?;
+    let response = response
+        .energy_unit
+        .to_json(&response)
+        .map_err(|e| InferError::StreamSerializationError(e.to_string()))?;
+    Ok((headers, Json(response)))
 }
 
 pub(crate) async fn generate_internal(
@@ -281,6 +388,7 @@ pub(crate) async fn generate_internal(
     ComputeType(compute_type): ComputeType,
     Json(req): Json<GenerateRequest>,
     span: tracing::Span,
+    idempotency_key: Option<String>,
 ) -> Result<(HeaderMap, u32, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
     let start_time = Instant::now();
     metrics::counter!("tgi_request_count").increment(1);
@@ -292,20 +400,27 @@ pub(crate) async fn generate_internal(
     );
 
     let compute_characters = req.inputs.chars().count();
+    let energy_unit = req.parameters.energy_unit.unwrap_or(infer.energy_unit());
     let mut add_prompt = None;
     if req.parameters.return_full_text.unwrap_or(false) {
         add_prompt = Some(req.inputs.clone());
     }
 
     let details: bool = req.parameters.details || req.parameters.decoder_input_details;
+    let metadata = req.metadata.clone();
 
     // Inference
-    let (response, best_of_responses) = match req.parameters.best_of {
+    let (response, best_of_responses, replayed) = match req.parameters.best_of {
         Some(best_of) if best_of > 1 => {
             let (response, best_of_responses) = infer.generate_best_of(req, best_of).await?;
-            (response, Some(best_of_responses))
+            (response, Some(best_of_responses), false)
+        }
+        _ => {
+            let (response, replayed) = infer
+                .generate_idempotent(req, idempotency_key.as_deref())
+                .await?;
+            (response, None, replayed)
         }
-        _ => (infer.generate(req).await?, None),
     };
 
     // Token details
@@ -338,12 +453,14 @@ pub(crate) async fn generate_internal(
 
             Some(Details {
                 finish_reason: response.generated_text.finish_reason,
+                length_limit: response.generated_text.length_limit,
                 generated_tokens: response.generated_text.generated_tokens,
                 prefill: response.prefill,
                 tokens: response.tokens,
                 seed: response.generated_text.seed,
                 best_of_sequences,
                 top_tokens: response.top_tokens,
+                top_logprobs: response.top_logprobs,
             })
         }
         false => None,
@@ -356,6 +473,7 @@ pub(crate) async fn generate_internal(
     let inference_time = Instant::now() - response.start;
     let time_per_token = inference_time / response.generated_text.generated_tokens;
     let energy_consumption = response.energy_consumption;
+    let long_output_warning = infer.observe_output_length(response.generated_text.generated_tokens);
 
     // Tracing metadata
     span.record("total_time", format!("{total_time:?}"));
@@ -386,11 +504,11 @@ pub(crate) async fn generate_internal(
     );
     headers.insert(
         "x-queue-time",
-        queue_time.as_millis().to_string().parse().unwrap(),
+        response.queue_time_ms.to_string().parse().unwrap(),
     );
     headers.insert(
         "x-inference-time",
-        inference_time.as_millis().to_string().parse().unwrap(),
+        response.inference_time_ms.to_string().parse().unwrap(),
     );
     headers.insert(
         "x-time-per-token",
@@ -401,13 +519,20 @@ pub(crate) async fn generate_internal(
         "x-generated-tokens",
         response.generated_text.generated_tokens.into(),
     );
-    if let Some(energy_consumption) = response.energy_consumption {
+    if let Some(energy_consumption) = response
+        .batch_energy_consumption
+        .or(response.energy_consumption)
+    {
         headers.insert(
             "x-energy-consumption",
             energy_consumption.to_string().parse().unwrap(),
         );
     }
 
+    if replayed {
+        headers.insert("x-idempotent-replay", HeaderValue::from_static("true"));
+    }
+
     // Metrics
     metrics::counter!("tgi_request_success").increment(1);
     metrics::histogram!("tgi_request_duration").record(total_time.as_secs_f64());
@@ -432,10 +557,117 @@ pub(crate) async fn generate_internal(
         generated_text: output_text,
         details,
         energy_consumption,
+        prefill_energy: response.prefill_energy,
+        decode_energy: response.decode_energy,
+        prefill_energy_saved_estimate: response.prefill_energy_saved,
+        batch_energy_consumption: response.batch_energy_consumption,
+        wasted_energy: response.wasted_energy,
+        tokens_per_joule: response.tokens_per_joule,
+        co2_grams: response.co2_grams,
+        token_energy_stats: response.token_energy_stats,
+        per_device_energy: response
+            .per_device_energy
+            .into_iter()
+            .map(DeviceEnergy::from)
+            .collect(),
+        long_output_warning,
+        energy_unit,
+        metadata,
     };
     Ok((headers, input_length, Json(response)))
 }
 
+/// Generate `n` sequences of the same request and return them all, in order
+///
+/// The details are always returned as they are needed to build the choices.
+pub(crate) async fn generate_n_internal(
+    infer: Extension<Infer>,
+    ComputeType(compute_type): ComputeType,
+    Json(req): Json<GenerateRequest>,
+    n: usize,
+    span: tracing::Span,
+) -> Result<(HeaderMap, u32, Vec<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let start_time = Instant::now();
+    metrics::counter!("tgi_request_count").increment(1);
+
+    let energy_unit = req.parameters.energy_unit.unwrap_or(infer.energy_unit());
+    let metadata = req.metadata.clone();
+    let responses = infer.generate_n(req, n).await?;
+
+    let total_time = start_time.elapsed();
+    let input_length = responses[0]._input_length;
+    let generated_tokens: u32 = responses
+        .iter()
+        .map(|response| response.generated_text.generated_tokens)
+        .sum();
+    let batch_energy = responses[0].batch_energy_consumption;
+    span.record("total_time", format!("{total_time:?}"));
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-compute-type", compute_type.parse().unwrap());
+    headers.insert(
+        "x-compute-time",
+        total_time.as_secs_f64().to_string().parse().unwrap(),
+    );
+    headers.insert(
+        "x-total-time",
+        total_time.as_millis().to_string().parse().unwrap(),
+    );
+    headers.insert("x-prompt-tokens", input_length.into());
+    headers.insert("x-generated-tokens", generated_tokens.into());
+    if let Some(energy_consumption) = batch_energy {
+        headers.insert(
+            "x-energy-consumption",
+            energy_consumption.to_string().parse().unwrap(),
+        );
+    }
+
+    metrics::counter!("tgi_request_success").increment(1);
+    metrics::histogram!("tgi_request_duration").record(total_time.as_secs_f64());
+    metrics::histogram!("tgi_request_generated_tokens").record(generated_tokens as f64);
+    tracing::info!("Success");
+
+    let responses = responses
+        .into_iter()
+        .map(|response| {
+            let long_output_warning =
+                infer.observe_output_length(response.generated_text.generated_tokens);
+            GenerateResponse {
+                generated_text: response.generated_text.text,
+                details: Some(Details {
+                    finish_reason: response.generated_text.finish_reason,
+                    length_limit: response.generated_text.length_limit,
+                    generated_tokens: response.generated_text.generated_tokens,
+                    prefill: response.prefill,
+                    tokens: response.tokens,
+                    seed: response.generated_text.seed,
+                    best_of_sequences: None,
+                    top_tokens: response.top_tokens,
+                    top_logprobs: response.top_logprobs,
+                }),
+                energy_consumption: response.energy_consumption,
+                prefill_energy: response.prefill_energy,
+                decode_energy: response.decode_energy,
+                prefill_energy_saved_estimate: response.prefill_energy_saved,
+                batch_energy_consumption: response.batch_energy_consumption,
+                wasted_energy: None,
+                tokens_per_joule: response.tokens_per_joule,
+                co2_grams: response.co2_grams,
+                token_energy_stats: response.token_energy_stats,
+                per_device_energy: response
+                    .per_device_energy
+                    .into_iter()
+                    .map(DeviceEnergy::from)
+                    .collect(),
+                long_output_warning,
+                energy_unit,
+                metadata: metadata.clone(),
+            }
+        })
+        .collect();
+    Ok((headers, input_length, responses))
+}
+
 /// Generate a stream of token using Server-Sent Events
 #[utoipa::path(
 post,
@@ -474,12 +706,16 @@ seed,
 async fn generate_stream(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
-    Json(req): Json<GenerateRequest>,
+    Extension(info): Extension<Info>,
+    headers: HeaderMap,
+    Json(mut req): Json<GenerateRequest>,
 ) -> (
     HeaderMap,
     Sse<impl Stream<Item = Result<Event, Infallible>>>,
 ) {
     let span = tracing::Span::current();
+    req.parameters.api_key = api_key(&headers);
+    let token_ids_only = req.parameters.token_ids_only;
     let (headers, response_stream) =
         generate_stream_internal(infer, compute_type, Json(req), span).await;
 
@@ -487,18 +723,50 @@ async fn generate_stream(
         let mut response_stream = Box::pin(response_stream);
         while let Some(raw_event) = response_stream.next().await {
             yield Ok(raw_event.map_or_else(Event::from, |token| {
-                Event::default()
-                    .json_data(token)
-                    .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into())
+                // The last event carries the generated text and details, it is always sent in full
+                let token = if token_ids_only && token.generated_text.is_none() {
+                    token.energy_unit.to_json(&StreamTokenId::from(&token))
+                } else {
+                    token.energy_unit.to_json(&token)
+                };
+                let token = token.map_err(|e| e.to_string());
+                token
+                    .and_then(|token| Event::default().json_data(token).map_err(|e| e.to_string()))
+                    .unwrap_or_else(|e| InferError::StreamSerializationError(e).into())
             }));
         }
     };
 
-    let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
+    let sse = with_keep_alive(Sse::new(response_stream), &info);
     (headers, sse)
 }
 
-async fn generate_stream_internal(
+/// Header of the API key identifying the tenant of a request
+pub(crate) const API_KEY_HEADER: &str = "x-api-key";
+
+/// API key of the tenant the request counts against for the concurrency quotas
+pub(crate) fn api_key(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Keep-alive of the SSE streams: a comment is sent when no event was sent during the interval.
+/// An interval of 0 disables the keep-alive.
+fn with_keep_alive<S>(sse: Sse<S>, info: &Info) -> Sse<S> {
+    match info.sse_keep_alive_interval_ms {
+        Some(0) => sse,
+        Some(interval) => sse.keep_alive(
+            KeepAlive::new()
+                .interval(Duration::from_millis(interval))
+                .text("ping"),
+        ),
+        None => sse.keep_alive(KeepAlive::default()),
+    }
+}
+
+pub(crate) async fn generate_stream_internal(
     infer: Infer,
     ComputeType(compute_type): ComputeType,
     Json(req): Json<GenerateRequest>,
@@ -513,6 +781,7 @@ async fn generate_stream_internal(
     tracing::debug!("Input: {}", req.inputs);
 
     let compute_characters = req.inputs.chars().count();
+    let energy_unit = req.parameters.energy_unit.unwrap_or(infer.energy_unit());
 
     let mut headers = HeaderMap::new();
     headers.insert("x-compute-type", compute_type.parse().unwrap());
@@ -532,6 +801,8 @@ async fn generate_stream_internal(
             add_prompt = Some(req.inputs.clone());
         }
         let details = req.parameters.details;
+        let metadata = req.metadata.clone();
+        let mut chunker = TokenChunker::new(req.parameters.chunking);
 
         let best_of = req.parameters.best_of.unwrap_or(1);
         if best_of != 1 {
@@ -557,25 +828,39 @@ async fn generate_stream_internal(
                             Ok(response) => {
                                 match response {
                                     // Prefill is ignored
-                                    InferStreamResponse::Prefill(_) => {}
+                                    InferStreamResponse::Prefill { .. } => {}
                                     // Yield event for every new token
                                     InferStreamResponse::Intermediate{
                                         token,
                                         top_tokens,
                                         energy_consumption,
+                                        ..
                                     } => {
                                         tracing::debug!(parent: &span, "Token: {:?}", token);
 
-                                        // StreamResponse
-                                        let stream_token = StreamResponse {
-                                            index,
-                                            token,
-                                            top_tokens,
-                                            generated_text: None,
-                                            details: None,
-                                            energy_consumption: None,
+                                        // Grouped tokens are sent once their chunk is complete
+                                        let tokens = match chunker.as_mut() {
+                                            Some(chunker) => chunker.push(token).into_iter().map(|token| (token, Vec::new())).collect(),
+                                            None => vec![(token, top_tokens)],
                                         };
-                                        yield Ok(stream_token);
+                                        for (token, top_tokens) in tokens {
+                                            // StreamResponse
+                                            let stream_token = StreamResponse {
+                                                index,
+                                                token,
+                                                top_tokens,
+                                                generated_text: None,
+                                                details: None,
+                                                energy_consumption,
+                                                prefill_energy: None,
+                                                decode_energy: None,
+                                                prefill_energy_saved_estimate: None,
+                                                long_output_warning: false,
+                                                energy_unit,
+                                                metadata: None,
+                                            };
+                                            yield Ok(stream_token);
+                                        }
                                     }
                                     // Yield event for last token and compute timings
                                     InferStreamResponse::End {
@@ -585,11 +870,42 @@ async fn generate_stream_internal(
                                         queued,
                                         top_tokens,
                                         energy_consumption,
+                                        prefill_energy,
+                                        decode_energy,
+                                        prefill_energy_saved,
+                                        ..
                                     } => {
+                                        // The chunks completed by the last token are sent before
+                                        // the last chunk, which ends the stream
+                                        let (token, top_tokens) = match chunker.as_mut() {
+                                            Some(chunker) => {
+                                                let (chunks, last) = chunker.finish(token);
+                                                for chunk in chunks {
+                                                    yield Ok(StreamResponse {
+                                                        index,
+                                                        token: chunk,
+                                                        top_tokens: Vec::new(),
+                                                        generated_text: None,
+                                                        details: None,
+                                                        energy_consumption,
+                                                        prefill_energy: None,
+                                                        decode_energy: None,
+                                                        prefill_energy_saved_estimate: None,
+                                                        long_output_warning: false,
+                                                        energy_unit,
+                                                        metadata: None,
+                                                    });
+                                                }
+                                                (last, Vec::new())
+                                            }
+                                            None => (token, top_tokens),
+                                        };
+
                                         // Token details
                                         let details = match details {
                                             true => Some(StreamDetails {
                                                 finish_reason: generated_text.finish_reason,
+                                                length_limit: generated_text.length_limit,
                                                 generated_tokens: generated_text.generated_tokens,
                                                 seed: generated_text.seed,
                                                 input_length,
@@ -631,6 +947,7 @@ async fn generate_stream_internal(
 
                                         // StreamResponse
                                         end_reached = true;
+                                        let long_output_warning = infer.observe_output_length(generated_text.generated_tokens);
 
                                         let mut output_text = generated_text.text;
                                         if let Some(prompt) = add_prompt {
@@ -647,6 +964,12 @@ async fn generate_stream_internal(
                                             generated_text: Some(output_text),
                                             details,
                                             energy_consumption,
+                                            prefill_energy,
+                                            decode_energy,
+                                            prefill_energy_saved_estimate: prefill_energy_saved,
+                                            long_output_warning,
+                                            energy_unit,
+                                            metadata: metadata.clone(),
                                         };
 
                                         yield Ok(stream_token);
@@ -721,6 +1044,7 @@ pub(crate) async fn completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
+    headers: HeaderMap,
     Json(req): Json<CompletionRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
@@ -733,6 +1057,7 @@ pub(crate) async fn completions(
         stop,
         stream,
         temperature,
+        echo,
         ..
     } = req;
 
@@ -753,6 +1078,8 @@ pub(crate) async fn completions(
                 error: "Suffix is not supported and can be achieved by preprocessing the prompt."
                     .to_string(),
                 error_type: "suffix not supported".to_string(),
+                energy_consumption: None,
+                template_error: None,
             }),
         ));
     }
@@ -767,6 +1094,8 @@ pub(crate) async fn completions(
                     info.max_client_batch_size
                 ),
                 error_type: "batch size exceeded".to_string(),
+                energy_consumption: None,
+                template_error: None,
             }),
         ));
     }
@@ -778,6 +1107,7 @@ pub(crate) async fn completions(
         .map(|prompt| GenerateRequest {
             inputs: prompt.to_string(),
             add_special_tokens: true,
+            metadata: None,
             parameters: GenerateParameters {
                 best_of: None,
                 temperature,
@@ -788,7 +1118,7 @@ pub(crate) async fn completions(
                 typical_p: None,
                 do_sample,
                 max_new_tokens,
-                return_full_text: None,
+                return_full_text: Some(echo),
                 stop: stop.clone(),
                 truncate: None,
                 watermark: false,
@@ -796,8 +1126,24 @@ pub(crate) async fn completions(
                 decoder_input_details: !stream,
                 seed,
                 top_n_tokens: None,
+                top_logprobs: None,
                 grammar: None,
                 adapter_id: model.as_ref().filter(|m| *m != "tgi").map(String::from),
+                request_class: None,
+                api_key: api_key(&headers),
+                continue_on_length: false,
+                max_total_new_tokens: None,
+                max_energy_millijoules: None,
+                max_time_ms: None,
+                subtract_idle: false,
+                measure_energy: true,
+                energy_priority: EnergyPriority::Throughput,
+                energy_unit: None,
+                return_offsets: false,
+                return_inter_token_latency: false,
+                token_ids_only: false,
+                chunking: Chunking::None,
+                priority: None,
             },
         })
         .collect();
@@ -815,11 +1161,14 @@ pub(crate) async fn completions(
             let infer_clone = infer.clone();
             let compute_type_clone = compute_type.clone();
             let span_clone = span.clone();
+            // The prompt is streamed as is before the tokens
+            let echoed_prompt = echo.then(|| generate_request.inputs.clone());
 
             // Create a future for each generate_stream_internal call.
             let generate_future = async move {
                 let (header_tx, header_rx) = oneshot::channel();
-                let (sse_tx, sse_rx) = tokio::sync::mpsc::unbounded_channel();
+                // Bounded so that the generations of a slow client are stopped instead of buffered
+                let (sse_tx, sse_rx) = tokio::sync::mpsc::channel(STREAM_BUFFER_SIZE);
 
                 tokio::spawn(async move {
                     let (headers, response_stream) = generate_stream_internal(
@@ -832,11 +1181,34 @@ pub(crate) async fn completions(
 
                     let response_stream = async_stream::stream! {
                         let mut response_stream = Box::pin(response_stream);
+                        let start = Instant::now();
+                        let mut token_energy_mj = Vec::new();
+
+                        if let Some(prompt) = echoed_prompt {
+                            let current_time = std::time::SystemTime::now()
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                                .as_secs();
+                            let message = Completion::Chunk(Chunk {
+                                id: String::new(),
+                                created: current_time,
+                                choices: vec![CompletionComplete {
+                                    finish_reason: String::new(),
+                                    index: index as u32,
+                                    logprobs: None,
+                                    text: prompt,
+                                }],
+                                model: model_id.clone(),
+                                system_fingerprint: system_fingerprint.clone(),
+                            });
+                            yield Ok(Event::default().json_data(message).unwrap_or_else(|_e| Event::default()));
+                        }
 
                         while let Some(stream_token) = response_stream.next().await {
                             match stream_token {
                                 Ok(stream_token) => {
                                     let event = Event::default();
+                                    token_energy_mj.push(stream_token.energy_consumption);
 
                                     let current_time = std::time::SystemTime::now()
                                         .duration_since(std::time::UNIX_EPOCH)
@@ -845,9 +1217,14 @@ pub(crate) async fn completions(
 
                                     let message = match stream_token.details {
                                         Some(details) => {
-                                            let completion_tokens = details.generated_tokens;
-                                            let prompt_tokens = details.input_length;
-                                            let total_tokens = prompt_tokens + completion_tokens;
+                                            let report = completion_report(
+                                                details.input_length,
+                                                details.generated_tokens,
+                                                details.finish_reason.clone(),
+                                                stream_token.energy_consumption,
+                                                std::mem::take(&mut token_energy_mj),
+                                                start.elapsed().as_millis() as u64,
+                                            );
 
                                             Completion::Final(CompletionFinal {
                                                 id: String::new(),
@@ -860,11 +1237,7 @@ pub(crate) async fn completions(
                                                     logprobs: None,
                                                     text: stream_token.token.text,
                                                 }],
-                                                usage: Usage {
-                                                    prompt_tokens,
-                                                    completion_tokens,
-                                                    total_tokens,
-                                                },
+                                                usage: Usage::from_reports([&report]),
                                             })
                                         }
                                         None => Completion::Chunk(Chunk {
@@ -898,7 +1271,7 @@ pub(crate) async fn completions(
                     // pin an emit messages to the sse_tx
                     let mut sse = Box::pin(response_stream);
                     while let Some(event) = sse.next().await {
-                        if sse_tx.send(event).is_err() {
+                        if sse_tx.send(event).await.is_err() {
                             tracing::error!("Failed to send event. Receiver dropped.");
                             break;
                         }
@@ -923,6 +1296,8 @@ pub(crate) async fn completions(
                     Json(ErrorResponse {
                         error: "Failed to get headers".to_string(),
                         error_type: "headers".to_string(),
+                        energy_consumption: None,
+                        template_error: None,
                     }),
                 )
             })?;
@@ -981,7 +1356,7 @@ pub(crate) async fn completions(
             Ok(Event::default().data("[DONE]"))
         }));
 
-        let sse = Sse::new(stream).keep_alive(KeepAlive::default());
+        let sse = with_keep_alive(Sse::new(stream), &info);
         Ok((headers, sse).into_response())
     } else {
         let current_time = std::time::SystemTime::now()
@@ -1000,6 +1375,7 @@ pub(crate) async fn completions(
                     compute_type_clone,
                     Json(generate_request),
                     span_clone,
+                    None,
                 )
                 .await;
                 result.map(|(headers, input_length, generation)| {
@@ -1010,9 +1386,7 @@ pub(crate) async fn completions(
         }
         let generate_responses = responses.try_collect::<Vec<_>>().await?;
 
-        let mut prompt_tokens = 0u32;
-        let mut completion_tokens = 0u32;
-        let mut total_tokens = 0u32;
+        let mut reports = Vec::new();
 
         let mut x_compute_time = 0u32;
         let mut x_total_time = 0u32;
@@ -1032,6 +1406,8 @@ pub(crate) async fn completions(
                     Json(ErrorResponse {
                         error: "No details in generation".to_string(),
                         error_type: "no details".to_string(),
+                        energy_consumption: None,
+                        template_error: None,
                     }),
                 ))?;
 
@@ -1051,10 +1427,11 @@ pub(crate) async fn completions(
                     .get("x-compute-characters")
                     .and_then(|v| v.to_str().ok()?.parse().ok())
                     .unwrap_or(0);
-                x_total_time += headers
+                let total_time: u32 = headers
                     .get("x-total-time")
                     .and_then(|v| v.to_str().ok()?.parse().ok())
                     .unwrap_or(0);
+                x_total_time += total_time;
                 x_validation_time += headers
                     .get("x-validation-time")
                     .and_then(|v| v.to_str().ok()?.parse().ok())
@@ -1080,36 +1457,36 @@ pub(crate) async fn completions(
                     .and_then(|v| v.to_str().ok()?.parse().ok())
                     .unwrap_or(0);
 
-                prompt_tokens += input_length;
-                completion_tokens += details.generated_tokens;
-                total_tokens += input_length + details.generated_tokens;
+                reports.push(completion_report(
+                    input_length,
+                    details.generated_tokens,
+                    details.finish_reason.clone(),
+                    generation.energy_consumption,
+                    details
+                        .tokens
+                        .iter()
+                        .map(|token| token.energy_consumption)
+                        .collect(),
+                    total_time.into(),
+                ));
 
                 Ok(CompletionComplete {
                     finish_reason: details.finish_reason.format(true),
                     index: index as u32,
-                    logprobs: None,
+                    logprobs: echo.then(|| details.echo_logprobs()),
                     text: generation.generated_text,
                 })
             })
             .collect::<Result<Vec<_>, _>>()
             .map_err(|(status, Json(err))| (status, Json(err)))?;
 
-        let response = Completion::Final(CompletionFinal {
-            id: "".to_string(),
-            created: current_time,
-            model: info.model_id.clone(),
-            system_fingerprint: format!(
-                "{}-{}",
-                info.version,
-                info.docker_label.unwrap_or("native")
-            ),
+        let response = completion_final(
+            info.model_id.clone(),
+            format!("{}-{}", info.version, info.docker_label.unwrap_or("native")),
+            current_time,
             choices,
-            usage: Usage {
-                prompt_tokens,
-                completion_tokens,
-                total_tokens,
-            },
-        });
+            &reports,
+        );
 
         // headers similar to `generate` but aggregated
         let mut headers = HeaderMap::new();
@@ -1131,6 +1508,50 @@ pub(crate) async fn completions(
     }
 }
 
+/// Energy report of one prompt of `/v1/completions`, the usage of the responses is built from them
+fn completion_report(
+    input_length: u32,
+    generated_tokens: u32,
+    finish_reason: FinishReason,
+    energy_consumption: Option<u64>,
+    token_energy_mj: Vec<Option<u64>>,
+    duration_ms: u64,
+) -> EnergyReport {
+    EnergyReport {
+        timestamp_ms: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0),
+        // Only the energy log numbers the requests
+        request_id: 0,
+        input_tokens: input_length,
+        generated_tokens,
+        energy_mj: energy_consumption,
+        duration_ms,
+        finish_reason,
+        metadata: None,
+        token_energy_mj,
+    }
+}
+
+/// Response of `/v1/completions` once all its prompts are generated
+fn completion_final(
+    model: String,
+    system_fingerprint: String,
+    created: u64,
+    choices: Vec<CompletionComplete>,
+    reports: &[EnergyReport],
+) -> Completion {
+    Completion::Final(CompletionFinal {
+        id: "".to_string(),
+        created,
+        model,
+        system_fingerprint,
+        choices,
+        usage: Usage::from_reports(reports),
+    })
+}
+
 /// Generate tokens
 #[utoipa::path(
 post,
@@ -1169,6 +1590,7 @@ pub(crate) async fn chat_completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
+    headers: HeaderMap,
     Json(mut chat): Json<ChatRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
@@ -1178,15 +1600,32 @@ pub(crate) async fn chat_completions(
         stream,
         stream_options,
         logprobs,
+        n,
         ..
     } = chat.clone();
+    let n = n.unwrap_or(1) as usize;
 
-    tracing::debug!("Got chat_template {:?}", infer.chat_template);
+    tracing::debug!(
+        "Got chat templates {:?}",
+        infer.chat_templates.keys().collect::<Vec<_>>()
+    );
     let id = chat.next_tool_call_id();
-    let (generate_request, using_tools): (GenerateRequest, bool) =
+    let (mut generate_request, using_tools, template_name) =
         chat.clone().try_into_generate(&infer)?;
+    generate_request.parameters.api_key = api_key(&headers);
+    // The applied template is returned with the response
+    let template_header = template_name.and_then(|name| HeaderValue::from_str(&name).ok());
     span.record("parameters", format!("{:?}", generate_request.parameters));
     let logprobs = logprobs.unwrap_or_default();
+    if n > 1 && (stream || using_tools) {
+        let reason = if stream {
+            "when streaming"
+        } else {
+            "with tools"
+        };
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err(InferError::from(ValidationError::NUnsupported(reason)).into());
+    }
 
     // extract model id from request if specified
     let model_id = match model.as_deref() {
@@ -1196,13 +1635,16 @@ pub(crate) async fn chat_completions(
     let system_fingerprint = format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
     // switch on stream
     if stream {
-        let (headers, response_stream) = generate_stream_internal(
+        let (mut headers, response_stream) = generate_stream_internal(
             infer.clone(),
             compute_type.clone(),
             Json(generate_request),
             span.clone(),
         )
         .await;
+        if let Some(template_header) = template_header {
+            headers.insert("x-chat-template", template_header);
+        }
 
         let response_stream = async_stream::stream! {
             let mut response_stream = Box::pin(response_stream);
@@ -1215,7 +1657,7 @@ pub(crate) async fn chat_completions(
                         ChatEvent::NoTool => {
                             chat.tools = None;
                             chat.response_format = None;
-                            let (generate_request, using_tools): (GenerateRequest, bool) =
+                            let (generate_request, using_tools, _template_name) =
                                 chat.clone().try_into_generate(&infer).unwrap();
                             assert!(!using_tools);
                             let (_headers, response_stream2) =
@@ -1239,14 +1681,56 @@ pub(crate) async fn chat_completions(
             yield Ok::<Event, Infallible>(Event::default().data("[DONE]"));
         };
 
-        let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
+        let sse = with_keep_alive(Sse::new(response_stream), &info);
         Ok((headers, sse).into_response())
+    } else if n > 1 {
+        let (mut headers, input_length, generations) = generate_n_internal(
+            Extension(infer),
+            compute_type,
+            Json(generate_request),
+            n,
+            span,
+        )
+        .await?;
+        if let Some(template_header) = template_header {
+            headers.insert("x-chat-template", template_header);
+        }
+
+        let current_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+            .as_secs();
+
+        let mut generations = generations.into_iter();
+        let first = generations.next().unwrap();
+        let mut response = ChatCompletion::new(
+            model_id,
+            system_fingerprint,
+            Some(first.generated_text),
+            current_time,
+            first.details.unwrap(),
+            logprobs,
+            None,
+            input_length,
+            first.batch_energy_consumption,
+        );
+        response.choices[0].energy_consumption = first.energy_consumption;
+        for generation in generations {
+            response.push_choice(
+                generation.generated_text,
+                generation.details.unwrap(),
+                logprobs,
+                generation.energy_consumption,
+            );
+        }
+        Ok((headers, Json(CompletionType::ChatCompletion(response))).into_response())
     } else {
         let (mut headers, mut input_length, Json(generation)) = generate_internal(
             Extension(infer.clone()),
             compute_type.clone(),
             Json(generate_request),
             span.clone(),
+            None,
         )
         .await?;
 
@@ -1256,11 +1740,12 @@ pub(crate) async fn chat_completions(
             .as_secs();
 
         let (tool_calls, output) = if using_tools {
-            match crate::chat::parse_output(&generation.generated_text)? {
+            let finish_reason = generation.details.as_ref().map(|d| &d.finish_reason);
+            match crate::chat::parse_output(&generation.generated_text, finish_reason)? {
                 ChatChoice::NoTool => {
                     chat.tools = None;
                     chat.response_format = None;
-                    let (generate_request, using_tools): (GenerateRequest, bool) =
+                    let (generate_request, using_tools, _template_name) =
                         chat.clone().try_into_generate(&infer)?;
                     assert!(!using_tools);
                     let (headers_final, input_length_final, Json(generation)) = generate_internal(
@@ -1268,6 +1753,7 @@ pub(crate) async fn chat_completions(
                         compute_type,
                         Json(generate_request),
                         span,
+                        None,
                     )
                     .await?;
                     headers = headers_final;
@@ -1289,7 +1775,11 @@ pub(crate) async fn chat_completions(
             logprobs,
             tool_calls,
             input_length,
+            generation.energy_consumption,
         ));
+        if let Some(template_header) = template_header {
+            headers.insert("x-chat-template", template_header);
+        }
 
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(response)).into_response())
@@ -1319,6 +1809,186 @@ async fn tokenize(
     Ok(Json(TokenizeResponse(tokens)))
 }
 
+/// Validate and tokenize a request without generating it
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/validate",
+request_body = GenerateRequest,
+responses(
+(status = 200, description = "Whether the request would be accepted, with its input length, effective parameters and estimated energy", body = ValidationReport),
+(status = 422, description = "Tokenizer error", body = ErrorResponse,
+example = json ! ({"error": "tokenizer error", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn validate(
+    Extension(infer): Extension<Infer>,
+    Json(req): Json<GenerateRequest>,
+) -> Result<Json<ValidationReport>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(infer.validate_only(req).await?))
+}
+
+/// Benchmark the latency, throughput and energy of the model on a prompt
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/benchmark",
+request_body = BenchmarkRequest,
+responses(
+(status = 200, description = "Mean, median and 95th percentile over the iterations", body = BenchmarkReport),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error", "error_type": "validation"})),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation", "error_type": "generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+)
+)]
+#[instrument(skip_all)]
+async fn benchmark(
+    Extension(infer): Extension<Infer>,
+    Json(req): Json<BenchmarkRequest>,
+) -> Result<Json<BenchmarkReport>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(infer.benchmark(req).await?))
+}
+
+/// Tokenizer special tokens
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/special_tokens",
+responses((status = 200, description = "Special tokens of the tokenizer", body = SpecialTokensResponse))
+)]
+#[instrument(skip_all)]
+async fn special_tokens(Extension(infer): Extension<Infer>) -> Json<SpecialTokensResponse> {
+    Json(infer.special_tokens().await)
+}
+
+/// Instantaneous power draw of the GPUs
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/power",
+responses(
+(status = 200, description = "Power draw of each device, empty when energy tracking is disabled", body = PowerResponse),
+(status = 500, description = "Power draw could not be read", body = ErrorResponse,
+example = json ! ({"error": "Unknown Error", "error_type": "energy_consumption_error"})),
+)
+)]
+#[instrument(skip_all)]
+async fn power(
+    Extension(infer): Extension<Infer>,
+) -> Result<Json<PowerResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let power_usage = infer.current_power_usage()?;
+    Ok(Json(PowerResponse {
+        devices: infer.energy_devices().to_vec(),
+        power_usage,
+    }))
+}
+
+/// Thermal state of the GPUs, to investigate throttling under load
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/telemetry",
+responses(
+(status = 200, description = "Temperature, power draw and throttle reasons of each device, empty when energy is not read through NVML", body = Vec<DeviceTelemetry>),
+(status = 500, description = "Devices could not be read", body = ErrorResponse,
+example = json ! ({"error": "Unknown Error", "error_type": "energy_consumption_error"})),
+)
+)]
+#[instrument(skip_all)]
+async fn telemetry(
+    Extension(infer): Extension<Infer>,
+) -> Result<Json<Vec<DeviceTelemetry>>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(infer.device_telemetry()?))
+}
+
+/// Devices visible to NVML, to pick the ones energy is measured on
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/devices",
+responses(
+(status = 200, description = "Index, name, memory and energy counter support of each device, empty when energy is not read through NVML", body = Vec<DeviceInfo>),
+(status = 500, description = "Devices could not be read", body = ErrorResponse,
+example = json ! ({"error": "Unknown Error", "error_type": "energy_consumption_error"})),
+)
+)]
+#[instrument(skip_all)]
+async fn devices(
+    Extension(infer): Extension<Infer>,
+) -> Result<Json<Vec<DeviceInfo>>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(infer.list_devices()?))
+}
+
+/// Aggregate energy of the requests served since the router started
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/metrics-snapshot",
+responses((status = 200, description = "Energy totals and current power draw", body = EnergySummary))
+)]
+#[instrument(skip_all)]
+async fn metrics_snapshot(Extension(infer): Extension<Infer>) -> Json<EnergySummary> {
+    Json(infer.energy_summary())
+}
+
+/// Maximum number of concurrent requests
+///
+/// Only served with `--admin-api-key`, which is the bearer token of the request.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/concurrency_limit",
+responses((status = 200, description = "Current concurrency limit", body = ConcurrencyLimit))
+)]
+#[instrument(skip_all)]
+async fn get_concurrency_limit(Extension(infer): Extension<Infer>) -> Json<ConcurrencyLimit> {
+    Json(ConcurrencyLimit {
+        limit: infer.current_concurrency_limit(),
+    })
+}
+
+/// Change the maximum number of concurrent requests without restarting
+///
+/// Running requests are never cancelled: when the limit is lowered below the number of running
+/// requests, new requests are rejected until they drain. Only served with `--admin-api-key`, which
+/// is the bearer token of the request.
+#[utoipa::path(
+put,
+tag = "Text Generation Inference",
+path = "/admin/concurrency_limit",
+request_body = ConcurrencyLimit,
+responses(
+(status = 200, description = "New concurrency limit", body = ConcurrencyLimit),
+(status = 422, description = "Invalid limit", body = ErrorResponse,
+example = json ! ({"error": "The concurrency limit must be greater than 0", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all, fields(limit = req.limit))]
+async fn set_concurrency_limit(
+    Extension(infer): Extension<Infer>,
+    Json(req): Json<ConcurrencyLimit>,
+) -> Result<Json<ConcurrencyLimit>, (StatusCode, Json<ErrorResponse>)> {
+    if req.limit == 0 {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: "The concurrency limit must be greater than 0".to_string(),
+                error_type: "validation".to_string(),
+                energy_consumption: None,
+                template_error: None,
+            }),
+        ));
+    }
+    infer.set_concurrency_limit(req.limit);
+    Ok(Json(ConcurrencyLimit {
+        limit: infer.current_concurrency_limit(),
+    }))
+}
+
 /// Prometheus metrics scrape endpoint
 #[utoipa::path(
     get,
@@ -1339,20 +2009,32 @@ pub(crate) struct ComputeType(String);
 paths(
 health,
 get_model_info,
+model_metadata,
 compat_generate,
 generate,
 generate_stream,
 chat_completions,
 completions,
 tokenize,
+validate,
+benchmark,
+special_tokens,
+power,
+telemetry,
+devices,
+metrics_snapshot,
+get_concurrency_limit,
+set_concurrency_limit,
 metrics,
 openai_get_model_info,
 sagemaker_compatibility,
 get_chat_tokenize,
+apply_template,
 ),
 components(
 schemas(
 Info,
+ModelMetadata,
 CompatGenerateRequest,
 SagemakerRequest,
 GenerateRequest,
@@ -1371,6 +2053,7 @@ ChatCompletionComplete,
 ChatCompletionChoice,
 ChatCompletionDelta,
 ChatCompletionChunk,
+ChunkEnergy,
 ChatCompletionLogprob,
 ChatCompletionLogprobs,
 ChatCompletionTopLogprob,
@@ -1392,9 +2075,15 @@ SimpleToken,
 BestOfSequence,
 Details,
 FinishReason,
+LengthLimit,
 StreamResponse,
+StreamTokenId,
+BenchmarkRequest,
+BenchmarkReport,
+BenchmarkStats,
 StreamDetails,
 ErrorResponse,
+TemplateErrorDetail,
 GrammarType,
 Usage,
 StreamOptions,
@@ -1406,7 +2095,29 @@ FunctionDefinition,
 ToolChoice,
 ModelInfo,
 ChatTokenizeResponse,
+ApplyTemplateRequest,
+ApplyTemplateResponse,
 MessageBody,
+SpecialToken,
+SpecialTokensResponse,
+PowerResponse,
+DeviceTelemetry,
+DeviceInfo,
+EnergySummary,
+DeviceEnergy,
+TokenEnergyStats,
+TokenOffsets,
+ConcurrencyLimit,
+TelemetryField,
+TelemetryValue,
+RequestClass,
+EnergyPriority,
+Chunking,
+HealthResponse,
+EnergyHealth,
+EnergyUnit,
+ValidationReport,
+EffectiveParameters,
 )
 ),
 tags(
@@ -1491,7 +2202,34 @@ pub fn legacy_tokenizer_handle(config_filename: Option<&PathBuf>) -> Option<()>
     })
 }
 
+/// `Authorization` header value of `api_key`
+pub(crate) fn bearer_token(api_key: String) -> &'static str {
+    // Leaked to be shared by all the requests
+    format!("Bearer {api_key}").leak()
+}
+
+/// Reject the requests whose `Authorization` header is not `expected`
+async fn require_bearer_token(
+    State(expected): State<&'static str>,
+    headers: HeaderMap,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Result<Response, StatusCode> {
+    match headers.get(AUTHORIZATION) {
+        Some(token) => match token.to_str() {
+            Ok(token_str) if token_str.to_lowercase() == expected.to_lowercase() => {
+                Ok(next.run(request).await)
+            }
+            _ => Err(StatusCode::UNAUTHORIZED),
+        },
+        None => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
 /// Serving method
+///
+/// `sampling_hook` lets a program embedding the router change the sampling parameters during the
+/// generations, see [`SamplingHook`].
 #[allow(clippy::too_many_arguments)]
 pub async fn run(
     backend: impl Backend + Send + Sync + 'static,
@@ -1503,6 +2241,7 @@ pub async fn run(
     max_total_tokens: usize,
     validation_workers: usize,
     api_key: Option<String>,
+    admin_api_key: Option<String>,
     tokenizer_name: String,
     tokenizer_config_path: Option<String>,
     revision: Option<String>,
@@ -1517,6 +2256,33 @@ pub async fn run(
     max_client_batch_size: usize,
     usage_stats_level: usage_stats::UsageStatsLevel,
     payload_limit: usize,
+    length_energy_penalty: Option<f32>,
+    telemetry_fields: Vec<TelemetryField>,
+    interactive_max_new_tokens: Option<u32>,
+    max_concurrent_interactive_requests: Option<usize>,
+    max_concurrent_batch_requests: Option<usize>,
+    energy_devices: Vec<u32>,
+    sse_keep_alive_interval_ms: Option<u64>,
+    energy_source: EnergySource,
+    carbon_intensity_g_per_kwh: Option<f64>,
+    energy_sampling_interval: usize,
+    energy_unit: EnergyUnit,
+    efficiency_ewma_alpha: f64,
+    max_input_bytes: usize,
+    efficiency_floor: Option<f64>,
+    idempotency_cache_size: usize,
+    idempotency_ttl_secs: u64,
+    tenant_quotas: Vec<TenantQuota>,
+    default_tenant_quota: Option<usize>,
+    energy_device_uuid: Option<String>,
+    energy_log_path: Option<String>,
+    energy_log_format: EnergyLogFormat,
+    grpc_port: Option<u16>,
+    prefix_cache_blocks: Option<usize>,
+    token_energy_floor_mj: Option<u64>,
+    token_energy_cap_mj: Option<u64>,
+    energy_replay_readings: Vec<u64>,
+    sampling_hook: Option<SamplingHook>,
 ) -> Result<(), WebServerError> {
     // CORS allowed origins
     // map to go inside the option and then map to parse from String to HeaderValue
@@ -1806,6 +2572,7 @@ pub async fn run(
         max_total_tokens,
         validation_workers,
         api_key,
+        admin_api_key,
         config,
         (tokenizer?, tokenizer_config),
         (preprocessor_config, processor_config),
@@ -1820,6 +2587,33 @@ pub async fn run(
         compat_return_full_text,
         allow_origin,
         payload_limit,
+        length_energy_penalty,
+        telemetry_fields,
+        interactive_max_new_tokens,
+        max_concurrent_interactive_requests,
+        max_concurrent_batch_requests,
+        energy_devices,
+        sse_keep_alive_interval_ms,
+        energy_source,
+        carbon_intensity_g_per_kwh,
+        energy_sampling_interval,
+        energy_unit,
+        efficiency_ewma_alpha,
+        max_input_bytes,
+        efficiency_floor,
+        idempotency_cache_size,
+        idempotency_ttl_secs,
+        tenant_quotas,
+        default_tenant_quota,
+        energy_device_uuid,
+        energy_log_path,
+        energy_log_format,
+        grpc_port,
+        prefix_cache_blocks,
+        token_energy_floor_mj,
+        token_energy_cap_mj,
+        energy_replay_readings,
+        sampling_hook,
     )
     .await;
 
@@ -1867,6 +2661,7 @@ async fn start(
     max_total_tokens: usize,
     validation_workers: usize,
     api_key: Option<String>,
+    admin_api_key: Option<String>,
     config: Option<Config>,
     (tokenizer, tokenizer_config): (Tokenizer, HubTokenizerConfig),
     (preprocessor_config, processor_config): (Option<HubPreprocessorConfig>, HubProcessorConfig),
@@ -1881,6 +2676,33 @@ async fn start(
     compat_return_full_text: bool,
     allow_origin: Option<AllowOrigin>,
     payload_limit: usize,
+    length_energy_penalty: Option<f32>,
+    telemetry_fields: Vec<TelemetryField>,
+    interactive_max_new_tokens: Option<u32>,
+    max_concurrent_interactive_requests: Option<usize>,
+    max_concurrent_batch_requests: Option<usize>,
+    energy_devices: Vec<u32>,
+    sse_keep_alive_interval_ms: Option<u64>,
+    energy_source: EnergySource,
+    carbon_intensity_g_per_kwh: Option<f64>,
+    energy_sampling_interval: usize,
+    energy_unit: EnergyUnit,
+    efficiency_ewma_alpha: f64,
+    max_input_bytes: usize,
+    efficiency_floor: Option<f64>,
+    idempotency_cache_size: usize,
+    idempotency_ttl_secs: u64,
+    tenant_quotas: Vec<TenantQuota>,
+    default_tenant_quota: Option<usize>,
+    energy_device_uuid: Option<String>,
+    energy_log_path: Option<String>,
+    energy_log_format: EnergyLogFormat,
+    grpc_port: Option<u16>,
+    prefix_cache_blocks: Option<usize>,
+    token_energy_floor_mj: Option<u64>,
+    token_energy_cap_mj: Option<u64>,
+    energy_replay_readings: Vec<u64>,
+    sampling_hook: Option<SamplingHook>,
 ) -> Result<(), WebServerError> {
     // Determine the server port based on the feature and environment variable.
     let port = if cfg!(feature = "google") {
@@ -1913,13 +2735,35 @@ async fn start(
         disable_grammar_support,
     );
 
-    let infer = Infer::new(
-        backend,
-        validation,
-        max_concurrent_requests,
-        tokenizer_config,
-        processor_config,
-    );
+    let infer = Infer::builder(backend, validation)
+        .max_concurrent_requests(max_concurrent_requests)
+        .tokenizer_config(tokenizer_config)
+        .processor_config(processor_config)
+        .length_energy_penalty(length_energy_penalty)
+        .telemetry_fields(telemetry_fields)
+        .interactive_max_new_tokens(interactive_max_new_tokens)
+        .max_concurrent_interactive_requests(max_concurrent_interactive_requests)
+        .max_concurrent_batch_requests(max_concurrent_batch_requests)
+        .energy_devices(energy_devices)
+        .energy_source(energy_source)
+        .energy_replay_readings(energy_replay_readings)
+        .carbon_intensity_g_per_kwh(carbon_intensity_g_per_kwh)
+        .energy_sampling_interval(energy_sampling_interval)
+        .token_energy_bounds(token_energy_floor_mj, token_energy_cap_mj)
+        .energy_unit(energy_unit)
+        .efficiency_ewma_alpha(efficiency_ewma_alpha)
+        .max_input_bytes(max_input_bytes)
+        .efficiency_floor(efficiency_floor)
+        .idempotency_cache_size(idempotency_cache_size)
+        .idempotency_ttl(Duration::from_secs(idempotency_ttl_secs))
+        .tenant_quotas(tenant_quotas, default_tenant_quota)
+        .energy_device_uuid(energy_device_uuid)
+        .energy_log(energy_log_path.map(PathBuf::from), energy_log_format)
+        .prefix_cache_blocks(prefix_cache_blocks)
+        .sampling_hook(sampling_hook)
+        .build();
+    // Measured before serving any request
+    infer.measure_idle_power(IDLE_POWER_WINDOW).await;
 
     // Duration buckets
     let duration_matcher = Matcher::Suffix(String::from("duration"));
@@ -1950,6 +2794,14 @@ async fn start(
     // Batch size buckets
     let batch_size_matcher = Matcher::Full(String::from("tgi_batch_next_size"));
     let batch_size_buckets: Vec<f64> = (0..1024).map(|x| (x + 1) as f64).collect();
+    // Token energy buckets, from 1mJ to ~16kJ
+    let token_energy_matcher = Matcher::Full(String::from("tgi_token_energy_millijoules"));
+    let token_energy_buckets: Vec<f64> = (0..25).map(|x| 2f64.powi(x)).collect();
+    // Continuation rounds buckets
+    let continuation_rounds_matcher =
+        Matcher::Full(String::from("tgi_request_continuation_rounds"));
+    let continuation_rounds_buckets: Vec<f64> =
+        (0..=MAX_CONTINUATION_ROUNDS).map(|x| x as f64).collect();
     // Speculated tokens buckets
     // let skipped_matcher = Matcher::Full(String::from("tgi_request_skipped_tokens"));
     // let skipped_buckets: Vec<f64> = (0..shard_info.speculate + 1).map(|x| x as f64).collect();
@@ -1965,6 +2817,10 @@ async fn start(
         .set_buckets_for_metric(max_new_tokens_matcher, &max_new_tokens_buckets)
         .unwrap()
         .set_buckets_for_metric(batch_size_matcher, &batch_size_buckets)
+        .unwrap()
+        .set_buckets_for_metric(token_energy_matcher, &token_energy_buckets)
+        .unwrap()
+        .set_buckets_for_metric(continuation_rounds_matcher, &continuation_rounds_buckets)
         .unwrap();
     // .set_buckets_for_metric(skipped_matcher, &skipped_buckets)
     // .unwrap();
@@ -2007,6 +2863,48 @@ async fn start(
         metrics::Unit::Count,
         "Generated tokens per request"
     );
+    metrics::describe_histogram!(
+        "tgi_token_energy_millijoules",
+        "Energy consumed by each generated token in millijoules"
+    );
+    metrics::describe_counter!(
+        "tgi_token_energy_outliers",
+        metrics::Unit::Count,
+        "Number of token energies above the cap, left out of the token energy histogram"
+    );
+    metrics::describe_counter!(
+        "tgi_request_energy_millijoules_total",
+        "Energy consumed by the finished requests in millijoules"
+    );
+    metrics::describe_gauge!(
+        "tgi_efficiency_ewma",
+        "Moving average of the tokens per joule of the finished requests"
+    );
+    metrics::describe_counter!(
+        "tgi_finish_reason",
+        metrics::Unit::Count,
+        "Number of finished requests per finish reason"
+    );
+    metrics::describe_counter!(
+        "tgi_energy_read_failure",
+        metrics::Unit::Count,
+        "Number of energy readings that still failed after being retried"
+    );
+    metrics::describe_gauge!(
+        "tgi_energy_reconciliation_error",
+        metrics::Unit::Count,
+        "Relative difference between the summed token energies and the request energy"
+    );
+    metrics::describe_counter!(
+        "tgi_request_idempotent_replay",
+        metrics::Unit::Count,
+        "Number of retried requests answered with the stored response of their idempotency key"
+    );
+    metrics::describe_counter!(
+        "tgi_request_long_output",
+        metrics::Unit::Count,
+        "Number of requests whose output greatly exceeds the rolling average length"
+    );
     metrics::describe_counter!(
         "tgi_batch_inference_count",
         metrics::Unit::Count,
@@ -2028,6 +2926,16 @@ async fn start(
         "Current batch size"
     );
     metrics::describe_gauge!("tgi_queue_size", metrics::Unit::Count, "Current queue size");
+    metrics::describe_gauge!(
+        "tgi_inflight_requests",
+        metrics::Unit::Count,
+        "Number of requests holding a concurrency permit"
+    );
+    metrics::describe_gauge!(
+        "tgi_available_permits",
+        metrics::Unit::Count,
+        "Number of concurrency permits left before new requests are rejected"
+    );
     metrics::describe_gauge!(
         "tgi_batch_current_max_tokens",
         metrics::Unit::Count,
@@ -2093,6 +3001,26 @@ async fn start(
         metrics::Unit::Count,
         "Batch size of the next batch"
     );
+    metrics::describe_counter!(
+        "tgi_request_continuations",
+        metrics::Unit::Count,
+        "Number of times a request was scheduled again after reaching its length limit"
+    );
+    metrics::describe_histogram!(
+        "tgi_request_continuation_rounds",
+        metrics::Unit::Count,
+        "Continuation rounds per request"
+    );
+    metrics::describe_counter!(
+        "tgi_prefix_cache_lookups",
+        metrics::Unit::Count,
+        "Prefix cache lookups per result (hit or miss)"
+    );
+    metrics::describe_gauge!(
+        "tgi_prefix_cache_hit_rate",
+        metrics::Unit::Count,
+        "Share of the requests whose input starts with a cached prefix"
+    );
 
     // CORS layer
     let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
@@ -2123,6 +3051,7 @@ async fn start(
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
         docker_label: option_env!("DOCKER_LABEL"),
+        sse_keep_alive_interval_ms,
     };
 
     #[allow(unused_mut)] // mut is needed for conditional compilation
@@ -2191,36 +3120,43 @@ async fn start(
         .route("/v1/completions", post(completions))
         .route("/vertex", post(vertex_compatibility))
         .route("/invocations", post(sagemaker_compatibility))
-        .route("/tokenize", post(tokenize));
-
-    if let Some(api_key) = api_key {
-        let mut prefix = "Bearer ".to_string();
-        prefix.push_str(&api_key);
-
-        // Leak to allow FnMut
-        let api_key: &'static str = prefix.leak();
-
-        let auth = move |headers: HeaderMap,
-                         request: axum::extract::Request,
-                         next: axum::middleware::Next| async move {
-            match headers.get(AUTHORIZATION) {
-                Some(token) => match token.to_str() {
-                    Ok(token_str) if token_str.to_lowercase() == api_key.to_lowercase() => {
-                        let response = next.run(request).await;
-                        Ok(response)
-                    }
-                    _ => Err(StatusCode::UNAUTHORIZED),
-                },
-                None => Err(StatusCode::UNAUTHORIZED),
-            }
-        };
-
-        base_routes = base_routes.layer(axum::middleware::from_fn(auth))
+        .route("/tokenize", post(tokenize))
+        .route("/validate", post(validate))
+        .route("/benchmark", post(benchmark));
+
+    let api_bearer_token = api_key.map(bearer_token);
+    if let Some(api_bearer_token) = api_bearer_token {
+        base_routes = base_routes.layer(axum::middleware::from_fn_with_state(
+            api_bearer_token,
+            require_bearer_token,
+        ))
     }
+
+    // The admin routes change the server for every client, they have their own key and are not
+    // served without it
+    let admin_routes = match admin_api_key {
+        Some(admin_api_key) => Router::new()
+            .route(
+                "/admin/concurrency_limit",
+                get(get_concurrency_limit).put(set_concurrency_limit),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                bearer_token(admin_api_key),
+                require_bearer_token,
+            )),
+        None => Router::new(),
+    };
     let info_routes = Router::new()
         .route("/", get(health))
         .route("/chat_tokenize", post(get_chat_tokenize))
+        .route("/apply_template", post(apply_template))
         .route("/info", get(get_model_info))
+        .route("/model-info", get(model_metadata))
+        .route("/special_tokens", get(special_tokens))
+        .route("/power", get(power))
+        .route("/telemetry", get(telemetry))
+        .route("/devices", get(devices))
+        .route("/metrics-snapshot", get(metrics_snapshot))
         .route("/health", get(health))
         .route("/ping", get(health))
         .route("/metrics", get(metrics))
@@ -2229,10 +3165,22 @@ async fn start(
     let compute_type =
         ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
 
+    // gRPC interface, served next to the HTTP one and behind the same API key
+    if let Some(grpc_port) = grpc_port {
+        let grpc_addr = SocketAddr::new(addr.ip(), grpc_port);
+        tokio::spawn(crate::grpc::serve(
+            grpc_addr,
+            infer.clone(),
+            compute_type.clone(),
+            api_bearer_token,
+        ));
+    }
+
     // Combine routes and layers
     let mut app = Router::new()
         .merge(swagger_ui)
         .merge(base_routes)
+        .merge(admin_routes)
         .merge(info_routes);
 
     #[cfg(feature = "google")]
@@ -2279,6 +3227,7 @@ async fn start(
         .layer(Extension(prom_handle.clone()))
         .layer(OtelAxumLayer::default())
         .layer(DefaultBodyLimit::max(payload_limit))
+        .layer(axum::middleware::from_fn(crate::compression::compress))
         .layer(cors_layer);
 
     tracing::info!("Connected");
@@ -2355,7 +3304,7 @@ pub async fn get_tokenizer_config(api_repo: &ApiRepo) -> Option<HubTokenizerConf
 }
 
 /// Shutdown signal handler
-async fn shutdown_signal() {
+pub(crate) async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -2385,24 +3334,16 @@ async fn shutdown_signal() {
 /// Convert to Axum supported formats
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
-        let status_code = match err {
-            InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
-            InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
-            InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
-            InferError::IncompleteGenerationStream => StatusCode::INTERNAL_SERVER_ERROR,
-            InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::MissingTemplateVariable(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::ToolError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::StreamSerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            InferError::EnergyConsumptionError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        let status_code =
+            StatusCode::from_u16(err.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
 
         (
             status_code,
             Json(ErrorResponse {
                 error: err.to_string(),
                 error_type: err.error_type().to_string(),
+                energy_consumption: err.energy_consumption(),
+                template_error: err.template_error_detail(),
             }),
         )
     }
@@ -2414,6 +3355,8 @@ impl From<InferError> for Event {
             .json_data(ErrorResponse {
                 error: err.to_string(),
                 error_type: err.error_type().to_string(),
+                energy_consumption: err.energy_consumption(),
+                template_error: err.template_error_detail(),
             })
             .unwrap()
     }
@@ -2426,3 +3369,133 @@ pub enum WebServerError {
     #[error("Tokenizer error: {0}")]
     Tokenizer(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completion_usage_energy() {
+        let reports = [
+            completion_report(
+                2,
+                3,
+                FinishReason::Length,
+                Some(1500),
+                vec![Some(500), Some(1000), Some(1500)],
+                40,
+            ),
+            completion_report(4, 1, FinishReason::EndOfSequenceToken, None, vec![None], 10),
+        ];
+        let completion = completion_final(
+            "gpt2".to_string(),
+            "3.0.0-native".to_string(),
+            0,
+            vec![],
+            &reports,
+        );
+        let serialized = serde_json::to_value(&completion).unwrap();
+        assert_eq!(serialized["object"], "text_completion");
+        assert_eq!(
+            serialized["usage"],
+            serde_json::json!({
+                "prompt_tokens": 6,
+                "completion_tokens": 4,
+                "total_tokens": 10,
+                "energy_consumption": 1500,
+            })
+        );
+
+        // Energy is omitted when tracking did not report anything
+        let completion = completion_final(
+            "gpt2".to_string(),
+            "3.0.0-native".to_string(),
+            0,
+            vec![],
+            &reports[1..],
+        );
+        let serialized = serde_json::to_value(&completion).unwrap();
+        assert!(serialized["usage"].get("energy_consumption").is_none());
+    }
+
+    fn info() -> Info {
+        Info {
+            model_id: "gpt2".to_string(),
+            model_sha: None,
+            model_pipeline_tag: None,
+            max_concurrent_requests: 4,
+            max_best_of: 1,
+            max_stop_sequences: 4,
+            max_input_tokens: 32,
+            max_total_tokens: 64,
+            validation_workers: 1,
+            max_client_batch_size: 4,
+            router: "text-generation-router",
+            version: "3.0.0",
+            sha: None,
+            docker_label: None,
+            sse_keep_alive_interval_ms: Some(0),
+        }
+    }
+
+    async fn completions_body(stream: bool) -> String {
+        let infer = crate::infer::tests::infer(
+            crate::infer::tests::MockBackend::new(3, 1),
+            Some(crate::infer::tests::mock_meter(1)),
+        );
+        let request = serde_json::from_value(serde_json::json!({
+            "prompt": "hello world",
+            "max_tokens": 8,
+            "stream": stream,
+        }))
+        .unwrap();
+        let response = completions(
+            Extension(infer),
+            Extension(ComputeType("gpu+optimized".to_string())),
+            Extension(info()),
+            HeaderMap::new(),
+            Json(request),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn assert_usage(usage: &serde_json::Value) {
+        assert_eq!(usage["prompt_tokens"], 2);
+        assert_eq!(usage["completion_tokens"], 3);
+        assert_eq!(usage["total_tokens"], 5);
+        assert!(usage["energy_consumption"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_completions_usage() {
+        let body = completions_body(false).await;
+        let completion: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(completion["choices"][0]["text"], "mock");
+        assert_usage(&completion["usage"]);
+    }
+
+    #[tokio::test]
+    async fn test_completions_stream_usage() {
+        let body = completions_body(true).await;
+        let events: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+
+        // Only the last chunk of the prompt carries the usage
+        let chunks: Vec<serde_json::Value> = events[..events.len() - 1]
+            .iter()
+            .map(|event| serde_json::from_str(event).unwrap())
+            .collect();
+        let (last, tokens) = chunks.split_last().unwrap();
+        assert!(tokens.iter().all(|chunk| chunk.get("usage").is_none()));
+        assert_usage(&last["usage"]);
+    }
+}
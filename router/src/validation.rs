@@ -1,8 +1,8 @@
 use crate::config::Config;
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
 use crate::{
-    GenerateParameters, GenerateRequest, GrammarType, HubPreprocessorConfig, Idefics2Preprocessor,
-    TokenizerTrait,
+    EnergyPriority, GenerateParameters, GenerateRequest, GrammarType, HubPreprocessorConfig,
+    Idefics2Preprocessor, TokenizerTrait,
 };
 use crate::{PyTokenizer, Tokenizer};
 use base64::{engine::general_purpose::STANDARD, Engine};
@@ -134,6 +134,8 @@ impl Validation {
         add_special_tokens: bool,
         truncate: Option<usize>,
         max_new_tokens: Option<u32>,
+        continue_on_length: bool,
+        max_total_new_tokens: Option<u32>,
     ) -> Result<(Vec<Chunk>, Option<Vec<u32>>, usize, u32, u32), ValidationError> {
         // If we have a fast tokenizer
         let (encoding, inputs) = self
@@ -147,7 +149,18 @@ impl Validation {
         };
 
         // Get total tokens
-        let (max_new_tokens, max_total_new_tokens) = if let Some(max_new_tokens) = max_new_tokens {
+        let (max_new_tokens, max_total_new_tokens) = if !continue_on_length {
+            // Generate all the tokens in a single request
+            let max_new_tokens = max_new_tokens
+                .unwrap_or_else(|| self.max_total_tokens.saturating_sub(input_length) as u32);
+            (max_new_tokens, max_new_tokens)
+        } else if let Some(max_total_new_tokens) = max_total_new_tokens {
+            // `max_new_tokens` bounds every round and `max_total_new_tokens` all of them
+            let round_size = max_new_tokens
+                .unwrap_or(DEFAULT_GENERATION_LENGTH)
+                .min(max_total_new_tokens);
+            (round_size, max_total_new_tokens)
+        } else if let Some(max_new_tokens) = max_new_tokens {
             // Do not accept humongous max_new_tokens queries.
             // We preallocate the default but we prevent a single user
             // from taking up all the slots in a handful of queries that consume little
@@ -203,6 +216,19 @@ impl Validation {
         &self,
         request: GenerateRequest,
     ) -> Result<ValidGenerateRequest, ValidationError> {
+        let request = self.check_parameters(request)?;
+        self.validate_checked(request).await
+    }
+
+    /// Run the checks of a payload that do not need the tokenizer
+    ///
+    /// They are cheap, so that invalid requests can be rejected before they take a concurrency
+    /// slot. [`Validation::validate_checked`] then tokenizes the inputs.
+    #[instrument(skip_all)]
+    pub(crate) fn check_parameters(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<CheckedRequest, ValidationError> {
         let GenerateParameters {
             best_of,
             temperature,
@@ -219,8 +245,14 @@ impl Validation {
             watermark,
             decoder_input_details,
             top_n_tokens,
+            top_logprobs,
             grammar,
             adapter_id,
+            continue_on_length,
+            max_total_new_tokens,
+            max_energy_millijoules,
+            measure_energy,
+            energy_priority,
             ..
         } = request.parameters;
 
@@ -284,6 +316,18 @@ impl Validation {
             return Err(ValidationError::NegativeMaxNewTokens);
         }
 
+        if max_total_new_tokens == Some(0) {
+            return Err(ValidationError::NegativeMaxTotalNewTokens);
+        }
+
+        if max_energy_millijoules == Some(0) {
+            return Err(ValidationError::MaxEnergy);
+        }
+
+        if max_energy_millijoules.is_some() && !measure_energy {
+            return Err(ValidationError::EnergyBudgetUnavailable);
+        }
+
         if stop_sequences.len() > self.max_stop_sequences {
             return Err(ValidationError::StopSequence(
                 self.max_stop_sequences,
@@ -311,6 +355,15 @@ impl Validation {
             })
             .unwrap_or(Ok(0))?;
 
+        let top_logprobs = top_logprobs
+            .map(|value| {
+                if value > self.max_top_n_tokens {
+                    return Err(ValidationError::TopLogprobs(self.max_top_n_tokens, value));
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0))?;
+
         // Check if inputs is empty
         if request.inputs.is_empty() {
             return Err(EmptyInput);
@@ -326,16 +379,6 @@ impl Validation {
             })
             .unwrap_or(Ok(None))?;
 
-        // Validate inputs
-        let (inputs, input_ids, input_length, max_new_tokens, max_total_new_tokens) = self
-            .validate_input(
-                request.inputs,
-                request.add_special_tokens,
-                truncate,
-                max_new_tokens,
-            )
-            .await?;
-
         // TODO: we should build the FSM here and pass the compiled FSM instead of the grammar
         // NOTE: this is currently difficult because we need the tokenizer in Python to build
         // the FSM and we'd have to load a copy of the tokenizer into our Pyo3 instance which
@@ -349,40 +392,7 @@ impl Validation {
                 if self.disable_grammar_support {
                     return Err(ValidationError::Grammar);
                 }
-                let valid_grammar = match grammar {
-                    GrammarType::Json(json) => {
-                        let json = match json {
-                            // if value is a string, we need to parse it again to make sure its
-                            // a valid json
-                            Value::String(s) => serde_json::from_str(&s)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string())),
-                            Value::Object(_) => Ok(json),
-                            _ => Err(ValidationError::Grammar),
-                        }?;
-
-                        // Check if the json is a valid JSONSchema
-                        jsonschema::draft202012::meta::validate(&json)
-                            .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
-
-                        // The schema can be valid but lack properties.
-                        // We need properties for the grammar to be successfully parsed in Python.
-                        // Therefore, we must check and throw an error if properties are missing.
-                        json.get("properties")
-                            .ok_or(ValidationError::InvalidGrammar(
-                                "Grammar must have a 'properties' field".to_string(),
-                            ))?;
-
-                        // Do compilation in the router for performance. In the future, we
-                        // should also move regex -> automaton compilation in the router,
-                        // but this is not yet supported in pure Rust by outlines-core.
-                        let grammar_regex = json_schema_to_regex(&json, None, &json)
-                            .map_err(ValidationError::RegexFromSchema)?;
-
-                        ValidGrammar::Regex(grammar_regex.to_string())
-                    }
-                    GrammarType::Regex(regex) => ValidGrammar::Regex(regex),
-                };
-                Some(valid_grammar)
+                Some(validate_grammar(grammar)?)
             }
             None => None,
         };
@@ -399,10 +409,47 @@ impl Validation {
             watermark,
             grammar,
         };
-        let stopping_parameters = ValidStoppingParameters {
+
+        Ok(CheckedRequest {
+            inputs: request.inputs,
+            add_special_tokens: request.add_special_tokens,
+            truncate,
             max_new_tokens,
+            continue_on_length,
             max_total_new_tokens,
+            decoder_input_details,
+            measure_energy,
+            energy_priority,
+            parameters,
             stop_sequences,
+            top_n_tokens,
+            top_logprobs,
+            adapter_id,
+        })
+    }
+
+    /// Tokenize the inputs of a request that passed [`Validation::check_parameters`]
+    #[instrument(skip_all)]
+    pub(crate) async fn validate_checked(
+        &self,
+        request: CheckedRequest,
+    ) -> Result<ValidGenerateRequest, ValidationError> {
+        // Validate inputs
+        let (inputs, input_ids, input_length, max_new_tokens, max_total_new_tokens) = self
+            .validate_input(
+                request.inputs,
+                request.add_special_tokens,
+                request.truncate,
+                request.max_new_tokens,
+                request.continue_on_length,
+                request.max_total_new_tokens,
+            )
+            .await?;
+
+        let stopping_parameters = ValidStoppingParameters {
+            max_new_tokens,
+            max_total_new_tokens,
+            stop_sequences: request.stop_sequences,
             ignore_eos_token: false,
         };
 
@@ -412,13 +459,16 @@ impl Validation {
             inputs,
             input_ids: input_ids.map(Arc::new),
             add_special_tokens: request.add_special_tokens,
-            decoder_input_details,
+            decoder_input_details: request.decoder_input_details,
+            measure_energy: request.measure_energy,
+            energy_priority: request.energy_priority,
             input_length: input_length as u32,
-            truncate: truncate.unwrap_or(self.max_input_length) as u32,
-            parameters,
+            truncate: request.truncate.unwrap_or(self.max_input_length) as u32,
+            parameters: request.parameters,
             stopping_parameters,
-            top_n_tokens,
-            adapter_id,
+            top_n_tokens: request.top_n_tokens,
+            top_logprobs: request.top_logprobs,
+            adapter_id: request.adapter_id,
         })
     }
 
@@ -435,6 +485,14 @@ impl Validation {
 
         Ok(best_of)
     }
+
+    /// Validate the number of choices of a request, bounded by the same limit as `best_of`
+    pub(crate) fn validate_n(&self, n: usize) -> Result<usize, ValidationError> {
+        if n == 0 || n > self.max_best_of {
+            return Err(ValidationError::N(self.max_best_of, n));
+        }
+        Ok(n)
+    }
 }
 
 /// Round robin tokenization task
@@ -848,6 +906,56 @@ impl ChunksToString for Vec<Chunk> {
     }
 }
 
+/// Convert a user grammar into the grammar sent to the backend
+fn validate_grammar(grammar: GrammarType) -> Result<ValidGrammar, ValidationError> {
+    let json = match grammar {
+        GrammarType::Json(json) | GrammarType::JsonObject(Some(json)) => json,
+        GrammarType::JsonObject(None) => {
+            // JSON mode: any JSON object is accepted, so there are no properties to check
+            let json = serde_json::json!({"type": "object"});
+            let grammar_regex = json_schema_to_regex(&json, None, &json)
+                .map_err(ValidationError::RegexFromSchema)?;
+            return Ok(ValidGrammar::Regex(grammar_regex.to_string()));
+        }
+        GrammarType::Regex(regex) => {
+            // Fail here rather than in the backend, once the request is scheduled
+            regex::Regex::new(&regex)
+                .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
+            return Ok(ValidGrammar::Regex(regex));
+        }
+    };
+
+    let json = match json {
+        // if value is a string, we need to parse it again to make sure its
+        // a valid json
+        Value::String(s) => {
+            serde_json::from_str(&s).map_err(|e| ValidationError::InvalidGrammar(e.to_string()))
+        }
+        Value::Object(_) => Ok(json),
+        _ => Err(ValidationError::Grammar),
+    }?;
+
+    // Check if the json is a valid JSONSchema
+    jsonschema::draft202012::meta::validate(&json)
+        .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
+
+    // The schema can be valid but lack properties.
+    // We need properties for the grammar to be successfully parsed in Python.
+    // Therefore, we must check and throw an error if properties are missing.
+    json.get("properties")
+        .ok_or(ValidationError::InvalidGrammar(
+            "Grammar must have a 'properties' field".to_string(),
+        ))?;
+
+    // Do compilation in the router for performance. In the future, we
+    // should also move regex -> automaton compilation in the router,
+    // but this is not yet supported in pure Rust by outlines-core.
+    let grammar_regex =
+        json_schema_to_regex(&json, None, &json).map_err(ValidationError::RegexFromSchema)?;
+
+    Ok(ValidGrammar::Regex(grammar_regex.to_string()))
+}
+
 #[derive(Debug, Clone)]
 pub enum ValidGrammar {
     Json(String),
@@ -899,12 +1007,38 @@ pub struct ValidGenerateRequest {
     pub truncate: u32,
     pub add_special_tokens: bool,
     pub decoder_input_details: bool,
+    /// Whether the energy of the request is measured
+    pub measure_energy: bool,
+    /// Scheduling hint, backends are free to ignore it
+    pub energy_priority: EnergyPriority,
     pub parameters: ValidParameters,
     pub stopping_parameters: ValidStoppingParameters,
     pub top_n_tokens: u32,
+    /// Number of raw `(id, logprob)` pairs to return at each step
+    pub top_logprobs: u32,
     pub adapter_id: Option<String>,
 }
 
+/// Request that passed the checks not requiring the tokenizer, see
+/// [`Validation::check_parameters`]
+#[derive(Debug)]
+pub(crate) struct CheckedRequest {
+    inputs: String,
+    add_special_tokens: bool,
+    truncate: Option<usize>,
+    max_new_tokens: Option<u32>,
+    continue_on_length: bool,
+    max_total_new_tokens: Option<u32>,
+    decoder_input_details: bool,
+    measure_energy: bool,
+    energy_priority: EnergyPriority,
+    parameters: ValidParameters,
+    stop_sequences: Vec<String>,
+    top_n_tokens: u32,
+    top_logprobs: u32,
+    adapter_id: Option<String>,
+}
+
 #[derive(Error, Debug)]
 pub enum ValidationError {
     #[error("`best_of` must be > 0 and <= {0}. Given: {1}")]
@@ -917,8 +1051,16 @@ pub enum ValidationError {
     BestOfSeed,
     #[error("`best_of` != 1 is not supported when streaming tokens")]
     BestOfStream,
+    #[error("`n` must be > 0 and <= {0}. Given: {1}")]
+    N(usize, usize),
+    #[error("`iterations` must be > 0 and <= {0}. Given: {1}")]
+    BenchmarkIterations(u32, u32),
+    #[error("`n` > 1 is not supported {0}")]
+    NUnsupported(&'static str),
     #[error("`top_n_tokens` must be >= 0 and <= {0}. Given: {1}")]
     TopNTokens(u32, u32),
+    #[error("`top_logprobs` must be >= 0 and <= {0}. Given: {1}")]
+    TopLogprobs(u32, u32),
     #[error("`top_n_tokens` != 0 is not allowed for this endpoint")]
     TopNTokensDisabled,
     #[error("`decoder_input_details` == true is not supported when streaming tokens")]
@@ -941,12 +1083,20 @@ pub enum ValidationError {
     UnsetMaxNewTokens,
     #[error("`max_new_tokens` must be strictly positive")]
     NegativeMaxNewTokens,
+    #[error("`max_total_new_tokens` must be strictly positive")]
+    NegativeMaxTotalNewTokens,
     #[error("`max_new_tokens` must be <= {0}. Given: {1}")]
     MaxNewTokens(usize, u32),
+    #[error("`max_energy_millijoules` must be strictly positive")]
+    MaxEnergy,
+    #[error("`max_energy_millijoules` requires energy tracking, which is disabled")]
+    EnergyBudgetUnavailable,
     #[error("`inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
     MaxTotalTokens(usize, usize, u32),
     #[error("`inputs` must have less than {0} tokens. Given: {1}")]
     InputLength(usize, usize),
+    #[error("`inputs` must be at most {0} bytes. Given: {1}")]
+    InputBytes(usize, usize),
     #[error("`inputs` cannot be empty")]
     EmptyInput,
     #[error("`stop` supports up to {0} stop sequences. Given: {1}")]
@@ -971,6 +1121,8 @@ pub enum ValidationError {
     FailedFetchImage(#[from] reqwest::Error),
     #[error("{0} modality is not supported")]
     UnsupportedModality(&'static str),
+    #[error("`Idempotency-Key` was already used for a different request")]
+    IdempotencyKeyReused,
 }
 
 #[cfg(test)]
@@ -980,6 +1132,46 @@ mod tests {
     use crate::default_parameters;
     use crate::tests::get_tokenizer;
 
+    #[test]
+    fn test_json_mode_grammar() {
+        let request: GenerateParameters =
+            serde_json::from_value(serde_json::json!({"grammar": {"type": "json_object"}}))
+                .unwrap();
+        let grammar = request.grammar.unwrap();
+        assert_eq!(grammar, GrammarType::JsonObject(None));
+
+        let ValidGrammar::Regex(regex) = validate_grammar(grammar).unwrap() else {
+            panic!("JSON mode must compile to a regex grammar");
+        };
+        let regex = regex::Regex::new(&format!("^(?:{regex})$")).unwrap();
+        assert!(regex.is_match(r#"{"location": "Paris", "days": 3}"#));
+        assert!(!regex.is_match("not json"));
+    }
+
+    #[test]
+    fn test_json_mode_with_schema() {
+        let schema = serde_json::json!({"properties": {"location": {"type": "string"}}});
+        let json_mode = validate_grammar(GrammarType::JsonObject(Some(schema.clone()))).unwrap();
+        let json = validate_grammar(GrammarType::Json(schema)).unwrap();
+        match (json_mode, json) {
+            (ValidGrammar::Regex(json_mode), ValidGrammar::Regex(json)) => {
+                assert_eq!(json_mode, json)
+            }
+            _ => panic!("Expected regex grammars"),
+        }
+    }
+
+    #[test]
+    fn test_regex_grammar() {
+        let grammar = validate_grammar(GrammarType::Regex("[0-9]{3}-[a-z]+".to_string()));
+        assert!(matches!(grammar, Ok(ValidGrammar::Regex(regex)) if regex == "[0-9]{3}-[a-z]+"));
+
+        assert!(matches!(
+            validate_grammar(GrammarType::Regex("([0-9]".to_string())),
+            Err(ValidationError::InvalidGrammar(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_validation_max_new_tokens() {
         let tokenizer = get_tokenizer();
@@ -1006,7 +1198,14 @@ mod tests {
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), true, None, Some(max_new_tokens))
+            .validate_input(
+                "Hello".to_string(),
+                true,
+                None,
+                Some(max_new_tokens),
+                false,
+                None,
+            )
             .await
         {
             Err(ValidationError::MaxTotalTokens(6, 1, 10)) => (),
@@ -1041,7 +1240,14 @@ mod tests {
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), true, None, Some(max_new_tokens))
+            .validate_input(
+                "Hello".to_string(),
+                true,
+                None,
+                Some(max_new_tokens),
+                false,
+                None,
+            )
             .await
         {
             Err(ValidationError::MaxTotalTokens(6, 1, 10)) => (),
@@ -1076,6 +1282,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     best_of: Some(2),
                     do_sample: false,
@@ -1116,6 +1323,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     top_p: Some(1.0),
                     max_new_tokens: Some(5),
@@ -1132,6 +1340,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     top_p: Some(0.99),
                     max_new_tokens: Some(5),
@@ -1148,6 +1357,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     top_p: None,
                     max_new_tokens: Some(5),
@@ -1187,6 +1397,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(5),
                     max_new_tokens: Some(5),
@@ -1203,6 +1414,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(4),
                     max_new_tokens: Some(5),
@@ -1216,6 +1428,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(0),
                     max_new_tokens: Some(5),
@@ -1229,6 +1442,7 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     top_n_tokens: None,
                     max_new_tokens: Some(5),
@@ -1,5 +1,5 @@
 use crate::infer::Infer;
-use crate::server::{generate_internal, ComputeType};
+use crate::server::{api_key, generate_internal, ComputeType};
 use crate::{ChatRequest, ErrorResponse, GenerateParameters, GenerateRequest};
 use axum::extract::Extension;
 use axum::http::{HeaderMap, StatusCode};
@@ -70,6 +70,7 @@ example = json ! ({"error": "Incomplete generation"})),
 pub(crate) async fn vertex_compatibility(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
+    headers: HeaderMap,
     Json(req): Json<VertexRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
@@ -82,6 +83,8 @@ pub(crate) async fn vertex_compatibility(
             Json(ErrorResponse {
                 error: "Input validation error".to_string(),
                 error_type: "Input validation error".to_string(),
+                energy_consumption: None,
+                template_error: None,
             }),
         ));
     }
@@ -94,18 +97,21 @@ pub(crate) async fn vertex_compatibility(
             VertexInstance::Generate(instance) => GenerateRequest {
                 inputs: instance.inputs.clone(),
                 add_special_tokens: true,
+                metadata: None,
                 parameters: GenerateParameters {
                     do_sample: true,
                     max_new_tokens: instance.parameters.as_ref().and_then(|p| p.max_new_tokens),
                     seed: instance.parameters.as_ref().and_then(|p| p.seed),
                     details: true,
                     decoder_input_details: true,
+                    api_key: api_key(&headers),
                     ..Default::default()
                 },
             },
             VertexInstance::Chat(instance) => {
-                let (generate_request, _using_tools): (GenerateRequest, bool) =
+                let (mut generate_request, _using_tools, _template_name) =
                     instance.try_into_generate(&infer)?;
+                generate_request.parameters.api_key = api_key(&headers);
                 generate_request
             }
         };
@@ -120,6 +126,7 @@ pub(crate) async fn vertex_compatibility(
                 compute_type_clone,
                 Json(generate_request),
                 span_clone,
+                None,
             )
             .await
             .map(|(_, _, Json(generation))| generation.generated_text)
@@ -129,6 +136,8 @@ pub(crate) async fn vertex_compatibility(
                     Json(ErrorResponse {
                         error: "Incomplete generation".into(),
                         error_type: "Incomplete generation".into(),
+                        energy_consumption: None,
+                        template_error: None,
                     }),
                 )
             })